@@ -12,12 +12,66 @@
 // Example:
 // *2\r\n$4\r\nPING\r\n$4\r\nTEST\r\n
 
+// Besides PING, GET and SET (with an optional `PX ms` or `EX s` expiry), it supports
+// WATCH / MULTI / EXEC / DISCARD. That is enough for the optimistic compare-and-set the
+// middleware servers' shared rate limiter does, so this can stand in for Redis locally.
+
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 
-type Db = Arc<Mutex<HashMap<String, String>>>;
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+    // Changes on every write, so EXEC can tell whether a WATCHed key was touched.
+    version: u64,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    writes: u64,
+}
+
+impl Store {
+    /// The entry under `key`, dropping it first if it has expired.
+    fn live(&mut self, key: &str) -> Option<&Entry> {
+        let now = Instant::now();
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.expires_at.is_some_and(|at| at <= now))
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get(key)
+    }
+
+    fn version(&mut self, key: &str) -> Option<u64> {
+        self.live(key).map(|entry| entry.version)
+    }
+
+    fn set(&mut self, key: &str, value: &str, ttl: Option<Duration>) {
+        self.writes += 1;
+        let entry = Entry {
+            value: value.to_string(),
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            version: self.writes,
+        };
+        self.entries.insert(key.to_string(), entry);
+    }
+}
+
+type Db = Arc<Mutex<Store>>;
+
+/// What one client has WATCHed, and the commands it queued since MULTI.
+#[derive(Default)]
+struct Session {
+    watched: Vec<(String, Option<u64>)>,
+    queued: Option<Vec<Vec<String>>>,
+}
 
 /// Parse a RESP command into a vector of strings
 async fn parse_resp(stream: &mut (impl AsyncBufReadExt + Unpin)) -> Option<Vec<String>> {
@@ -49,34 +103,106 @@ async fn parse_resp(stream: &mut (impl AsyncBufReadExt + Unpin)) -> Option<Vec<S
     Some(parts)
 }
 
+/// The expiry in SET's trailing `PX ms` or `EX s`, `Ok(None)` if there is none.
+fn set_ttl(options: &[String]) -> Result<Option<Duration>, ()> {
+    match options {
+        [] => Ok(None),
+        [unit, amount] => {
+            let amount: u64 = amount.parse().map_err(|_| ())?;
+            match unit.to_uppercase().as_str() {
+                "PX" => Ok(Some(Duration::from_millis(amount))),
+                "EX" => Ok(Some(Duration::from_secs(amount))),
+                _ => Err(()),
+            }
+        }
+        _ => Err(()),
+    }
+}
+
+/// Runs a plain (non-transaction) command.
+fn execute(store: &mut Store, cmd: &[String]) -> String {
+    match cmd[0].to_uppercase().as_str() {
+        "PING" => {
+            if cmd.len() > 1 {
+                format!("+{}\r\n", cmd[1])
+            } else {
+                "+PONG\r\n".to_string()
+            }
+        }
+        "SET" if cmd.len() >= 3 => match set_ttl(&cmd[3..]) {
+            Ok(ttl) => {
+                store.set(&cmd[1], &cmd[2], ttl);
+                "+OK\r\n".to_string()
+            }
+            Err(()) => "-ERR syntax error\r\n".to_string(),
+        },
+        "GET" if cmd.len() == 2 => match store.live(&cmd[1]) {
+            Some(entry) => format!("${}\r\n{}\r\n", entry.value.len(), entry.value),
+            None => "$-1\r\n".to_string(), // Null bulk string
+        },
+        _ => "-ERR unknown command\r\n".to_string(),
+    }
+}
+
+fn respond(db: &Db, session: &mut Session, cmd: Vec<String>) -> String {
+    let name = cmd[0].to_uppercase();
+    match (name.as_str(), session.queued.is_some()) {
+        ("MULTI", true) => "-ERR MULTI calls can not be nested\r\n".to_string(),
+        ("MULTI", false) => {
+            session.queued = Some(Vec::new());
+            "+OK\r\n".to_string()
+        }
+        ("WATCH", true) => "-ERR WATCH inside MULTI is not allowed\r\n".to_string(),
+        ("WATCH", false) if cmd.len() > 1 => {
+            let mut store = db.lock().unwrap();
+            for key in &cmd[1..] {
+                session.watched.push((key.clone(), store.version(key)));
+            }
+            "+OK\r\n".to_string()
+        }
+        ("EXEC" | "DISCARD", false) => format!("-ERR {name} without MULTI\r\n"),
+        ("DISCARD", true) => {
+            session.queued = None;
+            session.watched.clear();
+            "+OK\r\n".to_string()
+        }
+        ("EXEC", true) => {
+            let queued = session.queued.take().unwrap_or_default();
+            let watched = std::mem::take(&mut session.watched);
+            let mut store = db.lock().unwrap();
+            // A WATCHed key changed (or expired) since: nothing runs, and the client
+            // gets a null array to tell it to try again.
+            if watched
+                .iter()
+                .any(|(key, version)| store.version(key) != *version)
+            {
+                return "*-1\r\n".to_string();
+            }
+            let mut response = format!("*{}\r\n", queued.len());
+            for cmd in &queued {
+                response.push_str(&execute(&mut store, cmd));
+            }
+            response
+        }
+        (_, true) => {
+            session.queued.get_or_insert_default().push(cmd);
+            "+QUEUED\r\n".to_string()
+        }
+        (_, false) => execute(&mut db.lock().unwrap(), &cmd),
+    }
+}
+
 /// Handle a single TCP client
 async fn handle_client(mut socket: TcpStream, db: Db) {
     let (reader, mut writer) = socket.split();
     let mut reader = BufReader::new(reader);
+    let mut session = Session::default();
 
     while let Some(cmd) = parse_resp(&mut reader).await {
-        let response = match cmd[0].to_uppercase().as_str() {
-            "PING" => {
-                if cmd.len() > 1 {
-                    format!("+{}\r\n", cmd[1])
-                } else {
-                    "+PONG\r\n".to_string()
-                }
-            }
-            "SET" if cmd.len() == 3 => {
-                let mut store = db.lock().unwrap();
-                store.insert(cmd[1].clone(), cmd[2].clone());
-                "+OK\r\n".to_string()
-            }
-            "GET" if cmd.len() == 2 => {
-                let store = db.lock().unwrap();
-                match store.get(&cmd[1]) {
-                    Some(val) => format!("${}\r\n{}\r\n", val.len(), val),
-                    None => "$-1\r\n".to_string(), // Null bulk string
-                }
-            }
-            _ => "-ERR unknown command\r\n".to_string(),
-        };
+        if cmd.is_empty() {
+            break;
+        }
+        let response = respond(&db, &mut session, cmd);
 
         if writer.write_all(response.as_bytes()).await.is_err() {
             break;
@@ -84,12 +210,9 @@ async fn handle_client(mut socket: TcpStream, db: Db) {
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    let db: Db = Arc::new(Mutex::new(HashMap::new()));
-
-    println!("Mini Redis running on 127.0.0.1:6379");
+/// Serves clients from `listener` until accepting fails. They all share one keyspace.
+pub async fn serve(listener: TcpListener) -> std::io::Result<()> {
+    let db: Db = Arc::new(Mutex::new(Store::default()));
 
     loop {
         let (socket, _) = listener.accept().await?;
@@ -99,3 +222,13 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 }
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+
+    println!("Mini Redis running on 127.0.0.1:6379");
+
+    serve(listener).await?;
+    Ok(())
+}
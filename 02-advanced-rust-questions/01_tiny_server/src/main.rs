@@ -1,14 +1,15 @@
 use anyhow::Ok;
 use std::collections::HashMap;
 use std::str;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 
 mod routes;
 mod utils;
 
-mod rate_limiter;
-use rate_limiter::RateLimiter;
+mod ratelimit;
+use ratelimit::{Algorithm, Limiter};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -17,7 +18,20 @@ async fn main() -> anyhow::Result<()> {
     println!("Listening on 0.0.0.0:7878");
 
     // Allow burst of 5 requests, refill 1 request per second
-    let limiter = RateLimiter::new(5.0, 1.0);
+    let limiter = Arc::new(Limiter::new(Algorithm::TokenBucket {
+        capacity: 5,
+        refill_per_sec: 1.0,
+    }));
+
+    // `check` only sweeps when requests come in; this frees the buckets once traffic stops.
+    let sweeper = limiter.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            sweeper.evict_idle();
+        }
+    });
 
     loop {
         let (mut socket, addr) = listener.accept().await?;
@@ -26,10 +40,29 @@ async fn main() -> anyhow::Result<()> {
         let ip = addr.ip().to_string();
 
         tokio::spawn(async move {
-            if !limiter.check(&ip).await {
-                let _ = socket
-                    .write_all(b"HTTP/1.1 429 Too Many Requests\r\n\r\nRate limit exceeded")
-                    .await;
+            let decision = limiter.check(&ip);
+            if !decision.allowed {
+                // Rounded up so the client never retries too early.
+                let ceil = |d: std::time::Duration| d.as_secs() + u64::from(d.subsec_nanos() > 0);
+                let limit = decision.limit.to_string();
+                let reset = ceil(decision.reset).to_string();
+                let retry = decision
+                    .retry_after
+                    .map_or(1, |d| ceil(d).max(1))
+                    .to_string();
+                let headers = [
+                    ("RateLimit-Limit", limit.as_str()),
+                    ("RateLimit-Remaining", "0"),
+                    ("RateLimit-Reset", reset.as_str()),
+                    ("Retry-After", retry.as_str()),
+                ];
+                let response = utils::response_with_headers(
+                    429,
+                    "Too Many Requests",
+                    &headers,
+                    "Rate limit exceeded",
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
                 println!("Throttled request from {}", ip);
                 return;
             }
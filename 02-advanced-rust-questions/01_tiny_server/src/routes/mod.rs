@@ -5,22 +5,61 @@ mod router;
 pub mod time;
 mod users;
 
-use crate::utils::simple_response;
+use crate::utils::{response_with_headers, simple_response};
+use router::{Lookup, Router};
 use std::collections::HashMap;
+use std::sync::LazyLock;
+
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    Root,
+    Time,
+    Echo,
+    Json,
+    User,
+}
+
+static ROUTES: LazyLock<Router<Route>> = LazyLock::new(|| {
+    Router::new()
+        .route("GET", "/", Route::Root)
+        .route("GET", "/time", Route::Time)
+        .route("POST", "/echo", Route::Echo)
+        .route("POST", "/json", Route::Json)
+        .route("GET", "/users/:id", Route::User)
+});
 
 pub fn router(method: &str, path: &str, headers: &HashMap<String, String>, body: &str) -> String {
-    match (method, path) {
-        ("GET", "/") => root::handle(),
-        ("GET", "/time") => time::handle(),
-        ("POST", "/echo") => echo::handle(body),
-        ("POST", "/json") => json::handle(headers, body),
-        _ => {
-            // dynamic route check: /users/:id
-            if let Some(params) = router::match_route("/users/:id", path) {
-                return users::handle_get(params);
-            }
+    let (route, params) = match ROUTES.lookup(method, path) {
+        Lookup::Found { handler, params } => (*handler, params),
+        Lookup::Options { allow } => {
+            return response_with_headers(204, "No Content", &[("Allow", &allow)], "")
+        }
+        Lookup::MethodNotAllowed { allow } => {
+            return response_with_headers(
+                405,
+                "Method Not Allowed",
+                &[("Allow", &allow)],
+                "Method not allowed\n",
+            )
+        }
+        Lookup::NotFound => return simple_response(404, "Not Found", "Route not found\n"),
+    };
+
+    let response = match route {
+        Route::Root => root::handle(),
+        Route::Time => time::handle(),
+        Route::Echo => echo::handle(body),
+        Route::Json => json::handle(headers, body),
+        Route::User => users::handle_get(params),
+    };
 
-            simple_response(404, "Not Found", "Route not found\n")
+    // HEAD gets the GET response with the body cut off after the headers.
+    if method.eq_ignore_ascii_case("HEAD") {
+        match response.find("\r\n\r\n") {
+            Some(end) => response[..end + 4].to_string(),
+            None => response,
         }
+    } else {
+        response
     }
 }
@@ -1,22 +1,249 @@
+// Method + path router shared by the servers. Handlers are whatever `H` the server
+// registers (usually a plain enum), so the router itself never touches sockets or bodies.
+
 use std::collections::HashMap;
 
-pub fn match_route<'a>(pattern: &str, path: &'a str) -> Option<HashMap<String, String>> {
-    let mut params = HashMap::new();
-    let pattern_parts: Vec<&str> = pattern.split('/').collect();
-    let path_parts: Vec<&str> = path.split('/').collect();
+pub type Params = HashMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    // Higher wins: `/users/me` beats `/users/:id`, which beats `/users/*rest`.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+struct Entry<H> {
+    method: String,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+/// Outcome of matching a request against the route table.
+#[derive(Debug)]
+pub enum Lookup<'a, H> {
+    /// A route matched. For HEAD requests this is the GET route; the caller drops the body.
+    Found {
+        handler: &'a H,
+        params: Params,
+    },
+    /// OPTIONS for a known path without an explicit OPTIONS route.
+    Options {
+        allow: String,
+    },
+    /// The path exists but not for this method.
+    MethodNotAllowed {
+        allow: String,
+    },
+    NotFound,
+}
+
+pub struct Router<H> {
+    entries: Vec<Entry<H>>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Registers `handler` for `method` and `pattern`. Patterns are `/`-separated and may
+    /// contain `:name` segments and a trailing `*name` segment that captures the rest.
+    pub fn route(mut self, method: &str, pattern: &str, handler: H) -> Self {
+        let segments: Vec<Segment> = split_path(pattern)
+            .map(|seg| {
+                if let Some(name) = seg.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = seg.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Static(seg.to_string())
+                }
+            })
+            .collect();
+        assert!(
+            segments
+                .iter()
+                .rev()
+                .skip(1)
+                .all(|s| !matches!(s, Segment::Wildcard(_))),
+            "wildcard must be the last segment in {pattern}"
+        );
+
+        self.entries.push(Entry {
+            method: method.to_ascii_uppercase(),
+            segments,
+            handler,
+        });
+        self
+    }
+
+    pub fn lookup(&self, method: &str, path: &str) -> Lookup<'_, H> {
+        let method = method.to_ascii_uppercase();
+        let path = path.split('?').next().unwrap_or_default();
+        let parts: Vec<&str> = split_path(path).collect();
+
+        let mut best: Option<(Vec<u8>, &Entry<H>, Params)> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+
+        for entry in &self.entries {
+            let Some((rank, params)) = match_segments(&entry.segments, &parts) else {
+                continue;
+            };
+            if !allowed.contains(&entry.method.as_str()) {
+                allowed.push(&entry.method);
+            }
+
+            let method_ok = entry.method == method || (method == "HEAD" && entry.method == "GET");
+            if !method_ok {
+                continue;
+            }
+            // An explicit HEAD route takes precedence over the GET fallback for the same pattern.
+            let better = match &best {
+                None => true,
+                Some((best_rank, best_entry, _)) => {
+                    rank > *best_rank
+                        || (rank == *best_rank
+                            && entry.method == method
+                            && best_entry.method != method)
+                }
+            };
+            if better {
+                best = Some((rank, entry, params));
+            }
+        }
+
+        if let Some((_, entry, params)) = best {
+            return Lookup::Found {
+                handler: &entry.handler,
+                params,
+            };
+        }
+        if allowed.is_empty() {
+            return Lookup::NotFound;
+        }
+
+        if allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+        if !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+        let allow = allowed.join(", ");
+
+        if method == "OPTIONS" {
+            Lookup::Options { allow }
+        } else {
+            Lookup::MethodNotAllowed { allow }
+        }
+    }
+}
+
+// Empty segments are ignored, so `/user`, `/user/` and `//user` are the same path.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn match_segments(segments: &[Segment], parts: &[&str]) -> Option<(Vec<u8>, Params)> {
+    let mut params = Params::new();
+    let mut rank = Vec::with_capacity(segments.len());
+
+    for (i, segment) in segments.iter().enumerate() {
+        rank.push(segment.rank());
+        match segment {
+            Segment::Wildcard(name) => {
+                params.insert(name.clone(), parts.get(i..).unwrap_or_default().join("/"));
+                return Some((rank, params));
+            }
+            Segment::Static(expected) => {
+                if parts.get(i) != Some(&expected.as_str()) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                params.insert(name.clone(), parts.get(i)?.to_string());
+            }
+        }
+    }
+
+    (segments.len() == parts.len()).then_some((rank, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        Router::new()
+            .route("GET", "/users/:id", "user")
+            .route("GET", "/users/me", "me")
+            .route("DELETE", "/users/:id", "delete_user")
+            .route("GET", "/static/*path", "static")
+            .route("POST", "/user", "create")
+    }
+
+    fn found(lookup: Lookup<'_, &'static str>) -> (&'static str, Params) {
+        match lookup {
+            Lookup::Found { handler, params } => (*handler, params),
+            other => panic!("expected a match, got {:?}", other),
+        }
+    }
 
-    if pattern_parts.len() != path_parts.len() {
-        return None;
+    #[test]
+    fn matches_params_and_prefers_static_segments() {
+        let r = router();
+        let (handler, params) = found(r.lookup("GET", "/users/42?verbose=1"));
+        assert_eq!(handler, "user");
+        assert_eq!(params["id"], "42");
+
+        assert_eq!(found(r.lookup("GET", "/users/me")).0, "me");
+        assert!(matches!(r.lookup("GET", "/user/42"), Lookup::NotFound));
+        assert!(matches!(
+            r.lookup("GET", "/users/42/extra"),
+            Lookup::NotFound
+        ));
     }
 
-    for (p_pat, p_path) in pattern_parts.iter().zip(path_parts.iter()) {
-        if p_pat.starts_with(':') {
-            let key = p_pat.trim_start_matches(':').to_string();
-            params.insert(key, (*p_path).to_string());
-        } else if p_pat != p_path {
-            return None;
+    #[test]
+    fn wildcard_captures_the_rest_of_the_path() {
+        let (handler, params) = found(router().lookup("GET", "/static/css/site.css"));
+        assert_eq!(handler, "static");
+        assert_eq!(params["path"], "css/site.css");
+    }
+
+    #[test]
+    fn wrong_method_reports_allowed_methods() {
+        match router().lookup("PUT", "/users/42") {
+            Lookup::MethodNotAllowed { allow } => assert_eq!(allow, "GET, DELETE, HEAD, OPTIONS"),
+            other => panic!("expected 405, got {:?}", other),
         }
     }
 
-    Some(params)
+    #[test]
+    fn head_and_options_are_answered_automatically() {
+        let r = router();
+        assert_eq!(found(r.lookup("HEAD", "/users/7")).0, "user");
+        match r.lookup("OPTIONS", "/user") {
+            Lookup::Options { allow } => assert_eq!(allow, "POST, OPTIONS"),
+            other => panic!("expected OPTIONS, got {:?}", other),
+        }
+    }
 }
@@ -1,8 +1,5 @@
-pub fn split_once<'a>(s: &'a str, delim: char) -> Option<(&'a str, &'a str)> {
-    let mut iter = s.splitn(2, delim);
-    let a = iter.next()?;
-    let b = iter.next()?;
-    Some((a, b))
+pub fn split_once(s: &str, delim: char) -> Option<(&str, &str)> {
+    s.split_once(delim)
 }
 
 pub fn find_header_end(buf: &[u8]) -> Option<usize> {
@@ -10,12 +7,26 @@ pub fn find_header_end(buf: &[u8]) -> Option<usize> {
 }
 
 pub fn simple_response(status: u16, reason: &str, body: &str) -> String {
+    response_with_headers(status, reason, &[], body)
+}
+
+pub fn response_with_headers(
+    status: u16,
+    reason: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> String {
+    let extra: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
     let body_bytes = body.as_bytes();
     format!(
-        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\n\r\n{}",
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\n{}\r\n{}",
         status,
         reason,
         body_bytes.len(),
+        extra,
         body
     )
 }
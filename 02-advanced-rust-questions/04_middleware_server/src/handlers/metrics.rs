@@ -0,0 +1,13 @@
+use serde::Serialize;
+
+use crate::reload::ReloadCounts;
+use crate::types::Response;
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    config_reloads: ReloadCounts,
+}
+
+pub fn handle(config_reloads: ReloadCounts) -> Response {
+    Response::json(200, &MetricsResponse { config_reloads })
+}
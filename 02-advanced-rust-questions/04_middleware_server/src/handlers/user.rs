@@ -1,3 +1,4 @@
+use crate::request::Request;
 use crate::types::Response;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,28 +18,15 @@ struct UserResponse {
     email: String,
 }
 
-pub fn handle(req: &str) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body_str = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<CreateUserRequest>(body_str) {
-            let user = UserResponse {
-                id: Uuid::new_v4().to_string(),
-                name: payload.name,
-                age: payload.age,
-                email: payload.email,
-            };
-            if let Ok(body) = serde_json::to_string(&user) {
-                return Response {
-                    status: 201,
-                    content_type: "application/json".into(),
-                    body,
-                };
-            }
-        }
+pub fn handle(req: &Request) -> Response {
+    if let Ok(payload) = serde_json::from_slice::<CreateUserRequest>(&req.body) {
+        let user = UserResponse {
+            id: Uuid::new_v4().to_string(),
+            name: payload.name,
+            age: payload.age,
+            email: payload.email,
+        };
+        return Response::json(201, &user);
     }
-    return Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
-    };
+    Response::text(400, "Bad Request")
 }
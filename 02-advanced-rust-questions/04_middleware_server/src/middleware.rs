@@ -1,20 +1,26 @@
-use crate::{middlewares, types::Response};
+use crate::request::Request;
+use crate::types::Response;
 
 pub trait Middleware: Send + Sync {
-    fn handle(&self, req: &str, client_ip: &str, next: &dyn Fn(&str) -> Response) -> Response;
+    fn handle(
+        &self,
+        req: &Request,
+        client_ip: &str,
+        next: &dyn Fn(&Request) -> Response,
+    ) -> Response;
 }
 
-pub fn run_chain<'a>(
-    req: &str,
+pub fn run_chain(
+    req: &Request,
     client_ip: &str,
-    middlewares: &'a [&dyn Middleware],
-    handler: impl Fn(&str) -> Response,
+    middlewares: &[&dyn Middleware],
+    handler: impl Fn(&Request) -> Response,
 ) -> Response {
-    fn call<'a>(
-        req: &str,
+    fn call(
+        req: &Request,
         client_ip: &str,
-        middlewares: &'a [&dyn Middleware],
-        handler: &dyn Fn(&str) -> Response,
+        middlewares: &[&dyn Middleware],
+        handler: &dyn Fn(&Request) -> Response,
     ) -> Response {
         if let Some((first, rest)) = middlewares.split_first() {
             first.handle(req, client_ip, &|r| call(r, client_ip, rest, handler))
@@ -1,18 +1,290 @@
+use std::fmt::Write as _;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
 use crate::middleware::Middleware;
-use crate::types::Response;
+use crate::request::Request;
+use crate::router::{Lookup, Router};
+use crate::types::{Body, Response};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The ID that ties together every log line about a request, here and in the services
+/// it calls. Handlers read it with `req.extensions.get::<RequestId>()`, or forward the
+/// `X-Request-Id` header as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// How access-log lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
+/// One access-log line. Field order is the output order.
+#[derive(Debug, Serialize)]
+struct Entry {
+    ts: f64,
+    request_id: String,
+    method: String,
+    route: String,
+    path: String,
+    status: u16,
+    bytes: u64,
+    latency_ms: f64,
+    ip: String,
+}
+
+impl Entry {
+    fn render(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            LogFormat::Logfmt => {
+                let mut line = format!("ts={:.3}", self.ts);
+                for (key, value) in [
+                    ("request_id", self.request_id.as_str()),
+                    ("method", &self.method),
+                    ("route", &self.route),
+                    ("path", &self.path),
+                ] {
+                    let _ = write!(line, " {key}={}", logfmt_value(value));
+                }
+                let _ = write!(
+                    line,
+                    " status={} bytes={} latency_ms={:.3} ip={}",
+                    self.status,
+                    self.bytes,
+                    self.latency_ms,
+                    logfmt_value(&self.ip)
+                );
+                line
+            }
+        }
+    }
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == '"' || c == '=' || c == '\\' || c.is_whitespace() || c.is_control())
+    {
+        return value.to_string();
+    }
+    // Debug formatting quotes the value and escapes quotes, backslashes and control
+    // characters, which is what logfmt parsers expect.
+    format!("{value:?}")
+}
 
-pub struct LoggerMiddleware;
+/// Emits its line when dropped, so a streamed body is logged once it has been sent
+/// (or the client went away), with the bytes that actually went out.
+struct Pending {
+    entry: Entry,
+    format: LogFormat,
+    started: Instant,
+}
+
+impl Pending {
+    fn sent(&mut self, bytes: usize) {
+        self.entry.bytes += bytes as u64;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.entry.latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        println!("{}", self.entry.render(self.format));
+    }
+}
+
+/// Tags each request with an ID and writes one structured line per request. An incoming
+/// `X-Request-Id` is kept when it looks like an ID; otherwise a UUID is generated. The ID
+/// is passed on in the request's headers and extensions and echoed on the response.
+pub struct LoggerMiddleware {
+    templates: Router<String>,
+    format: LogFormat,
+}
+
+impl LoggerMiddleware {
+    /// Logs the route templates registered in `routes` alongside the raw path.
+    pub fn new<H>(routes: &Router<H>) -> Self {
+        let templates = routes
+            .routes()
+            .fold(Router::new(), |router, (method, pattern)| {
+                router.route(method, pattern, pattern.to_string())
+            });
+        LoggerMiddleware {
+            templates,
+            format: LogFormat::Json,
+        }
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn route(&self, req: &Request) -> String {
+        match self.templates.lookup(req.method.as_str(), &req.path) {
+            Lookup::Found { handler, .. } => handler.clone(),
+            // OPTIONS and 405 answers belong to the template whose path matched.
+            Lookup::Options { .. } | Lookup::MethodNotAllowed { .. } => self
+                .templates
+                .routes()
+                .find_map(
+                    |(method, _)| match self.templates.lookup(method, &req.path) {
+                        Lookup::Found { handler, .. } => Some(handler.clone()),
+                        _ => None,
+                    },
+                )
+                .unwrap_or_default(),
+            Lookup::NotFound => "unmatched".to_string(),
+        }
+    }
+}
+
+// IDs end up in log lines and other services' headers, so only short, plain ones are
+// trusted.
+fn valid_request_id(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
 
 impl Middleware for LoggerMiddleware {
-    fn handle(&self, req: &str, client_ip: &str, next: &dyn Fn(&str) -> Response) -> Response {
-        let mut parts = req.split_whitespace();
-        let response = next(req);
-        if let (Some(method), Some(path)) = (parts.next(), parts.next()) {
-            println!(
-                "[Logger] {} {}, status: {}, from {} ",
-                method, path, response.status, client_ip
-            )
+    fn handle(
+        &self,
+        req: &Request,
+        client_ip: &str,
+        next: &dyn Fn(&Request) -> Response,
+    ) -> Response {
+        let started = Instant::now();
+        let id = match req.header(REQUEST_ID_HEADER) {
+            Some(id) if valid_request_id(id) => id.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        let mut req = req.clone();
+        req.headers.remove(REQUEST_ID_HEADER);
+        req.headers.insert(REQUEST_ID_HEADER, &id);
+        req.extensions.insert(RequestId(id.clone()));
+
+        let mut pending = Pending {
+            entry: Entry {
+                ts: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                request_id: id,
+                method: req.method.as_str().to_string(),
+                route: self.route(&req),
+                path: req.path.clone(),
+                status: 0,
+                bytes: 0,
+                latency_ms: 0.0,
+                ip: client_ip.to_string(),
+            },
+            format: self.format,
+            started,
+        };
+        let mut res = next(&req);
+        res.headers
+            .insert(REQUEST_ID_HEADER, &pending.entry.request_id);
+        pending.entry.status = res.status;
+        match std::mem::replace(&mut res.body, Body::Empty) {
+            Body::Stream(stream) => {
+                res.body = Body::Stream(Box::pin(stream.inspect(move |chunk| {
+                    if let Ok(chunk) = chunk {
+                        pending.sent(chunk.len());
+                    }
+                })));
+            }
+            body => {
+                pending.sent(body.len().unwrap_or(0));
+                res.body = body;
+            }
         }
-        response
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::run_chain;
+    use crate::request::{Limits, RequestReader};
+
+    async fn request(headers: &str) -> Request {
+        let raw = format!("GET /hello/bob HTTP/1.1\r\nHost: test\r\n{headers}\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn logger() -> LoggerMiddleware {
+        LoggerMiddleware::new(&Router::new().route("GET", "/hello/:name", ()))
+    }
+
+    // Answers with the ID the handler saw, from the header and from the extensions.
+    fn run(req: &Request) -> Response {
+        run_chain(req, "127.0.0.1", &[&logger()], |req: &Request| {
+            let header = req
+                .header(REQUEST_ID_HEADER)
+                .unwrap_or_default()
+                .to_string();
+            let extension = req.extensions.get::<RequestId>().unwrap().0.clone();
+            Response::text(200, format!("{header} {extension}"))
+        })
+    }
+
+    #[tokio::test]
+    async fn keeps_a_valid_request_id_and_passes_it_on() {
+        let res = run(&request("X-Request-Id: edge-42:7\r\n").await);
+        assert_eq!(res.headers.get("X-Request-Id"), Some("edge-42:7"));
+        assert_eq!(res.body.as_bytes(), Some(&b"edge-42:7 edge-42:7"[..]));
+    }
+
+    #[tokio::test]
+    async fn replaces_missing_or_unsafe_request_ids() {
+        for headers in ["", "X-Request-Id: a b\"c\r\n"] {
+            let res = run(&request(headers).await);
+            let id = res.headers.get("X-Request-Id").unwrap();
+            assert!(Uuid::parse_str(id).is_ok(), "{id}");
+            assert_eq!(res.body.as_bytes(), Some(format!("{id} {id}").as_bytes()));
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_json_and_logfmt_lines() {
+        let logger = logger();
+        let entry = Entry {
+            ts: 1.5,
+            request_id: "r1".into(),
+            method: "GET".into(),
+            route: logger.route(&request("").await),
+            path: "/hello/bob".into(),
+            status: 200,
+            bytes: 9,
+            latency_ms: 0.25,
+            ip: "10.0.0.1".into(),
+        };
+        assert_eq!(
+            entry.render(LogFormat::Json),
+            r#"{"ts":1.5,"request_id":"r1","method":"GET","route":"/hello/:name","path":"/hello/bob","status":200,"bytes":9,"latency_ms":0.25,"ip":"10.0.0.1"}"#
+        );
+        assert_eq!(
+            entry.render(LogFormat::Logfmt),
+            "ts=1.500 request_id=r1 method=GET route=/hello/:name path=/hello/bob status=200 bytes=9 latency_ms=0.250 ip=10.0.0.1"
+        );
+        assert_eq!(logfmt_value("a \"b\""), r#""a \"b\"""#);
+        assert_eq!(logfmt_value(""), r#""""#);
     }
 }
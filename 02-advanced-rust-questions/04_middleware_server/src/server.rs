@@ -1,58 +1,115 @@
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 
+use crate::config::ServerConfig;
 use crate::handlers;
-use crate::middleware::{self, Middleware};
+use crate::middleware;
 use crate::middlewares::logger::LoggerMiddleware;
-use crate::middlewares::rate_limiting::TokenBucketMiddleware;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::middlewares::rate_limiting::{KeyBy, RateLimitMiddleware, Rule};
+use crate::reload::{evict_idle_buckets, Chain, Reloader};
+use crate::request::{Limits, Method, Request, RequestReader};
+use crate::router::{Lookup, Router};
+use arc_swap::ArcSwap;
 use tokio::net::TcpListener;
 
 use crate::types::Response;
 
-pub async fn run() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:7878").await?;
-    println!("Listening on port 7878");
+pub async fn run(reloader: Reloader) -> anyhow::Result<()> {
+    let reloader = Arc::new(reloader);
+    let config = reloader.config();
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!("Listening on {}", config.listen);
 
-    let middlewares: Vec<Arc<dyn Middleware>> = vec![
-        Arc::new(LoggerMiddleware),
-        Arc::new(TokenBucketMiddleware::new(5, 1)),
-    ];
+    let chain = Arc::new(ArcSwap::from_pointee(build_chain(&config, None)));
+    evict_idle_buckets(chain.clone());
+    {
+        let chain = chain.clone();
+        reloader.watch(move |_, config| {
+            let previous = chain.load();
+            chain.store(Arc::new(build_chain(config, Some(&previous))));
+        });
+    }
 
     loop {
         let (mut socket, addr) = listener.accept().await?;
-        let middlewares = middlewares.clone();
+        // The request is served by the chain that was current when it arrived.
+        let chain = chain.load_full();
+        let reloader = reloader.clone();
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            if let Ok(n) = socket.read(&mut buf).await {
-                if n == 0 {
+            let mut reader = RequestReader::new(Limits::default());
+            let req = match reader.read_request(&mut socket).await {
+                Ok(Some(req)) => req,
+                Ok(None) => return,
+                Err(err) => {
+                    let _ = err.into_response().write_to(&mut socket).await;
                     return;
                 }
+            };
 
-                let req = String::from_utf8_lossy(&buf[..n]);
-                let middleware_ref: Vec<&dyn Middleware> =
-                    middlewares.iter().map(|m| m.as_ref()).collect();
-                let res =
-                    middleware::run_chain(&req, &(addr.ip().to_string()), &middleware_ref, |req| {
-                        route_request(req)
-                    });
-                let _ = socket.write_all(res.into_http().as_bytes()).await;
-            }
+            let middleware_ref = chain.middlewares();
+            let res = middleware::run_chain(&req, &addr.ip().to_string(), &middleware_ref, |req| {
+                route_request(req, &reloader)
+            });
+            let _ = res.write_to(&mut socket).await;
         });
     }
 }
 
-pub fn route_request(req: &str) -> Response {
-    if req.starts_with("GET /health") {
-        handlers::health::handle()
-    } else if req.starts_with("GET /hello/") {
-        handlers::hello::handle(req)
-    } else if req.starts_with("POST /user") {
-        handlers::user::handle(req)
-    } else {
-        Response {
-            status: 404,
-            content_type: "text/plain".into(),
-            body: "Not Found".into(),
+/// Builds the middleware chain `config` asks for. The rate limiter takes over the buckets
+/// of the one in `previous`, so a reload does not reset anyone's quota.
+fn build_chain(config: &ServerConfig, previous: Option<&Chain>) -> Chain {
+    let limits = &config.rate_limit;
+    let enabled = &config.middlewares;
+    let mut chain = Chain::new();
+    if enabled.logger {
+        chain.push(Arc::new(
+            LoggerMiddleware::new(&ROUTES).format(config.log_format),
+        ));
+    }
+    if enabled.rate_limit {
+        let mut limit = RateLimitMiddleware::new(Rule::new(limits.ip.algorithm(), KeyBy::Ip))
+            .exempt("GET", "/health");
+        for route in &limits.routes {
+            limit = limit.rule(&route.method, &route.path, route.rule());
         }
+        chain.push_rate_limit("ip", limit, previous);
+    }
+    chain
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    Health,
+    Hello,
+    CreateUser,
+    Metrics,
+}
+
+static ROUTES: LazyLock<Router<Route>> = LazyLock::new(|| {
+    Router::new()
+        .route("GET", "/health", Route::Health)
+        .route("GET", "/hello/:name", Route::Hello)
+        .route("POST", "/user", Route::CreateUser)
+        .route("GET", "/api/metrics", Route::Metrics)
+});
+
+pub fn route_request(req: &Request, reloader: &Reloader) -> Response {
+    let (route, params) = match ROUTES.lookup(req.method.as_str(), &req.path) {
+        Lookup::Found { handler, params } => (*handler, params),
+        Lookup::Options { allow } => return Response::options(&allow),
+        Lookup::MethodNotAllowed { allow } => return Response::method_not_allowed(&allow),
+        Lookup::NotFound => return Response::not_found(),
+    };
+
+    let res = match route {
+        Route::Health => handlers::health::handle(),
+        Route::Hello => handlers::hello::handle(req, &params),
+        Route::CreateUser => handlers::user::handle(req),
+        Route::Metrics => handlers::metrics::handle(reloader.counts()),
+    };
+
+    if req.method == Method::Head {
+        res.without_body()
+    } else {
+        res
     }
 }
@@ -0,0 +1,22 @@
+use serde::Serialize;
+
+use crate::pool::mongo::MongoManager;
+use crate::pool::{Pool, PoolStats};
+use crate::reload::ReloadCounts;
+use crate::types::Response;
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    pool: PoolStats,
+    config_reloads: ReloadCounts,
+}
+
+pub async fn handle(pool: &Pool<MongoManager>, config_reloads: ReloadCounts) -> Response {
+    Response::json(
+        200,
+        &MetricsResponse {
+            pool: pool.stats(),
+            config_reloads,
+        },
+    )
+}
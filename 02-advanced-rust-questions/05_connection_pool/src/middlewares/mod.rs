@@ -1,2 +1,3 @@
+pub mod compression;
 pub mod logger;
 pub mod rate_limiting;
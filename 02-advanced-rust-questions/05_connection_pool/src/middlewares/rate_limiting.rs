@@ -1,52 +1,460 @@
 use std::collections::HashMap;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::middleware::Middleware;
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::ratelimit::resp::RespStore;
+use crate::ratelimit::store::{MemoryStore, SharedLimiter, Store};
+use crate::ratelimit::{Algorithm, Clock, Decision, Limiter, SystemClock, WallClock};
+use crate::request::Request;
+use crate::router::{Lookup, Router};
 use crate::types::Response;
+use serde::{Deserialize, Serialize};
 
-pub struct TokenBucketMiddleware {
-    capacity: u32,
-    refill_rate: u32,
-    buckets: Mutex<HashMap<String, (u32, std::time::Instant)>>,
+/// Id of the authenticated caller, for `KeyBy::Principal`. Whatever authenticates the
+/// request (the JWT middleware, where there is one) inserts it into the extensions.
+#[derive(Debug, Clone)]
+pub struct Identity(pub String);
+
+/// What a rule counts requests against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyBy {
+    Ip,
+    /// The authenticated caller; anonymous requests fall back to their IP.
+    Principal,
+    /// The `X-API-Key` header; requests without one fall back to their IP.
+    ApiKey,
+    /// One budget shared by everyone hitting the route.
+    Route,
 }
 
-impl TokenBucketMiddleware {
-    pub fn new(capacity: u32, refill_rate: u32) -> Self {
-        TokenBucketMiddleware {
-            capacity,
-            refill_rate,
-            buckets: Mutex::new(HashMap::new()),
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    pub algorithm: Algorithm,
+    pub key: KeyBy,
+}
+
+impl Rule {
+    pub fn new(algorithm: Algorithm, key: KeyBy) -> Self {
+        Rule { algorithm, key }
+    }
+}
+
+/// Where the limiters keep their counts.
+#[derive(Clone)]
+pub enum Backing {
+    /// In this process only.
+    Local,
+    /// In a store shared by `instances` servers, so the limits hold across all of them.
+    /// While the store is unreachable each server allows its 1/`instances` share.
+    Shared {
+        store: Arc<dyn Store>,
+        instances: u32,
+    },
+}
+
+impl Backing {
+    /// Shares limits through the RESP store at `store` (Redis or a compatible
+    /// server) among `instances` servers, giving up on an update after `timeout`.
+    /// `"memory"` keeps them in a `MemoryStore` in this process instead, and
+    /// without a store they are per process too.
+    pub fn from_store(store: Option<&str>, instances: u32, timeout: Duration) -> Self {
+        match store {
+            Some("memory") => Backing::Shared {
+                store: Arc::new(MemoryStore::new()),
+                instances,
+            },
+            Some(addr) => Backing::Shared {
+                store: Arc::new(RespStore::new(addr, timeout)),
+                instances,
+            },
+            None => Backing::Local,
+        }
+    }
+}
+
+enum Backend {
+    Local(Limiter),
+    Shared(Arc<SharedLimiter>),
+}
+
+struct Limited {
+    // "*" for the default rule, "{method} {pattern}" for the others.
+    name: String,
+    key: KeyBy,
+    backend: Backend,
+}
+
+/// Applies the rule registered for the request's route, or the default rule, and answers
+/// 429 once it is exhausted. Every limited response carries `RateLimit-*` headers.
+pub struct RateLimitMiddleware {
+    clock: Arc<dyn Clock>,
+    backing: Backing,
+    scope: String,
+    default: Option<Limited>,
+    // `None` marks a route as exempt.
+    rules: Router<Option<Limited>>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(default: Rule) -> Self {
+        Self::with_clock(default, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(default: Rule, clock: Arc<dyn Clock>) -> Self {
+        Self::build("", default, Backing::Local, clock)
+    }
+
+    /// `scope` names this middleware's keys in a shared store, so every rate-limit
+    /// middleware in a chain needs its own.
+    pub fn with_backing(scope: &str, default: Rule, backing: Backing) -> Self {
+        match backing {
+            Backing::Local => Self::new(default),
+            // Shared state is compared across processes, so it needs wall-clock time.
+            shared => Self::build(scope, default, shared, Arc::new(WallClock)),
+        }
+    }
+
+    fn build(scope: &str, default: Rule, backing: Backing, clock: Arc<dyn Clock>) -> Self {
+        let mut limit = RateLimitMiddleware {
+            clock,
+            backing,
+            scope: scope.to_string(),
+            default: None,
+            rules: Router::new(),
+        };
+        limit.default = Some(limit.limited("*", default));
+        limit
+    }
+
+    /// Gives `method` + `pattern` its own rule, with its own buckets.
+    pub fn rule(mut self, method: &str, pattern: &str, rule: Rule) -> Self {
+        let limited = self.limited(&format!("{method} {pattern}"), rule);
+        self.rules = self.rules.route(method, pattern, Some(limited));
+        self
+    }
+
+    /// Lets `method` + `pattern` through without counting it.
+    pub fn exempt(mut self, method: &str, pattern: &str) -> Self {
+        self.rules = self.rules.route(method, pattern, None);
+        self
+    }
+
+    /// Forgets every local bucket that has fully recovered. Each limiter also does this on
+    /// its own as requests come in; this is for callers that want to sweep on a timer.
+    /// Shared state expires in the store instead.
+    pub fn evict_idle(&self) -> usize {
+        self.limiteds()
+            .map(|l| match &l.backend {
+                Backend::Local(limiter) => limiter.evict_idle(),
+                Backend::Shared(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Carries `previous`'s local buckets over to the rules that are still registered for
+    /// the same method and pattern, so reloading the limits does not reset anyone's quota.
+    /// Shared state lives in the store under the same names and carries over by itself.
+    pub fn inherit(mut self, previous: &RateLimitMiddleware) -> Self {
+        let old: HashMap<&str, &Limiter> = previous
+            .limiteds()
+            .filter_map(|l| match &l.backend {
+                Backend::Local(limiter) => Some((l.name.as_str(), limiter)),
+                Backend::Shared(_) => None,
+            })
+            .collect();
+        let routes = self.rules.handlers_mut().flatten();
+        for limited in self.default.iter_mut().chain(routes) {
+            if let (Backend::Local(limiter), Some(old)) =
+                (&mut limited.backend, old.get(limited.name.as_str()))
+            {
+                limiter.inherit(old);
+            }
+        }
+        self
+    }
+
+    fn limiteds(&self) -> impl Iterator<Item = &Limited> {
+        self.default.iter().chain(self.rules.handlers().flatten())
+    }
+
+    // `name` keeps each rule's keys apart in a shared store.
+    fn limited(&self, name: &str, rule: Rule) -> Limited {
+        let backend = match &self.backing {
+            Backing::Local => {
+                Backend::Local(Limiter::with_clock(rule.algorithm, self.clock.clone()))
+            }
+            Backing::Shared { store, instances } => Backend::Shared(Arc::new(
+                SharedLimiter::with_clock(
+                    rule.algorithm,
+                    &format!("ratelimit:{}:{name}", self.scope),
+                    store.clone(),
+                    self.clock.clone(),
+                )
+                .fallback(rule.algorithm.split(*instances)),
+            )),
+        };
+        Limited {
+            name: name.to_string(),
+            key: rule.key,
+            backend,
+        }
+    }
+
+    fn limited_for(&self, req: &Request) -> Option<&Limited> {
+        match self.rules.lookup(req.method.as_str(), &req.path) {
+            Lookup::Found { handler, .. } => handler.as_ref(),
+            _ => self.default.as_ref(),
         }
     }
 }
 
-impl Middleware for TokenBucketMiddleware {
+fn key_for(key: KeyBy, req: &Request, client_ip: &str) -> String {
+    let by_ip = || format!("ip:{client_ip}");
+    match key {
+        KeyBy::Ip => by_ip(),
+        KeyBy::Principal => req
+            .extensions
+            .get::<Identity>()
+            .map_or_else(by_ip, |id| format!("sub:{}", id.0)),
+        KeyBy::ApiKey => req
+            .header("x-api-key")
+            .map_or_else(by_ip, |k| format!("key:{k}")),
+        KeyBy::Route => "route".to_string(),
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
     fn handle(
         &self,
-        req: &str,
+        req: &Request,
         client_ip: &str,
-        next: &dyn for<'a> Fn(&'a str) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>>,
-    ) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>> {
-        let mut buckets = self.buckets.lock().unwrap();
-        let (tokens, last) = buckets
-            .entry(client_ip.to_string())
-            .or_insert((self.capacity, std::time::Instant::now()));
-        let elapsed = last.elapsed().as_secs_f64();
-        let refill_token = (elapsed * self.refill_rate as f64) as u32;
-        *tokens = (*tokens + refill_token).min(self.capacity);
-        *last = std::time::Instant::now();
-
-        if *tokens == 0 {
-            let response = Response {
-                status: 429,
-                content_type: "text/plain".into(),
-                body: "Too Many Requests".into(),
-            };
-            return Box::pin(async move { response });
-        }
-        *tokens -= 1;
-        next(req)
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
+        let Some(limited) = self.limited_for(req) else {
+            return next(req);
+        };
+        let key = key_for(limited.key, req, client_ip);
+        match &limited.backend {
+            Backend::Local(limiter) => {
+                let decision = limiter.check(&key);
+                if !decision.allowed {
+                    let response = too_many_requests(&decision);
+                    return Box::pin(async move { response });
+                }
+                let fut = next(req);
+                Box::pin(async move { with_rate_limit_headers(fut.await, &decision) })
+            }
+            Backend::Shared(limiter) => {
+                let limiter = limiter.clone();
+                // The handler only runs once `fut` is polled, which a rejected request
+                // never is.
+                let fut = next(req);
+                Box::pin(async move {
+                    let decision = limiter.check(&key).await;
+                    if !decision.allowed {
+                        return too_many_requests(&decision);
+                    }
+                    with_rate_limit_headers(fut.await, &decision)
+                })
+            }
+        }
+    }
+}
+
+fn too_many_requests(decision: &Decision) -> Response {
+    with_rate_limit_headers(Response::text(429, "Too Many Requests"), decision)
+}
+
+// Header values are whole seconds, rounded up so clients never retry too early.
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+// With several limiters in the chain, the response reports whichever has the least left.
+fn with_rate_limit_headers(mut res: Response, decision: &Decision) -> Response {
+    let inner_remaining = res
+        .headers
+        .get("RateLimit-Remaining")
+        .and_then(|v| v.parse::<u32>().ok());
+    if inner_remaining.is_some_and(|remaining| remaining <= decision.remaining) {
+        return res;
+    }
+    res.headers.remove("Retry-After");
+    let res = res
+        .with_header("RateLimit-Limit", &decision.limit.to_string())
+        .with_header("RateLimit-Remaining", &decision.remaining.to_string())
+        .with_header("RateLimit-Reset", &ceil_secs(decision.reset).to_string());
+    match decision.retry_after {
+        Some(wait) => res.with_header("Retry-After", &ceil_secs(wait).max(1).to_string()),
+        None => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::MockClock;
+    use crate::request::{Limits, RequestReader};
+
+    async fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("{method} {path} HTTP/1.1\r\nHost: test\r\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn call(limit: &RateLimitMiddleware, req: &Request, ip: &str) -> Response {
+        let next =
+            |_: &Request| -> ResponseFuture { Box::pin(async { Response::text(200, "ok") }) };
+        limit.handle(req, ip, &next).await
+    }
+
+    fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+        res.headers.get(name)
+    }
+
+    fn bucket(capacity: u32) -> Algorithm {
+        Algorithm::TokenBucket {
+            capacity,
+            refill_per_sec: 1.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_with_retry_after_once_exhausted() {
+        let clock = MockClock::new();
+        let limit = RateLimitMiddleware::with_clock(Rule::new(bucket(2), KeyBy::Ip), clock.clone());
+        let req = request("GET", "/hello/a", &[]).await;
+
+        let res = call(&limit, &req, "10.0.0.1").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(header(&res, "RateLimit-Limit"), Some("2"));
+        assert_eq!(header(&res, "RateLimit-Remaining"), Some("1"));
+        assert_eq!(header(&res, "Retry-After"), None);
+
+        call(&limit, &req, "10.0.0.1").await;
+        let res = call(&limit, &req, "10.0.0.1").await;
+        assert_eq!(res.status, 429);
+        assert_eq!(header(&res, "Retry-After"), Some("1"));
+        assert_eq!(header(&res, "RateLimit-Reset"), Some("2"));
+
+        // Another client is unaffected, and the first recovers with time.
+        assert_eq!(call(&limit, &req, "10.0.0.2").await.status, 200);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(call(&limit, &req, "10.0.0.1").await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn routes_get_their_own_rules_and_keys() {
+        let clock = MockClock::new();
+        let limit = RateLimitMiddleware::with_clock(Rule::new(bucket(1), KeyBy::Ip), clock)
+            .rule("POST", "/user", Rule::new(bucket(1), KeyBy::Route))
+            .rule("GET", "/hello/:name", Rule::new(bucket(1), KeyBy::ApiKey))
+            .exempt("GET", "/health");
+
+        // The route budget is shared across clients.
+        let post = request("POST", "/user", &[]).await;
+        assert_eq!(call(&limit, &post, "10.0.0.1").await.status, 200);
+        assert_eq!(call(&limit, &post, "10.0.0.2").await.status, 429);
+
+        // Each API key has its own bucket, even from the same address.
+        for key in ["a", "b"] {
+            let req = request("GET", "/hello/x", &[("X-API-Key", key)]).await;
+            assert_eq!(call(&limit, &req, "10.0.0.1").await.status, 200);
+            assert_eq!(call(&limit, &req, "10.0.0.1").await.status, 429);
+        }
+
+        let health = request("GET", "/health", &[]).await;
+        for _ in 0..5 {
+            let res = call(&limit, &health, "10.0.0.1").await;
+            assert_eq!(res.status, 200);
+            assert_eq!(header(&res, "RateLimit-Limit"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn principal_falls_back_to_ip_when_anonymous() {
+        let limit = RateLimitMiddleware::with_clock(
+            Rule::new(bucket(1), KeyBy::Principal),
+            MockClock::new(),
+        );
+        let anonymous = request("GET", "/hello/x", &[]).await;
+        let mut alice = anonymous.clone();
+        alice.extensions.insert(Identity("alice".into()));
+
+        assert_eq!(call(&limit, &alice, "10.0.0.1").await.status, 200);
+        assert_eq!(call(&limit, &alice, "10.0.0.2").await.status, 429);
+        assert_eq!(call(&limit, &anonymous, "10.0.0.1").await.status, 200);
+        assert_eq!(call(&limit, &anonymous, "10.0.0.1").await.status, 429);
+    }
+
+    #[tokio::test]
+    async fn instances_sharing_a_store_share_the_limit() {
+        let backing = Backing::Shared {
+            store: Arc::new(MemoryStore::new()),
+            instances: 2,
+        };
+        let instances = [
+            RateLimitMiddleware::with_backing(
+                "ip",
+                Rule::new(bucket(2), KeyBy::Ip),
+                backing.clone(),
+            ),
+            RateLimitMiddleware::with_backing("ip", Rule::new(bucket(2), KeyBy::Ip), backing),
+        ];
+        let req = request("GET", "/hello/a", &[]).await;
+
+        let mut statuses = Vec::new();
+        for limit in instances.iter().cycle().take(4) {
+            statuses.push(call(limit, &req, "10.0.0.1").await.status);
+        }
+        assert_eq!(statuses, [200, 200, 429, 429]);
+    }
+
+    #[tokio::test]
+    async fn reloaded_rules_keep_their_buckets() {
+        let clock = MockClock::new();
+        let old = RateLimitMiddleware::with_clock(Rule::new(bucket(1), KeyBy::Ip), clock.clone())
+            .rule("POST", "/user", Rule::new(bucket(1), KeyBy::Ip));
+        let get = request("GET", "/hello/a", &[]).await;
+        let post = request("POST", "/user", &[]).await;
+        assert_eq!(call(&old, &get, "10.0.0.1").await.status, 200);
+        assert_eq!(call(&old, &post, "10.0.0.1").await.status, 200);
+
+        // The default rule survives; the POST rule moved to a new pattern and starts over.
+        let new = RateLimitMiddleware::with_clock(Rule::new(bucket(3), KeyBy::Ip), clock)
+            .rule("POST", "/users", Rule::new(bucket(1), KeyBy::Ip))
+            .inherit(&old);
+        assert_eq!(call(&new, &get, "10.0.0.1").await.status, 429);
+        assert_eq!(call(&new, &post, "10.0.0.1").await.status, 429);
+        let moved = request("POST", "/users", &[]).await;
+        assert_eq!(call(&new, &moved, "10.0.0.1").await.status, 200);
+    }
+
+    #[test]
+    fn stacked_limiters_report_the_tighter_one() {
+        let decision = |remaining| Decision {
+            allowed: true,
+            limit: 10,
+            remaining,
+            reset: Duration::from_secs(3),
+            retry_after: None,
+        };
+        let inner = || with_rate_limit_headers(Response::new(200), &decision(4));
+
+        let outer = with_rate_limit_headers(inner(), &decision(7));
+        assert_eq!(header(&outer, "RateLimit-Remaining"), Some("4"));
+        let outer = with_rate_limit_headers(inner(), &decision(2));
+        assert_eq!(header(&outer, "RateLimit-Remaining"), Some("2"));
+        assert_eq!(outer.headers.len(), 3);
     }
 }
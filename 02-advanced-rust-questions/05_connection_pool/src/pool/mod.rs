@@ -0,0 +1,474 @@
+pub mod mongo;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Knows how to open and validate one kind of connection.
+pub trait ManageConnection: Send + Sync + 'static {
+    type Connection: Send + 'static;
+    type Error: fmt::Debug + fmt::Display + Send + 'static;
+
+    fn connect(&self) -> impl Future<Output = Result<Self::Connection, Self::Error>> + Send;
+
+    /// Called before a pooled connection is handed out (when `test_on_checkout` is set).
+    fn is_valid(
+        &self,
+        conn: &mut Self::Connection,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections opened up front and kept around by the reaper.
+    pub min_idle: usize,
+    pub max_size: usize,
+    /// How long `get` waits for a free slot before giving up.
+    pub connection_timeout: Duration,
+    /// Idle connections older than this are closed (down to `min_idle`).
+    pub idle_timeout: Option<Duration>,
+    /// Connections older than this are closed whether idle or not, once returned.
+    pub max_lifetime: Option<Duration>,
+    pub test_on_checkout: bool,
+    pub reap_interval: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_idle: 1,
+            max_size: 10,
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(600)),
+            max_lifetime: Some(Duration::from_secs(1800)),
+            test_on_checkout: true,
+            reap_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError<E> {
+    Timeout,
+    Connect(E),
+}
+
+impl<E: fmt::Display> fmt::Display for PoolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Timeout => write!(f, "timed out waiting for a pooled connection"),
+            PoolError::Connect(e) => write!(f, "failed to open connection: {}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for PoolError<E> {}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct PoolStats {
+    pub max_size: usize,
+    pub in_use: usize,
+    pub idle: usize,
+    pub waiters: usize,
+}
+
+struct IdleConn<C> {
+    conn: C,
+    created_at: Instant,
+    idle_since: Instant,
+}
+
+struct State<C> {
+    idle: VecDeque<IdleConn<C>>,
+    in_use: usize,
+}
+
+struct Shared<M: ManageConnection> {
+    manager: M,
+    config: PoolConfig,
+    state: Mutex<State<M::Connection>>,
+    // One permit per connection slot; holding a permit is what bounds the pool at max_size.
+    slots: Arc<Semaphore>,
+    waiters: AtomicUsize,
+}
+
+impl<M: ManageConnection> Shared<M> {
+    fn over_lifetime(&self, created_at: Instant, now: Instant) -> bool {
+        self.config
+            .max_lifetime
+            .is_some_and(|max| now.duration_since(created_at) >= max)
+    }
+
+    fn expired(&self, created_at: Instant, idle_since: Instant, now: Instant) -> bool {
+        let too_old = self.over_lifetime(created_at, now);
+        let too_idle = self
+            .config
+            .idle_timeout
+            .is_some_and(|max| now.duration_since(idle_since) >= max);
+        too_old || too_idle
+    }
+}
+
+/// Async connection pool, e.g. `Pool<MongoManager>`. Cheap to clone.
+pub struct Pool<M: ManageConnection> {
+    shared: Arc<Shared<M>>,
+}
+
+impl<M: ManageConnection> Clone for Pool<M> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<M: ManageConnection> Pool<M> {
+    /// Opens `min_idle` connections and starts the idle reaper.
+    pub async fn new(manager: M, config: PoolConfig) -> Result<Self, PoolError<M::Error>> {
+        let max_size = config.max_size.max(1);
+        let mut idle = VecDeque::new();
+        for _ in 0..config.min_idle.min(max_size) {
+            let conn = manager.connect().await.map_err(PoolError::Connect)?;
+            let now = Instant::now();
+            idle.push_back(IdleConn {
+                conn,
+                created_at: now,
+                idle_since: now,
+            });
+        }
+
+        let shared = Arc::new(Shared {
+            manager,
+            config,
+            state: Mutex::new(State { idle, in_use: 0 }),
+            slots: Arc::new(Semaphore::new(max_size)),
+            waiters: AtomicUsize::new(0),
+        });
+        spawn_reaper(Arc::downgrade(&shared));
+        Ok(Self { shared })
+    }
+
+    /// Checks out a connection, reusing an idle one when possible.
+    pub async fn get(&self) -> Result<PooledConnection<M>, PoolError<M::Error>> {
+        let shared = &self.shared;
+
+        shared.waiters.fetch_add(1, Ordering::SeqCst);
+        let permit = tokio::time::timeout(
+            shared.config.connection_timeout,
+            shared.slots.clone().acquire_owned(),
+        )
+        .await;
+        shared.waiters.fetch_sub(1, Ordering::SeqCst);
+        let permit = match permit {
+            Ok(Ok(permit)) => permit,
+            // The semaphore is never closed, so only the timeout can land here.
+            _ => return Err(PoolError::Timeout),
+        };
+
+        loop {
+            let candidate = shared.state.lock().unwrap().idle.pop_front();
+            let Some(mut idle) = candidate else {
+                break;
+            };
+            // Idle time is the reaper's call, since it keeps `min_idle` connections past
+            // `idle_timeout`; only a connection past its lifetime is thrown away here.
+            if shared.over_lifetime(idle.created_at, Instant::now()) {
+                continue;
+            }
+            if shared.config.test_on_checkout
+                && shared.manager.is_valid(&mut idle.conn).await.is_err()
+            {
+                continue;
+            }
+            return Ok(self.checked_out(idle.conn, idle.created_at, permit));
+        }
+
+        let conn = shared.manager.connect().await.map_err(PoolError::Connect)?;
+        Ok(self.checked_out(conn, Instant::now(), permit))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock().unwrap();
+        PoolStats {
+            max_size: self.shared.config.max_size.max(1),
+            in_use: state.in_use,
+            idle: state.idle.len(),
+            waiters: self.shared.waiters.load(Ordering::SeqCst),
+        }
+    }
+
+    fn checked_out(
+        &self,
+        conn: M::Connection,
+        created_at: Instant,
+        permit: OwnedSemaphorePermit,
+    ) -> PooledConnection<M> {
+        self.shared.state.lock().unwrap().in_use += 1;
+        PooledConnection {
+            conn: Some(conn),
+            created_at,
+            shared: self.shared.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+/// RAII guard: derefs to the connection and hands it back to the pool on drop.
+pub struct PooledConnection<M: ManageConnection> {
+    conn: Option<M::Connection>,
+    created_at: Instant,
+    shared: Arc<Shared<M>>,
+    // Released after `drop` has put the connection back, so the next waiter can reuse it.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<M: ManageConnection> Deref for PooledConnection<M> {
+    type Target = M::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("connection taken")
+    }
+}
+
+impl<M: ManageConnection> DerefMut for PooledConnection<M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().expect("connection taken")
+    }
+}
+
+impl<M: ManageConnection> Drop for PooledConnection<M> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.in_use -= 1;
+        if let Some(conn) = self.conn.take() {
+            let now = Instant::now();
+            if !self.shared.over_lifetime(self.created_at, now) {
+                state.idle.push_back(IdleConn {
+                    conn,
+                    created_at: self.created_at,
+                    idle_since: now,
+                });
+            }
+        }
+    }
+}
+
+// Closes expired idle connections and tops the pool back up to `min_idle`.
+// Holds only a weak reference so the task ends once the last `Pool` is dropped.
+fn spawn_reaper<M: ManageConnection>(weak: Weak<Shared<M>>) {
+    let interval = match weak.upgrade() {
+        Some(shared) => shared.config.reap_interval,
+        None => return,
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(shared) = weak.upgrade() else {
+                break;
+            };
+
+            let missing = {
+                let mut state = shared.state.lock().unwrap();
+                let now = Instant::now();
+                let min_idle = shared.config.min_idle;
+                let mut kept = VecDeque::with_capacity(state.idle.len());
+                // Newest connections are at the back; keep those when trimming to min_idle.
+                while let Some(idle) = state.idle.pop_back() {
+                    let keep = !shared.over_lifetime(idle.created_at, now)
+                        && (kept.len() < min_idle
+                            || !shared.expired(idle.created_at, idle.idle_since, now));
+                    if keep {
+                        kept.push_front(idle);
+                    }
+                }
+                state.idle = kept;
+                let total = state.idle.len() + state.in_use;
+                min_idle.min(shared.config.max_size).saturating_sub(total)
+            };
+
+            for _ in 0..missing {
+                match shared.manager.connect().await {
+                    Ok(conn) => {
+                        let now = Instant::now();
+                        shared.state.lock().unwrap().idle.push_back(IdleConn {
+                            conn,
+                            created_at: now,
+                            idle_since: now,
+                        });
+                    }
+                    Err(err) => {
+                        eprintln!("pool: failed to refill idle connection: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[derive(Default)]
+    struct TestManager {
+        opened: AtomicUsize,
+        broken: AtomicBool,
+    }
+
+    #[derive(Debug)]
+    struct TestConn {
+        id: usize,
+    }
+
+    impl ManageConnection for Arc<TestManager> {
+        type Connection = TestConn;
+        type Error = String;
+
+        async fn connect(&self) -> Result<TestConn, String> {
+            let id = self.opened.fetch_add(1, Ordering::SeqCst);
+            Ok(TestConn { id })
+        }
+
+        async fn is_valid(&self, _conn: &mut TestConn) -> Result<(), String> {
+            if self.broken.swap(false, Ordering::SeqCst) {
+                Err("connection reset".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn config() -> PoolConfig {
+        PoolConfig {
+            min_idle: 0,
+            max_size: 2,
+            connection_timeout: Duration::from_millis(50),
+            idle_timeout: None,
+            max_lifetime: None,
+            test_on_checkout: true,
+            reap_interval: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_connections_on_drop_and_reuses_them() {
+        let manager = Arc::new(TestManager::default());
+        let pool = Pool::new(manager.clone(), config()).await.unwrap();
+
+        let conn = pool.get().await.unwrap();
+        assert_eq!(conn.id, 0);
+        assert_eq!(pool.stats().in_use, 1);
+        drop(conn);
+
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.idle), (0, 1));
+        assert_eq!(pool.get().await.unwrap().id, 0);
+        assert_eq!(manager.opened.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn checkout_times_out_when_pool_is_exhausted() {
+        let pool = Pool::new(Arc::new(TestManager::default()), config())
+            .await
+            .unwrap();
+        let _a = pool.get().await.unwrap();
+        let b = pool.get().await.unwrap();
+
+        let waiter = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.get().await.map(|c| c.id) })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(pool.stats().waiters, 1);
+        assert!(matches!(waiter.await.unwrap(), Err(PoolError::Timeout)));
+
+        // Returning a connection unblocks the next checkout.
+        drop(b);
+        assert_eq!(pool.get().await.unwrap().id, 1);
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_replaced_on_checkout() {
+        let manager = Arc::new(TestManager::default());
+        let pool = Pool::new(manager.clone(), config()).await.unwrap();
+        drop(pool.get().await.unwrap());
+
+        manager.broken.store(true, Ordering::SeqCst);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(conn.id, 1);
+        assert_eq!(pool.stats().idle, 0);
+    }
+
+    #[tokio::test]
+    async fn old_connections_are_not_reused() {
+        let manager = Arc::new(TestManager::default());
+        let pool = Pool::new(
+            manager.clone(),
+            PoolConfig {
+                max_lifetime: Some(Duration::from_millis(20)),
+                ..config()
+            },
+        )
+        .await
+        .unwrap();
+
+        drop(pool.get().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(pool.get().await.unwrap().id, 1);
+    }
+
+    #[tokio::test]
+    async fn reaper_closes_idle_connections_down_to_min_idle() {
+        let manager = Arc::new(TestManager::default());
+        let pool = Pool::new(
+            manager.clone(),
+            PoolConfig {
+                min_idle: 1,
+                idle_timeout: Some(Duration::from_millis(10)),
+                reap_interval: Duration::from_millis(20),
+                ..config()
+            },
+        )
+        .await
+        .unwrap();
+
+        let a = pool.get().await.unwrap();
+        let b = pool.get().await.unwrap();
+        drop((a, b));
+        assert_eq!(pool.stats().idle, 2);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.stats().idle, 1);
+    }
+
+    #[tokio::test]
+    async fn connections_kept_for_min_idle_are_reused_after_idle_timeout() {
+        let manager = Arc::new(TestManager::default());
+        let pool = Pool::new(
+            manager.clone(),
+            PoolConfig {
+                min_idle: 1,
+                idle_timeout: Some(Duration::from_millis(10)),
+                reap_interval: Duration::from_millis(20),
+                ..config()
+            },
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.get().await.unwrap().id, 0);
+        assert_eq!(manager.opened.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,42 @@
+use mongodb::bson::doc;
+use mongodb::options::ClientOptions;
+use mongodb::{Client, Database};
+
+use super::ManageConnection;
+
+/// Hands out `Database` handles from one shared `Client` and pings them on checkout.
+///
+/// The driver keeps its own pool of sockets inside the client, sized here to the
+/// pool's `max_size`, so every checked-out handle can have a socket of its own.
+pub struct MongoManager {
+    client: Client,
+    db_name: String,
+}
+
+impl MongoManager {
+    pub async fn new(
+        uri: &str,
+        db_name: impl Into<String>,
+        max_size: usize,
+    ) -> Result<Self, mongodb::error::Error> {
+        let mut options = ClientOptions::parse(uri).await?;
+        options.max_pool_size = Some(max_size.try_into().unwrap_or(u32::MAX));
+        Ok(Self {
+            client: Client::with_options(options)?,
+            db_name: db_name.into(),
+        })
+    }
+}
+
+impl ManageConnection for MongoManager {
+    type Connection = Database;
+    type Error = mongodb::error::Error;
+
+    async fn connect(&self) -> Result<Database, Self::Error> {
+        Ok(self.client.database(&self.db_name))
+    }
+
+    async fn is_valid(&self, conn: &mut Database) -> Result<(), Self::Error> {
+        conn.run_command(doc! { "ping": 1 }).await.map(|_| ())
+    }
+}
@@ -0,0 +1,129 @@
+// In-memory stand-in for Redis, so tests can run the shared limiter over real sockets.
+// It speaks just the RESP that `RespStore` sends: WATCH, GET, MULTI, SET and EXEC. Every
+// write bumps the key's version, and EXEC answers a null array if a watched key moved on
+// after the WATCH. Expiry is not modelled; tests finish well inside any TTL.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// Each key's value and how many times it has been written.
+type Keys = Arc<Mutex<HashMap<String, (String, u64)>>>;
+
+/// Starts a server on a free local port and returns its `host:port`.
+pub async fn spawn() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let keys = Keys::default();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(BufReader::new(stream), keys.clone()));
+        }
+    });
+    addr
+}
+
+async fn serve(mut conn: BufReader<TcpStream>, keys: Keys) {
+    // Versions of the keys watched, as they were at the WATCH.
+    let mut watched: Vec<(String, u64)> = Vec::new();
+    // SETs waiting for EXEC, once MULTI has been sent.
+    let mut queued: Option<Vec<(String, String)>> = None;
+
+    while let Ok(Some(command)) = read_command(&mut conn).await {
+        let args: Vec<&str> = command.iter().map(String::as_str).collect();
+        let reply = {
+            let mut keys = keys.lock().unwrap();
+            let version = |key: &str| keys.get(key).map_or(0, |(_, version)| *version);
+            match args.as_slice() {
+                ["WATCH", names @ ..] => {
+                    for name in names {
+                        watched.push((name.to_string(), version(name)));
+                    }
+                    "+OK\r\n".to_string()
+                }
+                ["GET", key] => match keys.get(*key) {
+                    Some((value, _)) => format!("${}\r\n{value}\r\n", value.len()),
+                    None => "$-1\r\n".to_string(),
+                },
+                ["MULTI"] => {
+                    queued = Some(Vec::new());
+                    "+OK\r\n".to_string()
+                }
+                ["SET", key, value, ..] => match &mut queued {
+                    Some(sets) => {
+                        sets.push((key.to_string(), value.to_string()));
+                        "+QUEUED\r\n".to_string()
+                    }
+                    None => {
+                        set(&mut keys, key, value);
+                        "+OK\r\n".to_string()
+                    }
+                },
+                ["EXEC"] => {
+                    let sets = queued.take().unwrap_or_default();
+                    let moved_on = watched.drain(..).any(|(key, seen)| version(&key) != seen);
+                    if moved_on {
+                        "*-1\r\n".to_string()
+                    } else {
+                        for (key, value) in &sets {
+                            set(&mut keys, key, value);
+                        }
+                        format!("*{}\r\n{}", sets.len(), "+OK\r\n".repeat(sets.len()))
+                    }
+                }
+                _ => format!("-ERR unsupported command {args:?}\r\n"),
+            }
+        };
+        if conn.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn set(keys: &mut HashMap<String, (String, u64)>, key: &str, value: &str) {
+    let entry = keys.entry(key.to_string()).or_default();
+    entry.0 = value.to_string();
+    entry.1 += 1;
+}
+
+/// Reads one command, an array of bulk strings. `None` once the client hangs up.
+async fn read_command<R>(conn: &mut R) -> io::Result<Option<Vec<String>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let Some(count) = read_length(conn, '*').await? else {
+        return Ok(None);
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = read_length(conn, '$')
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let mut buf = vec![0; len + 2];
+        conn.read_exact(&mut buf).await?;
+        buf.truncate(len);
+        args.push(
+            String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+    }
+    Ok(Some(args))
+}
+
+// Reads a `*<n>` or `$<n>` line.
+async fn read_length<R>(conn: &mut R, prefix: char) -> io::Result<Option<usize>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    line.trim_end()
+        .strip_prefix(prefix)
+        .and_then(|n| n.parse().ok())
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad line: {line}")))
+}
@@ -1,76 +1,161 @@
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 
+use crate::config::ServerConfig;
 use crate::handlers;
-use crate::middleware::{self, Middleware};
+use crate::middleware::{self, ResponseFuture};
+use crate::middlewares::compression::CompressionMiddleware;
 use crate::middlewares::logger::LoggerMiddleware;
-use crate::middlewares::rate_limiting::TokenBucketMiddleware;
-use mongodb::{Client, Database};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::middlewares::rate_limiting::{Backing, KeyBy, RateLimitMiddleware, Rule};
+use crate::pool::mongo::MongoManager;
+use crate::pool::Pool;
+use crate::reload::{evict_idle_buckets, Chain, Reloader};
+use crate::request::{Limits, Method, Request, RequestReader};
+use crate::router::{Lookup, Router};
+use arc_swap::ArcSwap;
 use tokio::net::TcpListener;
+use tokio::time::Duration;
 
 use crate::types::Response;
 
-pub async fn run() -> anyhow::Result<()> {
-    // Load environment variables from .env file
-    dotenvy::dotenv().ok();
+pub async fn run(reloader: Reloader) -> anyhow::Result<()> {
+    let reloader = Arc::new(reloader);
+    let config = reloader.config();
     let mongodb_uri =
         std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
     let mongodb_db = std::env::var("MONGODB_DB").unwrap_or_else(|_| "my_app".to_string());
-    let client = Client::with_uri_str(&mongodb_uri).await?;
-    let db = Arc::new(client.database(&mongodb_db));
+    let pool_config = config.pool.pool_config();
+    let manager = MongoManager::new(&mongodb_uri, mongodb_db, pool_config.max_size).await?;
+    let pool = Pool::new(manager, pool_config).await?;
 
-    let listener = TcpListener::bind("0.0.0.0:7878").await?;
-    println!("Listening on port 7878");
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!("Listening on {}", config.listen);
 
-    let middlewares: Vec<Arc<dyn Middleware>> = vec![
-        Arc::new(LoggerMiddleware),
-        Arc::new(TokenBucketMiddleware::new(5, 1)),
-    ];
+    let chain = Arc::new(ArcSwap::from_pointee(build_chain(&config, None)));
+    evict_idle_buckets(chain.clone());
+    {
+        let chain = chain.clone();
+        reloader.watch(move |_, config| {
+            let previous = chain.load();
+            chain.store(Arc::new(build_chain(config, Some(&previous))));
+        });
+    }
 
     loop {
         let (mut socket, addr) = listener.accept().await?;
-        let middlewares = middlewares.clone();
-        let db = db.clone();
+        // The request is served by the chain that was current when it arrived.
+        let chain = chain.load_full();
+        let pool = pool.clone();
+        let reloader = reloader.clone();
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            if let Ok(n) = socket.read(&mut buf).await {
-                if n == 0 {
+            let mut reader = RequestReader::new(Limits::default());
+            let req = match reader.read_request(&mut socket).await {
+                Ok(Some(req)) => req,
+                Ok(None) => return,
+                Err(err) => {
+                    let _ = err.into_response().write_to(&mut socket).await;
                     return;
                 }
+            };
 
-                let req = String::from_utf8_lossy(&buf[..n]);
-                let middleware_ref: Vec<&dyn Middleware> =
-                    middlewares.iter().map(|m| m.as_ref()).collect();
-                let handler: Box<
-                    dyn Fn(&str) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync,
-                > = Box::new(move |req: &str| {
-                    let req_owned = req.to_string();
-                    let db = db.clone();
-                    Box::pin(async move { route_request(&req_owned, &db).await })
+            let middleware_ref = chain.middlewares();
+            let handler: Box<dyn Fn(&Request) -> ResponseFuture + Send + Sync> =
+                Box::new(move |req: &Request| {
+                    let req_owned = req.clone();
+                    let pool = pool.clone();
+                    let reloader = reloader.clone();
+                    Box::pin(async move { route_request(&req_owned, &pool, &reloader).await })
                 });
-                let res =
-                    middleware::run_chain(&req, &(addr.ip().to_string()), &middleware_ref, handler)
-                        .await;
-                let _ = socket.write_all(res.into_http().as_bytes()).await;
-            }
+            let res =
+                middleware::run_chain(&req, &addr.ip().to_string(), &middleware_ref, handler).await;
+            let _ = res.write_to(&mut socket).await;
         });
     }
 }
 
-pub async fn route_request(req: &str, db: &Database) -> Response {
-    if req.starts_with("GET /health") {
-        handlers::health::handle().await
-    } else if req.starts_with("GET /hello/") {
-        handlers::hello::handle(req).await
-    } else if req.starts_with("POST /user") {
-        handlers::user::handle(req, db).await
-    } else {
-        Response {
-            status: 404,
-            content_type: "text/plain".into(),
-            body: "Not Found".into(),
+/// Builds the middleware chain `config` asks for. The rate limiter takes over the buckets
+/// of the one in `previous`, so a reload does not reset anyone's quota.
+fn build_chain(config: &ServerConfig, previous: Option<&Chain>) -> Chain {
+    let limits = &config.rate_limit;
+    let rate_limits = Backing::from_store(
+        limits.store.as_deref(),
+        limits.instances,
+        Duration::from_millis(limits.store_timeout_ms),
+    );
+    let enabled = &config.middlewares;
+    let mut chain = Chain::new();
+    if enabled.logger {
+        chain.push(Arc::new(
+            LoggerMiddleware::new(&ROUTES).format(config.log_format),
+        ));
+    }
+    if enabled.compression {
+        let compression = &config.compression;
+        chain.push(Arc::new(
+            CompressionMiddleware::new()
+                .encodings(&compression.encodings)
+                .min_size(compression.min_size)
+                .max_request_body(compression.max_request_body),
+        ));
+    }
+    if enabled.rate_limit {
+        let mut limit = RateLimitMiddleware::with_backing(
+            "ip",
+            Rule::new(limits.ip.algorithm(), KeyBy::Ip),
+            rate_limits,
+        )
+        .exempt("GET", "/health");
+        for route in &limits.routes {
+            limit = limit.rule(&route.method, &route.path, route.rule());
         }
+        chain.push_rate_limit("ip", limit, previous);
+    }
+    chain
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    Health,
+    Hello,
+    CreateUser,
+    Metrics,
+}
+
+static ROUTES: LazyLock<Router<Route>> = LazyLock::new(|| {
+    Router::new()
+        .route("GET", "/health", Route::Health)
+        .route("GET", "/hello/:name", Route::Hello)
+        .route("POST", "/user", Route::CreateUser)
+        .route("GET", "/api/metrics", Route::Metrics)
+});
+
+pub async fn route_request(
+    req: &Request,
+    pool: &Pool<MongoManager>,
+    reloader: &Reloader,
+) -> Response {
+    let (route, params) = match ROUTES.lookup(req.method.as_str(), &req.path) {
+        Lookup::Found { handler, params } => (*handler, params),
+        Lookup::Options { allow } => return Response::options(&allow),
+        Lookup::MethodNotAllowed { allow } => return Response::method_not_allowed(&allow),
+        Lookup::NotFound => return Response::not_found(),
+    };
+
+    let res = match route {
+        Route::Health => handlers::health::handle().await,
+        Route::Hello => handlers::hello::handle(req, &params).await,
+        Route::CreateUser => match pool.get().await {
+            Ok(db) => handlers::user::handle(req, &db).await,
+            Err(err) => {
+                eprintln!("db pool error: {}", err);
+                Response::text(503, "Service Unavailable")
+            }
+        },
+        Route::Metrics => handlers::metrics::handle(pool, reloader.counts()).await,
+    };
+
+    if req.method == Method::Head {
+        res.without_body()
+    } else {
+        res
     }
 }
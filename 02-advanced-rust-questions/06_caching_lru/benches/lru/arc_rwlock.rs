@@ -0,0 +1,194 @@
+// The Arc<RwLock<Node>> linked-list LRUCache that src/cache/mod.rs used before the slab,
+// kept here as a benchmark baseline. Only what the benchmark calls is left of its API.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+type Link<K, V> = Option<Arc<RwLock<Node<K, V>>>>;
+
+#[derive(Debug)]
+pub struct Node<K, V> {
+    key: K,
+    value: V,
+    expires_at: Option<Instant>,
+    weight: usize,
+    prev: Option<Weak<RwLock<Node<K, V>>>>,
+    next: Link<K, V>,
+}
+
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries pushed out to stay within capacity or the weight limit.
+    pub evictions: u64,
+    /// Entries dropped because their TTL ran out.
+    pub expirations: u64,
+}
+
+pub struct LRUCache<K: std::hash::Hash + Eq + Clone, V: Clone> {
+    capacity: usize,
+    map: HashMap<K, Link<K, V>>,
+    head: Link<K, V>, // most recently used
+    tail: Link<K, V>, // Least recently used
+    default_ttl: Option<Duration>,
+    weigher: Option<Weigher<K, V>>,
+    max_weight: usize,
+    total_weight: usize,
+    stats: CacheStats,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V: Clone> fmt::Debug for LRUCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LRUCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.map.len())
+            .field("default_ttl", &self.default_ttl)
+            .field("max_weight", &self.max_weight)
+            .field("total_weight", &self.total_weight)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V: Clone> LRUCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LRUCache {
+            capacity,
+            map: HashMap::new(),
+            head: None,
+            tail: None,
+            default_ttl: None,
+            weigher: None,
+            max_weight: usize::MAX,
+            total_weight: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let Some(node_rc) = self.map.get(key).and_then(|n| n.clone()) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        // Lazy expiry: an expired entry is dropped the first time someone asks for it.
+        let expires_at = node_rc.read().unwrap().expires_at;
+        if expires_at.is_some_and(|at| at <= Instant::now()) {
+            self.unlink(&node_rc);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+
+        let value = node_rc.read().unwrap().value.clone();
+        self.move_to_head(&node_rc);
+        self.stats.hits += 1;
+        Some(value)
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.insert(key, value, self.default_ttl);
+    }
+
+    fn insert(&mut self, key: K, value: V, ttl: Option<Duration>) {
+        let weight = self.weigher.as_ref().map_or(0, |w| w(&key, &value));
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+
+        if let Some(node_rc) = self.map.get(&key).and_then(|n| n.clone()) {
+            {
+                let mut node = node_rc.write().unwrap();
+                self.total_weight = self.total_weight - node.weight + weight;
+                node.value = value;
+                node.expires_at = expires_at;
+                node.weight = weight;
+            }
+            self.move_to_head(&node_rc);
+        } else {
+            let new_node = Arc::new(RwLock::new(Node {
+                key: key.clone(),
+                value,
+                expires_at,
+                weight,
+                prev: None,
+                next: None,
+            }));
+
+            if self.map.len() == self.capacity {
+                self.remove_tail();
+            }
+
+            self.add_to_head(&new_node);
+            self.map.insert(key, Some(new_node));
+            self.total_weight += weight;
+        }
+
+        // An entry heavier than the whole budget ends up evicting itself too.
+        while self.total_weight > self.max_weight && self.tail.is_some() {
+            self.remove_tail();
+        }
+    }
+
+    fn move_to_head(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
+        self.remove_node(node);
+        self.add_to_head(node);
+    }
+
+    fn add_to_head(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
+        node.write().unwrap().next = self.head.clone();
+        node.write().unwrap().prev = None;
+
+        if let Some(head) = &self.head {
+            head.write().unwrap().prev = Some(Arc::downgrade(node));
+        }
+        self.head = Some(node.clone());
+
+        if self.tail.is_none() {
+            self.tail = Some(node.clone());
+        }
+    }
+
+    fn remove_node(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
+        let prev = node.read().unwrap().prev.clone();
+        let next = node.read().unwrap().next.clone();
+
+        let prev_for_next = prev.clone();
+
+        if let Some(prev) = prev.and_then(|w| w.upgrade()) {
+            prev.write().unwrap().next = next.clone();
+        } else {
+            self.head = next.clone();
+        }
+
+        if let Some(next) = &next {
+            next.write().unwrap().prev = prev_for_next;
+        } else {
+            self.tail = prev_for_next.and_then(|w| w.upgrade());
+        }
+        node.write().unwrap().prev = None;
+        node.write().unwrap().next = None;
+    }
+
+    fn remove_tail(&mut self) {
+        if let Some(tail_rc) = self.tail.clone() {
+            self.unlink(&tail_rc);
+            self.stats.evictions += 1;
+        }
+    }
+
+    // Removes the node from both the list and the map.
+    fn unlink(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
+        let (key, weight) = {
+            let n = node.read().unwrap();
+            (n.key.clone(), n.weight)
+        };
+        self.remove_node(node);
+        self.map.remove(&key);
+        self.total_weight -= weight;
+    }
+}
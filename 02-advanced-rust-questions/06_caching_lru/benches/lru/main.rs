@@ -0,0 +1,75 @@
+// Compares the slab-backed LRUCache in src/cache against the two linked-list versions
+// it replaced. Run with `cargo bench --bench lru`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+
+use middleware_server::cache::LRUCache;
+
+mod arc_rwlock;
+mod rc_refcell;
+
+const CAPACITY: usize = 1_000;
+
+// A key stream with some locality: mostly hits inside the working set, plus misses.
+fn keys(n: usize) -> Vec<u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % (CAPACITY as u64 * 2)
+        })
+        .collect()
+}
+
+fn bench_lru(c: &mut Criterion) {
+    let keys = keys(10_000);
+    let mut group = c.benchmark_group("lru_get_put");
+
+    group.bench_with_input(BenchmarkId::new("slab", CAPACITY), &keys, |b, keys| {
+        let mut cache = LRUCache::new(CAPACITY);
+        b.iter(|| {
+            for &k in keys {
+                if black_box(cache.get(&k)).is_none() {
+                    cache.put(k, k);
+                }
+            }
+        })
+    });
+
+    group.bench_with_input(
+        BenchmarkId::new("arc_rwlock", CAPACITY),
+        &keys,
+        |b, keys| {
+            let mut cache = arc_rwlock::LRUCache::new(CAPACITY);
+            b.iter(|| {
+                for &k in keys {
+                    if black_box(cache.get(&k)).is_none() {
+                        cache.put(k, k);
+                    }
+                }
+            })
+        },
+    );
+
+    group.bench_with_input(
+        BenchmarkId::new("rc_refcell", CAPACITY),
+        &keys,
+        |b, keys| {
+            let mut cache = rc_refcell::LRUCache::new(CAPACITY);
+            b.iter(|| {
+                for &k in keys {
+                    if black_box(cache.get(&k)).is_none() {
+                        cache.put(k, k);
+                    }
+                }
+            })
+        },
+    );
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_lru);
+criterion_main!(benches);
@@ -0,0 +1,469 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use config::{Config, Environment, File};
+use serde::{Deserialize, Serialize};
+
+use crate::middlewares::compression::Encoding;
+use crate::middlewares::logger::LogFormat;
+use crate::middlewares::rate_limiting::{KeyBy, Rule};
+use crate::ratelimit::Algorithm;
+use crate::request::Limits;
+
+/// Command-line flags. They override the config file and the environment.
+#[derive(Debug, Clone, Default, Parser)]
+#[command(about = "Middleware server")]
+pub struct Cli {
+    /// TOML config file. Without this flag `server.toml` is read if it exists at startup.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<String>,
+
+    /// Overrides any setting by its dotted path, e.g. `--set cache.capacity=500`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Prints the effective settings as TOML and exits.
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+impl Cli {
+    /// The file the settings are read from, and watched for changes.
+    pub fn config_file(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("server.toml"))
+    }
+}
+
+/// Everything the server used to hardcode. Loaded from the built-in defaults, then the
+/// config file, then `SERVER_*` environment variables, then the command line; each
+/// layer overrides the ones before it. Nested keys use `__` in variable names, e.g.
+/// `SERVER_CACHE__CAPACITY=500`. Mongo settings stay in their own variables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub log_format: LogFormat,
+    pub middlewares: Middlewares,
+    pub compression: CompressionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cache: CacheConfig,
+}
+
+/// Which middlewares the server puts in its chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Middlewares {
+    pub logger: bool,
+    pub compression: bool,
+    pub rate_limit: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// Codings offered to clients, most preferred first, e.g. `["br", "gzip"]`.
+    pub encodings: Vec<Encoding>,
+    /// Response bodies smaller than this many bytes go out uncompressed.
+    pub min_size: usize,
+    /// Largest a compressed request body may grow to once decoded, in bytes.
+    pub max_request_body: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// `host:port` of a RESP store (Redis or a compatible server) to
+    /// share limits through, or `"memory"` for a store inside this
+    /// process. Without it the limits are per process.
+    pub store: Option<String>,
+    /// How many servers share the store.
+    pub instances: u32,
+    /// How long one update to a RESP store may take before the server
+    /// falls back to its own share of the limits.
+    pub store_timeout_ms: u64,
+    /// Every client, by IP.
+    pub ip: LimitConfig,
+    /// Limits for single routes, each used there instead of the default one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteLimit>,
+}
+
+/// A rate-limit algorithm and its parameters, e.g.
+/// `{ algorithm = "fixed_window", limit = 100, window_secs = 60 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum LimitConfig {
+    TokenBucket { capacity: u32, refill_per_sec: f64 },
+    LeakyBucket { capacity: u32, leak_per_sec: f64 },
+    FixedWindow { limit: u32, window_secs: u64 },
+    SlidingWindowLog { limit: u32, window_secs: u64 },
+}
+
+/// One entry of `rate_limit.routes`, e.g. `{ method = "POST", path = "/user", key = "route",
+/// algorithm = "fixed_window", limit = 10, window_secs = 60 }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteLimit {
+    pub method: String,
+    /// A router pattern such as `/hello/:name`.
+    pub path: String,
+    /// What the limit counts against: `ip`, `principal`, `api_key` or `route`.
+    pub key: KeyBy,
+    #[serde(flatten)]
+    pub limit: LimitConfig,
+}
+
+impl RouteLimit {
+    pub fn rule(&self) -> Rule {
+        Rule::new(self.limit.algorithm(), self.key)
+    }
+}
+
+impl LimitConfig {
+    pub fn algorithm(&self) -> Algorithm {
+        match *self {
+            LimitConfig::TokenBucket {
+                capacity,
+                refill_per_sec,
+            } => Algorithm::TokenBucket {
+                capacity,
+                refill_per_sec,
+            },
+            LimitConfig::LeakyBucket {
+                capacity,
+                leak_per_sec,
+            } => Algorithm::LeakyBucket {
+                capacity,
+                leak_per_sec,
+            },
+            LimitConfig::FixedWindow { limit, window_secs } => Algorithm::FixedWindow {
+                limit,
+                window: Duration::from_secs(window_secs),
+            },
+            LimitConfig::SlidingWindowLog { limit, window_secs } => Algorithm::SlidingWindowLog {
+                limit,
+                window: Duration::from_secs(window_secs),
+            },
+        }
+    }
+
+    fn check(&self, name: &str, problems: &mut Vec<String>) {
+        let (quota, rate) = match *self {
+            LimitConfig::TokenBucket {
+                capacity,
+                refill_per_sec: rate,
+            }
+            | LimitConfig::LeakyBucket {
+                capacity,
+                leak_per_sec: rate,
+            } => (capacity, rate),
+            LimitConfig::FixedWindow { limit, window_secs }
+            | LimitConfig::SlidingWindowLog { limit, window_secs } => (limit, window_secs as f64),
+        };
+        if quota == 0 {
+            problems.push(format!("rate_limit.{name}: the limit must be at least 1"));
+        }
+        if !(rate.is_finite() && rate > 0.0) {
+            problems.push(format!(
+                "rate_limit.{name}: the rate or window must be positive"
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Off, every lookup goes to the store behind the cache.
+    pub enabled: bool,
+    /// Entries kept in memory.
+    pub capacity: usize,
+    /// How long a user stays cached once it is looked up or created.
+    pub ttl_secs: u64,
+    /// How often expired entries are swept out.
+    pub sweep_secs: u64,
+}
+
+/// Longest `cache.ttl_secs` accepted; expiry times much further out stop fitting the clock.
+const MAX_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+impl CacheConfig {
+    /// What the in-memory tier is sized to; zero keeps nothing when the cache is off.
+    pub fn effective_capacity(&self) -> usize {
+        if self.enabled {
+            self.capacity
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:7878".to_string(),
+            log_format: LogFormat::Json,
+            middlewares: Middlewares {
+                logger: true,
+                compression: true,
+                rate_limit: true,
+            },
+            compression: CompressionConfig {
+                encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+                min_size: 1024,
+                max_request_body: Limits::default().max_body_bytes,
+            },
+            rate_limit: RateLimitConfig {
+                store: None,
+                instances: 1,
+                store_timeout_ms: 250,
+                ip: LimitConfig::TokenBucket {
+                    capacity: 5,
+                    refill_per_sec: 1.0,
+                },
+                routes: Vec::new(),
+            },
+            cache: CacheConfig {
+                enabled: true,
+                capacity: 100,
+                ttl_secs: 300,
+                sweep_secs: 30,
+            },
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Merges the layers and validates the result. The config file must exist.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        Self::merge(cli, true)
+    }
+
+    /// Like `load`, but a missing `server.toml` is skipped unless `--config` named it. For
+    /// startup only: on reload, a file deleted or mid-replace must not reset the live
+    /// settings to the defaults.
+    pub fn load_or_default(cli: &Cli) -> anyhow::Result<Self> {
+        Self::merge(cli, cli.config.is_some())
+    }
+
+    fn merge(cli: &Cli, file_required: bool) -> anyhow::Result<Self> {
+        let file = File::from(cli.config_file().as_path()).required(file_required);
+        let mut builder = Config::builder()
+            .add_source(Config::try_from(&ServerConfig::default())?)
+            .add_source(file)
+            .add_source(
+                Environment::with_prefix("SERVER")
+                    .prefix_separator("_")
+                    .separator("__")
+                    .try_parsing(true),
+            );
+        for entry in &cli.overrides {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("--set {entry}: expected KEY=VALUE"))?;
+            builder = builder.set_override(key.trim(), value.trim())?;
+        }
+        if let Some(listen) = &cli.listen {
+            builder = builder.set_override("listen", listen.as_str())?;
+        }
+
+        let config: ServerConfig = builder
+            .build()?
+            .try_deserialize()
+            .context("invalid configuration")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every problem at once rather than the first.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if self.listen.parse::<SocketAddr>().is_err() {
+            problems.push(format!("listen: {:?} is not an ip:port", self.listen));
+        }
+
+        let compression = &self.compression;
+        if compression.encodings.is_empty() {
+            problems.push(
+                "compression.encodings: list at least one, or turn middlewares.compression off"
+                    .to_string(),
+            );
+        }
+        if compression.max_request_body == 0 {
+            problems.push("compression.max_request_body: must be at least 1".to_string());
+        }
+
+        let limits = &self.rate_limit;
+        if limits.store.as_deref() == Some("") {
+            problems.push("rate_limit.store: leave it out rather than empty".to_string());
+        }
+        if limits.instances == 0 {
+            problems.push("rate_limit.instances: must be at least 1".to_string());
+        }
+        if limits.store.as_deref() == Some("memory") && limits.instances != 1 {
+            problems.push("rate_limit.instances: a memory store is not shared, so 1".to_string());
+        }
+        if limits.store_timeout_ms == 0 {
+            problems.push("rate_limit.store_timeout_ms: must be at least 1".to_string());
+        }
+        limits.ip.check("ip", &mut problems);
+        for (i, route) in limits.routes.iter().enumerate() {
+            let name = format!("routes[{i}]");
+            if !route.path.starts_with('/') {
+                problems.push(format!("rate_limit.{name}: the path must start with /"));
+            }
+            // The router only takes a wildcard as the last segment.
+            if route.path.rsplit('/').skip(1).any(|s| s.starts_with('*')) {
+                problems.push(format!("rate_limit.{name}: a wildcard must come last"));
+            }
+            route.limit.check(&name, &mut problems);
+        }
+
+        let cache = &self.cache;
+        if cache.enabled && cache.capacity == 0 {
+            problems.push("cache.capacity: must be at least 1; set enabled = false instead".into());
+        }
+        if cache.ttl_secs > MAX_TTL_SECS {
+            problems.push(format!(
+                "cache.ttl_secs: must be at most {MAX_TTL_SECS} (a year)"
+            ));
+        }
+        if cache.sweep_secs == 0 {
+            problems.push("cache.sweep_secs: must be at least 1".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            bail!("invalid configuration:\n  {}", problems.join("\n  "))
+        }
+    }
+
+    /// Sections that differ in `next` but are only read at startup, so a reload leaves
+    /// them as they were until the server is restarted.
+    pub fn needs_restart(&self, next: &ServerConfig) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.listen != next.listen {
+            sections.push("listen");
+        }
+        if self.cache != next.cache {
+            sections.push("cache");
+        }
+        sections
+    }
+
+    /// The effective settings, in the config file's format.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config serializes to TOML")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_round_trip_through_toml_and_validate() {
+        let defaults = ServerConfig::default();
+        defaults.validate().unwrap();
+        let parsed: ServerConfig = toml::from_str(&defaults.to_toml()).unwrap();
+        assert_eq!(parsed, defaults);
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let dir = std::env::temp_dir().join(format!("server-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        std::fs::write(
+            &path,
+            r#"
+                listen = "127.0.0.1:9000"
+                log_format = "logfmt"
+
+                [cache]
+                capacity = 500
+
+                [rate_limit.ip]
+                algorithm = "fixed_window"
+                limit = 50
+                window_secs = 10
+
+                [middlewares]
+                compression = false
+
+                [[rate_limit.routes]]
+                method = "GET"
+                path = "/hello/:name"
+                key = "api_key"
+                algorithm = "fixed_window"
+                limit = 10
+                window_secs = 60
+            "#,
+        )
+        .unwrap();
+
+        let cli = Cli {
+            config: Some(path),
+            overrides: vec!["cache.capacity=700".into()],
+            listen: Some("127.0.0.1:9001".into()),
+            ..Cli::default()
+        };
+        let config = ServerConfig::load(&cli).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(config.listen, "127.0.0.1:9001");
+        assert_eq!(
+            config.rate_limit.routes,
+            [RouteLimit {
+                method: "GET".into(),
+                path: "/hello/:name".into(),
+                key: KeyBy::ApiKey,
+                limit: LimitConfig::FixedWindow {
+                    limit: 10,
+                    window_secs: 60
+                },
+            }]
+        );
+        assert_eq!(config.log_format, LogFormat::Logfmt);
+        assert_eq!(config.cache.capacity, 700);
+        // Settings the file leaves out keep their defaults.
+        assert_eq!(config.cache.ttl_secs, 300);
+        assert!(!config.middlewares.compression && config.middlewares.logger);
+        assert_eq!(
+            config.rate_limit.ip,
+            LimitConfig::FixedWindow {
+                limit: 50,
+                window_secs: 10
+            }
+        );
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let mut config = ServerConfig {
+            listen: "7878".into(),
+            ..ServerConfig::default()
+        };
+        config.cache.sweep_secs = 0;
+        config.cache.ttl_secs = u64::MAX;
+        config.rate_limit.ip = LimitConfig::FixedWindow {
+            limit: 0,
+            window_secs: 60,
+        };
+        config.compression.encodings.clear();
+        let message = config.validate().unwrap_err().to_string();
+        for needle in [
+            "listen",
+            "compression.encodings",
+            "cache.sweep_secs",
+            "cache.ttl_secs",
+            "rate_limit.ip",
+        ] {
+            assert!(message.contains(needle), "{message}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod health;
 pub mod hello;
+pub mod metrics;
 pub mod user;
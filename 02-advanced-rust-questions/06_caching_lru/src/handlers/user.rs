@@ -1,10 +1,9 @@
-use std::{clone, sync::Arc};
+use std::sync::{Arc, Mutex};
 
-use crate::{cache::LRUCache, server::UserCache, types::Response};
+use crate::{request::Request, server::UserCache, types::Response};
 use mongodb::bson::doc;
 use mongodb::Database;
 use serde::{Deserialize, Serialize};
-// use uuid::Uuid;
 
 #[derive(Deserialize)]
 struct CreateUserRequest {
@@ -26,146 +25,97 @@ struct GetUserRequest {
     email: String,
 }
 
-pub async fn get(
-    req: &str,
-    db: &Database,
-    cache: &Arc<std::sync::Mutex<LRUCache<std::string::String, UserResponse>>>,
-) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body_string = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<GetUserRequest>(body_string) {
-            if let Ok(mut cache_guard) = cache.lock() {
-                if let Some(user) = cache_guard.get(&payload.email) {
-                    if let Ok(body) = serde_json::to_string(&vec![user]) {
-                        println!("cache hit!");
-                        return Response {
-                            status: 200,
-                            content_type: "application/json".into(),
-                            body,
-                        };
-                    }
-                }
+impl GetUserRequest {
+    /// `?email=` wins; older clients still send `{"email": ...}` as the body.
+    fn from_request(req: &Request) -> Option<Self> {
+        match req.query_param("email") {
+            Some(email) => Some(GetUserRequest {
+                email: email.to_string(),
+            }),
+            None => serde_json::from_slice(&req.body).ok(),
+        }
+    }
+}
+
+pub async fn get(req: &Request, db: &Database, cache: &Arc<Mutex<UserCache>>) -> Response {
+    if let Some(payload) = GetUserRequest::from_request(req) {
+        if let Ok(mut cache_guard) = cache.lock() {
+            if let Some(user) = cache_guard.get(&payload.email) {
+                println!("cache hit!");
+                return Response::json(200, &vec![user]);
             }
-            let collection = db.collection::<mongodb::bson::Document>("users");
-            // println!("email: {}", &payload.email);
-            let filter = doc! { "email": &payload.email };
-            match collection.find(filter).await {
-                Ok(mut cursor) => {
-                    use futures::stream::TryStreamExt;
-                    let mut users = Vec::new();
-                    while let Some(doc) = cursor.try_next().await.unwrap_or(None) {
-                        // Extract fields manually, mapping _id to id
-                        let id = doc
-                            .get_object_id("_id")
-                            .map(|oid| oid.to_hex())
-                            .unwrap_or_default();
-                        let name = doc.get_str("name").unwrap_or("").to_string();
-                        let age = doc.get_i32("age").unwrap_or(0) as u32;
-                        let email = doc.get_str("email").unwrap_or("").to_string();
-                        let user = UserResponse {
-                            id,
-                            name,
-                            age,
-                            email: email.clone(),
-                        };
+        }
+        let collection = db.collection::<mongodb::bson::Document>("users");
+        // println!("email: {}", &payload.email);
+        let filter = doc! { "email": &payload.email };
+        match collection.find(filter).await {
+            Ok(mut cursor) => {
+                use futures::stream::TryStreamExt;
+                let mut users = Vec::new();
+                while let Some(doc) = cursor.try_next().await.unwrap_or(None) {
+                    // Extract fields manually, mapping _id to id
+                    let id = doc
+                        .get_object_id("_id")
+                        .map(|oid| oid.to_hex())
+                        .unwrap_or_default();
+                    let name = doc.get_str("name").unwrap_or("").to_string();
+                    let age = doc.get_i32("age").unwrap_or(0) as u32;
+                    let email = doc.get_str("email").unwrap_or("").to_string();
+                    let user = UserResponse {
+                        id,
+                        name,
+                        age,
+                        email: email.clone(),
+                    };
 
-                        if let Ok(mut cache_guard) = cache.lock() {
-                            println!("Entering in cache");
-                            cache_guard.put(email.clone(), user.clone());
-                        }
-                        users.push(user);
-                    }
-                    if !users.is_empty() {
-                        if let Ok(body) = serde_json::to_string(&users) {
-                            return Response {
-                                status: 200,
-                                content_type: "application/json".into(),
-                                body,
-                            };
-                        }
+                    if let Ok(mut cache_guard) = cache.lock() {
+                        println!("Entering in cache");
+                        cache_guard.put(email.clone(), user.clone());
                     }
-                    return Response {
-                        status: 404,
-                        content_type: "text/plain".into(),
-                        body: "No users found".into(),
-                    };
+                    users.push(user);
                 }
-                Err(_) => {
-                    return Response {
-                        status: 500,
-                        content_type: "text/plain".into(),
-                        body: "Database error".into(),
-                    };
+                if !users.is_empty() {
+                    return Response::json(200, &users);
                 }
+                return Response::text(404, "No users found");
+            }
+            Err(_) => {
+                return Response::text(500, "Database error");
             }
         }
     }
-    Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
-    }
+    Response::text(400, "Bad Request")
 }
 
-pub async fn handle(
-    req: &str,
-    db: &Database,
-    cache: &Arc<std::sync::Mutex<LRUCache<std::string::String, UserResponse>>>,
-) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body_str = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<CreateUserRequest>(body_str) {
-            let user_doc = doc! {
-                "name": &payload.name,
-                "age": payload.age as i32,
-                "email": &payload.email,
-            };
-            let collection = db.collection("users");
-            match collection.insert_one(user_doc).await {
-                Ok(result) => {
-                    if let Some(oid) = result.inserted_id.as_object_id() {
-                        let user = UserResponse {
-                            id: oid.to_hex(),
-                            name: payload.name,
-                            age: payload.age,
-                            email: payload.email.clone(),
-                        };
-                        if let Ok(body) = serde_json::to_string(&user) {
-                            if let Ok(mut cache_guard) = cache.lock() {
-                                println!("Entering in cache");
-                                cache_guard.put(payload.email, user.clone());
-                            }
-
-                            return Response {
-                                status: 201,
-                                content_type: "application/json".into(),
-                                body,
-                            };
-                        }
+pub async fn handle(req: &Request, db: &Database, cache: &Arc<Mutex<UserCache>>) -> Response {
+    if let Ok(payload) = serde_json::from_slice::<CreateUserRequest>(&req.body) {
+        let user_doc = doc! {
+            "name": &payload.name,
+            "age": payload.age as i32,
+            "email": &payload.email,
+        };
+        let collection = db.collection("users");
+        match collection.insert_one(user_doc).await {
+            Ok(result) => {
+                if let Some(oid) = result.inserted_id.as_object_id() {
+                    let user = UserResponse {
+                        id: oid.to_hex(),
+                        name: payload.name,
+                        age: payload.age,
+                        email: payload.email.clone(),
+                    };
+                    if let Ok(mut cache_guard) = cache.lock() {
+                        println!("Entering in cache");
+                        cache_guard.put(payload.email, user.clone());
                     }
+
+                    return Response::json(201, &user);
                 }
-                Err(err) => {
-                    eprintln!("mongo insert error: {:?}", err);
-                }
             }
-            // let user = UserResponse {
-            //     id: Uuid::new_v4().to_string(),
-            //     name: payload.name,
-            //     age: payload.age,
-            //     email: payload.email,
-            // };
-            // if let Ok(body) = serde_json::to_string(&user) {
-            //     return Response {
-            //         status: 201,
-            //         content_type: "application/json".into(),
-            //         body,
-            //     };
-            // }
+            Err(err) => {
+                eprintln!("mongo insert error: {:?}", err);
+            }
         }
     }
-    return Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
-    };
+    Response::text(400, "Bad Request")
 }
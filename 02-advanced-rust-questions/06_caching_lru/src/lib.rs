@@ -0,0 +1,3 @@
+// The LRU cache is a library as well, so the benchmark builds against the same module the
+// server uses.
+pub mod cache;
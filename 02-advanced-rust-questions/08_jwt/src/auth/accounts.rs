@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::Subject;
+
+#[derive(Debug)]
+pub struct AccountError {
+    pub name: String,
+    pub reason: String,
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "account {:?}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+const PBKDF2_ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+struct Account {
+    // PBKDF2-HMAC-SHA256 of the password under `salt`, so equal passwords do not share a
+    // hash and a leaked hash is slow to guess from.
+    salt: [u8; 16],
+    password: [u8; 32],
+    roles: Vec<String>,
+    scopes: Vec<String>,
+}
+
+impl Account {
+    // `pbkdf2::verify` compares the derived key in constant time.
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            PBKDF2_ITERATIONS,
+            &self.salt,
+            password.as_bytes(),
+            &self.password,
+        )
+        .is_ok()
+    }
+}
+
+/// The accounts that may sign in with a password, and the roles and scopes each one is
+/// granted. Grants only ever come from here, never from the token request.
+#[derive(Default)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    anonymous: bool,
+}
+
+impl fmt::Debug for Accounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.accounts.keys()).finish()
+    }
+}
+
+impl Accounts {
+    /// Reads `AUTH_ACCOUNTS`; without it nobody can sign in. `AUTH_ANONYMOUS=true` lets
+    /// any other name have a token without grants.
+    pub fn from_env() -> Result<Self, AccountError> {
+        let accounts = match std::env::var("AUTH_ACCOUNTS") {
+            Ok(spec) => Self::parse(&spec)?,
+            Err(_) => Self::default(),
+        };
+        let anonymous = std::env::var("AUTH_ANONYMOUS").is_ok_and(|v| v == "true" || v == "1");
+        Ok(accounts.allow_anonymous(anonymous))
+    }
+
+    /// Whether names without an account get a token, with no roles or scopes.
+    pub fn allow_anonymous(mut self, allow: bool) -> Self {
+        self.anonymous = allow;
+        self
+    }
+
+    /// `name:password:roles:scopes`, comma separated. Roles and scopes are space separated
+    /// and may be empty; scopes come last since they contain colons themselves.
+    pub fn parse(spec: &str) -> Result<Self, AccountError> {
+        let rng = SystemRandom::new();
+        let mut accounts = HashMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(4, ':');
+            let name = parts.next().unwrap_or_default();
+            let (Some(password), Some(roles), Some(scopes)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid(name, "expected name:password:roles:scopes"));
+            };
+            if password.is_empty() {
+                return Err(invalid(name, "empty password"));
+            }
+            let mut salt = [0; 16];
+            rng.fill(&mut salt)
+                .map_err(|_| invalid(name, "no randomness for a salt"))?;
+            let mut hash = [0; 32];
+            pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                PBKDF2_ITERATIONS,
+                &salt,
+                password.as_bytes(),
+                &mut hash,
+            );
+            let account = Account {
+                salt,
+                password: hash,
+                roles: roles.split_whitespace().map(String::from).collect(),
+                scopes: scopes.split_whitespace().map(String::from).collect(),
+            };
+            if accounts.insert(name.to_string(), account).is_some() {
+                return Err(invalid(name, "duplicate account"));
+            }
+        }
+        Ok(Accounts {
+            accounts,
+            anonymous: false,
+        })
+    }
+
+    /// Who a token request is for. A configured account gets its grants once the password
+    /// checks out. Any other name is refused, unless anonymous tokens are allowed.
+    pub fn subject(
+        &self,
+        name: String,
+        email: Option<String>,
+        password: Option<&str>,
+    ) -> Option<Subject> {
+        if let Some(account) = self.accounts.get(&name) {
+            if !account.verify(password?) {
+                return None;
+            }
+        }
+        let (roles, scopes) = self.grants(&name)?;
+        Some(Subject {
+            name,
+            email,
+            roles,
+            scopes,
+        })
+    }
+
+    /// The roles and scopes `name` holds now, or `None` if it may not have a token.
+    pub fn grants(&self, name: &str) -> Option<(Vec<String>, Vec<String>)> {
+        match self.accounts.get(name) {
+            Some(account) => Some((account.roles.clone(), account.scopes.clone())),
+            None if self.anonymous => Some((Vec::new(), Vec::new())),
+            None => None,
+        }
+    }
+}
+
+fn invalid(name: &str, reason: &str) -> AccountError {
+    AccountError {
+        name: name.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_need_the_right_password() {
+        let accounts = Accounts::parse("admin:s3cret:admin:users:write users:read").unwrap();
+
+        let admin = accounts
+            .subject("admin".into(), None, Some("s3cret"))
+            .unwrap();
+        assert_eq!(admin.roles, ["admin"]);
+        assert_eq!(admin.scopes, ["users:write", "users:read"]);
+
+        assert!(accounts
+            .subject("admin".into(), None, Some("guess"))
+            .is_none());
+        assert!(accounts.subject("admin".into(), None, None).is_none());
+    }
+
+    #[test]
+    fn unknown_names_need_anonymous_tokens_allowed() {
+        let accounts = Accounts::parse("admin:s3cret:admin:").unwrap();
+        assert!(accounts.subject("guest".into(), None, None).is_none());
+
+        let accounts = accounts.allow_anonymous(true);
+        let guest = accounts.subject("guest".into(), None, None).unwrap();
+        assert!(guest.roles.is_empty() && guest.scopes.is_empty());
+        // The account itself still needs its password.
+        assert!(accounts.subject("admin".into(), None, None).is_none());
+    }
+
+    #[test]
+    fn equal_passwords_hash_differently() {
+        let accounts = Accounts::parse("a:pw::,b:pw::").unwrap();
+        let (a, b) = (&accounts.accounts["a"], &accounts.accounts["b"]);
+        assert_ne!(a.password, b.password);
+        assert!(a.verify("pw") && b.verify("pw"));
+    }
+
+    #[test]
+    fn malformed_specs_are_rejected() {
+        assert!(Accounts::parse("").unwrap().accounts.is_empty());
+        assert!(Accounts::parse("bot:pw").is_err());
+        assert!(Accounts::parse("bot::admin:").is_err());
+        assert!(Accounts::parse("bot:a::,bot:b::").is_err());
+    }
+}
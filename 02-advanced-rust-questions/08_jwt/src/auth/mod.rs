@@ -0,0 +1,522 @@
+// Token issuing and checking shared by the auth handlers and AuthMiddleware.
+
+pub mod accounts;
+pub mod keys;
+
+use std::fmt;
+use std::sync::{Arc, Weak};
+
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, encode, errors, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+pub use accounts::Accounts;
+pub use keys::Keyring;
+
+pub const ACCESS_TTL: Duration = Duration::hours(1);
+pub const REFRESH_TTL: Duration = Duration::days(14);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+    Access,
+    Refresh,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // subject = username
+    pub exp: usize,  // expiry timestamp
+    pub iat: usize,  // usize
+    pub jti: String, // unique per token, the key for revocation
+    pub fam: String, // refresh-token family the token was issued in
+    pub token_use: TokenUse,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Who a token pair is issued to.
+#[derive(Debug, Clone)]
+pub struct Subject {
+    pub name: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Invalid(errors::Error),
+    UnknownKey,
+    WrongTokenUse,
+    Revoked,
+    /// A refresh token was presented after it had already been rotated. The whole family
+    /// is revoked, since either the client or an attacker holds a stolen copy.
+    Reused,
+    Store(sled::Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Invalid(err) => write!(f, "{err}"),
+            AuthError::UnknownKey => write!(f, "unknown signing key"),
+            AuthError::WrongTokenUse => write!(f, "wrong token type"),
+            AuthError::Revoked => write!(f, "token revoked"),
+            AuthError::Reused => write!(f, "refresh token reused"),
+            AuthError::Store(err) => write!(f, "token store: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<errors::Error> for AuthError {
+    fn from(err: errors::Error) -> Self {
+        AuthError::Invalid(err)
+    }
+}
+
+impl From<sled::Error> for AuthError {
+    fn from(err: sled::Error) -> Self {
+        AuthError::Store(err)
+    }
+}
+
+/// Issues and checks tokens. Revocation state lives in two sled trees next to the user cache:
+/// `revoked_jti` maps a revoked token id to its expiry (so it can be purged once the token
+/// could no longer verify anyway), and `refresh_families` maps each live family to the expiry
+/// and jti of the only refresh token in it that may still be used.
+pub struct AuthService {
+    keys: Keyring,
+    accounts: Accounts,
+    revoked: sled::Tree,
+    families: sled::Tree,
+}
+
+impl fmt::Debug for AuthService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthService")
+            .field("keys", &self.keys)
+            .field("accounts", &self.accounts)
+            .finish()
+    }
+}
+
+impl AuthService {
+    pub fn new(keys: Keyring, db: &sled::Db) -> Result<Self, sled::Error> {
+        Ok(AuthService {
+            keys,
+            accounts: Accounts::default(),
+            revoked: db.open_tree("revoked_jti")?,
+            families: db.open_tree("refresh_families")?,
+        })
+    }
+
+    /// Accounts whose roles and scopes go into the tokens issued to them.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    pub fn keys(&self) -> &Keyring {
+        &self.keys
+    }
+
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    /// Starts a new refresh family for `subject`.
+    pub fn issue(&self, subject: &Subject) -> Result<TokenPair, AuthError> {
+        self.issue_in(subject, &uuid::Uuid::new_v4().to_string(), None)
+    }
+
+    /// Trades a refresh token for a new pair. The old refresh token is spent; presenting it
+    /// again revokes the family, including access tokens issued from it.
+    pub fn refresh(&self, refresh_token: &str) -> Result<TokenPair, AuthError> {
+        let claims = self.decode(refresh_token)?;
+        if claims.token_use != TokenUse::Refresh {
+            return Err(AuthError::WrongTokenUse);
+        }
+        // Only the family pointer is consulted: revoking a refresh token drops its family,
+        // and a spent one is no longer the family's current token, which marks a replay.
+        let current = match self.families.get(claims.fam.as_bytes())? {
+            None => return Err(AuthError::Revoked),
+            Some(current) if family_jti(&current) != claims.jti.as_bytes() => {
+                self.families.remove(claims.fam.as_bytes())?;
+                return Err(AuthError::Reused);
+            }
+            Some(current) => current,
+        };
+        // Grants come from the account as it is now, so a changed or removed account
+        // takes effect at the next refresh rather than when the family expires.
+        let Some((roles, scopes)) = self.accounts.grants(&claims.sub) else {
+            self.families.remove(claims.fam.as_bytes())?;
+            return Err(AuthError::Revoked);
+        };
+        self.deny(&claims)?;
+
+        let subject = Subject {
+            name: claims.sub,
+            email: claims.email,
+            roles,
+            scopes,
+        };
+        self.issue_in(&subject, &claims.fam, Some(&current))
+    }
+
+    /// Checks an access token: signature, expiry, denylist and that its family is live.
+    pub fn verify_access(&self, token: &str) -> Result<Claims, AuthError> {
+        let claims = self.decode(token)?;
+        if claims.token_use != TokenUse::Access {
+            return Err(AuthError::WrongTokenUse);
+        }
+        if self.revoked.contains_key(claims.jti.as_bytes())?
+            || !self.families.contains_key(claims.fam.as_bytes())?
+        {
+            return Err(AuthError::Revoked);
+        }
+        Ok(claims)
+    }
+
+    /// Revokes a token. Revoking a refresh token ends its whole family.
+    pub fn revoke(&self, token: &str) -> Result<(), AuthError> {
+        let claims = self.decode(token)?;
+        self.deny(&claims)?;
+        if claims.token_use == TokenUse::Refresh {
+            self.families.remove(claims.fam.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Drops denylist entries and refresh families whose tokens have expired anyway.
+    /// Returns how many went.
+    pub fn purge_expired(&self) -> Result<usize, AuthError> {
+        let now = Utc::now().timestamp() as u64;
+        let mut purged = 0;
+        for entry in self.revoked.iter() {
+            let (jti, exp) = entry?;
+            let exp = u64::from_be_bytes(exp.as_ref().try_into().unwrap_or_default());
+            if exp < now {
+                self.revoked.remove(jti)?;
+                purged += 1;
+            }
+        }
+        for entry in self.families.iter() {
+            let (family, current) = entry?;
+            // Swapped out rather than removed, so a family rotated meanwhile is kept.
+            if family_exp(&current) < now
+                && self
+                    .families
+                    .compare_and_swap(&family, Some(&current), None as Option<&[u8]>)?
+                    .is_ok()
+            {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    // `current` is the family entry the caller saw; the new refresh token only replaces it
+    // if nobody else rotated the family first. Losing that race means the same refresh
+    // token was used twice, which is treated as reuse.
+    fn issue_in(
+        &self,
+        subject: &Subject,
+        family: &str,
+        current: Option<&[u8]>,
+    ) -> Result<TokenPair, AuthError> {
+        let access_jti = uuid::Uuid::new_v4().to_string();
+        let refresh_jti = uuid::Uuid::new_v4().to_string();
+        let (access_token, _) = self.sign(subject, family, TokenUse::Access, &access_jti)?;
+        let (refresh_token, refresh_exp) =
+            self.sign(subject, family, TokenUse::Refresh, &refresh_jti)?;
+        let entry = family_entry(refresh_exp as u64, &refresh_jti);
+        if self
+            .families
+            .compare_and_swap(family.as_bytes(), current, Some(entry))?
+            .is_err()
+        {
+            self.families.remove(family.as_bytes())?;
+            return Err(AuthError::Reused);
+        }
+
+        Ok(TokenPair {
+            access_token,
+            refresh_token,
+            token_type: "Bearer",
+            expires_in: ACCESS_TTL.num_seconds(),
+        })
+    }
+
+    fn sign(
+        &self,
+        subject: &Subject,
+        family: &str,
+        token_use: TokenUse,
+        jti: &str,
+    ) -> Result<(String, usize), AuthError> {
+        let ttl = match token_use {
+            TokenUse::Access => ACCESS_TTL,
+            TokenUse::Refresh => REFRESH_TTL,
+        };
+        let now = Utc::now();
+        let claims = Claims {
+            sub: subject.name.clone(),
+            exp: (now + ttl).timestamp() as usize,
+            iat: now.timestamp() as usize,
+            jti: jti.to_string(),
+            fam: family.to_string(),
+            token_use,
+            email: subject.email.clone(),
+            roles: subject.roles.clone(),
+            scopes: subject.scopes.clone(),
+        };
+        let key = self.keys.active();
+        let mut header = Header::new(key.alg);
+        header.kid = Some(key.kid.clone());
+        Ok((encode(&header, &claims, &key.encoding)?, claims.exp))
+    }
+
+    // Signature and expiry only; the key is picked by `kid` and must match the header's alg.
+    fn decode(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = decode_header(token)?;
+        let key = header
+            .kid
+            .as_deref()
+            .and_then(|kid| self.keys.get(kid))
+            .ok_or(AuthError::UnknownKey)?;
+        let validation = Validation::new(key.alg);
+        Ok(decode::<Claims>(token, &key.decoding, &validation)?.claims)
+    }
+
+    fn deny(&self, claims: &Claims) -> Result<(), AuthError> {
+        self.revoked
+            .insert(claims.jti.as_bytes(), &(claims.exp as u64).to_be_bytes())?;
+        Ok(())
+    }
+}
+
+// A family entry is the refresh token's expiry (big-endian seconds) followed by its jti.
+fn family_entry(exp: u64, jti: &str) -> Vec<u8> {
+    let mut entry = exp.to_be_bytes().to_vec();
+    entry.extend_from_slice(jti.as_bytes());
+    entry
+}
+
+fn family_exp(entry: &[u8]) -> u64 {
+    u64::from_be_bytes(
+        entry
+            .get(..8)
+            .and_then(|b| b.try_into().ok())
+            .unwrap_or_default(),
+    )
+}
+
+fn family_jti(entry: &[u8]) -> &[u8] {
+    entry.get(8..).unwrap_or_default()
+}
+
+/// Purges the denylist every `every`. The task ends once the service is dropped.
+pub fn spawn_purge(auth: &Arc<AuthService>, every: std::time::Duration) {
+    let auth: Weak<AuthService> = Arc::downgrade(auth);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            ticker.tick().await;
+            let Some(auth) = auth.upgrade() else { break };
+            if let Err(err) = auth.purge_expired() {
+                eprintln!("jti purge failed: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::keys::KeyError;
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+
+    fn service(keys: Keyring) -> AuthService {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        AuthService::new(keys, &db)
+            .unwrap()
+            .with_accounts(Accounts::parse("alice:pw::users:write").unwrap())
+    }
+
+    fn alice() -> Subject {
+        Subject {
+            name: "alice".into(),
+            email: None,
+            roles: Vec::new(),
+            scopes: vec!["users:write".into()],
+        }
+    }
+
+    fn ed25519_pem() -> String {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref()))
+    }
+
+    #[test]
+    fn refresh_rotates_and_detects_reuse() {
+        let auth = service(Keyring::parse("k1:HS256:test-secret").unwrap());
+        let first = auth.issue(&alice()).unwrap();
+        assert_eq!(
+            auth.verify_access(&first.access_token).unwrap().scopes,
+            ["users:write"]
+        );
+
+        let second = auth.refresh(&first.refresh_token).unwrap();
+        assert!(auth.verify_access(&second.access_token).is_ok());
+
+        // Replaying the spent token kills the family, so the newer tokens stop working too.
+        assert!(matches!(
+            auth.refresh(&first.refresh_token),
+            Err(AuthError::Reused)
+        ));
+        assert!(matches!(
+            auth.refresh(&second.refresh_token),
+            Err(AuthError::Revoked)
+        ));
+        assert!(matches!(
+            auth.verify_access(&second.access_token),
+            Err(AuthError::Revoked)
+        ));
+    }
+
+    #[test]
+    fn refresh_reads_the_current_grants() {
+        let auth = service(Keyring::parse("k1:HS256:test-secret").unwrap());
+        let stale = Subject {
+            scopes: vec!["users:read".into()],
+            ..alice()
+        };
+        let pair = auth.issue(&stale).unwrap();
+        let pair = auth.refresh(&pair.refresh_token).unwrap();
+        assert_eq!(
+            auth.verify_access(&pair.access_token).unwrap().scopes,
+            ["users:write"]
+        );
+
+        // Without an account there is nothing left to refresh.
+        let gone = Subject {
+            name: "bob".into(),
+            ..alice()
+        };
+        let pair = auth.issue(&gone).unwrap();
+        assert!(matches!(
+            auth.refresh(&pair.refresh_token),
+            Err(AuthError::Revoked)
+        ));
+        assert!(matches!(
+            auth.verify_access(&pair.access_token),
+            Err(AuthError::Revoked)
+        ));
+    }
+
+    #[test]
+    fn concurrent_refreshes_rotate_only_once() {
+        let auth = Arc::new(service(Keyring::parse("k1:HS256:test-secret").unwrap()));
+        for _ in 0..20 {
+            let pair = auth.issue(&alice()).unwrap();
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let racers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (auth, barrier) = (auth.clone(), barrier.clone());
+                    let token = pair.refresh_token.clone();
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        auth.refresh(&token)
+                    })
+                })
+                .collect();
+            let results: Vec<_> = racers.into_iter().map(|r| r.join().unwrap()).collect();
+            assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+            assert!(results.iter().any(|r| matches!(r, Err(AuthError::Reused))));
+        }
+    }
+
+    #[test]
+    fn purge_drops_families_whose_refresh_token_expired() {
+        let auth = service(Keyring::parse("k1:HS256:test-secret").unwrap());
+        let live = auth.issue(&alice()).unwrap();
+        auth.families
+            .insert("stale", family_entry(1, "old-jti"))
+            .unwrap();
+
+        assert_eq!(auth.purge_expired().unwrap(), 1);
+        assert!(!auth.families.contains_key("stale").unwrap());
+        assert!(auth.verify_access(&live.access_token).is_ok());
+    }
+
+    #[test]
+    fn revoked_tokens_are_rejected() {
+        let auth = service(Keyring::parse("k1:HS256:test-secret").unwrap());
+        let pair = auth.issue(&alice()).unwrap();
+        auth.revoke(&pair.access_token).unwrap();
+        assert!(matches!(
+            auth.verify_access(&pair.access_token),
+            Err(AuthError::Revoked)
+        ));
+        assert!(matches!(
+            auth.verify_access(&pair.refresh_token),
+            Err(AuthError::WrongTokenUse)
+        ));
+        assert_eq!(auth.purge_expired().unwrap(), 0);
+    }
+
+    #[test]
+    fn rotated_keys_still_verify_old_tokens() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let old = AuthService::new(Keyring::parse("k1:HS256:old-secret").unwrap(), &db).unwrap();
+        let pair = old.issue(&alice()).unwrap();
+
+        let pem_path = std::env::temp_dir().join(format!("ed25519-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&pem_path, ed25519_pem()).unwrap();
+        let spec = format!("k2:EdDSA:{},k1:HS256:old-secret", pem_path.display());
+        let rotated = AuthService::new(Keyring::parse(&spec).unwrap(), &db).unwrap();
+        std::fs::remove_file(&pem_path).unwrap();
+
+        assert!(rotated.verify_access(&pair.access_token).is_ok());
+        let fresh = rotated.issue(&alice()).unwrap();
+        assert_eq!(
+            decode_header(&fresh.access_token).unwrap().kid.as_deref(),
+            Some("k2")
+        );
+        assert!(rotated.verify_access(&fresh.access_token).is_ok());
+        // The old service has never heard of k2.
+        assert!(matches!(
+            old.verify_access(&fresh.access_token),
+            Err(AuthError::UnknownKey)
+        ));
+
+        let jwks = rotated.keys().jwks();
+        let keys = jwks["keys"].as_array().unwrap();
+        assert_eq!(keys.len(), 1, "HS256 secrets must not be published");
+        assert_eq!(keys[0]["kid"], "k2");
+        assert_eq!(keys[0]["crv"], "Ed25519");
+    }
+
+    #[test]
+    fn missing_or_bad_key_config_is_an_error() {
+        assert!(matches!(Keyring::new(Vec::new()), Err(KeyError::Missing)));
+        assert!(Keyring::parse("k1:HS512:secret").is_err());
+        assert!(Keyring::parse("k1:HS256:a,k1:HS256:b").is_err());
+        assert!(Keyring::parse("k1:HS256:").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod health;
 pub mod hello;
+pub mod metrics;
 pub mod user;
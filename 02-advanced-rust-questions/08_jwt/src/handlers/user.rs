@@ -1,11 +1,7 @@
-use std::{clone, sync::Arc};
-
-use crate::{cache::LRUCache, server::UserCache, types::Response};
+use crate::{request::Request, server::UserCache, types::Response};
 use mongodb::bson::doc;
 use mongodb::Database;
 use serde::{Deserialize, Serialize};
-use sled;
-// use uuid::Uuid;
 
 #[derive(Deserialize)]
 struct CreateUserRequest {
@@ -27,135 +23,162 @@ struct GetUserRequest {
     email: String,
 }
 
-pub async fn get(req: &str, db: &Database, cache: &sled::Db) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body_string = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<GetUserRequest>(body_string) {
-            if let Ok(Some(value)) = cache.get(&payload.email) {
-                if let Ok(user) = serde_json::from_slice::<UserResponse>(&value) {
-                    println!("SLED hit!");
-                    if let Ok(body) = serde_json::to_string(&vec![user]) {
-                        return Response {
-                            status: 200,
-                            content_type: "application/json".into(),
-                            body,
-                        };
-                    }
-                }
-            }
-            let collection = db.collection::<mongodb::bson::Document>("users");
-            // println!("email: {}", &payload.email);
-            let filter = doc! { "email": &payload.email };
-            match collection.find(filter).await {
-                Ok(mut cursor) => {
-                    use futures::stream::TryStreamExt;
-                    let mut users = Vec::new();
-                    while let Some(doc) = cursor.try_next().await.unwrap_or(None) {
-                        // Extract fields manually, mapping _id to id
-                        let id = doc
-                            .get_object_id("_id")
-                            .map(|oid| oid.to_hex())
-                            .unwrap_or_default();
-                        let name = doc.get_str("name").unwrap_or("").to_string();
-                        let age = doc.get_i32("age").unwrap_or(0) as u32;
-                        let email = doc.get_str("email").unwrap_or("").to_string();
-                        let user = UserResponse {
-                            id,
-                            name,
-                            age,
-                            email: email.clone(),
-                        };
-
-                        let _ = cache.insert(email.as_bytes(), serde_json::to_vec(&user).unwrap());
-                        let _ = cache.flush();
-                        users.push(user);
-                    }
-                    if !users.is_empty() {
-                        if let Ok(body) = serde_json::to_string(&users) {
-                            return Response {
-                                status: 200,
-                                content_type: "application/json".into(),
-                                body,
-                            };
-                        }
-                    }
-                    return Response {
-                        status: 404,
-                        content_type: "text/plain".into(),
-                        body: "No users found".into(),
+impl GetUserRequest {
+    /// `?email=` wins; older clients still send `{"email": ...}` as the body.
+    fn from_request(req: &Request) -> Option<Self> {
+        match req.query_param("email") {
+            Some(email) => Some(GetUserRequest {
+                email: email.to_string(),
+            }),
+            None => serde_json::from_slice(&req.body).ok(),
+        }
+    }
+}
+
+pub async fn get(req: &Request, db: &Database, cache: &UserCache) -> Response {
+    if let Some(payload) = GetUserRequest::from_request(req) {
+        if let Some(user) = cache.get(&payload.email) {
+            return Response::json(200, &vec![user]);
+        }
+        let collection = db.collection::<mongodb::bson::Document>("users");
+        // println!("email: {}", &payload.email);
+        let filter = doc! { "email": &payload.email };
+        match collection.find(filter).await {
+            Ok(mut cursor) => {
+                use futures::stream::TryStreamExt;
+                let mut users = Vec::new();
+                while let Some(doc) = cursor.try_next().await.unwrap_or(None) {
+                    // Extract fields manually, mapping _id to id
+                    let id = doc
+                        .get_object_id("_id")
+                        .map(|oid| oid.to_hex())
+                        .unwrap_or_default();
+                    let name = doc.get_str("name").unwrap_or("").to_string();
+                    let age = doc.get_i32("age").unwrap_or(0) as u32;
+                    let email = doc.get_str("email").unwrap_or("").to_string();
+                    let user = UserResponse {
+                        id,
+                        name,
+                        age,
+                        email: email.clone(),
                     };
+
+                    cache.put(&email, &user);
+                    users.push(user);
                 }
-                Err(_) => {
-                    return Response {
-                        status: 500,
-                        content_type: "text/plain".into(),
-                        body: "Database error".into(),
-                    };
+                if !users.is_empty() {
+                    return Response::json(200, &users);
                 }
+                return Response::text(404, "No users found");
+            }
+            Err(_) => {
+                return Response::text(500, "Database error");
             }
         }
     }
-    Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
-    }
+    Response::text(400, "Bad Request")
 }
 
-pub async fn handle(req: &str, db: &Database, cache: &sled::Db) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body_str = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<CreateUserRequest>(body_str) {
-            let user_doc = doc! {
-                "name": &payload.name,
-                "age": payload.age as i32,
-                "email": &payload.email,
-            };
-            let collection = db.collection("users");
-            match collection.insert_one(user_doc).await {
-                Ok(result) => {
-                    if let Some(oid) = result.inserted_id.as_object_id() {
-                        let user = UserResponse {
-                            id: oid.to_hex(),
-                            name: payload.name,
-                            age: payload.age,
-                            email: payload.email.clone(),
-                        };
-                        if let Ok(body) = serde_json::to_string(&user) {
-                            let _ = cache
-                                .insert(user.email.as_bytes(), serde_json::to_vec(&user).unwrap());
-                            let _ = cache.flush();
-
-                            return Response {
-                                status: 201,
-                                content_type: "application/json".into(),
-                                body,
-                            };
-                        }
-                    }
-                }
-                Err(err) => {
-                    eprintln!("mongo insert error: {:?}", err);
+pub async fn handle(req: &Request, db: &Database, cache: &UserCache) -> Response {
+    if let Ok(payload) = serde_json::from_slice::<CreateUserRequest>(&req.body) {
+        let user_doc = doc! {
+            "name": &payload.name,
+            "age": payload.age as i32,
+            "email": &payload.email,
+        };
+        let collection = db.collection("users");
+        match collection.insert_one(user_doc).await {
+            Ok(result) => {
+                if let Some(oid) = result.inserted_id.as_object_id() {
+                    let user = UserResponse {
+                        id: oid.to_hex(),
+                        name: payload.name,
+                        age: payload.age,
+                        email: payload.email.clone(),
+                    };
+                    cache.put(&user.email, &user);
+
+                    return Response::json(201, &user);
                 }
             }
-            // let user = UserResponse {
-            //     id: Uuid::new_v4().to_string(),
-            //     name: payload.name,
-            //     age: payload.age,
-            //     email: payload.email,
-            // };
-            // if let Ok(body) = serde_json::to_string(&user) {
-            //     return Response {
-            //         status: 201,
-            //         content_type: "application/json".into(),
-            //         body,
-            //     };
-            // }
+            Err(err) => {
+                eprintln!("mongo insert error: {:?}", err);
+            }
         }
     }
-    return Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
-    };
+    Response::text(400, "Bad Request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::request::{Limits, RequestReader};
+
+    async fn lookup(email: &str) -> Request {
+        let raw = format!("GET /users?email={email} HTTP/1.1\r\nHost: test\r\n\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // Nothing listens on port 1, so a lookup that gets as far as MongoDB fails quickly.
+    async fn unreachable_db() -> Database {
+        mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap()
+            .database("test")
+    }
+
+    fn ada() -> UserResponse {
+        UserResponse {
+            id: "1".to_string(),
+            name: "Ada".to_string(),
+            age: 36,
+            email: "ada@example.com".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_users_are_not_served_from_sled() {
+        let disk = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = ServerConfig::default().cache;
+        let cache = UserCache::new(&config, disk.clone());
+        config.ttl_secs = 0;
+        let expiring = UserCache::new(&config, disk.clone());
+        let db = unreachable_db().await;
+        let user = ada();
+        let req = lookup(&user.email).await;
+
+        // A live entry is still found in sled once memory has lost it.
+        cache.put(&user.email, &user);
+        let restarted = UserCache::new(&ServerConfig::default().cache, disk);
+        assert_eq!(get(&req, &db, &restarted).await.status, 200);
+
+        // Expired in both tiers, so the read goes on to MongoDB, which is down.
+        expiring.put(&user.email, &user);
+        assert_eq!(get(&req, &db, &expiring).await.status, 500);
+    }
+
+    #[tokio::test]
+    async fn a_disabled_cache_leaves_users_to_mongodb() {
+        let disk = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = ServerConfig::default().cache;
+        let enabled = UserCache::new(&config, disk.clone());
+        config.enabled = false;
+        let disabled = UserCache::new(&config, disk.clone());
+        let db = unreachable_db().await;
+        let user = ada();
+        let req = lookup(&user.email).await;
+
+        disabled.put(&user.email, &user);
+        assert!(disk.is_empty());
+
+        // Even a live entry in sled is not read.
+        enabled.put(&user.email, &user);
+        assert_eq!(get(&req, &db, &disabled).await.status, 500);
+    }
 }
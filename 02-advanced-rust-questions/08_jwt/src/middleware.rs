@@ -1,32 +1,35 @@
+use crate::request::Request;
 use crate::types::Response;
 use std::future::Future;
 use std::pin::Pin;
 
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+
 pub trait Middleware: Send + Sync {
     fn handle(
         &self,
-        req: &str,
+        req: &Request,
         client_ip: &str,
-        next: &dyn Fn(&str) -> Pin<Box<dyn Future<Output = Response> + Send>>,
-    ) -> Pin<Box<dyn Future<Output = Response> + Send>>;
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture;
 }
 
-pub async fn run_chain<'a, F, Fut>(
-    req: &str,
+pub async fn run_chain<F, Fut>(
+    req: &Request,
     client_ip: &str,
-    middlewares: &'a [&dyn Middleware],
+    middlewares: &[&dyn Middleware],
     handler: F,
 ) -> Response
 where
-    F: Fn(&str) -> Fut + Send + Sync + 'static,
+    F: Fn(&Request) -> Fut + Send + Sync + 'static,
     Fut: Future<Output = Response> + Send + 'static,
 {
-    fn call<'a>(
-        req: &str,
+    fn call(
+        req: &Request,
         client_ip: &str,
-        middlewares: &'a [&dyn Middleware],
-        handler: &dyn Fn(&str) -> Pin<Box<dyn Future<Output = Response> + Send>>,
-    ) -> Pin<Box<dyn Future<Output = Response> + Send>> {
+        middlewares: &[&dyn Middleware],
+        handler: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
         if let Some((first, rest)) = middlewares.split_first() {
             first.handle(req, client_ip, &move |r| call(r, client_ip, rest, handler))
         } else {
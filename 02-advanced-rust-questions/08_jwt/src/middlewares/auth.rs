@@ -0,0 +1,253 @@
+use std::sync::Arc;
+
+use crate::auth::{AuthError, AuthService, Claims};
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::middlewares::rate_limiting::Identity;
+use crate::request::Request;
+use crate::router::{Lookup, Router};
+use crate::types::Response;
+use serde::Serialize;
+
+/// Who a request is acting as. Handlers read it with `req.extensions.get::<Principal>()`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Principal {
+    pub subject: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+impl From<Claims> for Principal {
+    fn from(claims: Claims) -> Self {
+        Principal {
+            subject: claims.sub,
+            email: claims.email,
+            roles: claims.roles,
+            scopes: claims.scopes,
+        }
+    }
+}
+
+/// What a route requires of the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Public,
+    Authenticated,
+    Role(&'static str),
+    Scope(&'static str),
+}
+
+/// Validates `Authorization: Bearer` tokens against the per-route policy table.
+/// Routes without an entry fall back to the default policy.
+pub struct AuthMiddleware {
+    auth: Arc<AuthService>,
+    default: Policy,
+    policies: Router<Policy>,
+}
+
+impl AuthMiddleware {
+    pub fn new(auth: Arc<AuthService>, default: Policy) -> Self {
+        AuthMiddleware {
+            auth,
+            default,
+            policies: Router::new(),
+        }
+    }
+
+    pub fn policy(mut self, method: &str, pattern: &str, policy: Policy) -> Self {
+        self.policies = self.policies.route(method, pattern, policy);
+        self
+    }
+
+    fn policy_for(&self, req: &Request) -> Policy {
+        match self.policies.lookup(req.method.as_str(), &req.path) {
+            Lookup::Found { handler, .. } => *handler,
+            _ => self.default,
+        }
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn handle(
+        &self,
+        req: &Request,
+        _client_ip: &str,
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
+        let policy = self.policy_for(req);
+        let token = req
+            .header("authorization")
+            .and_then(|v| {
+                v.strip_prefix("Bearer ")
+                    .or_else(|| v.strip_prefix("bearer "))
+            })
+            .map(str::trim);
+
+        let principal = match token.map(|t| self.auth.verify_access(t)) {
+            Some(Ok(claims)) => Principal::from(claims),
+            // A bad token on a public route is ignored rather than rejected.
+            _ if policy == Policy::Public => return next(req),
+            None => return reject(unauthorized("Bearer realm=\"api\"")),
+            Some(Err(AuthError::Store(err))) => {
+                eprintln!("token store error: {:?}", err);
+                return reject(Response::text(500, "Internal Server Error"));
+            }
+            Some(Err(_)) => {
+                return reject(unauthorized(
+                    "Bearer realm=\"api\", error=\"invalid_token\"",
+                ))
+            }
+        };
+
+        let allowed = match policy {
+            Policy::Public | Policy::Authenticated => true,
+            Policy::Role(role) => principal.roles.iter().any(|r| r == role),
+            Policy::Scope(scope) => principal.scopes.iter().any(|s| s == scope),
+        };
+        if !allowed {
+            return reject(forbidden(policy));
+        }
+
+        let mut req = req.clone();
+        req.extensions.insert(Identity(principal.subject.clone()));
+        req.extensions.insert(principal);
+        next(&req)
+    }
+}
+
+fn reject(response: Response) -> ResponseFuture {
+    Box::pin(async move { response })
+}
+
+fn unauthorized(challenge: &str) -> Response {
+    Response::text(401, "Unauthorized").with_header("WWW-Authenticate", challenge)
+}
+
+fn forbidden(policy: Policy) -> Response {
+    let mut res = Response::text(403, "Forbidden");
+    if let Policy::Scope(scope) = policy {
+        res = res.with_header(
+            "WWW-Authenticate",
+            &format!("Bearer realm=\"api\", error=\"insufficient_scope\", scope=\"{scope}\""),
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Keyring, Subject};
+    use crate::request::{Limits, RequestReader};
+
+    fn service() -> Arc<AuthService> {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let keys = Keyring::parse("test:HS256:test-secret").unwrap();
+        Arc::new(AuthService::new(keys, &db).unwrap())
+    }
+
+    fn token(auth: &AuthService, scopes: &[&str]) -> String {
+        token_with_roles(auth, &[], scopes)
+    }
+
+    fn token_with_roles(auth: &AuthService, roles: &[&str], scopes: &[&str]) -> String {
+        let subject = Subject {
+            name: "alice".into(),
+            email: None,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        };
+        auth.issue(&subject).unwrap().access_token
+    }
+
+    async fn request(method: &str, path: &str, auth: Option<&str>) -> Request {
+        let mut raw = format!("{method} {path} HTTP/1.1\r\nHost: test\r\n");
+        if let Some(auth) = auth {
+            raw.push_str(&format!("Authorization: {auth}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn call(auth: &AuthMiddleware, req: &Request) -> Response {
+        let next = |req: &Request| -> ResponseFuture {
+            let subject = req
+                .extensions
+                .get::<Principal>()
+                .map(|p| p.subject.clone())
+                .unwrap_or_default();
+            Box::pin(async move { Response::text(200, subject) })
+        };
+        auth.handle(req, "127.0.0.1", &next).await
+    }
+
+    fn middleware(auth: Arc<AuthService>) -> AuthMiddleware {
+        AuthMiddleware::new(auth, Policy::Public)
+            .policy("GET", "/me", Policy::Authenticated)
+            .policy("POST", "/user", Policy::Scope("users:write"))
+            .policy("DELETE", "/user", Policy::Role("admin"))
+    }
+
+    #[tokio::test]
+    async fn missing_or_bad_token_is_401_with_challenge() {
+        let auth = middleware(service());
+        let res = call(&auth, &request("GET", "/me", None).await).await;
+        assert_eq!(res.status, 401);
+        assert!(res.headers.contains("WWW-Authenticate"));
+
+        let res = call(&auth, &request("GET", "/me", Some("Bearer nope")).await).await;
+        assert_eq!(res.status, 401);
+
+        // Public routes pass through without a principal.
+        let res = call(&auth, &request("GET", "/health", None).await).await;
+        assert_eq!((res.status, res.body.as_bytes()), (200, Some(&b""[..])));
+    }
+
+    #[tokio::test]
+    async fn scopes_are_enforced_and_principal_is_attached() {
+        let service = service();
+        let auth = middleware(service.clone());
+        let bearer = format!("Bearer {}", token(&service, &[]));
+        let res = call(&auth, &request("GET", "/me", Some(&bearer)).await).await;
+        assert_eq!(
+            (res.status, res.body.as_bytes()),
+            (200, Some(&b"alice"[..]))
+        );
+
+        let res = call(&auth, &request("POST", "/user", Some(&bearer)).await).await;
+        assert_eq!(res.status, 403);
+
+        let bearer = format!("Bearer {}", token(&service, &["users:write"]));
+        let res = call(&auth, &request("POST", "/user", Some(&bearer)).await).await;
+        assert_eq!(
+            (res.status, res.body.as_bytes()),
+            (200, Some(&b"alice"[..]))
+        );
+
+        // Revoked tokens are turned away even though they have not expired.
+        service
+            .revoke(bearer.trim_start_matches("Bearer "))
+            .unwrap();
+        let res = call(&auth, &request("POST", "/user", Some(&bearer)).await).await;
+        assert_eq!(res.status, 401);
+    }
+
+    #[tokio::test]
+    async fn roles_are_enforced() {
+        let service = service();
+        let auth = middleware(service.clone());
+        let bearer = format!("Bearer {}", token(&service, &["users:write"]));
+        let res = call(&auth, &request("DELETE", "/user", Some(&bearer)).await).await;
+        assert_eq!(res.status, 403);
+        assert!(!res.headers.contains("WWW-Authenticate"));
+
+        let bearer = format!("Bearer {}", token_with_roles(&service, &["admin"], &[]));
+        let res = call(&auth, &request("DELETE", "/user", Some(&bearer)).await).await;
+        assert_eq!(res.status, 200);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod auth;
+pub mod compression;
 pub mod logger;
 pub mod rate_limiting;
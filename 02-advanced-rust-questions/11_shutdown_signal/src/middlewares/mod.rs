@@ -1,3 +1,5 @@
+pub mod auth;
+pub mod compression;
 pub mod logger;
 pub mod metrics;
 pub mod rate_limiting;
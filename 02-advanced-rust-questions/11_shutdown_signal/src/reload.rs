@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::config::{Cli, ServerConfig};
+use crate::middleware::Middleware;
+use crate::middlewares::rate_limiting::RateLimitMiddleware;
+
+/// How often the config file is checked for changes.
+const POLL_EVERY: Duration = Duration::from_secs(2);
+/// How often idle rate-limit buckets are swept while no requests come in to do it.
+const EVICT_EVERY: Duration = Duration::from_secs(60);
+
+/// The middlewares built from one version of the settings. A connection keeps the chain
+/// that was current when it was accepted, so a reload only affects later connections.
+#[derive(Default)]
+pub struct Chain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    // By scope, so the next chain's limiters can take over their buckets.
+    rate_limits: HashMap<&'static str, Arc<RateLimitMiddleware>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Adds a rate limiter that keeps the buckets of the one `previous` had under `scope`.
+    pub fn push_rate_limit(
+        &mut self,
+        scope: &'static str,
+        limit: RateLimitMiddleware,
+        previous: Option<&Chain>,
+    ) {
+        let limit = match previous.and_then(|chain| chain.rate_limits.get(scope)) {
+            Some(old) => limit.inherit(old),
+            None => limit,
+        };
+        let limit = Arc::new(limit);
+        self.rate_limits.insert(scope, limit.clone());
+        self.middlewares.push(limit);
+    }
+
+    pub fn middlewares(&self) -> Vec<&dyn Middleware> {
+        self.middlewares.iter().map(|m| m.as_ref()).collect()
+    }
+
+    /// Forgets the fully recovered buckets of every rate limiter in the chain.
+    pub fn evict_idle(&self) -> usize {
+        self.rate_limits
+            .values()
+            .map(|limit| limit.evict_idle())
+            .sum()
+    }
+}
+
+/// Sweeps the current chain's idle rate-limit buckets once a minute. The limiters also do
+/// this as requests come in, which stops when the traffic does.
+pub fn evict_idle_buckets(chain: Arc<ArcSwap<Chain>>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EVICT_EVERY);
+        loop {
+            ticker.tick().await;
+            chain.load().evict_idle();
+        }
+    });
+}
+
+/// Reload attempts since startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ReloadCounts {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Unix seconds of the last successful reload; 0 if there has not been one.
+    pub last_success: u64,
+}
+
+/// Holds the current settings and replaces them on SIGHUP or when the config file
+/// changes. New settings are swapped in only once they have loaded and validated;
+/// otherwise the current ones stay and the error is logged.
+pub struct Reloader {
+    cli: Cli,
+    current: ArcSwap<ServerConfig>,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    last_success: AtomicU64,
+}
+
+impl Reloader {
+    /// `config` is what `cli` loaded at startup.
+    pub fn new(cli: Cli, config: ServerConfig) -> Self {
+        Reloader {
+            cli,
+            current: ArcSwap::from_pointee(config),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            last_success: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.current.load_full()
+    }
+
+    pub fn counts(&self) -> ReloadCounts {
+        ReloadCounts {
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            last_success: self.last_success.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Loads the settings again and swaps them in. Returns the previous and the new ones.
+    pub fn reload(&self) -> anyhow::Result<(Arc<ServerConfig>, Arc<ServerConfig>)> {
+        let next = match ServerConfig::load(&self.cli) {
+            Ok(next) => Arc::new(next),
+            Err(err) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("❌ Config reload failed, keeping the current settings: {err:#}");
+                return Err(err);
+            }
+        };
+        let previous = self.current.swap(next.clone());
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_success.store(now.as_secs(), Ordering::Relaxed);
+        println!("🔄 Reloaded {}", self.cli.config_file().display());
+        let restart = previous.needs_restart(&next);
+        if !restart.is_empty() {
+            println!(
+                "⚠️ Changes to {} take effect after a restart",
+                restart.join(", ")
+            );
+        }
+        Ok((previous, next))
+    }
+
+    /// Reloads on SIGHUP and whenever the config file changes, then hands the previous
+    /// and new settings to `apply`. Failed reloads are logged and counted, and `apply` is
+    /// not called.
+    pub fn watch<F>(self: &Arc<Self>, apply: F)
+    where
+        F: Fn(&ServerConfig, &ServerConfig) + Send + 'static,
+    {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(err) => {
+                    eprintln!("⚠️ Cannot listen for SIGHUP, watching the file only: {err}");
+                    None
+                }
+            };
+            let mut ticker = tokio::time::interval(POLL_EVERY);
+            let mut seen = reloader.stamp();
+            loop {
+                tokio::select! {
+                    _ = hangups(&mut hangup) => {}
+                    _ = ticker.tick() => {
+                        if reloader.stamp() == seen {
+                            continue;
+                        }
+                    }
+                }
+                // Also after SIGHUP, so the edit that prompted it is not reloaded twice.
+                seen = reloader.stamp();
+                if let Ok((previous, next)) = reloader.reload() {
+                    apply(&previous, &next);
+                }
+            }
+        });
+    }
+
+    // Changes when the file is written, created or removed.
+    fn stamp(&self) -> Option<(SystemTime, u64)> {
+        let meta = std::fs::metadata(self.cli.config_file()).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
+}
+
+// Resolves on the next SIGHUP, and never once there is no way to receive one.
+async fn hangups(hangup: &mut Option<Signal>) {
+    if let Some(signal) = hangup {
+        if signal.recv().await.is_some() {
+            return;
+        }
+        *hangup = None;
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_current_settings_when_the_new_ones_are_invalid() {
+        let dir = std::env::temp_dir().join(format!("server-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        let cli = Cli {
+            config: Some(path.clone()),
+            ..Cli::default()
+        };
+        std::fs::write(&path, "listen = \"127.0.0.1:9000\"\n").unwrap();
+        let reloader = Reloader::new(cli.clone(), ServerConfig::load(&cli).unwrap());
+
+        std::fs::write(&path, "listen = \"9000\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        // As when the file is deleted, or briefly gone while an editor replaces it.
+        std::fs::remove_file(&path).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.config().listen, "127.0.0.1:9000");
+
+        std::fs::write(
+            &path,
+            "listen = \"127.0.0.1:9001\"\nlog_format = \"logfmt\"\n",
+        )
+        .unwrap();
+        let (previous, next) = reloader.reload().unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(previous.listen, "127.0.0.1:9000");
+        assert_eq!(reloader.config(), next);
+        assert_eq!(previous.needs_restart(&next), ["listen"]);
+        let counts = reloader.counts();
+        assert_eq!((counts.succeeded, counts.failed), (1, 2));
+        assert!(counts.last_success > 0);
+    }
+}
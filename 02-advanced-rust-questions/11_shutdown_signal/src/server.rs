@@ -1,45 +1,163 @@
-use sled;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use tokio::signal;
 use tokio::sync::Notify;
 
-use crate::cache::LRUCache;
+use crate::auth::{self, Accounts, AuthService, Keyring};
+use crate::cache::{self, LRUCache};
+use crate::config::{CacheConfig, ServerConfig};
 use crate::handlers;
 use crate::handlers::user::UserResponse;
-use crate::middleware::{self, Middleware};
+use crate::middleware::{self, ResponseFuture};
+use crate::middlewares::auth::{AuthMiddleware, Policy};
+use crate::middlewares::compression::CompressionMiddleware;
 use crate::middlewares::logger::LoggerMiddleware;
-use crate::middlewares::metrics::{self, MetricsMiddleware};
-use crate::middlewares::rate_limiting::TokenBucketMiddleware;
+use crate::middlewares::metrics::MetricsMiddleware;
+use crate::middlewares::rate_limiting::{Backing, KeyBy, RateLimitMiddleware, Rule};
+use crate::reload::{evict_idle_buckets, Chain, Reloader};
+use crate::request::{Limits, Method, Request, RequestReader};
+use crate::router::{Lookup, Router};
+use arc_swap::ArcSwap;
 use mongodb::{Client, Database};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
+use tokio::time::Duration;
 
 use crate::types::Response;
 
-pub type UserCache = sled::Db;
+/// Users cached in memory (with TTLs) in front of the on-disk sled store.
+#[derive(Clone)]
+pub struct UserCache {
+    memory: Arc<Mutex<LRUCache<String, UserResponse>>>,
+    disk: sled::Db,
+    ttl: Duration,
+    /// Off, users are neither looked up in nor written to either tier. sled stays open,
+    /// as other state lives there too.
+    enabled: bool,
+}
+
+// A user as kept in sled. The expiry is wall-clock time, since sled outlives the process.
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    user: UserResponse,
+    expires_at_ms: u64,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+impl UserCache {
+    pub fn open(config: &CacheConfig) -> Self {
+        Self::new(config, sled::open(&config.path).expect("open sled db"))
+    }
+
+    pub fn new(config: &CacheConfig, disk: sled::Db) -> Self {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        let memory = Arc::new(Mutex::new(
+            LRUCache::new(config.effective_capacity()).with_default_ttl(ttl),
+        ));
+        cache::spawn_expiry(&memory, Duration::from_secs(config.sweep_secs));
+        Self {
+            memory,
+            disk,
+            ttl,
+            enabled: config.enabled,
+        }
+    }
+
+    /// The user cached under `email`, from memory or else from sled. An entry past its TTL
+    /// counts as a miss in either tier.
+    pub fn get(&self, email: &str) -> Option<UserResponse> {
+        if !self.enabled {
+            return None;
+        }
+        let key = email.to_string();
+        let memory_hit = self
+            .memory
+            .lock()
+            .ok()
+            .and_then(|mut memory| memory.get(&key));
+        if memory_hit.is_some() {
+            println!("cache hit!");
+            return memory_hit;
+        }
+
+        let value = self.disk.get(email).ok()??;
+        let stored: StoredUser = serde_json::from_slice(&value).ok()?;
+        let left = Duration::from_millis(stored.expires_at_ms.saturating_sub(unix_millis()));
+        if left.is_zero() {
+            let _ = self.disk.remove(email);
+            return None;
+        }
+        println!("SLED hit!");
+        // Only for the rest of its TTL, so memory never serves what sled has expired.
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.put_with_ttl(key, stored.user.clone(), left);
+        }
+        Some(stored.user)
+    }
+
+    /// Caches `user` under `email` in both tiers for the configured TTL.
+    pub fn put(&self, email: &str, user: &UserResponse) {
+        if !self.enabled {
+            return;
+        }
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.put(email.to_string(), user.clone());
+        }
+        let stored = StoredUser {
+            user: user.clone(),
+            expires_at_ms: unix_millis().saturating_add(self.ttl.as_millis() as u64),
+        };
+        let _ = self
+            .disk
+            .insert(email.as_bytes(), serde_json::to_vec(&stored).unwrap());
+        let _ = self.disk.flush();
+    }
+}
 
-pub async fn run() -> anyhow::Result<()> {
+pub async fn run(reloader: Reloader) -> anyhow::Result<()> {
+    let reloader = Arc::new(reloader);
+    // Read once; only the middleware chain follows reloads.
+    let config = reloader.config();
     let metrics = Arc::new(MetricsMiddleware::new());
-    dotenvy::dotenv().ok();
     let mongodb_uri =
         std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
     let mongodb_db = std::env::var("MONGODB_DB").unwrap_or_else(|_| "my_app".to_string());
     let client = Client::with_uri_str(&mongodb_uri).await?;
     let db = Arc::new(client.database(&mongodb_db));
-    let cache = sled::open("user_cache").expect("open sled db");
+    let cache = UserCache::open(&config.cache);
+    let auth = Arc::new(
+        AuthService::new(Keyring::from_env()?, &cache.disk)?.with_accounts(Accounts::from_env()?),
+    );
+    auth::spawn_purge(&auth, Duration::from_secs(3600));
 
-    let listener = TcpListener::bind("0.0.0.0:7878").await?;
-    println!("🚀 Listening on port 7878");
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!("🚀 Listening on {}", config.listen);
 
-    let middlewares: Vec<Arc<dyn Middleware>> = vec![
-        Arc::new(LoggerMiddleware),
-        Arc::new(TokenBucketMiddleware::new(5, 1)),
-        metrics.clone(),
-    ];
+    let chain = Arc::new(ArcSwap::from_pointee(build_chain(
+        &config, None, &metrics, &auth,
+    )));
+    evict_idle_buckets(chain.clone());
+    {
+        let chain = chain.clone();
+        let metrics = metrics.clone();
+        let auth = auth.clone();
+        reloader.watch(move |_, config| {
+            let previous = chain.load();
+            chain.store(Arc::new(build_chain(
+                config,
+                Some(&previous),
+                &metrics,
+                &auth,
+            )));
+        });
+    }
 
     // Shared shutdown signal
     let is_shutting_down = Arc::new(AtomicBool::new(false));
@@ -67,35 +185,40 @@ pub async fn run() -> anyhow::Result<()> {
                     break;
                 }
 
-                let middlewares = middlewares.clone();
+                // Served by the chain that was current when the connection was accepted.
+                let chain = chain.load_full();
                 let db = db.clone();
                 let cache_clone = cache.clone();
                 let metrics = metrics.clone();
+                let auth = auth.clone();
+                let reloader = reloader.clone();
                 tokio::spawn(async move {
-                    let mut buf = [0; 1024];
-                    if let Ok(n) = socket.read(&mut buf).await {
-                        if n == 0 {
+                    let mut reader = RequestReader::new(Limits::default());
+                    let req = match reader.read_request(&mut socket).await {
+                        Ok(Some(req)) => req,
+                        Ok(None) => return,
+                        Err(err) => {
+                            let _ = err.into_response().write_to(&mut socket).await;
                             return;
                         }
+                    };
 
-                        let req = String::from_utf8_lossy(&buf[..n]);
-                        let middleware_ref: Vec<&dyn Middleware> =
-                            middlewares.iter().map(|m| m.as_ref()).collect();
+                    let middleware_ref = chain.middlewares();
 
-                        let cache_for_handler = cache_clone.clone();
-                        let handler: Box<
-                            dyn Fn(&str) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync,
-                        > = Box::new(move |req: &str| {
-                            let req_owned = req.to_string();
+                    let cache_for_handler = cache_clone.clone();
+                    let handler: Box<dyn Fn(&Request) -> ResponseFuture + Send + Sync> =
+                        Box::new(move |req: &Request| {
+                            let req_owned = req.clone();
                             let db = db.clone();
                             let cache = cache_for_handler.clone();
                             let metrics = metrics.clone();
-                            Box::pin(async move { route_request(&req_owned, &db, &cache, metrics).await })
+                            let auth = auth.clone();
+                            let reloader = reloader.clone();
+                            Box::pin(async move { route_request(&req_owned, &db, &cache, metrics, auth, &reloader).await })
                         });
 
-                        let res = middleware::run_chain(&req, &(addr.ip().to_string()), &middleware_ref, handler).await;
-                        let _ = socket.write_all(res.into_http().as_bytes()).await;
-                    }
+                    let res = middleware::run_chain(&req, &addr.ip().to_string(), &middleware_ref, handler).await;
+                    let _ = res.write_to(&mut socket).await;
                 });
             }
 
@@ -109,35 +232,148 @@ pub async fn run() -> anyhow::Result<()> {
 
     println!("✅ Graceful shutdown complete. Closing resources...");
     drop(listener);
-    cache.flush().ok();
+    cache.disk.flush().ok();
     Ok(())
 }
 
+/// Builds the middleware chain `config` asks for. The rate limiters take over the buckets
+/// of those in `previous`, so a reload does not reset anyone's quota.
+fn build_chain(
+    config: &ServerConfig,
+    previous: Option<&Chain>,
+    metrics: &Arc<MetricsMiddleware>,
+    auth: &Arc<AuthService>,
+) -> Chain {
+    let limits = &config.rate_limit;
+    let rate_limits = Backing::from_store(
+        limits.store.as_deref(),
+        limits.instances,
+        Duration::from_millis(limits.store_timeout_ms),
+    );
+    let enabled = &config.middlewares;
+    let mut chain = Chain::new();
+    if enabled.logger {
+        chain.push(Arc::new(
+            LoggerMiddleware::new(&ROUTES).format(config.log_format),
+        ));
+    }
+    if enabled.compression {
+        let compression = &config.compression;
+        chain.push(Arc::new(
+            CompressionMiddleware::new()
+                .encodings(&compression.encodings)
+                .min_size(compression.min_size)
+                .max_request_body(compression.max_request_body),
+        ));
+    }
+    if enabled.rate_limit {
+        chain.push_rate_limit(
+            "ip",
+            RateLimitMiddleware::with_backing(
+                "ip",
+                Rule::new(limits.ip.algorithm(), KeyBy::Ip),
+                rate_limits.clone(),
+            )
+            // Slow down credential guessing harder than ordinary traffic.
+            .rule(
+                "POST",
+                "/auth/token",
+                Rule::new(limits.login.algorithm(), KeyBy::Ip),
+            )
+            .exempt("GET", "/health"),
+            previous,
+        );
+    }
+    if enabled.auth {
+        chain.push(Arc::new(
+            AuthMiddleware::new(auth.clone(), Policy::Public)
+                .policy("GET", "/me", Policy::Authenticated)
+                .policy("GET", "/user", Policy::Scope("users:read"))
+                .policy("POST", "/user", Policy::Role("admin")),
+        ));
+    }
+    // Runs after auth so that signed-in callers are limited per account, wherever
+    // they connect from.
+    if enabled.rate_limit {
+        let mut limit = RateLimitMiddleware::with_backing(
+            "principal",
+            Rule::new(limits.principal.algorithm(), KeyBy::Principal),
+            rate_limits,
+        )
+        .exempt("GET", "/health");
+        for route in &limits.routes {
+            limit = limit.rule(&route.method, &route.path, route.rule());
+        }
+        chain.push_rate_limit("principal", limit, previous);
+    }
+    if enabled.metrics {
+        chain.push(metrics.clone());
+    }
+    chain
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    Health,
+    Hello,
+    CreateUser,
+    GetUser,
+    Me,
+    CreateToken,
+    RefreshToken,
+    VerifyToken,
+    RevokeToken,
+    Jwks,
+    Metrics,
+}
+
+static ROUTES: LazyLock<Router<Route>> = LazyLock::new(|| {
+    Router::new()
+        .route("GET", "/health", Route::Health)
+        .route("GET", "/hello/:name", Route::Hello)
+        .route("POST", "/user", Route::CreateUser)
+        .route("GET", "/user", Route::GetUser)
+        .route("GET", "/me", Route::Me)
+        .route("POST", "/auth/token", Route::CreateToken)
+        .route("POST", "/auth/refresh", Route::RefreshToken)
+        .route("POST", "/auth/verify", Route::VerifyToken)
+        .route("POST", "/auth/revoke", Route::RevokeToken)
+        .route("GET", "/.well-known/jwks.json", Route::Jwks)
+        .route("GET", "/api/metrics", Route::Metrics)
+});
+
 pub async fn route_request(
-    req: &str,
+    req: &Request,
     db: &Database,
-    cache: &sled::Db,
+    cache: &UserCache,
     metrics: Arc<MetricsMiddleware>,
+    auth: Arc<AuthService>,
+    reloader: &Reloader,
 ) -> Response {
-    if req.starts_with("GET /health") {
-        handlers::health::handle().await
-    } else if req.starts_with("GET /hello/") {
-        handlers::hello::handle(req).await
-    } else if req.starts_with("POST /user") {
-        handlers::user::handle(req, db, cache).await
-    } else if req.starts_with("GET /user") {
-        handlers::user::get(req, db, cache).await
-    } else if req.starts_with("POST /auth/token") {
-        handlers::auth::create_token(req).await
-    } else if req.starts_with("POST /auth/verify") {
-        handlers::auth::verify_token(req).await
-    } else if req.starts_with("GET /api/metrics") {
-        metrics.handle_metrics()
+    let (route, params) = match ROUTES.lookup(req.method.as_str(), &req.path) {
+        Lookup::Found { handler, params } => (*handler, params),
+        Lookup::Options { allow } => return Response::options(&allow),
+        Lookup::MethodNotAllowed { allow } => return Response::method_not_allowed(&allow),
+        Lookup::NotFound => return Response::not_found(),
+    };
+
+    let res = match route {
+        Route::Health => handlers::health::handle().await,
+        Route::Hello => handlers::hello::handle(req, &params).await,
+        Route::CreateUser => handlers::user::handle(req, db, cache).await,
+        Route::GetUser => handlers::user::get(req, db, cache).await,
+        Route::Me => handlers::auth::me(req).await,
+        Route::CreateToken => handlers::auth::create_token(req, &auth).await,
+        Route::RefreshToken => handlers::auth::refresh_token(req, &auth).await,
+        Route::VerifyToken => handlers::auth::verify_token(req, &auth).await,
+        Route::RevokeToken => handlers::auth::revoke_token(req, &auth).await,
+        Route::Jwks => handlers::auth::jwks(&auth).await,
+        Route::Metrics => metrics.handle_metrics(reloader.counts()),
+    };
+
+    if req.method == Method::Head {
+        res.without_body()
     } else {
-        Response {
-            status: 404,
-            content_type: "text/plain".into(),
-            body: "Not Found".into(),
-        }
+        res
     }
 }
@@ -1,31 +1,411 @@
-#[derive(Debug, Clone)]
+// Handlers use only some of the response builders so far; the tests cover the rest.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Response headers in the order they were set. Names compare case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First value set for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to `value`, replacing any values it already had.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a value without touching existing ones, for headers such as `Set-Cookie`
+    /// that may appear more than once.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+pub enum Body {
+    Empty,
+    Full(Bytes),
+    /// Sent with chunked transfer encoding as the chunks arrive.
+    Stream(BodyStream),
+}
+
+impl Body {
+    /// Length in bytes, unknown for a stream.
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Full(bytes) => Some(bytes.len()),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// The whole body, if it is already in memory.
+    #[cfg(test)]
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Empty => Some(&[]),
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            Body::Stream(_) => f.write_str("Stream(..)"),
+        }
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        if bytes.is_empty() {
+            Body::Empty
+        } else {
+            Body::Full(bytes)
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes::from(bytes).into()
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Bytes::from(text).into()
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(text: &'static str) -> Self {
+        Bytes::from_static(text.as_bytes()).into()
+    }
+}
+
+#[derive(Debug)]
 pub struct Response {
     pub status: u16,
-    pub content_type: String,
-    pub body: String,
+    pub headers: HeaderMap,
+    pub body: Body,
 }
 
+/// Reason phrases from the IANA HTTP status code registry.
 fn status_text(code: u16) -> &'static str {
     match code {
-        200 => "Ok",
-        400 => "BAD REQUEST",
-        403 => "FORBIDDEN",
-        404 => "NOT FOUND",
-        500 => "INTERNAL SERVER ERROR",
-        429 => "TOO MANY REQUESTS",
-        _ => "UNKNOWN",
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        511 => "Network Authentication Required",
+        // The reason phrase is optional; an empty one is still a valid status line.
+        _ => "",
     }
 }
 
 impl Response {
-    pub fn into_http(self) -> String {
-        format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
-            self.status,
-            status_text(self.status),
-            self.content_type,
-            self.body.len(),
-            self.body
-        )
+    /// Empty response with no headers.
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::bytes(status, "text/plain", body.into())
+    }
+
+    /// Serialises `value` as the body; a value that cannot be serialised is a 500.
+    pub fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::bytes(status, "application/json", body),
+            Err(err) => {
+                eprintln!("failed to serialise response: {}", err);
+                Self::text(500, "Internal Server Error")
+            }
+        }
+    }
+
+    pub fn bytes(status: u16, content_type: &str, body: impl Into<Body>) -> Self {
+        Response {
+            body: body.into(),
+            ..Self::new(status)
+        }
+        .with_header("Content-Type", content_type)
+    }
+
+    pub fn stream<S>(status: u16, content_type: &str, body: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::bytes(status, content_type, Body::Stream(Box::pin(body)))
+    }
+
+    /// `status` should be one of the 3xx codes, typically 302, 303 or 307.
+    pub fn redirect(status: u16, location: &str) -> Self {
+        Self::new(status).with_header("Location", location)
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "Not Found")
+    }
+
+    pub fn method_not_allowed(allow: &str) -> Self {
+        Self::text(405, "Method Not Allowed").with_header("Allow", allow)
+    }
+
+    /// Automatic answer to OPTIONS for a known path.
+    pub fn options(allow: &str) -> Self {
+        Self::new(204).with_header("Allow", allow)
+    }
+
+    /// Sets a header, replacing any earlier value.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// HEAD response: same headers as the GET, including its Content-Length, but no body.
+    pub fn without_body(mut self) -> Self {
+        let framing = match self.body.len() {
+            Some(len) => ("Content-Length", len.to_string()),
+            None => ("Transfer-Encoding", "chunked".to_string()),
+        };
+        self.body = Body::Empty;
+        self.with_header(framing.0, &framing.1)
+    }
+
+    /// Writes the status line, headers and body to `out`. Content-Length is filled in
+    /// for in-memory bodies; streams go out chunked.
+    pub async fn write_to<W>(self, out: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, status_text(self.status));
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // A HEAD response already says how the GET would have been framed.
+        let framed =
+            self.headers.contains("Content-Length") || self.headers.contains("Transfer-Encoding");
+        let bodiless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !framed && !bodiless {
+            match self.body.len() {
+                Some(len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+                None => head.push_str("Transfer-Encoding: chunked\r\n"),
+            }
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes()).await?;
+
+        match self.body {
+            Body::Empty => {}
+            Body::Full(bytes) => out.write_all(&bytes).await?,
+            Body::Stream(mut chunks) => {
+                while let Some(chunk) = chunks.next().await {
+                    let chunk = chunk?;
+                    // An empty chunk would read as the end of the body.
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    out.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                        .await?;
+                    out.write_all(&chunk).await?;
+                    out.write_all(b"\r\n").await?;
+                }
+                out.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+        out.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn written(res: Response) -> String {
+        let mut out = Vec::new();
+        res.write_to(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_headers_and_content_length() {
+        let mut res =
+            Response::json(201, &serde_json::json!({ "id": 1 })).with_header("Set-Cookie", "a=1");
+        res.headers.append("Set-Cookie", "b=2");
+        assert_eq!(
+            written(res).await,
+            "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\
+             Set-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 8\r\n\r\n{\"id\":1}"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_are_chunked() {
+        let chunks = futures::stream::iter(["hello", "", " world"].map(|s| Ok(Bytes::from(s))));
+        let res = Response::stream(200, "text/plain", chunks);
+        assert_eq!(
+            written(res).await,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_keeps_the_length_of_the_get() {
+        let res = Response::text(200, "hello").without_body();
+        assert_eq!(
+            written(res).await,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\n"
+        );
+        let res = Response::redirect(303, "/users/1");
+        assert!(written(res).await.starts_with("HTTP/1.1 303 See Other\r\n"));
+    }
+
+    #[test]
+    fn header_names_ignore_case() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "text/plain");
+        headers.insert("content-type", "application/json");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("application/json"));
+        headers.remove("Content-type");
+        assert!(headers.is_empty());
+
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(
+            headers.get_all("SET-COOKIE").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_carry_a_location_and_no_body() {
+        let res = Response::redirect(308, "/hello/world");
+        assert_eq!(res.content_type(), None);
+        assert_eq!(
+            written(res).await,
+            "HTTP/1.1 308 Permanent Redirect\r\nLocation: /hello/world\r\nContent-Length: 0\r\n\r\n"
+        );
     }
 }
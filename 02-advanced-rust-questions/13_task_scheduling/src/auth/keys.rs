@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents};
+use serde_json::{json, Value};
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// Neither `JWT_KEYS` nor `JWT_SECRET` is set.
+    Missing,
+    Invalid {
+        kid: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Missing => write!(f, "no signing keys: set JWT_KEYS or JWT_SECRET"),
+            KeyError::Invalid { kid, reason } => write!(f, "signing key {kid:?}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+fn invalid(kid: &str, reason: impl ToString) -> KeyError {
+    KeyError::Invalid {
+        kid: kid.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub alg: Algorithm,
+    pub encoding: EncodingKey,
+    pub decoding: DecodingKey,
+    // Public half for the JWKS document; `None` for shared secrets.
+    jwk: Option<Value>,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .finish()
+    }
+}
+
+impl SigningKey {
+    pub fn hs256(kid: &str, secret: &[u8]) -> Result<Self, KeyError> {
+        if secret.is_empty() {
+            return Err(invalid(kid, "empty secret"));
+        }
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            alg: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        })
+    }
+
+    /// RSA private key in PKCS#1 (`RSA PRIVATE KEY`) or PKCS#8 (`PRIVATE KEY`) PEM.
+    pub fn rs256_pem(kid: &str, pem: &[u8]) -> Result<Self, KeyError> {
+        let parsed = pem::parse(pem).map_err(|e| invalid(kid, e))?;
+        let pair = match parsed.tag() {
+            "RSA PRIVATE KEY" => RsaKeyPair::from_der(parsed.contents()),
+            _ => RsaKeyPair::from_pkcs8(parsed.contents()),
+        }
+        .map_err(|e| invalid(kid, e))?;
+        let public = RsaPublicKeyComponents::<Vec<u8>>::from(pair.public());
+
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            alg: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(pem).map_err(|e| invalid(kid, e))?,
+            decoding: DecodingKey::from_rsa_raw_components(&public.n, &public.e),
+            jwk: Some(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": URL_SAFE_NO_PAD.encode(&public.n),
+                "e": URL_SAFE_NO_PAD.encode(&public.e),
+            })),
+        })
+    }
+
+    /// Ed25519 private key in PKCS#8 PEM, as written by `openssl genpkey -algorithm ed25519`.
+    pub fn ed25519_pem(kid: &str, pem: &[u8]) -> Result<Self, KeyError> {
+        let parsed = pem::parse(pem).map_err(|e| invalid(kid, e))?;
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(parsed.contents())
+            .map_err(|e| invalid(kid, e))?;
+        let public = pair.public_key().as_ref();
+
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            alg: Algorithm::EdDSA,
+            encoding: EncodingKey::from_ed_der(parsed.contents()),
+            decoding: DecodingKey::from_ed_der(public),
+            jwk: Some(json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(public),
+            })),
+        })
+    }
+}
+
+/// Every key tokens may be signed with, by `kid`. New tokens use the active key; the others
+/// only verify, so a secret can be rotated without invalidating tokens already handed out.
+#[derive(Debug)]
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, SigningKey>,
+}
+
+impl Keyring {
+    /// The first key is the active one.
+    pub fn new(keys: Vec<SigningKey>) -> Result<Self, KeyError> {
+        let active = keys.first().ok_or(KeyError::Missing)?.kid.clone();
+        let mut map = HashMap::with_capacity(keys.len());
+        for key in keys {
+            let kid = key.kid.clone();
+            if map.insert(kid.clone(), key).is_some() {
+                return Err(invalid(&kid, "duplicate kid"));
+            }
+        }
+        Ok(Keyring { active, keys: map })
+    }
+
+    /// Reads `JWT_KEYS`, a comma-separated list of `kid:ALG:value` with the active key first.
+    /// `value` is the secret for HS256 and the path to a private key PEM for RS256 and EdDSA.
+    /// Without `JWT_KEYS`, `JWT_SECRET` is used as a single HS256 key. There is no default.
+    pub fn from_env() -> Result<Self, KeyError> {
+        if let Ok(spec) = std::env::var("JWT_KEYS") {
+            return Self::parse(&spec);
+        }
+        match std::env::var("JWT_SECRET") {
+            Ok(secret) => Self::new(vec![SigningKey::hs256("default", secret.as_bytes())?]),
+            Err(_) => Err(KeyError::Missing),
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, KeyError> {
+        let mut keys = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let kid = parts.next().unwrap_or_default();
+            let (Some(alg), Some(value)) = (parts.next(), parts.next()) else {
+                return Err(invalid(kid, "expected kid:ALG:value"));
+            };
+            let key = match alg {
+                "HS256" => SigningKey::hs256(kid, value.as_bytes())?,
+                "RS256" | "EdDSA" => {
+                    let pem = std::fs::read(value).map_err(|e| invalid(kid, e))?;
+                    if alg == "RS256" {
+                        SigningKey::rs256_pem(kid, &pem)?
+                    } else {
+                        SigningKey::ed25519_pem(kid, &pem)?
+                    }
+                }
+                other => return Err(invalid(kid, format!("unsupported algorithm {other}"))),
+            };
+            keys.push(key);
+        }
+        Self::new(keys)
+    }
+
+    pub fn active(&self) -> &SigningKey {
+        &self.keys[&self.active]
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys.get(kid)
+    }
+
+    /// Public keys for `/.well-known/jwks.json`. Shared secrets are never published.
+    pub fn jwks(&self) -> Value {
+        let mut keys: Vec<&SigningKey> = self.keys.values().collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        json!({ "keys": keys.iter().filter_map(|k| k.jwk.clone()).collect::<Vec<_>>() })
+    }
+}
@@ -1,111 +1,422 @@
+// The server only calls part of the cache API; the tests cover the rest.
+#![cfg_attr(not(test), allow(dead_code))]
+
 use std::collections::HashMap;
-use std::sync::RwLock;
-use std::sync::{Arc, Weak};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-type Link<K, V> = Option<Arc<RwLock<Node<K, V>>>>;
+// Sentinel for "no slot" in the prev/next links.
+const NIL: usize = usize::MAX;
 
 #[derive(Debug)]
-pub struct Node<K, V> {
+struct Node<K, V> {
     key: K,
     value: V,
-    prev: Option<Weak<RwLock<Node<K, V>>>>,
-    next: Link<K, V>,
+    expires_at: Option<Instant>,
+    weight: usize,
+    prev: usize,
+    next: usize,
 }
 
-#[derive(Debug)]
+impl<K, V> Node<K, V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries pushed out to stay within capacity or the weight limit.
+    pub evictions: u64,
+    /// Entries dropped because their TTL ran out.
+    pub expirations: u64,
+}
+
+/// LRU cache whose recency list lives in a slab: nodes sit in a `Vec` and link to each
+/// other by index, so `get` and `put` are O(1) with no per-node locks or `Rc`s.
 pub struct LRUCache<K: std::hash::Hash + Eq + Clone, V: Clone> {
     capacity: usize,
-    map: HashMap<K, Link<K, V>>,
-    head: Link<K, V>, // most recently used
-    tail: Link<K, V>, // Least recently used
+    map: HashMap<K, usize>,
+    slots: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    head: usize, // most recently used
+    tail: usize, // Least recently used
+    default_ttl: Option<Duration>,
+    weigher: Option<Weigher<K, V>>,
+    max_weight: usize,
+    total_weight: usize,
+    stats: CacheStats,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V: Clone> fmt::Debug for LRUCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LRUCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.map.len())
+            .field("default_ttl", &self.default_ttl)
+            .field("max_weight", &self.max_weight)
+            .field("total_weight", &self.total_weight)
+            .field("stats", &self.stats)
+            .finish()
+    }
 }
 
 impl<K: std::hash::Hash + Eq + Clone, V: Clone> LRUCache<K, V> {
     pub fn new(capacity: usize) -> Self {
         LRUCache {
             capacity,
-            map: HashMap::new(),
-            head: None,
-            tail: None,
+            map: HashMap::with_capacity(capacity),
+            slots: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            default_ttl: None,
+            weigher: None,
+            max_weight: usize::MAX,
+            total_weight: 0,
+            stats: CacheStats::default(),
         }
     }
 
+    /// TTL applied by `put`; `put_with_ttl` overrides it per entry.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Bounds the cache by total weight as well as entry count, e.g. approximate bytes.
+    pub fn with_weigher<F>(mut self, max_weight: usize, weigher: F) -> Self
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        self.weigher = Some(Box::new(weigher));
+        self.max_weight = max_weight;
+        self
+    }
+
     pub fn get(&mut self, key: &K) -> Option<V> {
-        if let Some(node_rc) = self.map.get(key).and_then(|n| n.clone()) {
-            let value = node_rc.read().unwrap().value.clone();
-            self.move_to_head(&node_rc);
-            Some(value)
-        } else {
-            None
+        let Some(&idx) = self.map.get(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        // Lazy expiry: an expired entry is dropped the first time someone asks for it.
+        if self.node(idx).is_expired(Instant::now()) {
+            self.remove_slot(idx);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return None;
         }
+
+        self.move_to_head(idx);
+        self.stats.hits += 1;
+        Some(self.node(idx).value.clone())
+    }
+
+    /// Looks up a value without touching recency or the hit/miss counters.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let node = self.node(*self.map.get(key)?);
+        (!node.is_expired(Instant::now())).then_some(&node.value)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.peek(key).is_some()
     }
 
     pub fn put(&mut self, key: K, value: V) {
-        if let Some(node_rc) = self.map.get(&key).and_then(|n| n.clone()) {
-            node_rc.write().unwrap().value = value;
-            self.move_to_head(&node_rc);
-        } else {
-            let new_node = Arc::new(RwLock::new(Node {
-                key: key.clone(),
-                value,
-                prev: None,
-                next: None,
-            }));
+        self.insert(key, value, self.default_ttl);
+    }
 
-            if self.map.len() == self.capacity {
-                self.remove_tail();
+    pub fn put_with_ttl(&mut self, key: K, value: V, ttl: Duration) {
+        self.insert(key, value, Some(ttl));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = *self.map.get(key)?;
+        let node = self.remove_slot(idx);
+        (!node.is_expired(Instant::now())).then_some(node.value)
+    }
+
+    /// Changes the entry limit, evicting least recently used entries if it shrinks.
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.map.len() > self.capacity {
+            self.evict_tail();
+        }
+    }
+
+    /// Live entries from most to least recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        let now = Instant::now();
+        let mut idx = self.head;
+        std::iter::from_fn(move || {
+            while idx != NIL {
+                let node = self.node(idx);
+                idx = node.next;
+                if !node.is_expired(now) {
+                    return Some((&node.key, &node.value));
+                }
             }
+            None
+        })
+    }
 
-            self.add_to_head(&new_node);
-            self.map.insert(key, Some(new_node));
+    /// Drops every expired entry and returns how many were removed.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .map
+            .values()
+            .copied()
+            .filter(|&idx| self.node(idx).is_expired(now))
+            .collect();
+        for &idx in &expired {
+            self.remove_slot(idx);
         }
+        self.stats.expirations += expired.len() as u64;
+        expired.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
     }
 
-    fn move_to_head(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
-        self.remove_node(node);
-        self.add_to_head(node);
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
 
-    fn add_to_head(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
-        node.write().unwrap().next = self.head.clone();
-        node.write().unwrap().prev = None;
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn weight(&self) -> usize {
+        self.total_weight
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
 
-        if let Some(head) = &self.head {
-            head.write().unwrap().prev = Some(Arc::downgrade(node));
+    fn insert(&mut self, key: K, value: V, ttl: Option<Duration>) {
+        let weight = self.weigher.as_ref().map_or(0, |w| w(&key, &value));
+        // A TTL past what the clock can represent never expires.
+        let expires_at = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+
+        if let Some(&idx) = self.map.get(&key) {
+            let node = self.node_mut(idx);
+            let old_weight = std::mem::replace(&mut node.weight, weight);
+            node.value = value;
+            node.expires_at = expires_at;
+            self.total_weight = self.total_weight - old_weight + weight;
+            self.move_to_head(idx);
+        } else {
+            if self.capacity == 0 {
+                return;
+            }
+            if self.map.len() >= self.capacity {
+                self.evict_tail();
+            }
+
+            let node = Node {
+                key: key.clone(),
+                value,
+                expires_at,
+                weight,
+                prev: NIL,
+                next: NIL,
+            };
+            let idx = match self.free.pop() {
+                Some(idx) => {
+                    self.slots[idx] = Some(node);
+                    idx
+                }
+                None => {
+                    self.slots.push(Some(node));
+                    self.slots.len() - 1
+                }
+            };
+            self.push_head(idx);
+            self.map.insert(key, idx);
+            self.total_weight += weight;
         }
-        self.head = Some(node.clone());
 
-        if self.tail.is_none() {
-            self.tail = Some(node.clone());
+        // An entry heavier than the whole budget ends up evicting itself too.
+        while self.total_weight > self.max_weight && self.tail != NIL {
+            self.evict_tail();
         }
     }
 
-    fn remove_node(&mut self, node: &Arc<RwLock<Node<K, V>>>) {
-        let prev = node.read().unwrap().prev.clone();
-        let next = node.read().unwrap().next.clone();
+    fn node(&self, idx: usize) -> &Node<K, V> {
+        self.slots[idx].as_ref().expect("live slot")
+    }
 
-        let prev_for_next = prev.clone();
+    fn node_mut(&mut self, idx: usize) -> &mut Node<K, V> {
+        self.slots[idx].as_mut().expect("live slot")
+    }
 
-        if let Some(prev) = prev.and_then(|w| w.upgrade()) {
-            prev.write().unwrap().next = next.clone();
-        } else {
-            self.head = next.clone();
+    fn move_to_head(&mut self, idx: usize) {
+        if self.head != idx {
+            self.detach(idx);
+            self.push_head(idx);
         }
+    }
 
-        if let Some(next) = &next {
-            next.write().unwrap().prev = prev_for_next;
+    fn push_head(&mut self, idx: usize) {
+        let old_head = self.head;
+        {
+            let node = self.node_mut(idx);
+            node.prev = NIL;
+            node.next = old_head;
+        }
+        if old_head != NIL {
+            self.node_mut(old_head).prev = idx;
+        }
+        self.head = idx;
+        if self.tail == NIL {
+            self.tail = idx;
+        }
+    }
+
+    fn detach(&mut self, idx: usize) {
+        let (prev, next) = {
+            let node = self.node(idx);
+            (node.prev, node.next)
+        };
+        if prev != NIL {
+            self.node_mut(prev).next = next;
         } else {
-            self.tail = prev_for_next.and_then(|w| w.upgrade());
+            self.head = next;
+        }
+        if next != NIL {
+            self.node_mut(next).prev = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    fn evict_tail(&mut self) {
+        if self.tail != NIL {
+            self.remove_slot(self.tail);
+            self.stats.evictions += 1;
         }
-        node.write().unwrap().prev = None;
-        node.write().unwrap().next = None;
     }
 
-    fn remove_tail(&mut self) {
-        if let Some(tail_rc) = self.tail.take() {
-            let key = tail_rc.read().unwrap().key.clone();
-            self.remove_node(&tail_rc);
-            self.map.remove(&key);
+    // Unlinks the node, frees its slot and drops it from the map.
+    fn remove_slot(&mut self, idx: usize) -> Node<K, V> {
+        self.detach(idx);
+        let node = self.slots[idx].take().expect("live slot");
+        self.free.push(idx);
+        self.map.remove(&node.key);
+        self.total_weight -= node.weight;
+        node
+    }
+}
+
+/// Background expiry for a shared cache. The task stops once the cache is dropped.
+pub fn spawn_expiry<K, V>(cache: &Arc<Mutex<LRUCache<K, V>>>, every: Duration)
+where
+    K: std::hash::Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    let weak = Arc::downgrade(cache);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            ticker.tick().await;
+            let Some(cache) = weak.upgrade() else {
+                break;
+            };
+            if let Ok(mut guard) = cache.lock() {
+                guard.purge_expired();
+            };
         }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_and_counts_hits() {
+        let mut cache = LRUCache::new(2);
+        cache.put("a", 1);
+        cache.put("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.put("c", 3);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(3));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 1));
+    }
+
+    #[test]
+    fn entries_expire_lazily_and_on_purge() {
+        let mut cache = LRUCache::new(10).with_default_ttl(Duration::from_millis(20));
+        cache.put("short", 1);
+        cache.put("other", 2);
+        cache.put_with_ttl("long", 3, Duration::from_secs(60));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get(&"short"), None);
+        assert_eq!(cache.purge_expired(), 1);
+        assert_eq!(cache.get(&"long"), Some(3));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats().expirations, 2);
+
+        // A TTL too long to add to the clock means no expiry rather than a panic.
+        cache.put_with_ttl("forever", 4, Duration::MAX);
+        assert_eq!(cache.purge_expired(), 0);
+        assert_eq!(cache.get(&"forever"), Some(4));
+    }
+
+    #[test]
+    fn peek_remove_iter_and_resize() {
+        let mut cache = LRUCache::new(3);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+
+        // peek does not refresh recency, so 1 is still the eviction candidate.
+        assert_eq!(cache.peek(&1), Some(&"a"));
+        assert!(cache.contains(&2));
+        let order: Vec<i32> = cache.iter().map(|(k, _)| *k).collect();
+        assert_eq!(order, vec![3, 2, 1]);
+
+        assert_eq!(cache.remove(&2), Some("b"));
+        assert_eq!(cache.remove(&2), None);
+        cache.put(4, "d");
+        cache.put(5, "e");
+        assert!(!cache.contains(&1));
+
+        cache.resize(1);
+        assert_eq!((cache.len(), cache.capacity()), (1, 1));
+        assert_eq!(cache.iter().collect::<Vec<_>>(), vec![(&5, &"e")]);
+        assert_eq!(cache.stats().evictions, 3);
+
+        cache.resize(0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn weigher_bounds_total_weight() {
+        let mut cache = LRUCache::new(100).with_weigher(10, |_k: &&str, v: &String| v.len());
+        cache.put("a", "xxxx".to_string());
+        cache.put("b", "yyyy".to_string());
+        cache.put("c", "zzzz".to_string());
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.weight(), 8);
+
+        // Overwriting replaces the old weight rather than adding to it.
+        cache.put("b", "y".to_string());
+        assert_eq!(cache.weight(), 5);
+        assert_eq!(cache.stats().evictions, 1);
     }
 }
@@ -1,12 +1,31 @@
+use clap::Parser;
+
+mod auth;
 mod cache;
+mod config;
 mod handlers;
 mod middleware;
 mod middlewares;
+mod ratelimit;
+mod reload;
+mod request;
+mod router;
 mod server;
 mod types;
 
+use config::{Cli, ServerConfig};
+use reload::Reloader;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    server::run().await?;
+    let cli = Cli::parse();
+    // Before loading, so `.env` can hold `SERVER_*` settings too.
+    dotenvy::dotenv().ok();
+    let config = ServerConfig::load_or_default(&cli)?;
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    server::run(Reloader::new(cli, config)).await?;
     Ok(())
 }
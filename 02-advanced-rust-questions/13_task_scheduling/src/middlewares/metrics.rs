@@ -1,11 +1,11 @@
-use std::future::Future;
-use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use serde::Serialize;
 
-use crate::middleware::Middleware;
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::reload::ReloadCounts;
+use crate::request::Request;
 use crate::types::Response;
 
 pub struct MetricsMiddleware {
@@ -18,6 +18,7 @@ pub struct MetricsMiddleware {
 pub struct MetricsResponse {
     total_requests: usize,
     average_response_time: f64,
+    config_reloads: ReloadCounts,
 }
 impl MetricsMiddleware {
     pub fn new() -> Self {
@@ -28,48 +29,37 @@ impl MetricsMiddleware {
         }
     }
 
-    pub fn handle_metrics(&self) -> Response {
+    pub fn handle_metrics(&self, config_reloads: ReloadCounts) -> Response {
         let total_requests = *self.total_requests.lock().unwrap();
         let average_response_time = self.average_response_time.lock().unwrap().unwrap_or(0.0);
-        Response {
-            status: 200,
-            content_type: "application/json".into(),
-            body: serde_json::to_string(&MetricsResponse {
+        Response::json(
+            200,
+            &MetricsResponse {
                 total_requests,
                 average_response_time,
-            })
-            .unwrap_or_else(|_| "{}".to_string()),
-        }
+                config_reloads,
+            },
+        )
     }
 }
 impl Middleware for MetricsMiddleware {
     fn handle(
         &self,
-        req: &str,
-        client_ip: &str,
-        next: &dyn for<'a> Fn(&'a str) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>>,
-    ) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>> {
-        let req = req.to_string();
-        let client_ip = client_ip.to_string();
+        req: &Request,
+        _client_ip: &str,
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
         let total_requests = Arc::clone(&self.total_requests);
         let total_response_time = Arc::clone(&self.total_response_time);
         let average_response_time = Arc::clone(&self.average_response_time);
 
-        let fut = next(&req);
+        let fut = next(req);
         Box::pin(async move {
             let start_time = Instant::now();
-            let mut parts = req.split_whitespace();
             let response = fut.await;
             let duration = start_time.elapsed();
             let response_time_ms = duration.as_secs_f64() * 1000.0; // Convert to milliseconds
 
-            // if let (Some(method), Some(path)) = (parts.next(), parts.next()) {
-            //     println!(
-            //         "[Metric gatherer] {} {}, status: {}, response_time: {:.2}ms, from {} ",
-            //         method, path, response.status, response_time_ms, client_ip
-            //     )
-            // }
-
             // Update metrics
             {
                 let mut total = total_requests.lock().unwrap();
@@ -0,0 +1,398 @@
+// `Store` over RESP, for Redis or a compatible server.
+// Each update is an optimistic compare-and-set: WATCH the key, GET it, then
+// MULTI / SET / EXEC. EXEC answers a null array if someone else wrote the key after our
+// WATCH, in which case the update is recomputed from the new value and tried again.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::store::{Store, StoreFuture, Update};
+use super::Decision;
+
+/// Connections kept open between updates.
+const MAX_IDLE: usize = 8;
+/// Lost races before an update gives up; only reachable under heavy contention on one key.
+const MAX_ATTEMPTS: u32 = 16;
+
+pub struct RespStore {
+    addr: String,
+    timeout: Duration,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+}
+
+impl RespStore {
+    /// `addr` is `host:port`. Nothing is dialled until the first update. `timeout` bounds
+    /// a whole update, connecting included; past it the limiter treats the store as down,
+    /// so keep it well under the latency budget of a request.
+    pub fn new(addr: &str, timeout: Duration) -> Self {
+        RespStore {
+            addr: addr.to_string(),
+            timeout,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    async fn connection(&self) -> io::Result<BufReader<TcpStream>> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(conn) => Ok(conn),
+            None => {
+                let stream = TcpStream::connect(&self.addr).await?;
+                stream.set_nodelay(true)?;
+                Ok(BufReader::new(stream))
+            }
+        }
+    }
+}
+
+impl Store for RespStore {
+    fn update<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Duration,
+        update: Update<'a>,
+    ) -> StoreFuture<'a, io::Result<Decision>> {
+        Box::pin(async move {
+            let attempt = async {
+                let mut conn = self.connection().await?;
+                let decision = compare_and_set(&mut conn, key, ttl, update).await?;
+                Ok::<_, io::Error>((conn, decision))
+            };
+            // A connection is only reused after a clean round trip; one that failed or
+            // timed out half way may still have replies in flight.
+            let (conn, decision) =
+                tokio::time::timeout(self.timeout, attempt)
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "rate limit store timed out")
+                    })??;
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+            Ok(decision)
+        })
+    }
+}
+
+async fn compare_and_set(
+    conn: &mut BufReader<TcpStream>,
+    key: &str,
+    ttl: Duration,
+    update: Update<'_>,
+) -> io::Result<Decision> {
+    let px = ttl.as_millis().max(1).to_string();
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(backoff(attempt)).await;
+        }
+        send(conn, &[&["WATCH", key], &["GET", key]]).await?;
+        expect_ok(read_reply(conn).await?)?;
+        let current = match read_reply(conn).await? {
+            Reply::Bulk(value) => value
+                .map(String::from_utf8)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            other => return Err(unexpected(other)),
+        };
+
+        let (value, decision) = update(current.as_deref());
+        send(
+            conn,
+            &[&["MULTI"], &["SET", key, &value, "PX", &px], &["EXEC"]],
+        )
+        .await?;
+        expect_ok(read_reply(conn).await?)?;
+        match read_reply(conn).await? {
+            Reply::Simple(s) if s == "QUEUED" => {}
+            other => return Err(unexpected(other)),
+        }
+        match read_reply(conn).await? {
+            Reply::Array(Some(_)) => return Ok(decision),
+            // Someone else wrote the key first.
+            Reply::Array(None) => continue,
+            other => return Err(unexpected(other)),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        "rate limit store: too much contention",
+    ))
+}
+
+/// Up to 2^attempt ms (capped at 32), jittered so racing writers spread out.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = 1_000_000u64 << attempt.min(5);
+    let jitter = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+    Duration::from_nanos(jitter % ceiling)
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn unexpected(reply: Reply) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply: {reply:?}"),
+    )
+}
+
+fn expect_ok(reply: Reply) -> io::Result<()> {
+    match reply {
+        Reply::Simple(s) if s == "OK" => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Writes the commands back to back as RESP arrays of bulk strings, in one write.
+async fn send(conn: &mut BufReader<TcpStream>, commands: &[&[&str]]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for command in commands {
+        buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+        for arg in *command {
+            buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            buf.extend_from_slice(arg.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+    conn.get_mut().write_all(&buf).await
+}
+
+fn read_reply<R>(reader: &mut R) -> Pin<Box<dyn Future<Output = io::Result<Reply>> + Send + '_>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    Box::pin(async move {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (kind, rest) = line.split_at_checked(1).unwrap_or(("", ""));
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad reply: {line}"));
+        let len = || rest.parse::<i64>().map_err(|_| bad());
+        Ok(match kind {
+            "+" => Reply::Simple(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(len()?),
+            "$" => match len()? {
+                n if n < 0 => Reply::Bulk(None),
+                n => {
+                    let mut buf = vec![0; n as usize + 2];
+                    reader.read_exact(&mut buf).await?;
+                    buf.truncate(n as usize);
+                    Reply::Bulk(Some(buf))
+                }
+            },
+            "*" => match len()? {
+                n if n < 0 => Reply::Array(None),
+                n => {
+                    let mut items = Vec::with_capacity(n as usize);
+                    for _ in 0..n {
+                        items.push(read_reply(reader).await?);
+                    }
+                    Reply::Array(Some(items))
+                }
+            },
+            _ => return Err(bad()),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::store::SharedLimiter;
+    use crate::ratelimit::Algorithm;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// A RESP server that speaks just enough for `compare_and_set`. Between rounds another
+    /// writer moves the key on, so GET answers 0, 10, 20, ... on each connection, and the
+    /// first `lose` EXECs answer a null array. Returns its address and every SET value.
+    async fn racing_server(lose: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let sets = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sets.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sets = recorded.clone();
+                tokio::spawn(async move {
+                    let mut conn = BufReader::new(stream);
+                    let mut round = 0;
+                    while let Ok(Reply::Array(Some(args))) = read_reply(&mut conn).await {
+                        let args: Vec<String> = args
+                            .into_iter()
+                            .map(|arg| match arg {
+                                Reply::Bulk(Some(arg)) => String::from_utf8(arg).unwrap(),
+                                other => panic!("unexpected argument {other:?}"),
+                            })
+                            .collect();
+                        let reply = match args[0].as_str() {
+                            "WATCH" | "MULTI" => "+OK\r\n".to_string(),
+                            "GET" => {
+                                let value = (round * 10).to_string();
+                                format!("${}\r\n{}\r\n", value.len(), value)
+                            }
+                            "SET" => {
+                                sets.lock().unwrap().push(args[2].clone());
+                                "+QUEUED\r\n".to_string()
+                            }
+                            "EXEC" if round < lose => {
+                                round += 1;
+                                "*-1\r\n".to_string()
+                            }
+                            "EXEC" => "*1\r\n+OK\r\n".to_string(),
+                            other => panic!("unexpected command {other}"),
+                        };
+                        conn.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, sets)
+    }
+
+    #[tokio::test]
+    async fn lost_races_recompute_the_update_from_the_new_value() {
+        let (addr, sets) = racing_server(2).await;
+        let store = RespStore::new(&addr, Duration::from_secs(5));
+        let calls = AtomicU32::new(0);
+        let update = |current: Option<&str>| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let count: u32 = current.map_or(0, |raw| raw.parse().unwrap());
+            let decision = Decision {
+                allowed: true,
+                limit: 100,
+                remaining: 99 - count,
+                reset: Duration::ZERO,
+                retry_after: None,
+            };
+            ((count + 1).to_string(), decision)
+        };
+
+        let decision = store
+            .update("rl:client", Duration::from_secs(60), &update)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // Each retry builds on what the other writer left, not on the first read.
+        assert_eq!(*sets.lock().unwrap(), ["1", "11", "21"]);
+        assert_eq!(decision.remaining, 79);
+    }
+
+    #[tokio::test]
+    async fn a_key_too_contended_to_update_is_still_limited() {
+        let (addr, sets) = racing_server(usize::MAX).await;
+        let store = Arc::new(RespStore::new(&addr, Duration::from_secs(5)));
+        let limiter = SharedLimiter::new(
+            Algorithm::SlidingWindowLog {
+                limit: 1,
+                window: Duration::from_secs(60),
+            },
+            "rl",
+            store,
+        );
+
+        // The local allowance would let both through. The second one only reaching the
+        // store again shows that being busy did not mark the store down.
+        for _ in 0..2 {
+            let decision = limiter.check("client").await;
+            assert!(!decision.allowed);
+            assert!(decision.retry_after.is_some());
+        }
+        assert_eq!(sets.lock().unwrap().len(), 2 * MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn instances_sharing_a_store_share_the_limit() {
+        let addr = crate::ratelimit::test_store::spawn().await;
+        let instances: Vec<SharedLimiter> = (0..2)
+            .map(|_| {
+                SharedLimiter::new(
+                    Algorithm::SlidingWindowLog {
+                        limit: 3,
+                        window: Duration::from_secs(60),
+                    },
+                    "rl",
+                    Arc::new(RespStore::new(&addr, Duration::from_secs(5))),
+                )
+            })
+            .collect();
+
+        // Each instance on its own, or falling back to its local allowance, would let 3
+        // through; together they let 3 through in all.
+        let mut allowed = 0;
+        for i in 0..8 {
+            if instances[i % 2].check("client").await.allowed {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 3);
+
+        // Concurrent updates from both instances race on the key, and WATCH sorts them out.
+        let racing = (0..6).map(|i| instances[i % 2].check("other"));
+        let decisions = futures::future::join_all(racing).await;
+        assert_eq!(decisions.iter().filter(|d| d.allowed).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn parses_nested_and_null_replies() {
+        let mut raw: &[u8] = b"*3\r\n+OK\r\n$3\r\nfoo\r\n$-1\r\n*-1\r\n:7\r\n-ERR nope\r\n";
+        assert_eq!(
+            read_reply(&mut raw).await.unwrap(),
+            Reply::Array(Some(vec![
+                Reply::Simple("OK".into()),
+                Reply::Bulk(Some(b"foo".to_vec())),
+                Reply::Bulk(None),
+            ]))
+        );
+        assert_eq!(read_reply(&mut raw).await.unwrap(), Reply::Array(None));
+        assert_eq!(read_reply(&mut raw).await.unwrap(), Reply::Integer(7));
+        assert_eq!(
+            read_reply(&mut raw).await.unwrap(),
+            Reply::Error("ERR nope".into())
+        );
+        assert!(read_reply(&mut raw).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_store_falls_back_to_the_local_limiter() {
+        // Bind and drop a listener to get a port nothing is listening on.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let store = Arc::new(RespStore::new(
+            &addr.to_string(),
+            Duration::from_millis(250),
+        ));
+        let limiter = SharedLimiter::new(
+            Algorithm::SlidingWindowLog {
+                limit: 1,
+                window: Duration::from_secs(60),
+            },
+            "rl",
+            store,
+        );
+        assert!(limiter.check("client").await.allowed);
+        assert!(!limiter.check("client").await.allowed);
+    }
+}
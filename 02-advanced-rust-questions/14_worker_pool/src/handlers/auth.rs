@@ -1,18 +1,14 @@
+use crate::auth::{AuthError, AuthService};
+use crate::middlewares::auth::Principal;
+use crate::request::Request;
 use crate::types::Response;
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
-
-fn get_secret() -> Vec<u8> {
-    std::env::var("JWT_SECRET")
-        .unwrap_or_else(|_| "fallback_secret".to_string())
-        .into_bytes()
-}
+use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct AuthRequest {
     pub username: String,
     pub email: Option<String>,
+    pub password: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -20,99 +16,146 @@ pub struct VerifyRequest {
     pub token: String,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String, // subject = username
-    pub exp: usize,  // expiry timestamp
-    pub iat: usize,  // usize
-    pub email: Option<String>,
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+fn bad_request() -> Response {
+    Response::text(400, "Bad Request")
+}
+
+// Token problems are the client's (401); a failing store is ours (500).
+fn auth_error(err: AuthError) -> Response {
+    if let AuthError::Store(err) = &err {
+        eprintln!("token store error: {:?}", err);
+        return Response::json(
+            500,
+            &serde_json::json!({"error": "Token store unavailable"}),
+        );
+    }
+    Response::json(
+        401,
+        &serde_json::json!({"valid": false, "error": err.to_string()}),
+    )
 }
 
-pub async fn create_token(req: &str) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<AuthRequest>(&body) {
-            let now = Utc::now();
-            let claims = Claims {
-                sub: payload.username.clone(),
-                email: payload.email.clone(),
-                iat: now.timestamp() as usize,
-                exp: (now + Duration::hours(1)).timestamp() as usize,
-            };
-            let secret = get_secret();
-
-            match encode(
-                &Header::default(),
-                &claims,
-                &EncodingKey::from_secret(&secret),
-            ) {
-                Ok(token) => {
-                    let body = serde_json::json!({"token": token}).to_string();
-                    return Response {
-                        status: 200,
-                        content_type: "application/json".into(),
-                        body,
-                    };
-                }
-                Err(err) => {
-                    eprintln!("jwt encode error: {:?}", err);
-                    return Response {
-                        status: 500,
-                        content_type: "text/plain".into(),
-                        body: "Token creation failed".into(),
-                    };
-                }
-            }
+pub async fn create_token(req: &Request, auth: &AuthService) -> Response {
+    let Ok(payload) = serde_json::from_slice::<AuthRequest>(&req.body) else {
+        return bad_request();
+    };
+    // Roles and scopes come from the account, whatever else the body asks for.
+    let Some(subject) =
+        auth.accounts()
+            .subject(payload.username, payload.email, payload.password.as_deref())
+    else {
+        return Response::json(401, &serde_json::json!({"error": "Invalid credentials"}));
+    };
+    match auth.issue(&subject) {
+        // `token` is kept for clients written against the access-token-only endpoint.
+        Ok(pair) => Response::json(
+            200,
+            &serde_json::json!({
+                "token": pair.access_token,
+                "access_token": pair.access_token,
+                "refresh_token": pair.refresh_token,
+                "token_type": pair.token_type,
+                "expires_in": pair.expires_in,
+            }),
+        ),
+        Err(err) => {
+            eprintln!("jwt encode error: {:?}", err);
+            Response::text(500, "Token creation failed")
         }
     }
+}
 
-    Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
+pub async fn refresh_token(req: &Request, auth: &AuthService) -> Response {
+    let Ok(payload) = serde_json::from_slice::<RefreshRequest>(&req.body) else {
+        return bad_request();
+    };
+    match auth.refresh(&payload.refresh_token) {
+        Ok(pair) => Response::json(200, &pair),
+        Err(err) => auth_error(err),
     }
 }
 
-pub async fn verify_token(req: &str) -> Response {
-    if let Some(idx) = req.find("\r\n\r\n") {
-        let body = &req[idx + 4..];
-        if let Ok(payload) = serde_json::from_str::<VerifyRequest>(body) {
-            let secret = get_secret();
-            match decode::<Claims>(
-                &payload.token,
-                &DecodingKey::from_secret(&secret),
-                &Validation::default(),
-            ) {
-                Ok(token_data) => {
-                    let body = serde_json::json!({
-                        "valid": true,
-                        "claims": token_data.claims
-                    })
-                    .to_string();
-                    return Response {
-                        status: 200,
-                        content_type: "application/json".into(),
-                        body,
-                    };
-                }
-                Err(err) => {
-                    let body = serde_json::json!({
-                        "valid": false,
-                        "error": err.to_string()
-                    })
-                    .to_string();
-                    return Response {
-                        status: 401,
-                        content_type: "application/json".into(),
-                        body,
-                    };
-                }
-            }
-        }
+pub async fn verify_token(req: &Request, auth: &AuthService) -> Response {
+    let Ok(payload) = serde_json::from_slice::<VerifyRequest>(&req.body) else {
+        return bad_request();
+    };
+    match auth.verify_access(&payload.token) {
+        Ok(claims) => Response::json(200, &serde_json::json!({"valid": true, "claims": claims})),
+        Err(err) => auth_error(err),
+    }
+}
+
+/// Revokes an access or refresh token. Holding the token is enough to revoke it.
+pub async fn revoke_token(req: &Request, auth: &AuthService) -> Response {
+    let Ok(payload) = serde_json::from_slice::<VerifyRequest>(&req.body) else {
+        return bad_request();
+    };
+    match auth.revoke(&payload.token) {
+        Ok(()) => Response::json(200, &serde_json::json!({"revoked": true})),
+        Err(err) => auth_error(err),
+    }
+}
+
+pub async fn jwks(auth: &AuthService) -> Response {
+    Response::json(200, &auth.keys().jwks())
+}
+
+/// Who the bearer token belongs to, as the auth middleware resolved it.
+pub async fn me(req: &Request) -> Response {
+    match req.extensions.get::<Principal>() {
+        Some(principal) => Response::json(200, principal),
+        // Only reachable with the auth middleware switched off.
+        None => Response::text(401, "Unauthorized"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Accounts, Keyring};
+    use crate::request::{Limits, RequestReader};
+
+    async fn token_request(body: &str) -> Request {
+        let raw = format!(
+            "POST /auth/token HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn issued_scopes(auth: &AuthService, res: &Response) -> Vec<String> {
+        let body: serde_json::Value = serde_json::from_slice(res.body.as_bytes().unwrap()).unwrap();
+        let token = body["access_token"].as_str().unwrap();
+        auth.verify_access(token).unwrap().scopes
     }
-    Response {
-        status: 400,
-        content_type: "text/plain".into(),
-        body: "Bad Request".into(),
+
+    #[tokio::test]
+    async fn requested_scopes_are_not_granted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth = AuthService::new(Keyring::parse("k1:HS256:test-secret").unwrap(), &db)
+            .unwrap()
+            .with_accounts(Accounts::parse("bot:pw::users:read").unwrap());
+
+        let unknown = r#"{"username":"mallory","roles":["admin"],"scopes":["users:write"]}"#;
+        let res = create_token(&token_request(unknown).await, &auth).await;
+        assert_eq!(res.status, 401);
+
+        let greedy = r#"{"username":"bot","password":"pw","scopes":["users:write"]}"#;
+        let res = create_token(&token_request(greedy).await, &auth).await;
+        assert_eq!(issued_scopes(&auth, &res), ["users:read"]);
+
+        let wrong = r#"{"username":"bot","password":"guess"}"#;
+        let res = create_token(&token_request(wrong).await, &auth).await;
+        assert_eq!(res.status, 401);
     }
 }
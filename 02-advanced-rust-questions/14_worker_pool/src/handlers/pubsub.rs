@@ -1,7 +1,5 @@
-use crate::{pubsub::SharedPubSub, types::Response};
+use crate::{pubsub::SharedPubSub, request::Request, types::Response};
 use serde::Deserialize;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
 #[derive(Deserialize)]
 struct PublishRequest {
@@ -24,69 +22,50 @@ impl PubSubHandler {
         Self { pubsub }
     }
 
-    pub async fn publish(&self, req: &str) -> Response {
-        if let Some(idx) = req.find("\r\n\r\n") {
-            let body = &req[idx + 4..];
-            if let Ok(payload) = serde_json::from_str::<PublishRequest>(body) {
-                let pubsub = self.pubsub.clone();
-                let pubsub = pubsub.lock().await;
-                pubsub
-                    .publish(&payload.channel, payload.message.clone())
-                    .await;
+    pub async fn publish(&self, req: &Request) -> Response {
+        if let Ok(payload) = serde_json::from_slice::<PublishRequest>(&req.body) {
+            let pubsub = self.pubsub.clone();
+            let pubsub = pubsub.lock().await;
+            pubsub
+                .publish(&payload.channel, payload.message.clone())
+                .await;
 
-                return Response {
-                    status: 200,
-                    content_type: "application/json".into(),
-                    body: format!(
-                        "{{\"status\": \"ok\", \"msg\": \"Published to {}\"}}",
-                        payload.channel
-                    ),
-                };
-            }
+            return Response::json(
+                200,
+                &serde_json::json!({
+                    "status": "ok",
+                    "msg": format!("Published to {}", payload.channel),
+                }),
+            );
         }
 
-        Response {
-            status: 400,
-            content_type: "application/json".into(),
-            body: "{\"error\": \"Invalid request\"}".into(),
-        }
+        Response::json(400, &serde_json::json!({ "error": "Invalid request" }))
     }
 
-    pub async fn subscribe(&self, req: &str) -> Response {
-        if let Some(idx) = req.find("\r\n\r\n") {
-            let body = &req[idx + 4..];
-            if let Ok(payload) = serde_json::from_str::<SubscribeRequest>(body) {
-                let pubsub = self.pubsub.clone();
-                let channel = payload.channel.clone();
-                let mut rx = {
-                    let mut guard = pubsub.lock().await;
-                    guard.subscribe(&channel)
-                };
+    pub async fn subscribe(&self, req: &Request) -> Response {
+        if let Ok(payload) = serde_json::from_slice::<SubscribeRequest>(&req.body) {
+            let pubsub = self.pubsub.clone();
+            let channel = payload.channel.clone();
+            let mut rx = {
+                let mut guard = pubsub.lock().await;
+                guard.subscribe(&channel)
+            };
 
-                tokio::spawn({
-                    let channel = channel.clone();
-                    async move {
-                        while let Ok(msg) = rx.recv().await {
-                            println!("📩 Message on '{}': {}", channel, msg);
-                        }
+            tokio::spawn({
+                let channel = channel.clone();
+                async move {
+                    while let Ok(msg) = rx.recv().await {
+                        println!("📩 Message on '{}': {}", channel, msg);
                     }
-                });
+                }
+            });
 
-                return Response {
-                    status: 200,
-                    content_type: "application/json".into(),
-                    body: format!(
-                        "{{\"status\": \"subscribed\", \"channel\": \"{}\"}}",
-                        channel
-                    ),
-                };
-            }
+            return Response::json(
+                200,
+                &serde_json::json!({ "status": "subscribed", "channel": channel }),
+            );
         }
 
-        Response {
-            status: 400,
-            content_type: "application/json".into(),
-            body: "{\"error\": \"Invalid request\"}".into(),
-        }
+        Response::json(400, &serde_json::json!({ "error": "Invalid request" }))
     }
 }
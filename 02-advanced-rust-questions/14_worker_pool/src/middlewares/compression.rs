@@ -0,0 +1,504 @@
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::request::{Limits, Method, Request};
+use crate::types::{Body, Response};
+
+/// Content codings the middleware can produce and accept, named in config by their
+/// `Content-Encoding` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[serde(rename = "br")]
+    Brotli,
+    Gzip,
+    /// The zlib format, which is what HTTP calls "deflate".
+    Deflate,
+}
+
+impl Encoding {
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the coding the client weighs highest in `Accept-Encoding`, breaking ties by the
+/// order of `supported`. `*` covers codings not listed; `q=0` rules one out.
+pub fn negotiate(accept: &str, supported: &[Encoding]) -> Option<Encoding> {
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let token = parts.next().unwrap_or("");
+        if token.is_empty() {
+            continue;
+        }
+        let q = parts
+            .find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+        // A malformed weight makes the whole entry unusable rather than preferred.
+        weights.push((token, q.unwrap_or(0.0)));
+    }
+    let weight = |encoding: Encoding| {
+        let named = weights
+            .iter()
+            .find(|(token, _)| Encoding::parse(token) == Some(encoding));
+        let wildcard = weights.iter().find(|(token, _)| *token == "*");
+        named.or(wildcard).map_or(0.0, |(_, q)| *q)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in supported {
+        let q = weight(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// Formats that are already compressed; running them through gzip again only costs CPU.
+fn is_precompressed(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    (mime.starts_with("image/") && mime != "image/svg+xml")
+        || mime.starts_with("audio/")
+        || mime.starts_with("video/")
+        || matches!(
+            mime.as_str(),
+            "application/zip"
+                | "application/gzip"
+                | "application/x-gzip"
+                | "application/zstd"
+                | "application/pdf"
+                | "font/woff"
+                | "font/woff2"
+        )
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            // Quality 5 keeps brotli close to gzip's speed on dynamic responses.
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Brotli(w) => w.as_mut(),
+            Encoder::Gzip(w) => w,
+            Encoder::Deflate(w) => w,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Brotli(w) => w.get_mut(),
+            Encoder::Gzip(w) => w.get_mut(),
+            Encoder::Deflate(w) => w.get_mut(),
+        }
+    }
+
+    /// Compresses `data` and flushes, returning everything produced so far, so a
+    /// streamed chunk reaches the client without waiting for the next one.
+    fn push(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let writer = self.writer();
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(std::mem::take(self.output()).into())
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Brotli(w) => w.into_inner(),
+            Encoder::Gzip(w) => w.finish()?,
+            Encoder::Deflate(w) => w.finish()?,
+        };
+        Ok(out.into())
+    }
+}
+
+/// Decodes a request body; `None` if it would come to more than `limit` bytes, so a
+/// small compressed upload cannot expand without bound.
+fn decode(encoding: Encoding, body: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        Encoding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+        Encoding::Gzip => Box::new(GzDecoder::new(body)),
+        Encoding::Deflate => Box::new(ZlibDecoder::new(body)),
+    };
+    let mut out = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+    Ok((out.len() <= limit).then_some(out))
+}
+
+/// Compresses responses with the best coding the client accepts and decodes compressed
+/// request bodies before they reach the handler.
+#[derive(Clone)]
+pub struct CompressionMiddleware {
+    encodings: Vec<Encoding>,
+    min_size: usize,
+    max_request_body: usize,
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> Self {
+        CompressionMiddleware {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            max_request_body: Limits::default().max_body_bytes,
+        }
+    }
+
+    /// Codings to offer, most preferred first.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Bodies smaller than this go out as they are; below about a kilobyte the
+    /// framing overhead eats most of the saving. Streams are always compressed.
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Cap on a request body after decoding.
+    pub fn max_request_body(mut self, bytes: usize) -> Self {
+        self.max_request_body = bytes;
+        self
+    }
+
+    fn decode_request(&self, req: &Request) -> Result<Option<Request>, Response> {
+        let Some(coding) = req.header("Content-Encoding") else {
+            return Ok(None);
+        };
+        let coding = coding.trim();
+        if coding.eq_ignore_ascii_case("identity") {
+            return Ok(None);
+        }
+        let Some(encoding) = Encoding::parse(coding) else {
+            return Err(Response::text(415, "Unsupported Content-Encoding")
+                .with_header("Accept-Encoding", &self.accepted()));
+        };
+        let body = match decode(encoding, &req.body, self.max_request_body) {
+            Ok(Some(body)) => body,
+            Ok(None) => return Err(Response::text(413, "Payload Too Large")),
+            Err(_) => return Err(Response::text(400, "Bad Request")),
+        };
+        let mut decoded = req.clone();
+        decoded.headers.remove("Content-Encoding");
+        decoded.headers.remove("Content-Length");
+        decoded
+            .headers
+            .insert("Content-Length", &body.len().to_string());
+        decoded.body = body;
+        Ok(Some(decoded))
+    }
+
+    fn accepted(&self) -> String {
+        let tokens: Vec<&str> = self.encodings.iter().map(|e| e.token()).collect();
+        tokens.join(", ")
+    }
+
+    fn compress(&self, mut res: Response, accept: Option<&str>) -> Response {
+        let compressible = (200..300).contains(&res.status)
+            && res.status != 204
+            && !res.headers.contains("Content-Encoding")
+            && !res.content_type().is_some_and(is_precompressed);
+        if !compressible {
+            return res;
+        }
+        // From here on the body depends on Accept-Encoding, so caches must key on it.
+        res.headers.vary("Accept-Encoding");
+        let Some(encoding) = accept.and_then(|a| negotiate(a, &self.encodings)) else {
+            return res;
+        };
+
+        let body = std::mem::replace(&mut res.body, Body::Empty);
+        res.body = match body {
+            Body::Full(bytes) if bytes.len() >= self.min_size => {
+                let mut encoder = Encoder::new(encoding);
+                match encoder
+                    .writer()
+                    .write_all(&bytes)
+                    .and_then(|_| encoder.finish())
+                {
+                    Ok(compressed) => Body::Full(compressed),
+                    Err(err) => {
+                        eprintln!("compression failed, sending as is: {}", err);
+                        res.body = Body::Full(bytes);
+                        return res;
+                    }
+                }
+            }
+            Body::Stream(chunks) => Body::Stream(Box::pin(compress_stream(chunks, encoding))),
+            small => {
+                res.body = small;
+                return res;
+            }
+        };
+        res.headers.remove("Content-Length");
+        res.with_header("Content-Encoding", encoding.token())
+    }
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn compress_stream(
+    chunks: crate::types::BodyStream,
+    encoding: Encoding,
+) -> impl futures::Stream<Item = io::Result<Bytes>> + Send {
+    // The encoder is dropped once it has been finished or the source failed.
+    futures::stream::unfold(
+        (chunks, Some(Encoder::new(encoding))),
+        |(mut chunks, mut encoder)| async move {
+            let enc = encoder.as_mut()?;
+            let out = match chunks.next().await {
+                Some(Ok(chunk)) => enc.push(&chunk),
+                Some(Err(err)) => {
+                    encoder = None;
+                    Err(err)
+                }
+                None => encoder.take()?.finish(),
+            };
+            Some((out, (chunks, encoder)))
+        },
+    )
+}
+
+impl Middleware for CompressionMiddleware {
+    fn handle(
+        &self,
+        req: &Request,
+        _client_ip: &str,
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
+        let fut = match self.decode_request(req) {
+            Ok(Some(decoded)) => next(&decoded),
+            Ok(None) => next(req),
+            Err(res) => return Box::pin(async move { res }),
+        };
+        // A HEAD response carries the GET's Content-Length, which no longer fits once
+        // the body would have been compressed; leave those alone.
+        if req.method == Method::Head {
+            return fut;
+        }
+        let accept = req.header("Accept-Encoding").map(str::to_string);
+        let this = self.clone();
+        Box::pin(async move { this.compress(fut.await, accept.as_deref()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::run_chain;
+    use crate::request::RequestReader;
+
+    async fn request(headers: &[(&str, &str)], body: &[u8]) -> Request {
+        let mut raw = b"POST /user HTTP/1.1\r\nHost: test\r\n".to_vec();
+        for (name, value) in headers {
+            raw.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        raw.extend_from_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+        raw.extend_from_slice(body);
+        let mut reader = RequestReader::new(Limits::default());
+        reader.read_request(&mut &raw[..]).await.unwrap().unwrap()
+    }
+
+    fn users() -> String {
+        let users: Vec<_> = (0..100)
+            .map(|i| serde_json::json!({ "id": i, "name": "alice", "email": "alice@example.com" }))
+            .collect();
+        serde_json::to_string(&users).unwrap()
+    }
+
+    // Handler that echoes the request body back, so the tests see both directions.
+    async fn run(req: &Request) -> Response {
+        let compression = CompressionMiddleware::new();
+        run_chain(req, "127.0.0.1", &[&compression], |req: &Request| {
+            let body = req.body.clone();
+            async move { Response::bytes(200, "application/json", body) }
+        })
+        .await
+    }
+
+    fn gunzip(body: &[u8]) -> String {
+        let mut out = String::new();
+        GzDecoder::new(body).read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn negotiation_follows_q_values_then_server_preference() {
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+        assert_eq!(negotiate("gzip, br", &all), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5", &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("br;q=0, *;q=0.1", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", &all), None);
+        assert_eq!(negotiate("gzip;q=0", &all), None);
+        assert_eq!(
+            negotiate("deflate;q=bad, gzip;q=0.2", &all),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[tokio::test]
+    async fn compresses_large_bodies_for_clients_that_accept_it() {
+        let body = users();
+        let req = request(
+            &[("Accept-Encoding", "gzip;q=0.8, deflate;q=0.5")],
+            body.as_bytes(),
+        )
+        .await;
+        let res = run(&req).await;
+        assert_eq!(res.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(res.headers.get("Vary"), Some("Accept-Encoding"));
+        let compressed = res.body.as_bytes().unwrap();
+        assert!(compressed.len() < body.len() / 4);
+        assert_eq!(gunzip(compressed), body);
+
+        let req = request(&[("Accept-Encoding", "br")], body.as_bytes()).await;
+        let res = run(&req).await;
+        assert_eq!(res.headers.get("Content-Encoding"), Some("br"));
+        let mut out = String::new();
+        brotli::Decompressor::new(res.body.as_bytes().unwrap(), 4096)
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, body);
+    }
+
+    #[tokio::test]
+    async fn leaves_small_or_unaccepted_bodies_alone() {
+        let req = request(&[("Accept-Encoding", "gzip")], b"{}").await;
+        let res = run(&req).await;
+        assert!(!res.headers.contains("Content-Encoding"));
+        assert_eq!(res.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(res.body.as_bytes(), Some(&b"{}"[..]));
+
+        let body = users();
+        let req = request(&[], body.as_bytes()).await;
+        let res = run(&req).await;
+        assert!(!res.headers.contains("Content-Encoding"));
+        assert_eq!(res.body.len(), Some(body.len()));
+    }
+
+    #[tokio::test]
+    async fn skips_formats_that_are_already_compressed() {
+        let compression = CompressionMiddleware::new().min_size(0);
+        let req = request(&[("Accept-Encoding", "gzip")], b"").await;
+        let res = run_chain(&req, "127.0.0.1", &[&compression], |_: &Request| async {
+            Response::bytes(200, "image/png", vec![0x89, b'P', b'N', b'G'])
+        })
+        .await;
+        assert!(!res.headers.contains("Content-Encoding"));
+        assert!(!res.headers.contains("Vary"));
+    }
+
+    #[tokio::test]
+    async fn streams_are_compressed_chunk_by_chunk() {
+        let compression = CompressionMiddleware::new();
+        let req = request(&[("Accept-Encoding", "gzip")], b"").await;
+        let res = run_chain(&req, "127.0.0.1", &[&compression], |_: &Request| async {
+            let chunks = ["[1,", "2,", "3]"].map(|s| Ok(Bytes::from(s)));
+            Response::stream(200, "application/json", futures::stream::iter(chunks))
+        })
+        .await;
+        assert_eq!(res.headers.get("Content-Encoding"), Some("gzip"));
+        let Body::Stream(chunks) = res.body else {
+            panic!("expected a stream");
+        };
+        let parts: Vec<Bytes> = chunks.map(Result::unwrap).collect().await;
+        assert_eq!(gunzip(&parts.concat()), "[1,2,3]");
+    }
+
+    #[tokio::test]
+    async fn decodes_gzip_request_bodies() {
+        let body = users();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let req = request(&[("Content-Encoding", "gzip")], &gzipped).await;
+        let res = run(&req).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body.as_bytes(), Some(body.as_bytes()));
+
+        let req = request(&[("Content-Encoding", "gzip")], b"not gzip").await;
+        assert_eq!(run(&req).await.status, 400);
+        let req = request(&[("Content-Encoding", "zstd")], b"").await;
+        assert_eq!(run(&req).await.status, 415);
+    }
+
+    #[tokio::test]
+    async fn settings_limit_codings_and_decoded_size() {
+        let compression = CompressionMiddleware::new()
+            .encodings(&[Encoding::Deflate])
+            .min_size(0)
+            .max_request_body(16);
+        let echo = |req: &Request| {
+            let body = req.body.clone();
+            async move { Response::bytes(200, "application/json", body) }
+        };
+
+        let req = request(&[("Accept-Encoding", "br, gzip, deflate;q=0.1")], b"{}").await;
+        let res = run_chain(&req, "127.0.0.1", &[&compression], echo).await;
+        assert_eq!(res.headers.get("Content-Encoding"), Some("deflate"));
+        let mut out = String::new();
+        ZlibDecoder::new(res.body.as_bytes().unwrap())
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "{}");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(users().as_bytes()).unwrap();
+        let req = request(&[("Content-Encoding", "gzip")], &encoder.finish().unwrap()).await;
+        let res = run_chain(&req, "127.0.0.1", &[&compression], echo).await;
+        assert_eq!(res.status, 413);
+    }
+}
@@ -0,0 +1,322 @@
+use std::time::Duration;
+
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::request::{Method, Request};
+use crate::types::Response;
+
+/// Lets browsers on other origins call the API. Preflight requests are answered here,
+/// before rate limiting and auth, since browsers send them without credentials.
+#[derive(Clone)]
+pub struct CorsMiddleware {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl CorsMiddleware {
+    /// Allows no origins until some are added; simple methods and `Content-Type` only.
+    pub fn new() -> Self {
+        CorsMiddleware {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: vec!["Content-Type".to_string()],
+            expose: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// `https://app.example.com` matches exactly, `https://*.example.com` any subdomain,
+    /// and `*` any origin.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Response headers that scripts may read besides the CORS-safelisted ones.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Lets the browser send cookies and `Authorization`. The allowed origin is then
+    /// always echoed back, since browsers refuse `*` on credentialed requests.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|allowed| match allowed.split_once('*') {
+                None => allowed.eq_ignore_ascii_case(origin),
+                Some(("", "")) => true,
+                Some((prefix, suffix)) => {
+                    let end = origin.len().saturating_sub(suffix.len());
+                    // `get` rather than indexing: the header may not split on char boundaries.
+                    match (
+                        origin.get(..prefix.len()),
+                        origin.get(prefix.len()..end),
+                        origin.get(end..),
+                    ) {
+                        (Some(p), Some(label), Some(s)) => {
+                            p.eq_ignore_ascii_case(prefix)
+                                && s.eq_ignore_ascii_case(suffix)
+                                && !label.is_empty()
+                                && !label.contains(['/', ':'])
+                        }
+                        _ => false,
+                    }
+                }
+            })
+    }
+
+    // Echo the origin unless every origin is allowed and no credentials are involved;
+    // a literal `*` lets shared caches serve one answer to everyone.
+    fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        if !self.credentials && self.origins.iter().any(|o| o == "*") {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    fn preflight(&self, req: &Request, origin: &str, method: &str) -> Response {
+        let mut res = Response::new(204);
+        res.headers.vary("Origin");
+        res.headers.vary("Access-Control-Request-Method");
+        res.headers.vary("Access-Control-Request-Headers");
+
+        let requested: Vec<&str> = req
+            .header("Access-Control-Request-Headers")
+            .map(|h| {
+                h.split(',')
+                    .map(str::trim)
+                    .filter(|h| !h.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let allowed = self.origin_allowed(origin)
+            && self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            && requested
+                .iter()
+                .all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)));
+        if !allowed {
+            res.status = 403;
+            return res;
+        }
+
+        res = res
+            .with_header(
+                "Access-Control-Allow-Origin",
+                self.allow_origin_value(origin),
+            )
+            .with_header("Access-Control-Allow-Methods", &self.methods.join(", "))
+            .with_header("Access-Control-Allow-Headers", &self.headers.join(", "));
+        if self.credentials {
+            res = res.with_header("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = self.max_age {
+            res = res.with_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        res
+    }
+
+    fn decorate(&self, mut res: Response, origin: &str) -> Response {
+        let value = self.allow_origin_value(origin);
+        if value != "*" {
+            res.headers.vary("Origin");
+        }
+        res = res.with_header("Access-Control-Allow-Origin", value);
+        if self.credentials {
+            res = res.with_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose.is_empty() {
+            res = res.with_header("Access-Control-Expose-Headers", &self.expose.join(", "));
+        }
+        res
+    }
+}
+
+impl Default for CorsMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn handle(
+        &self,
+        req: &Request,
+        _client_ip: &str,
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
+        // Same-origin and non-browser requests carry no Origin; nothing to do.
+        let Some(origin) = req.header("Origin").map(str::to_string) else {
+            return next(req);
+        };
+        if req.method == Method::Options {
+            if let Some(method) = req.header("Access-Control-Request-Method") {
+                let res = self.preflight(req, &origin, method);
+                return Box::pin(async move { res });
+            }
+        }
+
+        let fut = next(req);
+        if !self.origin_allowed(&origin) {
+            // The browser withholds the response from the script; the server still has
+            // to say the answer depends on the origin.
+            return Box::pin(async move {
+                let mut res = fut.await;
+                res.headers.vary("Origin");
+                res
+            });
+        }
+        let this = self.clone();
+        Box::pin(async move { this.decorate(fut.await, &origin) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::run_chain;
+    use crate::request::{Limits, RequestReader};
+
+    async fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("{method} /user HTTP/1.1\r\nHost: api.example.com\r\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn cors() -> CorsMiddleware {
+        CorsMiddleware::new()
+            .allow_origin("https://dashboard.example.com")
+            .allow_origin("https://*.preview.example.com")
+            .allow_methods(&["GET", "POST", "DELETE"])
+            .allow_headers(&["Content-Type", "Authorization"])
+            .expose_headers(&["RateLimit-Remaining"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600))
+    }
+
+    async fn run(cors: &CorsMiddleware, req: &Request) -> Response {
+        run_chain(req, "127.0.0.1", &[cors], |_: &Request| async {
+            Response::text(404, "Not Found")
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_without_reaching_the_router() {
+        let req = request(
+            "OPTIONS",
+            &[
+                ("Origin", "https://dashboard.example.com"),
+                ("Access-Control-Request-Method", "DELETE"),
+                (
+                    "Access-Control-Request-Headers",
+                    "authorization, content-type",
+                ),
+            ],
+        )
+        .await;
+        let res = run(&cors(), &req).await;
+        assert_eq!(res.status, 204);
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some("https://dashboard.example.com")
+        );
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Methods"),
+            Some("GET, POST, DELETE")
+        );
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(res.headers.get("Access-Control-Max-Age"), Some("600"));
+
+        let req = request(
+            "OPTIONS",
+            &[
+                ("Origin", "https://dashboard.example.com"),
+                ("Access-Control-Request-Method", "POST"),
+                ("Access-Control-Request-Headers", "X-Debug"),
+            ],
+        )
+        .await;
+        let res = run(&cors(), &req).await;
+        assert_eq!(res.status, 403);
+        assert!(!res.headers.contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn matches_exact_and_wildcard_origins() {
+        let cors = cors();
+        for (origin, allowed) in [
+            ("https://dashboard.example.com", true),
+            ("https://pr-12.preview.example.com", true),
+            ("https://a.b.preview.example.com", true),
+            ("https://preview.example.com", false),
+            ("http://dashboard.example.com", false),
+            ("https://evil.com/.preview.example.com", false),
+        ] {
+            assert_eq!(cors.origin_allowed(origin), allowed, "{origin}");
+        }
+    }
+
+    #[tokio::test]
+    async fn decorates_actual_responses_for_allowed_origins() {
+        let req = request("GET", &[("Origin", "https://pr-1.preview.example.com")]).await;
+        let res = run(&cors(), &req).await;
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some("https://pr-1.preview.example.com")
+        );
+        assert_eq!(
+            res.headers.get("Access-Control-Expose-Headers"),
+            Some("RateLimit-Remaining")
+        );
+        assert_eq!(res.headers.get("Vary"), Some("Origin"));
+
+        let req = request("GET", &[("Origin", "https://evil.com")]).await;
+        let res = run(&cors(), &req).await;
+        assert!(!res.headers.contains("Access-Control-Allow-Origin"));
+
+        // Any origin without credentials gets the cacheable `*`.
+        let open = CorsMiddleware::new().allow_origin("*");
+        let req = request("GET", &[("Origin", "https://anyone.dev")]).await;
+        let res = run(&open, &req).await;
+        assert_eq!(res.headers.get("Access-Control-Allow-Origin"), Some("*"));
+        assert!(!res.headers.contains("Vary"));
+    }
+}
@@ -1,90 +1,458 @@
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use prometheus::core::{Collector, Desc, Describer};
+use prometheus::proto::{self, MetricFamily, MetricType};
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, PullingGauge, Registry, TextEncoder,
+};
 use serde::Serialize;
 
-use crate::middleware::Middleware;
+use crate::middleware::{Middleware, ResponseFuture};
+use crate::request::Request;
+use crate::router::{Lookup, Router};
 use crate::types::Response;
 
+// Request metrics are prometheus vectors with bounded labels: one series per route template
+// and method, each with a counter per status. The children are looked up once and cached in
+// tables sized up front, so recording never takes the vectors' locks after a series' first
+// request.
+
+const METHODS: [&str; 8] = [
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "OTHER",
+];
+const STATUSES: [u16; 22] = [
+    200, 201, 202, 204, 301, 302, 304, 400, 401, 403, 404, 405, 408, 409, 413, 422, 429, 431, 500,
+    502, 503, 504,
+];
+// Codes not in STATUSES are counted per class ("1xx" .. "5xx") after the listed ones.
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+const STATUS_SLOTS: usize = STATUSES.len() + STATUS_CLASSES.len();
+/// Route label for requests that matched no template, so stray paths cannot blow up the
+/// number of series.
+const UNMATCHED: &str = "unmatched";
+
+/// A counter kept elsewhere, such as a cache's hit count, read on every scrape. The
+/// counter counterpart of prometheus' `PullingGauge`; give each one its own const labels
+/// to split a family across several of them.
+pub struct PullingCounter {
+    desc: Desc,
+    value: Box<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl PullingCounter {
+    pub fn new(
+        opts: Opts,
+        value: impl Fn() -> f64 + Send + Sync + 'static,
+    ) -> prometheus::Result<Self> {
+        Ok(PullingCounter {
+            desc: opts.describe()?,
+            value: Box::new(value),
+        })
+    }
+}
+
+impl Collector for PullingCounter {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut counter = proto::Counter::default();
+        counter.set_value((self.value)());
+        let mut metric = proto::Metric::from_label(self.desc.const_label_pairs.clone());
+        metric.set_counter(counter);
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::COUNTER);
+        family.set_metric(vec![metric]);
+        vec![family]
+    }
+}
+
+/// Registers a gauge that reads `value` on every scrape.
+pub fn register_gauge_fn(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    value: impl Fn() -> f64 + Send + Sync + 'static,
+) -> prometheus::Result<()> {
+    registry.register(Box::new(PullingGauge::new(name, help, Box::new(value))?))
+}
+
+/// Registers a counter that reads `value` on every scrape.
+pub fn register_counter_fn(
+    registry: &Registry,
+    opts: Opts,
+    value: impl Fn() -> f64 + Send + Sync + 'static,
+) -> prometheus::Result<()> {
+    registry.register(Box::new(PullingCounter::new(opts, value)?))
+}
+
+/// Registers resident memory, open descriptors and threads, read from `/proc`. Registers
+/// nothing on platforms without it.
+pub fn register_process_metrics(registry: &Registry) -> prometheus::Result<()> {
+    if std::fs::metadata("/proc/self/status").is_err() {
+        return Ok(());
+    }
+    fn status_field(name: &str) -> f64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.split_whitespace().next()?.parse().ok())
+            .unwrap_or(0.0)
+    }
+    register_gauge_fn(
+        registry,
+        "process_resident_memory_bytes",
+        "Resident memory size in bytes.",
+        || status_field("VmRSS:") * 1024.0,
+    )?;
+    register_gauge_fn(
+        registry,
+        "process_threads",
+        "OS threads in the process.",
+        || status_field("Threads:"),
+    )?;
+    register_gauge_fn(
+        registry,
+        "process_open_fds",
+        "Open file descriptors.",
+        || std::fs::read_dir("/proc/self/fd").map_or(0.0, |fds| fds.count() as f64),
+    )
+}
+
+// What the response futures write to; shared with them so they can outlive `handle`.
+struct Recorder {
+    // Unique route templates in registration order, then UNMATCHED.
+    routes: Vec<String>,
+    requests: IntCounterVec,
+    durations: HistogramVec,
+    // `requests` children, STATUS_SLOTS per series; `durations` children, one per series.
+    request_children: Vec<OnceLock<IntCounter>>,
+    duration_children: Vec<OnceLock<Histogram>>,
+}
+
+impl Recorder {
+    fn labels(&self, series: usize) -> (&str, &str) {
+        (
+            self.routes[series / METHODS.len()].as_str(),
+            METHODS[series % METHODS.len()],
+        )
+    }
+
+    fn record(&self, series: usize, status: u16, elapsed: Duration) {
+        let slot = STATUSES
+            .iter()
+            .position(|s| *s == status)
+            .unwrap_or_else(|| STATUSES.len() + (status as usize / 100).clamp(1, 5) - 1);
+        let (route, method) = self.labels(series);
+        self.request_children[series * STATUS_SLOTS + slot]
+            .get_or_init(|| {
+                let status = match STATUSES.get(slot) {
+                    Some(code) => code.to_string(),
+                    None => STATUS_CLASSES[slot - STATUSES.len()].to_string(),
+                };
+                self.requests
+                    .with_label_values(&[method, route, status.as_str()])
+            })
+            .inc();
+        self.duration_children[series]
+            .get_or_init(|| self.durations.with_label_values(&[method, route]))
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
 pub struct MetricsMiddleware {
-    total_requests: Arc<Mutex<usize>>,
-    total_response_time: Arc<Mutex<f64>>,
-    average_response_time: Arc<Mutex<Option<f64>>>,
+    templates: Router<usize>,
+    recorder: Arc<Recorder>,
+    in_flight: IntGauge,
+    registry: Registry,
 }
 
 #[derive(Serialize)]
 pub struct MetricsResponse {
-    total_requests: usize,
+    total_requests: u64,
     average_response_time: f64,
 }
+
 impl MetricsMiddleware {
-    pub fn new() -> Self {
+    /// Labels requests with the route templates registered in `routes`. `buckets` are
+    /// the latency histogram's upper bounds in seconds; they are sorted and deduplicated.
+    pub fn with_buckets<H>(routes: &Router<H>, buckets: &[f64]) -> Self {
+        let mut names: Vec<String> = Vec::new();
+        let mut templates = Router::new();
+        for (method, pattern) in routes.routes() {
+            let index = match names.iter().position(|n| n == pattern) {
+                Some(index) => index,
+                None => {
+                    names.push(pattern.to_string());
+                    names.len() - 1
+                }
+            };
+            templates = templates.route(method, pattern, index);
+        }
+        names.push(UNMATCHED.to_string());
+
+        let mut bounds: Vec<f64> = buckets.iter().copied().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+
+        // The names, labels and buckets are fixed above, so none of these can fail.
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "Requests answered, by method, route template and status.",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let durations = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from the request reaching the middleware to its response.",
+            )
+            .buckets(bounds),
+            &["method", "route"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new(
+            "http_requests_in_flight",
+            "Requests currently being handled.",
+        )
+        .unwrap();
+        let started = Gauge::new(
+            "process_start_time_seconds",
+            "Start time of the process since the Unix epoch, in seconds.",
+        )
+        .unwrap();
+        started.set(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(durations.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(started)).unwrap();
+
+        let series = names.len() * METHODS.len();
         MetricsMiddleware {
-            total_requests: Arc::new(Mutex::new(0)),
-            total_response_time: Arc::new(Mutex::new(0.0)),
-            average_response_time: Arc::new(Mutex::new(None)),
+            templates,
+            recorder: Arc::new(Recorder {
+                routes: names,
+                requests,
+                durations,
+                request_children: (0..series * STATUS_SLOTS)
+                    .map(|_| OnceLock::new())
+                    .collect(),
+                duration_children: (0..series).map(|_| OnceLock::new()).collect(),
+            }),
+            in_flight,
+            registry,
         }
     }
 
+    /// Where the rest of the server registers what it wants scraped alongside the request
+    /// metrics.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn series_for(&self, req: &Request) -> usize {
+        let unmatched = self.recorder.routes.len() - 1;
+        // OPTIONS and 405 answers still belong to the template whose path matched.
+        let route = match self.templates.lookup(req.method.as_str(), &req.path) {
+            Lookup::Found { handler, .. } => *handler,
+            Lookup::Options { .. } | Lookup::MethodNotAllowed { .. } => {
+                self.find_any(&req.path).unwrap_or(unmatched)
+            }
+            Lookup::NotFound => unmatched,
+        };
+        let method = METHODS
+            .iter()
+            .position(|m| *m == req.method.as_str())
+            .unwrap_or(METHODS.len() - 1);
+        route * METHODS.len() + method
+    }
+
+    // The first template matching `path` for any registered method.
+    fn find_any(&self, path: &str) -> Option<usize> {
+        self.templates
+            .routes()
+            .find_map(|(method, _)| match self.templates.lookup(method, path) {
+                Lookup::Found { handler, .. } => Some(*handler),
+                _ => None,
+            })
+    }
+
+    /// The old JSON summary, kept for `/api/metrics`.
     pub fn handle_metrics(&self) -> Response {
-        let total_requests = *self.total_requests.lock().unwrap();
-        let average_response_time = self.average_response_time.lock().unwrap().unwrap_or(0.0);
-        Response {
-            status: 200,
-            content_type: "application/json".into(),
-            body: serde_json::to_string(&MetricsResponse {
-                total_requests,
+        let (count, secs) = self
+            .recorder
+            .duration_children
+            .iter()
+            .filter_map(OnceLock::get)
+            .fold((0, 0.0), |(c, s), h| {
+                (c + h.get_sample_count(), s + h.get_sample_sum())
+            });
+        let average_response_time = if count == 0 {
+            0.0
+        } else {
+            secs * 1000.0 / count as f64
+        };
+        Response::json(
+            200,
+            &MetricsResponse {
+                total_requests: count,
                 average_response_time,
-            })
-            .unwrap_or_else(|_| "{}".to_string()),
+            },
+        )
+    }
+
+    /// Prometheus text exposition of everything in the registry.
+    pub fn handle_prometheus(&self) -> Response {
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut body) {
+            eprintln!("⚠️ Failed to encode metrics: {e}");
+            return Response::new(500);
         }
+        Response::bytes(200, encoder.format_type(), body)
     }
 }
+
+// Decrements the in-flight gauge even if the response future is dropped midway.
+struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
 impl Middleware for MetricsMiddleware {
     fn handle(
         &self,
-        req: &str,
-        client_ip: &str,
-        next: &dyn for<'a> Fn(&'a str) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>>,
-    ) -> Pin<Box<dyn Future<Output = Response> + Send + 'static>> {
-        let req = req.to_string();
-        let client_ip = client_ip.to_string();
-        let total_requests = Arc::clone(&self.total_requests);
-        let total_response_time = Arc::clone(&self.total_response_time);
-        let average_response_time = Arc::clone(&self.average_response_time);
-
-        let fut = next(&req);
+        req: &Request,
+        _client_ip: &str,
+        next: &dyn Fn(&Request) -> ResponseFuture,
+    ) -> ResponseFuture {
+        let series = self.series_for(req);
+        let start_time = Instant::now();
+        self.in_flight.inc();
+        let guard = InFlight(self.in_flight.clone());
+        let recorder = Arc::clone(&self.recorder);
+        let fut = next(req);
+
         Box::pin(async move {
-            let start_time = Instant::now();
-            let mut parts = req.split_whitespace();
             let response = fut.await;
-            let duration = start_time.elapsed();
-            let response_time_ms = duration.as_secs_f64() * 1000.0; // Convert to milliseconds
-
-            // if let (Some(method), Some(path)) = (parts.next(), parts.next()) {
-            //     println!(
-            //         "[Metric gatherer] {} {}, status: {}, response_time: {:.2}ms, from {} ",
-            //         method, path, response.status, response_time_ms, client_ip
-            //     )
-            // }
-
-            // Update metrics
-            {
-                let mut total = total_requests.lock().unwrap();
-                let mut total_time = total_response_time.lock().unwrap();
-                let mut avg_time = average_response_time.lock().unwrap();
-
-                *total += 1;
-                *total_time += response_time_ms;
-
-                // Calculate new average response time
-                let new_average = *total_time / (*total as f64);
-                *avg_time = Some(new_average);
-            }
-
+            recorder.record(series, response.status, start_time.elapsed());
+            drop(guard);
             response
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Limits, RequestReader};
+    use prometheus::DEFAULT_BUCKETS;
+
+    async fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{method} {path} HTTP/1.1\r\nHost: test\r\n\r\n");
+        let mut reader = RequestReader::new(Limits::default());
+        reader
+            .read_request(&mut raw.as_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn call(metrics: &MetricsMiddleware, method: &str, path: &str, status: u16) {
+        let next =
+            move |_: &Request| -> ResponseFuture { Box::pin(async move { Response::new(status) }) };
+        metrics
+            .handle(&request(method, path).await, "127.0.0.1", &next)
+            .await;
+    }
+
+    fn scrape(metrics: &MetricsMiddleware) -> String {
+        let res = metrics.handle_prometheus();
+        String::from_utf8(res.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_labelled_by_route_template() {
+        let routes = Router::new()
+            .route("GET", "/hello/:name", ())
+            .route("POST", "/user", ());
+        let metrics = MetricsMiddleware::with_buckets(&routes, &[1.0, 0.1]);
+
+        call(&metrics, "GET", "/hello/ann", 200).await;
+        call(&metrics, "GET", "/hello/bob", 200).await;
+        call(&metrics, "POST", "/user", 401).await;
+        call(&metrics, "DELETE", "/user", 405).await;
+        call(&metrics, "GET", "/nope/1", 404).await;
+        call(&metrics, "GET", "/hello/x", 418).await;
+
+        let body = scrape(&metrics);
+        for line in [
+            r#"http_requests_total{method="GET",route="/hello/:name",status="200"} 2"#,
+            r#"http_requests_total{method="POST",route="/user",status="401"} 1"#,
+            r#"http_requests_total{method="DELETE",route="/user",status="405"} 1"#,
+            r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+            r#"http_requests_total{method="GET",route="/hello/:name",status="4xx"} 1"#,
+            r#"http_request_duration_seconds_bucket{method="GET",route="/hello/:name",le="0.1"} 3"#,
+            r#"http_request_duration_seconds_bucket{method="GET",route="/hello/:name",le="+Inf"} 3"#,
+            r#"http_request_duration_seconds_count{method="GET",route="/hello/:name"} 3"#,
+            "http_requests_in_flight 0",
+        ] {
+            assert!(body.contains(line), "missing {line} in\n{body}");
+        }
+        assert!(body.contains("# TYPE http_request_duration_seconds histogram"));
+    }
+
+    #[tokio::test]
+    async fn in_flight_counts_unfinished_requests() {
+        let routes = Router::new().route("GET", "/slow", ());
+        let metrics = MetricsMiddleware::with_buckets(&routes, DEFAULT_BUCKETS);
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let rx = std::sync::Mutex::new(Some(rx));
+        let next = |_: &Request| -> ResponseFuture {
+            let rx = rx.lock().unwrap().take().unwrap();
+            Box::pin(async move {
+                let _ = rx.await;
+                Response::not_found()
+            })
+        };
+
+        let pending = metrics.handle(&request("GET", "/slow").await, "127.0.0.1", &next);
+        assert!(scrape(&metrics).contains("http_requests_in_flight 1"));
+        tx.send(()).unwrap();
+        assert_eq!(pending.await.status, 404);
+        assert!(scrape(&metrics).contains("http_requests_in_flight 0"));
+    }
+
+    #[test]
+    fn pulled_counters_with_const_labels_share_a_family() {
+        let metrics = MetricsMiddleware::with_buckets(&Router::<()>::new(), DEFAULT_BUCKETS);
+        for (result, value) in [("success", 3.0), ("failure", 1.0)] {
+            let opts = Opts::new("reloads_total", "Reloads.").const_label("result", result);
+            register_counter_fn(metrics.registry(), opts, move || value).unwrap();
+        }
+
+        let body = scrape(&metrics);
+        assert_eq!(body.matches("# TYPE reloads_total counter").count(), 1);
+        assert!(body.contains("reloads_total{result=\"success\"} 3\n"));
+        assert!(body.contains("reloads_total{result=\"failure\"} 1\n"));
+    }
+}
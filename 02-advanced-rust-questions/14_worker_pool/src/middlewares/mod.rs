@@ -1,3 +1,7 @@
+pub mod auth;
+pub mod compression;
+pub mod cors;
 pub mod logger;
 pub mod metrics;
 pub mod rate_limiting;
+pub mod security_headers;
@@ -1,58 +1,284 @@
-use sled;
-use std::future::Future;
-use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use arc_swap::ArcSwap;
 use tokio::net::TcpListener;
 use tokio::signal;
-use tokio::sync::Notify;
+use tokio::sync::{mpsc, Notify};
 use tokio::time::{self, Duration};
 
 use mongodb::{Client, Database};
+use prometheus::{Opts, Registry};
+use serde::{Deserialize, Serialize};
 
-use crate::cache::LRUCache;
+use crate::auth::{self, Accounts, AuthService, Keyring};
+use crate::cache::{self, LRUCache};
+use crate::config::{CacheConfig, ServerConfig};
 use crate::handlers;
 use crate::handlers::pubsub::PubSubHandler;
 use crate::handlers::user::UserResponse;
-use crate::middleware::{self, Middleware};
+use crate::middleware::{self, ResponseFuture};
+use crate::middlewares::auth::{AuthMiddleware, Policy};
+use crate::middlewares::compression::CompressionMiddleware;
+use crate::middlewares::cors::CorsMiddleware;
 use crate::middlewares::logger::LoggerMiddleware;
-use crate::middlewares::metrics::{self, MetricsMiddleware};
-use crate::middlewares::rate_limiting::TokenBucketMiddleware;
+use crate::middlewares::metrics::{
+    register_counter_fn, register_gauge_fn, register_process_metrics, MetricsMiddleware,
+};
+use crate::middlewares::rate_limiting::{Backing, KeyBy, RateLimitMiddleware, Rule};
+use crate::middlewares::security_headers::SecurityHeadersMiddleware;
 use crate::pubsub::PubSubManager;
+use crate::reload::{evict_idle_buckets, Chain, ReloadCounts, Reloader};
+use crate::request::{Limits, Method, Request, RequestReader};
+use crate::router::{Lookup, Router};
 use crate::types::Response;
 use crate::workers::pool::WorkerPool; // 👈 NEW: import WorkerPool
-pub type UserCache = sled::Db;
 
-pub async fn run() -> anyhow::Result<()> {
+/// Users cached in memory (with TTLs) in front of the on-disk sled store.
+#[derive(Clone)]
+pub struct UserCache {
+    memory: Arc<Mutex<LRUCache<String, UserResponse>>>,
+    disk: sled::Db,
+    ttl: Duration,
+    /// Off, users are neither looked up in nor written to either tier. sled stays open,
+    /// as other state lives there too.
+    enabled: bool,
+}
+
+// A user as kept in sled. The expiry is wall-clock time, since sled outlives the process.
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    user: UserResponse,
+    expires_at_ms: u64,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+impl UserCache {
+    pub fn open(config: &CacheConfig) -> Self {
+        Self::new(config, sled::open(&config.path).expect("open sled db"))
+    }
+
+    pub fn new(config: &CacheConfig, disk: sled::Db) -> Self {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        let memory = Arc::new(Mutex::new(
+            LRUCache::new(config.effective_capacity()).with_default_ttl(ttl),
+        ));
+        cache::spawn_expiry(&memory, Duration::from_secs(config.sweep_secs));
+        Self {
+            memory,
+            disk,
+            ttl,
+            enabled: config.enabled,
+        }
+    }
+
+    /// The user cached under `email`, from memory or else from sled. An entry past its TTL
+    /// counts as a miss in either tier.
+    pub fn get(&self, email: &str) -> Option<UserResponse> {
+        if !self.enabled {
+            return None;
+        }
+        let key = email.to_string();
+        let memory_hit = self
+            .memory
+            .lock()
+            .ok()
+            .and_then(|mut memory| memory.get(&key));
+        if memory_hit.is_some() {
+            println!("cache hit!");
+            return memory_hit;
+        }
+
+        let value = self.disk.get(email).ok()??;
+        let stored: StoredUser = serde_json::from_slice(&value).ok()?;
+        let left = Duration::from_millis(stored.expires_at_ms.saturating_sub(unix_millis()));
+        if left.is_zero() {
+            let _ = self.disk.remove(email);
+            return None;
+        }
+        println!("SLED hit!");
+        // Only for the rest of its TTL, so memory never serves what sled has expired.
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.put_with_ttl(key, stored.user.clone(), left);
+        }
+        Some(stored.user)
+    }
+
+    /// Caches `user` under `email` in both tiers for the configured TTL.
+    pub fn put(&self, email: &str, user: &UserResponse) {
+        if !self.enabled {
+            return;
+        }
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.put(email.to_string(), user.clone());
+        }
+        let stored = StoredUser {
+            user: user.clone(),
+            expires_at_ms: unix_millis().saturating_add(self.ttl.as_millis() as u64),
+        };
+        let _ = self
+            .disk
+            .insert(email.as_bytes(), serde_json::to_vec(&stored).unwrap());
+        let _ = self.disk.flush();
+    }
+}
+
+impl UserCache {
+    /// Registers the memory tier's size and hit rates and the sled tier's size.
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        let stat = |read: fn(&LRUCache<String, UserResponse>) -> f64| {
+            let memory = self.memory.clone();
+            move || read(&memory.lock().unwrap())
+        };
+        register_gauge_fn(
+            registry,
+            "user_cache_entries",
+            "Users held in the memory tier.",
+            stat(|m| m.len() as f64),
+        )?;
+        register_gauge_fn(
+            registry,
+            "user_cache_capacity",
+            "Capacity of the memory tier.",
+            stat(|m| m.capacity() as f64),
+        )?;
+        register_counter_fn(
+            registry,
+            Opts::new("user_cache_hits_total", "Memory tier hits."),
+            stat(|m| m.stats().hits as f64),
+        )?;
+        register_counter_fn(
+            registry,
+            Opts::new("user_cache_misses_total", "Memory tier misses."),
+            stat(|m| m.stats().misses as f64),
+        )?;
+        register_counter_fn(
+            registry,
+            Opts::new(
+                "user_cache_evictions_total",
+                "Entries evicted to stay within capacity.",
+            ),
+            stat(|m| m.stats().evictions as f64),
+        )?;
+        register_counter_fn(
+            registry,
+            Opts::new(
+                "user_cache_expirations_total",
+                "Entries dropped when their TTL ran out.",
+            ),
+            stat(|m| m.stats().expirations as f64),
+        )?;
+        let disk = self.disk.clone();
+        register_gauge_fn(
+            registry,
+            "user_cache_disk_entries",
+            "Users stored in the sled tier.",
+            move || disk.len() as f64,
+        )
+    }
+}
+
+/// Registers how many reloads were applied or rejected and when the last one succeeded.
+fn register_reload_metrics(
+    reloader: &Arc<Reloader>,
+    registry: &Registry,
+) -> prometheus::Result<()> {
+    for (result, read) in [
+        ("success", (|c| c.succeeded) as fn(ReloadCounts) -> u64),
+        ("failure", |c| c.failed),
+    ] {
+        let reloader = reloader.clone();
+        register_counter_fn(
+            registry,
+            Opts::new(
+                "config_reloads_total",
+                "Config reloads, by whether the new settings were applied.",
+            )
+            .const_label("result", result),
+            move || read(reloader.counts()) as f64,
+        )?;
+    }
+    let reloader = reloader.clone();
+    register_gauge_fn(
+        registry,
+        "config_last_reload_success_timestamp_seconds",
+        "When the settings were last reloaded, or 0 if they never were.",
+        move || reloader.counts().last_success as f64,
+    )
+}
+
+/// How long an idle keep-alive connection may wait for the first byte of its next request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Once a request has started arriving, how long its head and then its body may take.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub async fn run(reloader: Reloader) -> anyhow::Result<()> {
     // --- Setup phase ---
-    let metrics = Arc::new(MetricsMiddleware::new());
-    dotenvy::dotenv().ok();
+    let reloader = Arc::new(reloader);
+    // Read once: the settings below apply until a restart. The chain is rebuilt on reload.
+    let config = reloader.config();
+    let metrics = Arc::new(MetricsMiddleware::with_buckets(
+        &ROUTES,
+        &config.metrics.buckets,
+    ));
     let mongodb_uri =
         std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
     let mongodb_db = std::env::var("MONGODB_DB").unwrap_or_else(|_| "my_app".to_string());
     let client = Client::with_uri_str(&mongodb_uri).await?;
     let db = Arc::new(client.database(&mongodb_db));
-    let cache = sled::open("user_cache").expect("open sled db");
+    let cache = UserCache::open(&config.cache);
+    let auth = Arc::new(
+        AuthService::new(Keyring::from_env()?, &cache.disk)?.with_accounts(Accounts::from_env()?),
+    );
+    auth::spawn_purge(&auth, Duration::from_secs(3600));
     let pubsub_manager = Arc::new(tokio::sync::Mutex::new(PubSubManager::new()));
-    let pubsub_handler = PubSubHandler::new(pubsub_manager.clone());
+    let pubsub_handler = config
+        .pubsub
+        .enabled
+        .then(|| PubSubHandler::new(pubsub_manager.clone()));
 
     // 👇 Initialize Worker Pool
-    let worker_pool = Arc::new(WorkerPool::new(4)); // 4 async workers
+    let worker_pool = Arc::new(WorkerPool::new(config.workers));
 
-    let listener = TcpListener::bind("0.0.0.0:7878").await?;
-    println!("🚀 Listening on port 7878");
+    register_process_metrics(metrics.registry())?;
+    worker_pool.register_metrics(metrics.registry())?;
+    cache.register_metrics(metrics.registry())?;
+    register_reload_metrics(&reloader, metrics.registry())?;
 
-    let middlewares: Vec<Arc<dyn Middleware>> = vec![
-        Arc::new(LoggerMiddleware),
-        Arc::new(TokenBucketMiddleware::new(5, 1)),
-        metrics.clone(),
-    ];
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!("🚀 Listening on {}", config.listen);
+
+    let chain = Arc::new(ArcSwap::from_pointee(build_chain(
+        &config, None, &metrics, &auth,
+    )));
+    evict_idle_buckets(chain.clone());
+    {
+        let chain = chain.clone();
+        let metrics = metrics.clone();
+        let auth = auth.clone();
+        reloader.watch(move |_, config| {
+            let previous = chain.load();
+            chain.store(Arc::new(build_chain(
+                config,
+                Some(&previous),
+                &metrics,
+                &auth,
+            )));
+        });
+    }
 
     // --- Shared shutdown signal ---
     let is_shutting_down = Arc::new(AtomicBool::new(false));
     let notify = Arc::new(Notify::new());
+    // Every connection task holds a sender; recv() returns None once all have finished.
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
 
     // --- Setup signal listener ---
     {
@@ -68,18 +294,13 @@ pub async fn run() -> anyhow::Result<()> {
     }
 
     // --- Start Cron Scheduler ---
-    {
+    if config.cron.enabled {
         let cron_flag = is_shutting_down.clone();
         let pool_clone = worker_pool.clone();
+        let interval_secs = config.cron.interval_secs;
+        let message = config.cron.message.clone();
 
         tokio::spawn(async move {
-            let interval_secs: u64 = std::env::var("CRON_INTERVAL_SECS")
-                .unwrap_or_else(|_| "15".into())
-                .parse()
-                .unwrap_or(15);
-            let message = std::env::var("CRON_MESSAGE")
-                .unwrap_or_else(|_| "⏰ Default cron task running...".into());
-
             let mut ticker = tokio::time::interval(Duration::from_secs(interval_secs));
             println!(
                 "🕓 Cron started: every {}s | message='{}'",
@@ -116,47 +337,84 @@ pub async fn run() -> anyhow::Result<()> {
                     break;
                 }
 
-                let middlewares = middlewares.clone();
+                // Kept for the whole connection, whatever is reloaded meanwhile.
+                let chain = chain.load_full();
                 let db = db.clone();
                 let cache_clone = cache.clone();
                 let metrics = metrics.clone();
+                let auth = auth.clone();
                 let pool_clone = worker_pool.clone();
-                let pubsub_manager = pubsub_manager.clone();
                 let pubsub_handler = pubsub_handler.clone();
+                let shutdown_notify = notify.clone();
+                let shutdown_flag = is_shutting_down.clone();
+                let drain_tx = drain_tx.clone();
 
                 tokio::spawn(async move {
-                    let mut buf = [0; 1024];
-                    if let Ok(n) = socket.read(&mut buf).await {
-                        if n == 0 {
-                            return;
+                    let _drain_guard = drain_tx;
+                    let client_ip = addr.ip().to_string();
+                    let middleware_ref = chain.middlewares();
+                    let mut reader = RequestReader::new(Limits {
+                        header_timeout: Some(HEADER_READ_TIMEOUT),
+                        body_timeout: Some(BODY_READ_TIMEOUT),
+                        ..Limits::default()
+                    });
+
+                    // Serve requests until the client closes, asks to close, goes idle,
+                    // or the server starts draining. Pipelined requests are answered in order.
+                    loop {
+                        // Registered before the flag check so a shutdown in between is not missed.
+                        let shutdown = shutdown_notify.notified();
+                        if shutdown_flag.load(Ordering::SeqCst) {
+                            break;
                         }
 
-                        let req = String::from_utf8_lossy(&buf[..n]);
-                        let middleware_ref: Vec<&dyn Middleware> =
-                            middlewares.iter().map(|m| m.as_ref()).collect();
+                        let waiting = tokio::select! {
+                            res = time::timeout(KEEP_ALIVE_TIMEOUT, reader.wait_for_request(&mut socket)) => Some(res),
+                            _ = shutdown => None,
+                        };
+                        // Peer closed, idle timeout, or shutdown while waiting.
+                        if !matches!(waiting, Some(Ok(Ok(true)))) {
+                            break;
+                        }
+                        // A request that stalls part-way is answered with a 408.
+                        let req = match reader.read_request(&mut socket).await {
+                            Ok(Some(req)) => req,
+                            Ok(None) => break,
+                            Err(err) => {
+                                let res = err.into_response().with_header("Connection", "close");
+                                let _ = res.write_to(&mut socket).await;
+                                break;
+                            }
+                        };
 
                         let cache_for_handler = cache_clone.clone();
-
-                        let pubsub_manager = pubsub_manager.clone();
+                        let db = db.clone();
+                        let metrics = metrics.clone();
+                        let auth = auth.clone();
+                        let pool = pool_clone.clone();
                         let pubsub_handler = pubsub_handler.clone();
-                        let handler: Box<
-                            dyn Fn(&str) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync,
-                        > = Box::new(move |req: &str| {
-                            let req_owned = req.to_string();
-                            let db = db.clone();
-                            let cache = cache_for_handler.clone();
-                            let metrics = metrics.clone();
-                            let pool = pool_clone.clone();
-
-                            let pubsub_manager = pubsub_manager.clone();
-                            let pubsub_handler = pubsub_handler.clone();
-                            Box::pin(async move {
-                                route_request(&req_owned, &db, &cache, metrics, pool, &pubsub_handler).await
-                            })
-                        });
-
-                        let res = middleware::run_chain(&req, &(addr.ip().to_string()), &middleware_ref, handler).await;
-                        let _ = socket.write_all(res.into_http().as_bytes()).await;
+                        let handler: Box<dyn Fn(&Request) -> ResponseFuture + Send + Sync> =
+                            Box::new(move |req: &Request| {
+                                let req_owned = req.clone();
+                                let db = db.clone();
+                                let cache = cache_for_handler.clone();
+                                let metrics = metrics.clone();
+                                let auth = auth.clone();
+                                let pool = pool.clone();
+                                let pubsub_handler = pubsub_handler.clone();
+                                Box::pin(async move {
+                                    route_request(&req_owned, &db, &cache, metrics, auth, pool, pubsub_handler.as_ref()).await
+                                })
+                            });
+
+                        let res = middleware::run_chain(&req, &client_ip, &middleware_ref, handler).await;
+                        let keep_alive = req.keep_alive() && !shutdown_flag.load(Ordering::SeqCst);
+                        let connection = if keep_alive { "keep-alive" } else { "close" };
+                        let res = res.with_header("Connection", connection);
+                        let written = res.write_to(&mut socket).await;
+                        if written.is_err() || !keep_alive {
+                            break;
+                        }
                     }
                 });
             }
@@ -169,61 +427,217 @@ pub async fn run() -> anyhow::Result<()> {
         }
     }
 
+    // --- Drain ---
+    drop(listener);
+    drop(drain_tx);
+    println!("⏳ Waiting for open connections to drain...");
+    let _ = drain_rx.recv().await;
+
     // --- Cleanup ---
     println!("✅ Graceful shutdown complete. Closing resources...");
-    drop(listener);
     drop(worker_pool);
-    cache.flush().ok();
+    cache.disk.flush().ok();
     Ok(())
 }
 
+/// Builds the middleware chain `config` asks for. The rate limiters take over the buckets
+/// of those in `previous`, so a reload does not reset anyone's quota.
+fn build_chain(
+    config: &ServerConfig,
+    previous: Option<&Chain>,
+    metrics: &Arc<MetricsMiddleware>,
+    auth: &Arc<AuthService>,
+) -> Chain {
+    let methods: Vec<&str> = config.cors.methods.iter().map(String::as_str).collect();
+    let cors = config
+        .cors
+        .origins
+        .iter()
+        .fold(CorsMiddleware::new(), |cors, origin| {
+            cors.allow_origin(origin)
+        })
+        .allow_methods(&methods)
+        .allow_headers(&["Content-Type", "Authorization"])
+        .expose_headers(&[
+            "RateLimit-Limit",
+            "RateLimit-Remaining",
+            "RateLimit-Reset",
+            "Retry-After",
+        ])
+        .allow_credentials(config.cors.credentials)
+        .max_age(Duration::from_secs(config.cors.max_age_secs));
+
+    // Metrics go first so that requests turned away by later middlewares are counted too.
+    let limits = &config.rate_limit;
+    let rate_limits = Backing::from_store(
+        limits.store.as_deref(),
+        limits.instances,
+        Duration::from_millis(limits.store_timeout_ms),
+    );
+    let enabled = &config.middlewares;
+    let mut chain = Chain::new();
+    if enabled.metrics {
+        chain.push(metrics.clone());
+    }
+    if enabled.logger {
+        chain.push(Arc::new(
+            LoggerMiddleware::new(&ROUTES).format(config.log_format),
+        ));
+    }
+    if enabled.compression {
+        let compression = &config.compression;
+        chain.push(Arc::new(
+            CompressionMiddleware::new()
+                .encodings(&compression.encodings)
+                .min_size(compression.min_size)
+                .max_request_body(compression.max_request_body),
+        ));
+    }
+    if enabled.security_headers {
+        chain.push(Arc::new(SecurityHeadersMiddleware::new()));
+    }
+    // Ahead of rate limiting and auth: preflights carry no credentials.
+    if enabled.cors {
+        chain.push(Arc::new(cors));
+    }
+    if enabled.rate_limit {
+        chain.push_rate_limit(
+            "ip",
+            RateLimitMiddleware::with_backing(
+                "ip",
+                Rule::new(limits.ip.algorithm(), KeyBy::Ip),
+                rate_limits.clone(),
+            )
+            // Slow down credential guessing harder than ordinary traffic.
+            .rule(
+                "POST",
+                "/auth/token",
+                Rule::new(limits.login.algorithm(), KeyBy::Ip),
+            )
+            .exempt("GET", "/health"),
+            previous,
+        );
+    }
+    if enabled.auth {
+        chain.push(Arc::new(
+            AuthMiddleware::new(auth.clone(), Policy::Public)
+                .policy("GET", "/me", Policy::Authenticated)
+                .policy("GET", "/user", Policy::Scope("users:read"))
+                .policy("POST", "/user", Policy::Role("admin")),
+        ));
+    }
+    // Runs after auth so that signed-in callers are limited per account, wherever
+    // they connect from.
+    if enabled.rate_limit {
+        let mut limit = RateLimitMiddleware::with_backing(
+            "principal",
+            Rule::new(limits.principal.algorithm(), KeyBy::Principal),
+            rate_limits.clone(),
+        )
+        .exempt("GET", "/health");
+        for route in &limits.routes {
+            limit = limit.rule(&route.method, &route.path, route.rule());
+        }
+        chain.push_rate_limit("principal", limit, previous);
+    }
+    chain
+}
+
 // --- Route Request Dispatcher ---
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    Health,
+    Hello,
+    CreateUser,
+    GetUser,
+    Me,
+    CreateToken,
+    RefreshToken,
+    VerifyToken,
+    RevokeToken,
+    Jwks,
+    Metrics,
+    Prometheus,
+    Publish,
+    Subscribe,
+    SubmitJob,
+}
+
+static ROUTES: LazyLock<Router<Route>> = LazyLock::new(|| {
+    Router::new()
+        .route("GET", "/health", Route::Health)
+        .route("GET", "/hello/:name", Route::Hello)
+        .route("POST", "/user", Route::CreateUser)
+        .route("GET", "/user", Route::GetUser)
+        .route("GET", "/me", Route::Me)
+        .route("POST", "/auth/token", Route::CreateToken)
+        .route("POST", "/auth/refresh", Route::RefreshToken)
+        .route("POST", "/auth/verify", Route::VerifyToken)
+        .route("POST", "/auth/revoke", Route::RevokeToken)
+        .route("GET", "/.well-known/jwks.json", Route::Jwks)
+        .route("GET", "/api/metrics", Route::Metrics)
+        .route("GET", "/metrics", Route::Prometheus)
+        .route("POST", "/publish", Route::Publish)
+        .route("POST", "/subscribe", Route::Subscribe)
+        .route("POST", "/jobs", Route::SubmitJob)
+});
+
+#[allow(clippy::too_many_arguments)]
 pub async fn route_request(
-    req: &str,
+    req: &Request,
     db: &Database,
-    cache: &sled::Db,
+    cache: &UserCache,
     metrics: Arc<MetricsMiddleware>,
+    auth: Arc<AuthService>,
     worker_pool: Arc<WorkerPool>,
-    pubsub_handler: &PubSubHandler,
+    pubsub_handler: Option<&PubSubHandler>,
 ) -> Response {
-    if req.starts_with("GET /health") {
-        handlers::health::handle().await
-    } else if req.starts_with("GET /hello/") {
-        handlers::hello::handle(req).await
-    } else if req.starts_with("POST /user") {
-        handlers::user::handle(req, db, cache).await
-    } else if req.starts_with("GET /user") {
-        handlers::user::get(req, db, cache).await
-    } else if req.starts_with("POST /auth/token") {
-        handlers::auth::create_token(req).await
-    } else if req.starts_with("POST /auth/verify") {
-        handlers::auth::verify_token(req).await
-    } else if req.starts_with("GET /api/metrics") {
-        metrics.handle_metrics()
-    } else if req.starts_with("POST /publish") {
-        return pubsub_handler.publish(req).await;
-    } else if req.starts_with("POST /subscribe") {
-        return pubsub_handler.subscribe(req).await;
-    } else if req.starts_with("POST /jobs") {
-        // Example: submit background job from HTTP route
-        worker_pool
-            .submit(async move {
-                println!("🧠 Processing background job...");
-                tokio::time::sleep(Duration::from_secs(3)).await;
-                println!("✅ Job done");
-            })
-            .await;
-
-        Response {
-            status: 200,
-            content_type: "text/plain".into(),
-            body: "Background job submitted!".into(),
+    let (route, params) = match ROUTES.lookup(req.method.as_str(), &req.path) {
+        Lookup::Found { handler, params } => (*handler, params),
+        Lookup::Options { allow } => return Response::options(&allow),
+        Lookup::MethodNotAllowed { allow } => return Response::method_not_allowed(&allow),
+        Lookup::NotFound => return Response::not_found(),
+    };
+
+    let res = match route {
+        Route::Health => handlers::health::handle().await,
+        Route::Hello => handlers::hello::handle(req, &params).await,
+        Route::CreateUser => handlers::user::handle(req, db, cache).await,
+        Route::GetUser => handlers::user::get(req, db, cache).await,
+        Route::Me => handlers::auth::me(req).await,
+        Route::CreateToken => handlers::auth::create_token(req, &auth).await,
+        Route::RefreshToken => handlers::auth::refresh_token(req, &auth).await,
+        Route::VerifyToken => handlers::auth::verify_token(req, &auth).await,
+        Route::RevokeToken => handlers::auth::revoke_token(req, &auth).await,
+        Route::Jwks => handlers::auth::jwks(&auth).await,
+        Route::Metrics => metrics.handle_metrics(),
+        Route::Prometheus => metrics.handle_prometheus(),
+        // With pub/sub turned off the routes answer as if they did not exist.
+        Route::Publish => match pubsub_handler {
+            Some(pubsub) => pubsub.publish(req).await,
+            None => Response::not_found(),
+        },
+        Route::Subscribe => match pubsub_handler {
+            Some(pubsub) => pubsub.subscribe(req).await,
+            None => Response::not_found(),
+        },
+        Route::SubmitJob => {
+            // Example: submit background job from HTTP route
+            worker_pool
+                .submit(async move {
+                    println!("🧠 Processing background job...");
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                    println!("✅ Job done");
+                })
+                .await;
+
+            Response::text(200, "Background job submitted!")
         }
+    };
+
+    if req.method == Method::Head {
+        res.without_body()
     } else {
-        Response {
-            status: 404,
-            content_type: "text/plain".into(),
-            body: "Not Found".into(),
-        }
+        res
     }
 }
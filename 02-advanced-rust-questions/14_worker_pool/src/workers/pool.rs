@@ -1,22 +1,37 @@
+use prometheus::{Opts, Registry};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
+use crate::middlewares::metrics::{register_counter_fn, register_gauge_fn};
+
 pub type Job = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
 
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+}
+
 #[derive(Clone)]
 pub struct WorkerPool {
     sender: Sender<Job>,
+    size: usize,
+    counters: Arc<Counters>,
 }
 
 impl WorkerPool {
     pub fn new(size: usize) -> Self {
         let (tx, rx): (Sender<Job>, Receiver<Job>) = mpsc::channel(100);
         let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        let counters = Arc::new(Counters::default());
 
         for i in 0..size {
             let rx = rx.clone();
+            let counters = counters.clone();
             tokio::spawn(async move {
                 println!("🧵 Worker {} started", i);
                 loop {
@@ -25,7 +40,13 @@ impl WorkerPool {
                         guard.recv().await
                     };
                     match job_opt {
-                        Some(job) => job.await,
+                        Some(job) => {
+                            counters.queued.fetch_sub(1, Ordering::Relaxed);
+                            counters.busy.fetch_add(1, Ordering::Relaxed);
+                            job.await;
+                            counters.busy.fetch_sub(1, Ordering::Relaxed);
+                            counters.completed.fetch_add(1, Ordering::Relaxed);
+                        }
                         None => {
                             println!("💤 Worker {} shutting down", i);
                             break;
@@ -34,15 +55,56 @@ impl WorkerPool {
                 }
             });
         }
-        Self { sender: tx }
+        Self {
+            sender: tx,
+            size,
+            counters,
+        }
     }
 
     pub async fn submit<F>(&self, fut: F)
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        if let Err(_) = self.sender.send(Box::pin(fut)).await {
+        // Counted before sending so a worker never sees the job before it is queued.
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(Box::pin(fut)).await.is_err() {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
             eprintln!("⚠️ Worker pool queue closed");
         }
     }
+
+    /// Registers the pool's size, load and completed jobs for scraping.
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        let size = self.size as f64;
+        register_gauge_fn(
+            registry,
+            "worker_pool_workers",
+            "Workers in the pool.",
+            move || size,
+        )?;
+        let c = self.counters.clone();
+        register_gauge_fn(
+            registry,
+            "worker_pool_busy_workers",
+            "Workers currently running a job.",
+            move || c.busy.load(Ordering::Relaxed) as f64,
+        )?;
+        let c = self.counters.clone();
+        register_gauge_fn(
+            registry,
+            "worker_pool_queued_jobs",
+            "Jobs submitted but not yet picked up.",
+            move || c.queued.load(Ordering::Relaxed) as f64,
+        )?;
+        let c = self.counters.clone();
+        register_counter_fn(
+            registry,
+            Opts::new(
+                "worker_pool_jobs_completed_total",
+                "Jobs run to completion.",
+            ),
+            move || c.completed.load(Ordering::Relaxed) as f64,
+        )
+    }
 }
@@ -0,0 +1,199 @@
+use std::hash::Hash;
+
+use tokio::sync::{mpsc, oneshot};
+
+use super::ring::{HashRing, DEFAULT_VNODES, RING_SEED};
+use super::LRUCache;
+
+// Actor-per-shard cache: every shard is a tokio task that owns its LRUCache outright and
+// serves commands from a bounded mailbox, so there are no locks on the data path. A full
+// mailbox makes callers wait, which is the backpressure.
+
+enum Command<K, V> {
+    Get {
+        keys: Vec<K>,
+        reply: oneshot::Sender<Vec<Option<V>>>,
+    },
+    Put {
+        entries: Vec<(K, V)>,
+    },
+    Clear {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+#[derive(Debug)]
+pub struct ActorShardedCache<K, V>
+where
+    K: Eq + Hash + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+    ring: HashRing,
+    mailboxes: Vec<mpsc::Sender<Command<K, V>>>,
+}
+
+impl<K, V> ActorShardedCache<K, V>
+where
+    K: Eq + Hash + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Spawns one task per shard. `mailbox` bounds how many commands can queue per shard.
+    pub fn new(num_shards: usize, shard_capacity: usize, mailbox: usize) -> Self {
+        let num_shards = num_shards.max(1);
+        let mut ring = HashRing::new(DEFAULT_VNODES, RING_SEED);
+        let mut mailboxes = Vec::with_capacity(num_shards);
+        for id in 0..num_shards {
+            ring.add(id);
+            let (tx, rx) = mpsc::channel(mailbox.max(1));
+            tokio::spawn(run_shard(LRUCache::new(shard_capacity), rx));
+            mailboxes.push(tx);
+        }
+        Self { ring, mailboxes }
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.get_many(vec![key.clone()]).await.pop().flatten()
+    }
+
+    pub async fn put(&self, key: K, value: V) {
+        self.put_many(vec![(key, value)]).await;
+    }
+
+    /// Looks up several keys with one message per shard. Results follow the order of `keys`.
+    pub async fn get_many(&self, keys: Vec<K>) -> Vec<Option<V>> {
+        let mut results = vec![None; keys.len()];
+        let mut batches: Vec<(Vec<usize>, Vec<K>)> =
+            vec![(Vec::new(), Vec::new()); self.mailboxes.len()];
+        for (pos, key) in keys.into_iter().enumerate() {
+            let batch = &mut batches[self.shard_for(&key)];
+            batch.0.push(pos);
+            batch.1.push(key);
+        }
+
+        let mut pending = Vec::new();
+        for (shard, (positions, keys)) in batches.into_iter().enumerate() {
+            if keys.is_empty() {
+                continue;
+            }
+            let (reply, rx) = oneshot::channel();
+            // A closed mailbox means the shard task is gone; its keys read as misses.
+            if self.mailboxes[shard]
+                .send(Command::Get { keys, reply })
+                .await
+                .is_ok()
+            {
+                pending.push((positions, rx));
+            }
+        }
+        for (positions, rx) in pending {
+            if let Ok(values) = rx.await {
+                for (pos, value) in positions.into_iter().zip(values) {
+                    results[pos] = value;
+                }
+            }
+        }
+        results
+    }
+
+    /// Stores several entries with one message per shard.
+    pub async fn put_many(&self, entries: Vec<(K, V)>) {
+        let mut batches: Vec<Vec<(K, V)>> = (0..self.mailboxes.len()).map(|_| Vec::new()).collect();
+        for (key, value) in entries {
+            batches[self.shard_for(&key)].push((key, value));
+        }
+        for (shard, entries) in batches.into_iter().enumerate() {
+            if !entries.is_empty() {
+                let _ = self.mailboxes[shard].send(Command::Put { entries }).await;
+            }
+        }
+    }
+
+    pub async fn clear(&self) {
+        let mut pending = Vec::new();
+        for mailbox in &self.mailboxes {
+            let (reply, rx) = oneshot::channel();
+            if mailbox.send(Command::Clear { reply }).await.is_ok() {
+                pending.push(rx);
+            }
+        }
+        for rx in pending {
+            let _ = rx.await;
+        }
+    }
+
+    fn shard_for(&self, key: &K) -> usize {
+        self.ring.shard_for(key).unwrap_or(0)
+    }
+}
+
+// The shard task ends once every sender (i.e. the cache) has been dropped.
+async fn run_shard<K, V>(mut cache: LRUCache<K, V>, mut rx: mpsc::Receiver<Command<K, V>>)
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            Command::Get { keys, reply } => {
+                let values = keys.iter().map(|k| cache.get(k)).collect();
+                let _ = reply.send(values);
+            }
+            Command::Put { entries } => {
+                for (key, value) in entries {
+                    cache.put(key, value);
+                }
+            }
+            Command::Clear { reply } => {
+                cache = LRUCache::new(cache.capacity());
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn batched_get_and_put_keep_caller_order() {
+        let cache = ActorShardedCache::new(4, 100, 8);
+        cache
+            .put_many((0..50u32).map(|i| (i, i * 2)).collect())
+            .await;
+        cache.put(7, 700).await;
+
+        let keys = vec![49, 7, 1_000, 0];
+        assert_eq!(
+            cache.get_many(keys).await,
+            vec![Some(98), Some(700), None, Some(0)]
+        );
+
+        cache.clear().await;
+        assert_eq!(cache.get(&7).await, None);
+    }
+
+    #[tokio::test]
+    async fn full_mailbox_makes_senders_wait() {
+        let cache = std::sync::Arc::new(ActorShardedCache::new(1, 10_000, 1));
+        let writers: Vec<_> = (0..8u32)
+            .map(|w| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for i in 0..250 {
+                        cache.put(w * 1_000 + i, i).await;
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        // Every write made it through the one-slot mailbox.
+        let keys: Vec<u32> = (0..8)
+            .flat_map(|w| (0..250).map(move |i| w * 1_000 + i))
+            .collect();
+        assert!(cache.get_many(keys).await.iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,92 @@
+use std::hash::Hash;
+
+use super::actor::ActorShardedCache;
+use super::sharded::ShardedCache;
+
+/// One async cache API over both sharding strategies, so the server can pick at startup.
+#[derive(Debug)]
+pub enum ShardedBackend<K, V>
+where
+    K: Eq + Hash + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Each shard's LRUCache behind a tokio RwLock.
+    Locked(ShardedCache<K, V>),
+    /// Each shard's LRUCache owned by its own task, reached over a channel.
+    Actor(ActorShardedCache<K, V>),
+}
+
+impl<K, V> ShardedBackend<K, V>
+where
+    K: Eq + Hash + Clone + std::fmt::Debug + Send + Sync + 'static,
+    V: Clone + std::fmt::Debug + Send + Sync + 'static,
+{
+    pub async fn get(&self, key: &K) -> Option<V> {
+        match self {
+            ShardedBackend::Locked(cache) => cache.get(key).await,
+            ShardedBackend::Actor(cache) => cache.get(key).await,
+        }
+    }
+
+    pub async fn put(&self, key: K, value: V) {
+        match self {
+            ShardedBackend::Locked(cache) => cache.put(key, value).await,
+            ShardedBackend::Actor(cache) => cache.put(key, value).await,
+        }
+    }
+
+    pub async fn get_many(&self, keys: Vec<K>) -> Vec<Option<V>> {
+        match self {
+            ShardedBackend::Locked(cache) => cache.get_many(keys).await,
+            ShardedBackend::Actor(cache) => cache.get_many(keys).await,
+        }
+    }
+
+    pub async fn put_many(&self, entries: Vec<(K, V)>) {
+        match self {
+            ShardedBackend::Locked(cache) => cache.put_many(entries).await,
+            ShardedBackend::Actor(cache) => cache.put_many(entries).await,
+        }
+    }
+
+    /// Moves the locked backend to `count` shards while it keeps serving. The actor backend
+    /// fixes its shards when it starts, so it returns `None` and needs a restart instead.
+    pub async fn reshard(&self, count: usize) -> Option<usize> {
+        match self {
+            ShardedBackend::Locked(cache) => Some(cache.reshard(count).await),
+            ShardedBackend::Actor(_) => None,
+        }
+    }
+
+    pub async fn clear(&self) {
+        match self {
+            ShardedBackend::Locked(cache) => cache.clear().await,
+            ShardedBackend::Actor(cache) => cache.clear().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn both_backends_batch_and_clear() {
+        let backends = [
+            ShardedBackend::Locked(ShardedCache::new(4, 100)),
+            ShardedBackend::Actor(ActorShardedCache::new(4, 100, 8)),
+        ];
+        for cache in backends {
+            cache
+                .put_many((0..20u32).map(|i| (i, i + 1)).collect())
+                .await;
+            assert_eq!(
+                cache.get_many(vec![19, 100, 0]).await,
+                vec![Some(20), None, Some(1)]
+            );
+
+            cache.clear().await;
+            assert_eq!(cache.get(&0).await, None);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+pub type ShardId = usize;
+
+/// Virtual nodes per shard; more points give a more even split.
+pub const DEFAULT_VNODES: usize = 160;
+/// Fixed so that a key lands on the same shard every time the server starts. Every
+/// backend uses it, so they agree on where each key lives.
+pub const RING_SEED: u64 = 0x5eed_cafe;
+
+/// FNV-1a with a seed and a final mix. Unlike `DefaultHasher` it has no per-process key, so
+/// the same bytes always land on the same point. Keys reach it through their `Hash` impl,
+/// so their placement is only as portable as that impl; shard points are hashed from
+/// fixed-width bytes and do not depend on it.
+pub struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: 0xcbf2_9ce4_8422_2325 ^ seed,
+        }
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state ^= b as u64;
+            self.state = self.state.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    // FNV alone clusters similar keys; the splitmix64 finaliser spreads them round the ring.
+    fn finish(&self) -> u64 {
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Consistent-hash ring. Each shard owns `vnodes` points; a key belongs to the first point
+/// at or after its hash, wrapping around. Adding or removing a shard only moves the keys
+/// between its points and their predecessors, roughly 1/n of them.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    seed: u64,
+    points: BTreeMap<u64, ShardId>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize, seed: u64) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            seed,
+            points: BTreeMap::new(),
+        }
+    }
+
+    pub fn hash<T: Hash + ?Sized>(&self, value: &T) -> u64 {
+        let mut hasher = StableHasher::new(self.seed);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn add(&mut self, shard: ShardId) {
+        for replica in 0..self.vnodes {
+            let mut hasher = StableHasher::new(self.seed);
+            hasher.write(&(shard as u64).to_le_bytes());
+            hasher.write(&(replica as u64).to_le_bytes());
+            let point = hasher.finish();
+            // On the (unlikely) collision the lower shard id keeps the point, whatever the
+            // order shards were added in.
+            let owner = self.points.entry(point).or_insert(shard);
+            *owner = (*owner).min(shard);
+        }
+    }
+
+    pub fn remove(&mut self, shard: ShardId) {
+        self.points.retain(|_, owner| *owner != shard);
+    }
+
+    pub fn shard_for<T: Hash + ?Sized>(&self, key: &T) -> Option<ShardId> {
+        let h = self.hash(key);
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, shard)| *shard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashing_is_stable_and_seeded() {
+        let ring = HashRing::new(10, 7);
+        assert_eq!(ring.hash("user@example.com"), ring.hash("user@example.com"));
+        assert_ne!(
+            ring.hash("user@example.com"),
+            HashRing::new(10, 8).hash("user@example.com")
+        );
+    }
+
+    #[test]
+    fn shard_points_do_not_depend_on_the_platform() {
+        let mut ring = HashRing::new(2, 7);
+        ring.add(3);
+        let points: Vec<u64> = ring.points.keys().copied().collect();
+        assert_eq!(points, vec![0x8174_244b_fc99_c8ab, 0xe29e_a17e_d34d_0bb0]);
+    }
+
+    #[test]
+    fn adding_a_shard_moves_about_one_in_n_keys() {
+        let mut ring = HashRing::new(160, 42);
+        for shard in 0..4 {
+            ring.add(shard);
+        }
+        let keys: Vec<String> = (0..20_000).map(|i| format!("user-{}", i)).collect();
+        let before: Vec<ShardId> = keys.iter().map(|k| ring.shard_for(k).unwrap()).collect();
+
+        ring.add(4);
+        let mut moved = 0;
+        for (key, old) in keys.iter().zip(&before) {
+            let new = ring.shard_for(key).unwrap();
+            if new != *old {
+                // Keys only ever move onto the new shard, never between old ones.
+                assert_eq!(new, 4);
+                moved += 1;
+            }
+        }
+
+        // Ideal is 1/5 of the keys; modulo hashing would move about 4/5.
+        let fraction = moved as f64 / keys.len() as f64;
+        assert!(
+            (0.15..0.25).contains(&fraction),
+            "moved fraction {}",
+            fraction
+        );
+    }
+
+    #[test]
+    fn removing_a_shard_only_moves_its_keys() {
+        let mut ring = HashRing::new(100, 1);
+        for shard in 0..3 {
+            ring.add(shard);
+        }
+        let keys: Vec<u64> = (0..5_000).collect();
+        let before: Vec<ShardId> = keys.iter().map(|k| ring.shard_for(k).unwrap()).collect();
+
+        ring.remove(1);
+        for (key, old) in keys.iter().zip(&before) {
+            let new = ring.shard_for(key).unwrap();
+            assert_ne!(new, 1);
+            if *old != 1 {
+                assert_eq!(new, *old);
+            }
+        }
+    }
+}
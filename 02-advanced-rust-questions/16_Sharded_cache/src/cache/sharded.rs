@@ -1,12 +1,27 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+use super::ring::{HashRing, ShardId, DEFAULT_VNODES, RING_SEED};
 use super::LRUCache;
 
 // A sharded cache that partitions data across multiple LRUCache instances.
-// Uses consistent hashing for shard selection.
+// Keys are placed on shards with a consistent-hash ring, so shards can be added or
+// removed at runtime and only the keys whose owner changed are migrated.
+
+type Shard<K, V> = Arc<RwLock<LRUCache<K, V>>>;
+
+#[derive(Debug)]
+struct Inner<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    ring: HashRing,
+    shards: HashMap<ShardId, Shard<K, V>>,
+    next_id: ShardId,
+}
 
 #[derive(Debug)]
 pub struct ShardedCache<K, V>
@@ -14,8 +29,10 @@ where
     K: Eq + Hash + Clone + std::fmt::Debug + Send + Sync + 'static,
     V: Clone + std::fmt::Debug + Send + Sync + 'static,
 {
-    shards: Vec<Arc<RwLock<LRUCache<K, V>>>>,
-    num_shards: usize,
+    // Lookups hold the read lock until they are done with the shard, so add_shard and
+    // remove_shard, which take it for writing, never migrate under a concurrent write.
+    inner: RwLock<Inner<K, V>>,
+    shard_capacity: usize,
 }
 
 impl<K, V> ShardedCache<K, V>
@@ -25,40 +42,232 @@ where
 {
     /// Create a new sharded cache with N shards
     pub fn new(num_shards: usize, shard_capacity: usize) -> Self {
-        let mut shards = Vec::with_capacity(num_shards);
-        for _ in 0..num_shards {
-            shards.push(Arc::new(RwLock::new(LRUCache::new(shard_capacity))));
-        }
-
-        Self { shards, num_shards }
+        Self::with_virtual_nodes(num_shards, DEFAULT_VNODES, shard_capacity)
     }
 
-    /// Hash the key to find the correct shard index
-    fn get_shard_index(&self, key: &K) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % self.num_shards
+    pub fn with_virtual_nodes(num_shards: usize, vnodes: usize, shard_capacity: usize) -> Self {
+        let mut ring = HashRing::new(vnodes, RING_SEED);
+        let mut shards = HashMap::with_capacity(num_shards);
+        for id in 0..num_shards {
+            ring.add(id);
+            shards.insert(id, Arc::new(RwLock::new(LRUCache::new(shard_capacity))));
+        }
+
+        Self {
+            inner: RwLock::new(Inner {
+                ring,
+                shards,
+                next_id: num_shards,
+            }),
+            shard_capacity,
+        }
     }
 
     /// Get a value from the appropriate shard asynchronously
     pub async fn get(&self, key: &K) -> Option<V> {
-        let index = self.get_shard_index(key);
-        let mut shard = self.shards[index].write().await;
+        let inner = self.inner.read().await;
+        let mut shard = inner.shard_for(key)?.write().await;
         shard.get(key)
     }
 
     /// Put a value into the appropriate shard asynchronously
     pub async fn put(&self, key: K, value: V) {
-        let index = self.get_shard_index(&key);
-        let mut shard = self.shards[index].write().await;
-        shard.put(key, value);
+        let inner = self.inner.read().await;
+        if let Some(shard) = inner.shard_for(&key) {
+            shard.write().await.put(key, value);
+        }
+    }
+
+    // The server caches users without a TTL; the tests use this to check that migration
+    // keeps expiry times.
+    #[cfg(test)]
+    pub async fn put_with_ttl(&self, key: K, value: V, ttl: std::time::Duration) {
+        let inner = self.inner.read().await;
+        if let Some(shard) = inner.shard_for(&key) {
+            shard.write().await.put_with_ttl(key, value, ttl);
+        }
+    }
+
+    pub async fn get_many(&self, keys: Vec<K>) -> Vec<Option<V>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            values.push(self.get(key).await);
+        }
+        values
+    }
+
+    pub async fn put_many(&self, entries: Vec<(K, V)>) {
+        for (key, value) in entries {
+            self.put(key, value).await;
+        }
+    }
+
+    /// Adds a shard and moves over the keys it now owns. Returns its id and how many keys moved.
+    pub async fn add_shard(&self) -> (ShardId, usize) {
+        let mut inner = self.inner.write().await;
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.ring.add(id);
+
+        let new_shard = LRUCache::new(self.shard_capacity);
+        let mut moving = Vec::new();
+        for shard in inner.shards.values() {
+            let mut shard = shard.write().await;
+            let keys: Vec<K> = shard
+                .iter()
+                .filter(|(k, _)| inner.ring.shard_for(*k) == Some(id))
+                .map(|(k, _)| k.clone())
+                .collect();
+            // Oldest first, so moved keys keep their relative recency.
+            for key in keys.into_iter().rev() {
+                if let Some((value, expires_at)) = shard.take(&key) {
+                    moving.push((key, value, expires_at));
+                }
+            }
+        }
+
+        let moved = moving.len();
+        let new_shard = Arc::new(RwLock::new(new_shard));
+        {
+            let mut target = new_shard.write().await;
+            for (key, value, expires_at) in moving {
+                target.put_until(key, value, expires_at);
+            }
+        }
+        inner.shards.insert(id, new_shard);
+        (id, moved)
+    }
+
+    /// Removes a shard and hands its keys to their new owners. Returns how many keys moved,
+    /// or `None` if the shard does not exist or is the last one.
+    pub async fn remove_shard(&self, id: ShardId) -> Option<usize> {
+        let mut inner = self.inner.write().await;
+        if inner.shards.len() <= 1 {
+            return None;
+        }
+        let removed = inner.shards.remove(&id)?;
+        inner.ring.remove(id);
+
+        let mut removed = removed.write().await;
+        let keys: Vec<K> = removed.iter().map(|(k, _)| k.clone()).collect();
+        let mut moved = 0;
+        // Oldest first, as in add_shard.
+        for key in keys.into_iter().rev() {
+            let Some((value, expires_at)) = removed.take(&key) else {
+                continue;
+            };
+            if let Some(owner) = inner.shard_for(&key) {
+                owner.write().await.put_until(key, value, expires_at);
+                moved += 1;
+            }
+        }
+        Some(moved)
+    }
+
+    pub async fn shard_count(&self) -> usize {
+        self.inner.read().await.shards.len()
+    }
+
+    /// Adds or removes shards until there are `count` (at least one), newest first when
+    /// removing. Returns how many keys moved.
+    pub async fn reshard(&self, count: usize) -> usize {
+        let count = count.max(1);
+        let mut moved = 0;
+        while self.shard_count().await < count {
+            moved += self.add_shard().await.1;
+        }
+        while self.shard_count().await > count {
+            let newest = self.inner.read().await.shards.keys().max().copied();
+            match newest {
+                Some(id) => moved += self.remove_shard(id).await.unwrap_or(0),
+                None => break,
+            }
+        }
+        moved
     }
 
     /// Clear all shards asynchronously
     pub async fn clear(&self) {
-        for shard in &self.shards {
+        let inner = self.inner.read().await;
+        for shard in inner.shards.values() {
             let mut s = shard.write().await;
-            *s = LRUCache::new(s.capacity);
+            *s = LRUCache::new(s.capacity());
+        }
+    }
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn shard_for(&self, key: &K) -> Option<&Shard<K, V>> {
+        let id = self.ring.shard_for(key)?;
+        self.shards.get(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn add_shard_migrates_only_the_keys_it_takes_over() {
+        let cache = ShardedCache::new(4, 1_000);
+        for i in 0..2_000u32 {
+            cache.put(i, i * 10).await;
+        }
+
+        let (id, moved) = cache.add_shard().await;
+        assert_eq!(id, 4);
+        // Roughly a fifth of the keys should now belong to the new shard.
+        assert!((250..550).contains(&moved), "moved {}", moved);
+        for i in 0..2_000u32 {
+            assert_eq!(cache.get(&i).await, Some(i * 10));
+        }
+
+        assert_eq!(cache.remove_shard(id).await, Some(moved));
+        assert_eq!(cache.shard_count().await, 4);
+        for i in 0..2_000u32 {
+            assert_eq!(cache.get(&i).await, Some(i * 10));
+        }
+    }
+
+    #[tokio::test]
+    async fn reshard_grows_and_shrinks_without_losing_keys() {
+        let cache = ShardedCache::new(2, 1_000);
+        for i in 0..500u32 {
+            cache.put(i, i).await;
+        }
+
+        assert!(cache.reshard(5).await > 0);
+        assert_eq!(cache.shard_count().await, 5);
+        assert!(cache.reshard(3).await > 0);
+        assert_eq!(cache.shard_count().await, 3);
+        assert!(cache.reshard(0).await > 0);
+        assert_eq!(cache.shard_count().await, 1);
+        for i in 0..500u32 {
+            assert_eq!(cache.get(&i).await, Some(i));
+        }
+    }
+
+    #[tokio::test]
+    async fn migrated_entries_keep_their_expiry() {
+        let cache = ShardedCache::new(2, 1_000);
+        for i in 0..200u32 {
+            cache.put_with_ttl(i, i, Duration::from_millis(50)).await;
+        }
+        cache.put(1_000, 1_000).await;
+
+        let (id, moved) = cache.add_shard().await;
+        assert!(moved > 0);
+        cache.remove_shard(id).await;
+        tokio::time::sleep(Duration::from_millis(80)).await;
+
+        for i in 0..200u32 {
+            assert_eq!(cache.get(&i).await, None);
         }
+        assert_eq!(cache.get(&1_000).await, Some(1_000));
     }
 }
@@ -0,0 +1,663 @@
+// Rate-limiting algorithms shared by the servers. Nothing here knows about HTTP; the
+// middleware turns a `Decision` into a 429 and `RateLimit-*` headers. `Limiter` keeps its
+// state in process; `store::SharedLimiter` keeps it in a `Store` that several instances
+// can share.
+
+pub mod resp;
+pub mod store;
+
+#[cfg(test)]
+mod test_store;
+
+use std::collections::{HashMap, VecDeque};
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Monotonic time source. Limiters only compare readings, so any fixed origin works.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// Time since the Unix epoch. Shared state is read and written by several processes, so
+/// it needs a time base they agree on; their clocks should be kept in sync (NTP).
+#[derive(Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockClock {
+    nanos: AtomicU64,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Bursts of up to `capacity`, refilled continuously at `refill_per_sec`.
+    TokenBucket { capacity: u32, refill_per_sec: f64 },
+    /// A meter that drains at `leak_per_sec`; a request is let through if it fits. Unlike
+    /// the token bucket it smooths bursts into a steady rate once the bucket is full.
+    LeakyBucket { capacity: u32, leak_per_sec: f64 },
+    /// At most `limit` requests per window, windows aligned to the clock.
+    FixedWindow { limit: u32, window: Duration },
+    /// At most `limit` requests in any `window`, tracked exactly with one timestamp per request.
+    SlidingWindowLog { limit: u32, window: Duration },
+}
+
+impl Algorithm {
+    fn limit(&self) -> u32 {
+        match *self {
+            Algorithm::TokenBucket { capacity, .. } | Algorithm::LeakyBucket { capacity, .. } => {
+                capacity
+            }
+            Algorithm::FixedWindow { limit, .. } | Algorithm::SlidingWindowLog { limit, .. } => {
+                limit
+            }
+        }
+    }
+
+    /// How long an untouched key takes to get its whole quota back.
+    pub fn recovery(&self) -> Duration {
+        match *self {
+            Algorithm::TokenBucket {
+                capacity,
+                refill_per_sec: rate,
+            }
+            | Algorithm::LeakyBucket {
+                capacity,
+                leak_per_sec: rate,
+            } => secs(capacity as f64 / rate),
+            Algorithm::FixedWindow { window, .. } | Algorithm::SlidingWindowLog { window, .. } => {
+                window
+            }
+        }
+    }
+
+    /// One instance's share when `instances` of them split this limit, for use while they
+    /// cannot coordinate. Never drops below one request.
+    pub fn split(&self, instances: u32) -> Algorithm {
+        let n = instances.max(1);
+        let share = |v: u32| (v / n).max(1);
+        match *self {
+            Algorithm::TokenBucket {
+                capacity,
+                refill_per_sec,
+            } => Algorithm::TokenBucket {
+                capacity: share(capacity),
+                refill_per_sec: refill_per_sec / n as f64,
+            },
+            Algorithm::LeakyBucket {
+                capacity,
+                leak_per_sec,
+            } => Algorithm::LeakyBucket {
+                capacity: share(capacity),
+                leak_per_sec: leak_per_sec / n as f64,
+            },
+            Algorithm::FixedWindow { limit, window } => Algorithm::FixedWindow {
+                limit: share(limit),
+                window,
+            },
+            Algorithm::SlidingWindowLog { limit, window } => Algorithm::SlidingWindowLog {
+                limit: share(limit),
+                window,
+            },
+        }
+    }
+}
+
+/// Outcome of one check, with what the `RateLimit-*` headers need.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the quota is fully restored.
+    pub reset: Duration,
+    /// Until a rejected request would be let through; `None` when allowed.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Debug)]
+enum State {
+    // Tokens left (token bucket) or current level (leaky bucket), as of `at`.
+    Bucket { value: f64, at: Duration },
+    Window { start: Duration, count: u32 },
+    Log(VecDeque<Duration>),
+}
+
+impl State {
+    /// Compact text form for stores: a tag, then nanosecond timestamps and counts.
+    fn encode(&self) -> String {
+        match self {
+            State::Bucket { value, at } => format!("b {} {}", value, at.as_nanos()),
+            State::Window { start, count } => format!("w {} {}", start.as_nanos(), count),
+            State::Log(log) => log.iter().fold("l".to_string(), |mut out, t| {
+                out.push(' ');
+                out.push_str(&t.as_nanos().to_string());
+                out
+            }),
+        }
+    }
+
+    /// `None` for anything `encode` would not have produced, including another
+    /// algorithm's state left behind under the same key.
+    fn decode(algorithm: &Algorithm, raw: &str) -> Option<State> {
+        let mut parts = raw.split(' ');
+        let tag = parts.next()?;
+        let nanos = |s: Option<&str>| s?.parse().ok().map(Duration::from_nanos);
+        let state = match (algorithm, tag) {
+            (Algorithm::TokenBucket { .. } | Algorithm::LeakyBucket { .. }, "b") => State::Bucket {
+                value: parts.next()?.parse().ok()?,
+                at: nanos(parts.next())?,
+            },
+            (Algorithm::FixedWindow { .. }, "w") => State::Window {
+                start: nanos(parts.next())?,
+                count: parts.next()?.parse().ok()?,
+            },
+            (Algorithm::SlidingWindowLog { .. }, "l") => State::Log(
+                parts
+                    .by_ref()
+                    .map(|t| nanos(Some(t)))
+                    .collect::<Option<_>>()?,
+            ),
+            _ => return None,
+        };
+        parts.next().is_none().then_some(state)
+    }
+}
+
+/// How often idle keys are swept out, in clock time.
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+/// One algorithm applied independently per key.
+pub struct Limiter {
+    algorithm: Algorithm,
+    clock: Arc<dyn Clock>,
+    // Shared with the limiters that inherited it, so all of them count the same requests.
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    states: HashMap<String, State>,
+    last_sweep: Duration,
+}
+
+impl Limiter {
+    #[cfg(test)]
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self::with_clock(algorithm, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(algorithm: Algorithm, clock: Arc<dyn Clock>) -> Self {
+        let last_sweep = clock.now();
+        Limiter {
+            algorithm,
+            clock,
+            inner: Arc::new(Mutex::new(Inner {
+                states: HashMap::new(),
+                last_sweep,
+            })),
+        }
+    }
+
+    /// Takes over `previous`'s keys, and its clock, so that replacing a limiter (say, on a
+    /// config reload) does not hand every client a fresh quota. Only done when both run the
+    /// same kind of algorithm, and for fixed windows the same window length; other state
+    /// means nothing here. What is carried over is cut down to this limiter's limit.
+    /// Returns whether the state was carried over.
+    pub fn inherit(&mut self, previous: &Limiter) -> bool {
+        let comparable = match (self.algorithm, previous.algorithm) {
+            (Algorithm::FixedWindow { window, .. }, Algorithm::FixedWindow { window: old, .. }) => {
+                window == old
+            }
+            (new, old) => std::mem::discriminant(&new) == std::mem::discriminant(&old),
+        };
+        if !comparable {
+            return false;
+        }
+        self.clock = previous.clock.clone();
+        self.inner = previous.inner.clone();
+        let algorithm = self.algorithm;
+        for state in self.inner.lock().unwrap().states.values_mut() {
+            clamp(&algorithm, state);
+        }
+        true
+    }
+
+    /// Counts a request against `key` if the limit allows it.
+    pub fn check(&self, key: &str) -> Decision {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        if now.saturating_sub(inner.last_sweep) >= SWEEP_EVERY {
+            inner.last_sweep = now;
+            let algorithm = self.algorithm;
+            inner
+                .states
+                .retain(|_, state| !is_idle(&algorithm, state, now));
+        }
+        let state = inner
+            .states
+            .entry(key.to_string())
+            .or_insert_with(|| fresh(&self.algorithm, now));
+        decide(&self.algorithm, state, now)
+    }
+
+    /// Drops keys whose state has fully recovered, so forgetting them changes nothing.
+    /// `check` does this on its own every minute.
+    pub fn evict_idle(&self) -> usize {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.states.len();
+        let algorithm = self.algorithm;
+        inner
+            .states
+            .retain(|_, state| !is_idle(&algorithm, state, now));
+        inner.last_sweep = now;
+        before - inner.states.len()
+    }
+
+    #[cfg(test)]
+    pub fn tracked_keys(&self) -> usize {
+        self.inner.lock().unwrap().states.len()
+    }
+}
+
+fn fresh(algorithm: &Algorithm, now: Duration) -> State {
+    match *algorithm {
+        Algorithm::TokenBucket { capacity, .. } => State::Bucket {
+            value: capacity as f64,
+            at: now,
+        },
+        Algorithm::LeakyBucket { .. } => State::Bucket {
+            value: 0.0,
+            at: now,
+        },
+        Algorithm::FixedWindow { window, .. } => State::Window {
+            start: window_start(now, window),
+            count: 0,
+        },
+        Algorithm::SlidingWindowLog { .. } => State::Log(VecDeque::new()),
+    }
+}
+
+/// Cuts state kept under another limit down to `algorithm`'s.
+fn clamp(algorithm: &Algorithm, state: &mut State) {
+    let limit = algorithm.limit();
+    match state {
+        State::Bucket { value, .. } => *value = value.min(limit as f64),
+        State::Window { count, .. } => *count = (*count).min(limit),
+        // The most recent requests are the ones that still count longest.
+        State::Log(log) => {
+            while log.len() > limit as usize {
+                log.pop_front();
+            }
+        }
+    }
+}
+
+fn window_start(now: Duration, window: Duration) -> Duration {
+    let window = window.as_nanos().max(1);
+    Duration::from_nanos((now.as_nanos() / window * window) as u64)
+}
+
+fn secs(value: f64) -> Duration {
+    Duration::from_secs_f64(value.max(0.0))
+}
+
+fn decide(algorithm: &Algorithm, state: &mut State, now: Duration) -> Decision {
+    let limit = algorithm.limit();
+    match (*algorithm, state) {
+        (
+            Algorithm::TokenBucket {
+                capacity,
+                refill_per_sec,
+            },
+            State::Bucket { value, at },
+        ) => {
+            let capacity = capacity as f64;
+            *value =
+                (*value + now.saturating_sub(*at).as_secs_f64() * refill_per_sec).min(capacity);
+            *at = (*at).max(now);
+            let allowed = *value >= 1.0;
+            if allowed {
+                *value -= 1.0;
+            }
+            Decision {
+                allowed,
+                limit,
+                remaining: *value as u32,
+                reset: secs((capacity - *value) / refill_per_sec),
+                retry_after: (!allowed).then(|| secs((1.0 - *value) / refill_per_sec)),
+            }
+        }
+        (
+            Algorithm::LeakyBucket {
+                capacity,
+                leak_per_sec,
+            },
+            State::Bucket { value, at },
+        ) => {
+            let capacity = capacity as f64;
+            *value = (*value - now.saturating_sub(*at).as_secs_f64() * leak_per_sec).max(0.0);
+            *at = (*at).max(now);
+            let allowed = *value + 1.0 <= capacity;
+            if allowed {
+                *value += 1.0;
+            }
+            Decision {
+                allowed,
+                limit,
+                remaining: (capacity - *value) as u32,
+                reset: secs(*value / leak_per_sec),
+                retry_after: (!allowed).then(|| secs((*value + 1.0 - capacity) / leak_per_sec)),
+            }
+        }
+        (Algorithm::FixedWindow { limit, window }, State::Window { start, count }) => {
+            let current = window_start(now, window);
+            // `>` rather than `!=`: with a shared store, an instance whose clock lags
+            // must not reset a window another instance has already moved on to.
+            if current > *start {
+                *start = current;
+                *count = 0;
+            }
+            let allowed = *count < limit;
+            if allowed {
+                *count += 1;
+            }
+            let reset = (*start + window).saturating_sub(now);
+            Decision {
+                allowed,
+                limit,
+                // The count can be over the limit after a reload lowered it, or when
+                // another instance with a higher limit shares the store.
+                remaining: limit.saturating_sub(*count),
+                reset,
+                retry_after: (!allowed).then_some(reset),
+            }
+        }
+        (Algorithm::SlidingWindowLog { limit, window }, State::Log(log)) => {
+            while log
+                .front()
+                .is_some_and(|t| now.saturating_sub(*t) >= window)
+            {
+                log.pop_front();
+            }
+            let allowed = (log.len() as u32) < limit;
+            if allowed {
+                log.push_back(now);
+            }
+            // A slot frees up when the oldest request leaves the window.
+            let until_oldest_expires = log
+                .front()
+                .map_or(Duration::ZERO, |t| (*t + window).saturating_sub(now));
+            Decision {
+                allowed,
+                limit,
+                remaining: limit.saturating_sub(log.len() as u32),
+                reset: log
+                    .back()
+                    .map_or(Duration::ZERO, |t| (*t + window).saturating_sub(now)),
+                retry_after: (!allowed).then_some(until_oldest_expires),
+            }
+        }
+        // `fresh` builds the state from the same algorithm, so the pairs always line up.
+        _ => unreachable!("state does not match algorithm"),
+    }
+}
+
+fn is_idle(algorithm: &Algorithm, state: &State, now: Duration) -> bool {
+    match (*algorithm, state) {
+        (
+            Algorithm::TokenBucket {
+                capacity,
+                refill_per_sec,
+            },
+            State::Bucket { value, at },
+        ) => *value + now.saturating_sub(*at).as_secs_f64() * refill_per_sec >= capacity as f64,
+        (Algorithm::LeakyBucket { leak_per_sec, .. }, State::Bucket { value, at }) => {
+            *value - now.saturating_sub(*at).as_secs_f64() * leak_per_sec <= 0.0
+        }
+        (Algorithm::FixedWindow { window, .. }, State::Window { start, .. }) => {
+            window_start(now, window) > *start
+        }
+        (Algorithm::SlidingWindowLog { window, .. }, State::Log(log)) => {
+            log.back().is_none_or(|t| now.saturating_sub(*t) >= window)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(algorithm: Algorithm) -> (Limiter, Arc<MockClock>) {
+        let clock = MockClock::new();
+        (Limiter::with_clock(algorithm, clock.clone()), clock)
+    }
+
+    fn allowed(limiter: &Limiter, n: usize) -> usize {
+        (0..n).filter(|_| limiter.check("k").allowed).count()
+    }
+
+    #[test]
+    fn token_bucket_keeps_fractional_refill() {
+        let (limiter, clock) = limiter(Algorithm::TokenBucket {
+            capacity: 5,
+            refill_per_sec: 1.0,
+        });
+        assert_eq!(allowed(&limiter, 10), 5);
+
+        // Two half-second waits add up to one token instead of being rounded away.
+        clock.advance(Duration::from_millis(500));
+        assert!(!limiter.check("k").allowed);
+        clock.advance(Duration::from_millis(500));
+        let decision = limiter.check("k");
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        let rejected = limiter.check("k");
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(rejected.reset, Duration::from_secs(5));
+        // Other keys have their own bucket.
+        assert!(limiter.check("other").allowed);
+    }
+
+    #[test]
+    fn leaky_bucket_drains_at_a_steady_rate() {
+        let (limiter, clock) = limiter(Algorithm::LeakyBucket {
+            capacity: 3,
+            leak_per_sec: 2.0,
+        });
+        assert_eq!(allowed(&limiter, 5), 3);
+        let rejected = limiter.check("k");
+        assert_eq!(rejected.retry_after, Some(Duration::from_millis(500)));
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(allowed(&limiter, 3), 1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(allowed(&limiter, 5), 3);
+    }
+
+    #[test]
+    fn fixed_window_resets_on_the_boundary() {
+        let (limiter, clock) = limiter(Algorithm::FixedWindow {
+            limit: 3,
+            window: Duration::from_secs(10),
+        });
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(allowed(&limiter, 5), 3);
+        assert_eq!(limiter.check("k").retry_after, Some(Duration::from_secs(2)));
+
+        // The window is aligned to the clock, so the quota is back two seconds later.
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(allowed(&limiter, 5), 3);
+    }
+
+    #[test]
+    fn sliding_log_counts_any_window_exactly() {
+        let (limiter, clock) = limiter(Algorithm::SlidingWindowLog {
+            limit: 3,
+            window: Duration::from_secs(10),
+        });
+        assert_eq!(allowed(&limiter, 2), 2);
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(allowed(&limiter, 2), 1);
+
+        // A fixed window would have reset at t=10; the log still sees all three.
+        clock.advance(Duration::from_secs(1));
+        let rejected = limiter.check("k");
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Some(Duration::from_secs(1)));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(allowed(&limiter, 3), 2);
+    }
+
+    #[test]
+    fn idle_keys_are_evicted_once_recovered() {
+        let (limiter, clock) = limiter(Algorithm::TokenBucket {
+            capacity: 2,
+            refill_per_sec: 1.0,
+        });
+        for i in 0..100 {
+            limiter.check(&format!("client-{i}"));
+        }
+        limiter.check("busy");
+        limiter.check("busy");
+        assert_eq!(limiter.evict_idle(), 0);
+
+        clock.advance(Duration::from_millis(1500));
+        // The 100 single-request buckets are full again; "busy" is still refilling.
+        assert_eq!(limiter.evict_idle(), 100);
+        assert_eq!(limiter.tracked_keys(), 1);
+
+        // check() sweeps on its own once a minute has passed.
+        clock.advance(Duration::from_secs(60));
+        limiter.check("new");
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+
+    #[test]
+    fn replacements_inherit_keys_of_the_same_algorithm() {
+        let (old, clock) = limiter(Algorithm::TokenBucket {
+            capacity: 2,
+            refill_per_sec: 1.0,
+        });
+        assert_eq!(allowed(&old, 2), 2);
+
+        // A bigger bucket picks up where the old one left off instead of starting full.
+        let mut new = Limiter::new(Algorithm::TokenBucket {
+            capacity: 4,
+            refill_per_sec: 1.0,
+        });
+        assert!(new.inherit(&old));
+        assert!(!new.check("k").allowed);
+        clock.advance(Duration::from_secs(1));
+        assert!(new.check("k").allowed);
+        // Requests still going through the old limiter count against the same key.
+        assert!(!old.check("k").allowed);
+
+        let mut window = Limiter::new(Algorithm::FixedWindow {
+            limit: 1,
+            window: Duration::from_secs(60),
+        });
+        assert!(!window.inherit(&old));
+        assert_eq!(window.tracked_keys(), 0);
+    }
+
+    #[test]
+    fn a_reload_that_lowers_the_limit_rejects_without_underflow() {
+        let window = Duration::from_secs(60);
+        let (old, _) = limiter(Algorithm::FixedWindow { limit: 10, window });
+        assert_eq!(allowed(&old, 8), 8);
+
+        let mut new = Limiter::new(Algorithm::FixedWindow { limit: 5, window });
+        assert!(new.inherit(&old));
+        let decision = new.check("k");
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+    }
+
+    #[test]
+    fn reloads_clamp_inherited_counts_and_reset_resized_windows() {
+        let window = Duration::from_secs(60);
+        let (old, clock) = limiter(Algorithm::SlidingWindowLog { limit: 10, window });
+        assert_eq!(allowed(&old, 8), 8);
+        let mut new = Limiter::new(Algorithm::SlidingWindowLog { limit: 3, window });
+        assert!(new.inherit(&old));
+        assert!(!new.check("k").allowed);
+        // The whole quota is back once the inherited requests leave the window.
+        clock.advance(window);
+        assert_eq!(allowed(&new, 4), 3);
+
+        let (old, _) = limiter(Algorithm::FixedWindow { limit: 10, window });
+        assert_eq!(allowed(&old, 8), 8);
+        let mut lowered = Limiter::new(Algorithm::FixedWindow { limit: 5, window });
+        assert!(lowered.inherit(&old));
+        assert_eq!(lowered.check("k").remaining, 0);
+        let mut raised = Limiter::new(Algorithm::FixedWindow { limit: 20, window });
+        assert!(raised.inherit(&lowered));
+        // The lowered limiter cut the count to 5; the request it rejected did not count.
+        assert_eq!(raised.check("k").remaining, 14);
+
+        // Windows of another length start and end elsewhere, so their counts mean nothing.
+        let mut resized = Limiter::new(Algorithm::FixedWindow {
+            limit: 5,
+            window: Duration::from_secs(10),
+        });
+        assert!(!resized.inherit(&old));
+        assert_eq!(resized.check("k").remaining, 4);
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{decide, fresh, Algorithm, Clock, Decision, Limiter, State, WallClock, SWEEP_EVERY};
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Called with the stored value (if any); returns the value to store and the decision.
+pub type Update<'a> = &'a (dyn Fn(Option<&str>) -> (String, Decision) + Send + Sync);
+
+/// Where `SharedLimiter` keeps its per-key state.
+pub trait Store: Send + Sync {
+    /// Replaces the value under `key` with what `update` computes from it, atomically:
+    /// if another writer gets in between, the store retries `update` on the new value.
+    /// The key expires `ttl` after the last write.
+    fn update<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Duration,
+        update: Update<'a>,
+    ) -> StoreFuture<'a, io::Result<Decision>>;
+}
+
+/// Single-node store: a map behind a mutex, so every update is trivially atomic.
+pub struct MemoryStore {
+    clock: Arc<dyn Clock>,
+    inner: Mutex<MemoryInner>,
+}
+
+struct MemoryInner {
+    entries: HashMap<String, (String, Duration)>,
+    last_sweep: Duration,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(WallClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let last_sweep = clock.now();
+        MemoryStore {
+            clock,
+            inner: Mutex::new(MemoryInner {
+                entries: HashMap::new(),
+                last_sweep,
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for MemoryStore {
+    fn update<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Duration,
+        update: Update<'a>,
+    ) -> StoreFuture<'a, io::Result<Decision>> {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        if now.saturating_sub(inner.last_sweep) >= SWEEP_EVERY {
+            inner.last_sweep = now;
+            inner.entries.retain(|_, (_, expires)| *expires > now);
+        }
+        let current = inner
+            .entries
+            .get(key)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(value, _)| value.as_str());
+        let (value, decision) = update(current);
+        inner.entries.insert(key.to_string(), (value, now + ttl));
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+/// How long to stay on the local allowance after the store fails, before trying it again.
+const STORE_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// What a request rejected because its key is too contended to update is told to wait.
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Stripes of the per-process lock that serialises updates to the same key.
+const LOCK_STRIPES: usize = 16;
+
+/// One algorithm applied per key, with the state in a `Store` so that every instance
+/// sharing the store enforces one combined limit. While the store is unreachable each
+/// instance falls back to a local limiter with its own allowance.
+pub struct SharedLimiter {
+    algorithm: Algorithm,
+    prefix: String,
+    store: Arc<dyn Store>,
+    clock: Arc<dyn Clock>,
+    fallback: Limiter,
+    // Clock reading before which the store is not tried again.
+    store_down_until: Mutex<Option<Duration>>,
+    // Requests for one key from this process queue here rather than racing each other
+    // in the store; only other instances can then make a compare-and-set fail.
+    locks: Vec<tokio::sync::Mutex<()>>,
+}
+
+impl SharedLimiter {
+    /// Keys are stored as `{prefix}:{key}`, so limiters sharing a store need distinct
+    /// prefixes. The fallback defaults to the full limit per instance; see `fallback`.
+    #[cfg(test)]
+    pub fn new(algorithm: Algorithm, prefix: &str, store: Arc<dyn Store>) -> Self {
+        Self::with_clock(algorithm, prefix, store, Arc::new(WallClock))
+    }
+
+    pub fn with_clock(
+        algorithm: Algorithm,
+        prefix: &str,
+        store: Arc<dyn Store>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        SharedLimiter {
+            algorithm,
+            prefix: prefix.to_string(),
+            store,
+            fallback: Limiter::with_clock(algorithm, clock.clone()),
+            clock,
+            store_down_until: Mutex::new(None),
+            locks: (0..LOCK_STRIPES)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+        }
+    }
+
+    /// What each instance allows on its own while the store is down, usually
+    /// `algorithm.split(instances)`.
+    pub fn fallback(mut self, algorithm: Algorithm) -> Self {
+        self.fallback = Limiter::with_clock(algorithm, self.clock.clone());
+        self
+    }
+
+    /// Counts a request against `key` if the limit allows it.
+    pub async fn check(&self, key: &str) -> Decision {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let _guard = self.locks[hasher.finish() as usize % LOCK_STRIPES]
+            .lock()
+            .await;
+        // Read after queueing, so that requests held up behind a failing update see the
+        // store marked down instead of each waiting out their own timeout.
+        let now = self.clock.now();
+        let store_down = self
+            .store_down_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| now < until);
+        if !store_down {
+            match self.check_store(key).await {
+                Ok(decision) => return decision,
+                // The store is fine, just busy with this key, so it is not marked down.
+                // Other instances keep winning the race for the key; letting the request
+                // through on the local allowance would skip the limit they share.
+                Err(err) if err.kind() == io::ErrorKind::ResourceBusy => return self.busy(),
+                Err(err) => {
+                    eprintln!("rate limit store unavailable, using local allowance: {err}");
+                    *self.store_down_until.lock().unwrap() = Some(now + STORE_RETRY_AFTER);
+                }
+            }
+        }
+        self.fallback.check(key)
+    }
+
+    /// Turns a request away without counting it anywhere.
+    fn busy(&self) -> Decision {
+        Decision {
+            allowed: false,
+            limit: self.algorithm.limit(),
+            remaining: 0,
+            reset: self.algorithm.recovery(),
+            retry_after: Some(BUSY_RETRY_AFTER),
+        }
+    }
+
+    async fn check_store(&self, key: &str) -> io::Result<Decision> {
+        let algorithm = self.algorithm;
+        let clock = &self.clock;
+        let update = move |current: Option<&str>| {
+            // Read the clock per attempt: a retried update must not reuse a stale time.
+            let now = clock.now();
+            let mut state = current
+                .and_then(|raw| State::decode(&algorithm, raw))
+                .unwrap_or_else(|| fresh(&algorithm, now));
+            let decision = decide(&algorithm, &mut state, now);
+            (state.encode(), decision)
+        };
+        let key = format!("{}:{}", self.prefix, key);
+        // A key that has gone this long without a write has recovered fully, so
+        // letting the store drop it changes nothing.
+        let ttl = self.algorithm.recovery() + Duration::from_secs(1);
+        self.store.update(&key, ttl, &update).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::MockClock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn bucket() -> Algorithm {
+        Algorithm::TokenBucket {
+            capacity: 4,
+            refill_per_sec: 1.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn instances_sharing_a_store_share_the_limit() {
+        let clock = MockClock::new();
+        let store = Arc::new(MemoryStore::with_clock(clock.clone()));
+        let instances: Vec<_> = (0..2)
+            .map(|_| SharedLimiter::with_clock(bucket(), "rl", store.clone(), clock.clone()))
+            .collect();
+
+        let mut allowed = 0;
+        for i in 0..10 {
+            allowed += instances[i % 2].check("client").await.allowed as usize;
+        }
+        // Separate in-process limiters would have allowed 4 each.
+        assert_eq!(allowed, 4);
+        assert_eq!(store.len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(instances[1].check("client").await.allowed);
+        assert!(!instances[0].check("client").await.allowed);
+    }
+
+    struct FlakyStore {
+        inner: MemoryStore,
+        down: AtomicBool,
+    }
+
+    impl Store for FlakyStore {
+        fn update<'a>(
+            &'a self,
+            key: &'a str,
+            ttl: Duration,
+            update: Update<'a>,
+        ) -> StoreFuture<'a, io::Result<Decision>> {
+            if self.down.load(Ordering::SeqCst) {
+                return Box::pin(async {
+                    Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+                });
+            }
+            self.inner.update(key, ttl, update)
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_local_share_while_the_store_is_down() {
+        let clock = MockClock::new();
+        let store = Arc::new(FlakyStore {
+            inner: MemoryStore::with_clock(clock.clone()),
+            down: AtomicBool::new(true),
+        });
+        let limiter = SharedLimiter::with_clock(bucket(), "rl", store.clone(), clock.clone())
+            .fallback(bucket().split(2));
+
+        let mut allowed = 0;
+        for _ in 0..5 {
+            allowed += limiter.check("client").await.allowed as usize;
+        }
+        assert_eq!(allowed, 2);
+
+        // The store is back, but is only retried once the back-off has passed.
+        store.down.store(false, Ordering::SeqCst);
+        assert!(!limiter.check("client").await.allowed);
+        assert!(store.inner.is_empty());
+        clock.advance(STORE_RETRY_AFTER);
+        assert!(limiter.check("client").await.allowed);
+        assert_eq!(store.inner.len(), 1);
+    }
+
+    #[test]
+    fn states_survive_encoding() {
+        let now = Duration::from_millis(1500);
+        for algorithm in [
+            bucket(),
+            Algorithm::FixedWindow {
+                limit: 3,
+                window: Duration::from_secs(10),
+            },
+            Algorithm::SlidingWindowLog {
+                limit: 3,
+                window: Duration::from_secs(10),
+            },
+        ] {
+            let mut state = fresh(&algorithm, now);
+            decide(&algorithm, &mut state, now);
+            decide(&algorithm, &mut state, now);
+            let raw = state.encode();
+            let decoded = State::decode(&algorithm, &raw).unwrap();
+            assert_eq!(decoded.encode(), raw);
+        }
+        assert!(State::decode(&bucket(), "w 0 1").is_none());
+        assert!(State::decode(&bucket(), "b 1.5").is_none());
+    }
+}
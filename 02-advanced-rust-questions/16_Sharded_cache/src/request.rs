@@ -0,0 +1,451 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::types::Response;
+
+/// Upper bounds enforced while a request is read off the socket.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Other(m) => m,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    fn from(s: &str) -> Self {
+        match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            other => Method::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Header map with case-insensitive lookups. Repeated headers are joined with ", ".
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    map: HashMap<String, String>,
+}
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.map.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.map
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.map.remove(&name.to_ascii_lowercase());
+    }
+}
+
+/// Per-request values that middlewares hand to later middlewares and handlers, keyed by
+/// type (e.g. the authenticated principal).
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub extensions: Extensions,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(std::io::Error),
+    Malformed(&'static str),
+    UnexpectedEof,
+    HeadersTooLarge,
+    BodyTooLarge,
+}
+
+impl ParseError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::HeadersTooLarge => 431,
+            ParseError::BodyTooLarge => 413,
+            _ => 400,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        Response::text(self.status(), self.to_string())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "io error: {}", err),
+            ParseError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            ParseError::UnexpectedEof => f.write_str("connection closed mid-request"),
+            ParseError::HeadersTooLarge => f.write_str("headers too large"),
+            ParseError::BodyTooLarge => f.write_str("body too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+/// Reads HTTP/1.1 requests from a stream. Bytes read past the end of one
+/// request stay buffered for the next call.
+pub struct RequestReader {
+    buf: Vec<u8>,
+    limits: Limits,
+}
+
+impl RequestReader {
+    pub fn new(limits: Limits) -> Self {
+        RequestReader {
+            buf: Vec::new(),
+            limits,
+        }
+    }
+
+    /// Returns `Ok(None)` if the peer closed the connection before sending anything.
+    pub async fn read_request<S>(&mut self, stream: &mut S) -> Result<Option<Request>, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let header_end = loop {
+            if let Some(pos) = find_header_end(&self.buf) {
+                break pos;
+            }
+            if self.buf.len() > self.limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ParseError::UnexpectedEof);
+            }
+        };
+        if header_end > self.limits.max_header_bytes {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let head = std::str::from_utf8(&self.buf[..header_end])
+            .map_err(|_| ParseError::Malformed("request head is not valid UTF-8"))?;
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines
+            .next()
+            .ok_or(ParseError::Malformed("missing request line"))?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts
+            .next()
+            .ok_or(ParseError::Malformed("missing method"))?;
+        let target = parts
+            .next()
+            .ok_or(ParseError::Malformed("missing request target"))?;
+        let version = parts
+            .next()
+            .ok_or(ParseError::Malformed("missing HTTP version"))?;
+        if !version.starts_with("HTTP/1.") {
+            return Err(ParseError::Malformed("unsupported HTTP version"));
+        }
+
+        let mut headers = Headers::default();
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(ParseError::Malformed("header line without ':'"))?;
+            headers.insert(name.trim(), value.trim());
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, qs)) => (path.to_string(), parse_query(qs)),
+            None => (target.to_string(), HashMap::new()),
+        };
+        let method = Method::from(method);
+        self.buf.drain(..header_end + 4);
+
+        let chunked = headers
+            .get("transfer-encoding")
+            .map(|te| te.to_ascii_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        let body = if chunked {
+            self.read_chunked_body(stream).await?
+        } else if let Some(len) = headers.get("content-length") {
+            let len: usize = len
+                .parse()
+                .map_err(|_| ParseError::Malformed("invalid Content-Length"))?;
+            if len > self.limits.max_body_bytes {
+                return Err(ParseError::BodyTooLarge);
+            }
+            self.read_exact(stream, len).await?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Some(Request {
+            method,
+            path,
+            query,
+            headers,
+            body,
+            extensions: Extensions::default(),
+        }))
+    }
+
+    async fn fill<S>(&mut self, stream: &mut S) -> Result<usize, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut tmp = [0u8; 1024];
+        let n = stream.read(&mut tmp).await?;
+        self.buf.extend_from_slice(&tmp[..n]);
+        Ok(n)
+    }
+
+    async fn read_exact<S>(&mut self, stream: &mut S, len: usize) -> Result<Vec<u8>, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        while self.buf.len() < len {
+            if self.fill(stream).await? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    async fn read_line<S>(&mut self, stream: &mut S) -> Result<String, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..pos]).into_owned();
+                self.buf.drain(..pos + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > self.limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+    }
+
+    async fn read_chunked_body<S>(&mut self, stream: &mut S) -> Result<Vec<u8>, ParseError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line(stream).await?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| ParseError::Malformed("invalid chunk size"))?;
+            if size == 0 {
+                // Trailer section ends with an empty line; trailers themselves are ignored.
+                while !self.read_line(stream).await?.is_empty() {}
+                return Ok(body);
+            }
+            // A huge chunk size must not overflow the running total.
+            match body.len().checked_add(size) {
+                Some(total) if total <= self.limits.max_body_bytes => {}
+                _ => return Err(ParseError::BodyTooLarge),
+            }
+            let chunk = self.read_exact(stream, size).await?;
+            body.extend_from_slice(&chunk);
+            if !self.read_line(stream).await?.is_empty() {
+                return Err(ParseError::Malformed("chunk not terminated by CRLF"));
+            }
+        }
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+fn parse_query(qs: &str) -> HashMap<String, String> {
+    qs.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (percent_decode(k), percent_decode(v)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex_val(bytes[i + 1]), hex_val(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        let mut stream = raw;
+        RequestReader::new(Limits::default())
+            .read_request(&mut stream)
+            .await
+    }
+
+    #[tokio::test]
+    async fn parses_content_length_body_and_query() {
+        let raw = b"POST /user?page=2&name=a%20b HTTP/1.1\r\nHost: x\r\nContent-Length: 11\r\n\r\nhello world";
+        let req = parse(raw).await.unwrap().unwrap();
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.path, "/user");
+        assert_eq!(req.query_param("page"), Some("2"));
+        assert_eq!(req.query_param("name"), Some("a b"));
+        assert_eq!(req.header("HOST"), Some("x"));
+        assert_eq!(req.body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn parses_chunked_body() {
+        let raw = b"POST /user HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: y\r\n\r\n";
+        let req = parse(raw).await.unwrap().unwrap();
+        assert_eq!(req.body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_body() {
+        let raw = b"POST /user HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n";
+        assert!(matches!(parse(raw).await, Err(ParseError::BodyTooLarge)));
+        let raw = b"POST /user HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\nffffffffffffffff\r\n";
+        assert!(matches!(parse(raw).await, Err(ParseError::BodyTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn keeps_pipelined_bytes_for_next_request() {
+        let raw: &[u8] = b"GET /health HTTP/1.1\r\n\r\nGET /hello/bob HTTP/1.1\r\n\r\n";
+        let mut stream = raw;
+        let mut reader = RequestReader::new(Limits::default());
+        let first = reader.read_request(&mut stream).await.unwrap().unwrap();
+        let second = reader.read_request(&mut stream).await.unwrap().unwrap();
+        assert_eq!(first.path, "/health");
+        assert_eq!(second.path, "/hello/bob");
+        assert!(reader.read_request(&mut stream).await.unwrap().is_none());
+    }
+}
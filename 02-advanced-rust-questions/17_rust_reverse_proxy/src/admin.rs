@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::proxy::Proxy;
+
+/// Serves the active route table and the upstream pool metrics on `addr`, apart from the
+/// traffic the proxy routes.
+pub async fn run(addr: String, proxy: Arc<Proxy>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("⚠️ Admin endpoint not started on {addr}: {e}");
+            return;
+        }
+    };
+    println!("Admin endpoint running on {addr}");
+
+    loop {
+        let Ok((mut socket, _)) = listener.accept().await else {
+            continue;
+        };
+        let proxy = proxy.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let n = match socket.read(&mut buffer).await {
+                Ok(n) if n > 0 => n,
+                _ => return,
+            };
+            let request = String::from_utf8_lossy(&buffer[..n]);
+
+            let response = if request.starts_with("GET /routes") {
+                let body =
+                    serde_json::to_string_pretty(&proxy.routes.to_json()).unwrap_or_default();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else if request.starts_with("GET /metrics") {
+                let body = proxy.upstreams.metrics();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 9\r\n\r\nNot Found".to_string()
+            };
+
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
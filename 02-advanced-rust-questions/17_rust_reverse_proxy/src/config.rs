@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::forwarding::Cidr;
+use crate::upstream::PoolConfig;
+
+/// Settings read from `proxy.toml`, or the file named by `PROXY_CONFIG`. Anything the file
+/// leaves out keeps its default; without a file the defaults are used as they are.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    /// Where `GET /routes` is served.
+    pub admin: String,
+    /// Used by routes that set none of their own.
+    pub timeouts: TimeoutConfig,
+    /// Addresses or blocks (`10.0.0.0/8`) of proxies in front of this one. Only their
+    /// `X-Forwarded-*` and `Forwarded` headers are passed on; anyone else's are replaced.
+    pub trusted_proxies: Vec<String>,
+    pub pool: PoolConfig,
+    /// Named groups of backends, taken in turn by the routes that send to them.
+    pub upstreams: BTreeMap<String, Vec<String>>,
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect_secs: u64,
+    pub read_secs: u64,
+    pub idle_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_secs: 5,
+            read_secs: 30,
+            idle_secs: 60,
+        }
+    }
+}
+
+/// Per-route overrides of `[timeouts]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteTimeouts {
+    pub connect_secs: Option<u64>,
+    pub read_secs: Option<u64>,
+    pub idle_secs: Option<u64>,
+}
+
+/// One `[[routes]]` entry. A request must meet every condition the route sets; routes are
+/// tried from the highest `priority` down, and in file order among equals.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    #[serde(default)]
+    pub priority: i32,
+    /// Matches this path and the paths below it: `/users` takes `/users/1` but not
+    /// `/usersx`.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    /// Any method when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Compared with the `Host` header, without its port.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Headers the request must carry with exactly these values.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub upstream: String,
+    /// Drops `path_prefix` from the path sent upstream.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replaces `path_prefix`, or whatever `path_regex` matched (`$1` and the like refer
+    /// to its groups), in the path sent upstream.
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    /// Set on the request sent upstream, replacing any the client sent.
+    #[serde(default)]
+    pub add_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    #[serde(default)]
+    pub timeouts: RouteTimeouts,
+}
+
+impl RouteConfig {
+    fn prefix(name: &str, prefix: &str) -> Self {
+        RouteConfig {
+            name: name.to_string(),
+            priority: 0,
+            path_prefix: Some(prefix.to_string()),
+            path_regex: None,
+            methods: Vec::new(),
+            host: None,
+            headers: BTreeMap::new(),
+            upstream: name.to_string(),
+            strip_prefix: false,
+            rewrite: None,
+            add_headers: BTreeMap::new(),
+            remove_headers: Vec::new(),
+            timeouts: RouteTimeouts::default(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "127.0.0.1:3000".to_string(),
+            admin: "127.0.0.1:3100".to_string(),
+            timeouts: TimeoutConfig::default(),
+            trusted_proxies: Vec::new(),
+            pool: PoolConfig::default(),
+            upstreams: BTreeMap::from([
+                ("users".to_string(), vec!["127.0.0.1:3001".to_string()]),
+                ("orders".to_string(), vec!["127.0.0.1:3002".to_string()]),
+            ]),
+            routes: vec![
+                RouteConfig::prefix("users", "/users"),
+                RouteConfig::prefix("orders", "/orders"),
+            ],
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        std::env::var_os("PROXY_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("proxy.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let config = match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("{}: {e}", path.display())),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every problem at once rather than the first.
+    fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (key, addr) in [("listen", &self.listen), ("admin", &self.admin)] {
+            if addr.parse::<SocketAddr>().is_err() {
+                problems.push(format!("{key}: {addr:?} is not an ip:port"));
+            }
+        }
+        for proxy in &self.trusted_proxies {
+            if let Err(e) = proxy.parse::<Cidr>() {
+                problems.push(format!("trusted_proxies: {e}"));
+            }
+        }
+        let timeouts = &self.timeouts;
+        for (key, value) in [
+            ("connect_secs", timeouts.connect_secs),
+            ("read_secs", timeouts.read_secs),
+            ("idle_secs", timeouts.idle_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("timeouts.{key}: must be at least 1"));
+            }
+        }
+        if self.pool.max_connections_per_backend == 0 {
+            problems.push("pool.max_connections_per_backend: must be at least 1".to_string());
+        }
+        for (name, backends) in &self.upstreams {
+            if backends.is_empty() {
+                problems.push(format!("upstreams.{name}: list at least one backend"));
+            }
+            for backend in backends {
+                let port = backend
+                    .rsplit_once(':')
+                    .map(|(_, port)| port.parse::<u16>());
+                if !matches!(port, Some(Ok(_))) {
+                    problems.push(format!("upstreams.{name}: {backend:?} is not a host:port"));
+                }
+            }
+        }
+
+        for route in &self.routes {
+            let name = &route.name;
+            if !self.upstreams.contains_key(&route.upstream) {
+                problems.push(format!(
+                    "routes.{name}: no upstream named {:?}",
+                    route.upstream
+                ));
+            }
+            match (&route.path_prefix, &route.path_regex) {
+                (Some(_), Some(_)) => problems.push(format!(
+                    "routes.{name}: set path_prefix or path_regex, not both"
+                )),
+                (Some(prefix), None) if !prefix.starts_with('/') => {
+                    problems.push(format!("routes.{name}: path_prefix must start with /"))
+                }
+                (None, Some(pattern)) => {
+                    if let Err(e) = Regex::new(pattern) {
+                        problems.push(format!("routes.{name}: path_regex: {e}"));
+                    }
+                }
+                _ => {}
+            }
+            let timeouts = &route.timeouts;
+            for (key, value) in [
+                ("connect_secs", timeouts.connect_secs),
+                ("read_secs", timeouts.read_secs),
+                ("idle_secs", timeouts.idle_secs),
+            ] {
+                if value == Some(0) {
+                    problems.push(format!("routes.{name}: timeouts.{key} must be at least 1"));
+                }
+            }
+            if route.strip_prefix && route.path_prefix.is_none() {
+                problems.push(format!("routes.{name}: strip_prefix needs a path_prefix"));
+            }
+            if route.strip_prefix && route.rewrite.is_some() {
+                problems.push(format!(
+                    "routes.{name}: set strip_prefix or rewrite, not both"
+                ));
+            }
+            if route.rewrite.is_some() && route.path_prefix.is_none() && route.path_regex.is_none()
+            {
+                problems.push(format!(
+                    "routes.{name}: rewrite needs a path_prefix or path_regex"
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "invalid configuration:\n  {}",
+                problems.join("\n  ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_tables_report_every_problem() {
+        let config: Config = toml::from_str(
+            r#"
+            upstreams = { api = [] }
+            timeouts = { read_secs = 0 }
+            [[routes]]
+            name = "a"
+            path_prefix = "/a"
+            path_regex = "("
+            upstream = "missing"
+            [[routes]]
+            name = "b"
+            path_regex = "("
+            strip_prefix = true
+            upstream = "api"
+            timeouts = { idle_secs = 0 }
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        for expected in [
+            "upstreams.api: list at least one",
+            "routes.a: no upstream",
+            "routes.a: set path_prefix or path_regex",
+            "routes.b: path_regex",
+            "routes.b: strip_prefix needs a path_prefix",
+            "timeouts.read_secs: must be at least 1",
+            "routes.b: timeouts.idle_secs must be at least 1",
+        ] {
+            assert!(err.contains(expected), "{expected:?} missing from {err}");
+        }
+        assert!(Config::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::http::{Headers, RequestHead, ResponseHead};
+
+/// How the proxy names itself in `Via`.
+const PSEUDONYM: &str = "rust-reverse-proxy";
+
+/// Headers that describe one connection rather than the message, so they stop at each
+/// hop (RFC 7230 6.1). `Transfer-Encoding` and `Trailer` are hop-by-hop too, but bodies
+/// are passed on with their framing as it is, so those stay with them.
+const HOP_BY_HOP: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Upgrade",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+];
+
+/// Headers `Connection` must not take away, as the body would no longer be delimited.
+const FRAMING: [&str; 3] = ["Content-Length", "Transfer-Encoding", "Trailer"];
+
+/// An address block such as `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    net: IpAddr,
+    bits: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, bits) = match s.split_once('/') {
+            Some((addr, bits)) => (addr, Some(bits)),
+            None => (s, None),
+        };
+        let net: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{s:?} is not an address or address/bits"))?;
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            Some(bits) => bits
+                .parse::<u8>()
+                .ok()
+                .filter(|&bits| bits <= max)
+                .ok_or_else(|| format!("{s:?} has a prefix longer than {max} bits"))?,
+            None => max,
+        };
+        Ok(Cidr { net, bits })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, width) = match (self.net, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u32::from(net) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = width - u32::from(self.bits);
+        shift == width || net >> shift == ip >> shift
+    }
+}
+
+/// Removes hop-by-hop headers from `headers`, along with any the `Connection` header
+/// names.
+pub fn strip_hop_by_hop(headers: &mut Headers) {
+    let named: Vec<String> = headers
+        .get_all("Connection")
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !FRAMING.iter().any(|f| f.eq_ignore_ascii_case(name)))
+        .collect();
+    for name in HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(named.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+// Adds this hop to `Via`, as `1.1 rust-reverse-proxy` for an HTTP/1.1 message.
+fn via(headers: &mut Headers, version: &str) {
+    let protocol = version.strip_prefix("HTTP/").unwrap_or(version);
+    headers.append("Via", &format!("{protocol} {PSEUDONYM}"));
+}
+
+/// Rewrites the headers of requests on their way to a backend, so it learns where each
+/// came from. What clients say about earlier hops is kept only when they connected from a
+/// trusted proxy; anyone else could claim any address.
+pub struct Forwarding {
+    trusted: Vec<Cidr>,
+}
+
+impl Forwarding {
+    /// `trusted` are the `trusted_proxies` of a validated config.
+    pub fn new(trusted: &[String]) -> Self {
+        Self {
+            trusted: trusted
+                .iter()
+                .map(|cidr| cidr.parse().expect("checked by Config::load"))
+                .collect(),
+        }
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Strips hop-by-hop headers, keeping an upgrade the client asked for, and adds
+    /// `X-Forwarded-For`, `-Proto`, `-Host`, `Forwarded` and `Via`. Returns the request's
+    /// `X-Request-Id`, the client's if it is a usable one and a new one otherwise.
+    pub fn request(&self, request: &mut RequestHead, peer: SocketAddr) -> String {
+        let upgrade = request.wants_upgrade().then(|| {
+            request
+                .headers
+                .get("Upgrade")
+                .unwrap_or_default()
+                .to_string()
+        });
+        let headers = &mut request.headers;
+        strip_hop_by_hop(headers);
+        if let Some(protocol) = upgrade {
+            headers.append("Connection", "Upgrade");
+            headers.append("Upgrade", &protocol);
+        }
+
+        let client = peer.ip().to_canonical();
+        let host = headers.get("Host").map(str::to_string);
+        let trusted = self.trusts(client);
+        let mut chain = Vec::new();
+        if trusted {
+            chain.extend(headers.get_all("X-Forwarded-For").map(str::to_string));
+        } else {
+            for name in [
+                "X-Forwarded-For",
+                "X-Forwarded-Proto",
+                "X-Forwarded-Host",
+                "Forwarded",
+            ] {
+                headers.remove(name);
+            }
+        }
+        chain.push(client.to_string());
+        headers.remove("X-Forwarded-For");
+        headers.append("X-Forwarded-For", &chain.join(", "));
+        // The proxy only listens for plain HTTP.
+        if headers.get("X-Forwarded-Proto").is_none() {
+            headers.append("X-Forwarded-Proto", "http");
+        }
+        if let Some(host) = &host
+            && headers.get("X-Forwarded-Host").is_none()
+        {
+            headers.append("X-Forwarded-Host", host);
+        }
+
+        // RFC 7239 4: IPv6 addresses are bracketed and quoted, and so is anything with
+        // characters a token cannot hold.
+        let mut forwarded = match client {
+            IpAddr::V4(ip) => format!("for={ip}"),
+            IpAddr::V6(ip) => format!("for=\"[{ip}]\""),
+        };
+        forwarded.push_str(";proto=http");
+        if let Some(host) = &host {
+            forwarded.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+        }
+        headers.append("Forwarded", &forwarded);
+
+        via(headers, &request.version);
+        request.tag_request_id()
+    }
+
+    /// Strips hop-by-hop headers from a backend's response and adds `Via`. A `101
+    /// Switching Protocols` keeps its `Upgrade`.
+    pub fn response(&self, response: &mut ResponseHead) {
+        let upgrade = (response.status == 101)
+            .then(|| response.headers.get("Upgrade").map(str::to_string))
+            .flatten();
+        strip_hop_by_hop(&mut response.headers);
+        if let Some(protocol) = upgrade {
+            response.headers.append("Connection", "Upgrade");
+            response.headers.append("Upgrade", &protocol);
+        }
+        via(&mut response.headers, &response.version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[&str]) -> RequestHead {
+        let mut lines = vec!["GET /users HTTP/1.1".to_string()];
+        lines.extend(headers.iter().map(|h| h.to_string()));
+        RequestHead::parse(&lines).unwrap()
+    }
+
+    fn values(request: &RequestHead, name: &str) -> Vec<String> {
+        request.headers.get_all(name).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn cidrs_match_their_block() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains("10.1.200.3".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!(block.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let one: Cidr = "::1".parse().unwrap();
+        assert!(one.contains("::1".parse().unwrap()));
+        assert!(!one.contains("127.0.0.1".parse().unwrap()));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+        for bad in ["10.0.0.0/33", "nope", "10.0.0.0/x"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn hop_by_hop_headers_stop_here_but_framing_does_not() {
+        let mut req = request(&[
+            "Connection: keep-alive, X-Secret, Content-Length",
+            "Keep-Alive: timeout=5",
+            "TE: trailers",
+            "X-Secret: 1",
+            "Content-Length: 3",
+            "Accept: */*",
+        ]);
+        strip_hop_by_hop(&mut req.headers);
+        assert_eq!(
+            req.to_bytes(),
+            b"GET /users HTTP/1.1\r\nContent-Length: 3\r\nAccept: */*\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn untrusted_clients_cannot_spoof_where_they_come_from() {
+        let forwarding = Forwarding::new(&["10.0.0.0/8".to_string()]);
+        let spoofed = [
+            "Host: api.example.com",
+            "X-Forwarded-For: 1.2.3.4",
+            "X-Forwarded-Proto: https",
+            "Forwarded: for=1.2.3.4",
+        ];
+
+        let mut req = request(&spoofed);
+        let id = forwarding.request(&mut req, "192.0.2.7:5000".parse().unwrap());
+        assert_eq!(values(&req, "X-Request-Id"), [id]);
+        assert_eq!(values(&req, "X-Forwarded-For"), ["192.0.2.7"]);
+        assert_eq!(values(&req, "X-Forwarded-Proto"), ["http"]);
+        assert_eq!(values(&req, "X-Forwarded-Host"), ["api.example.com"]);
+        assert_eq!(
+            values(&req, "Forwarded"),
+            ["for=192.0.2.7;proto=http;host=\"api.example.com\""]
+        );
+        assert_eq!(values(&req, "Via"), ["1.1 rust-reverse-proxy"]);
+
+        let mut req = request(&spoofed);
+        forwarding.request(&mut req, "10.0.0.2:5000".parse().unwrap());
+        assert_eq!(values(&req, "X-Forwarded-For"), ["1.2.3.4, 10.0.0.2"]);
+        assert_eq!(values(&req, "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(
+            values(&req, "Forwarded"),
+            [
+                "for=1.2.3.4",
+                "for=10.0.0.2;proto=http;host=\"api.example.com\""
+            ]
+        );
+
+        let mut req = request(&[]);
+        forwarding.request(&mut req, "[::1]:5000".parse().unwrap());
+        assert_eq!(values(&req, "Forwarded"), ["for=\"[::1]\";proto=http"]);
+    }
+
+    #[test]
+    fn upgrades_survive_the_stripping() {
+        let forwarding = Forwarding::new(&[]);
+        let mut req = request(&["Connection: keep-alive, Upgrade", "Upgrade: websocket"]);
+        forwarding.request(&mut req, "127.0.0.1:5000".parse().unwrap());
+        assert!(req.wants_upgrade());
+        assert_eq!(values(&req, "Connection"), ["Upgrade"]);
+
+        let mut res = ResponseHead::parse(&[
+            "HTTP/1.1 101 Switching Protocols".to_string(),
+            "Connection: upgrade".to_string(),
+            "Upgrade: websocket".to_string(),
+        ])
+        .unwrap();
+        forwarding.response(&mut res);
+        assert_eq!(res.headers.get("Upgrade"), Some("websocket"));
+        assert_eq!(res.headers.get("Via"), Some("1.1 rust-reverse-proxy"));
+    }
+}
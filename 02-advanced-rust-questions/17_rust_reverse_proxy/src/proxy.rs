@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+use crate::forwarding::Forwarding;
+use crate::http::{self, Error, RequestHead, reply};
+use crate::routes::{RouteTable, has_dot_segments};
+use crate::upstream::{Failed, Upstreams};
+
+/// What every client connection shares.
+pub struct Proxy {
+    pub routes: RouteTable,
+    pub forwarding: Forwarding,
+    pub upstreams: Upstreams,
+}
+
+/// Handles every request a client sends on one connection. Each request is routed on its
+/// head and then streamed to its backend, and the response streamed back, so neither body
+/// has to fit in memory. Upgraded connections are tunnelled until either side is done.
+/// Requests no route takes are answered 404.
+pub async fn serve(inbound: TcpStream, peer: SocketAddr, proxy: Arc<Proxy>) {
+    let (read, mut client_out) = inbound.into_split();
+    let mut client = BufReader::new(read);
+
+    loop {
+        // Waiting for the next request on a kept-alive connection is bounded by idle too.
+        let lines = match http::idle(proxy.routes.timeouts().idle, http::read_head(&mut client))
+            .await
+        {
+            Ok(Some(lines)) => lines,
+            Ok(None) | Err(Error::Timeout | Error::Io(_)) => return,
+            Err(Error::TooLarge) => {
+                return reply(&mut client_out, 431, "Request Header Fields Too Large").await;
+            }
+            Err(Error::Malformed(_)) => return reply(&mut client_out, 400, "Bad Request").await,
+        };
+        let mut request = match RequestHead::parse(&lines) {
+            Ok(request) => request,
+            Err(_) => return reply(&mut client_out, 400, "Bad Request").await,
+        };
+        let body = match request.body() {
+            Ok(body) => body,
+            Err(_) => return reply(&mut client_out, 400, "Bad Request").await,
+        };
+        if has_dot_segments(request.path()) {
+            return reply(&mut client_out, 400, "Bad Request").await;
+        }
+        let Some(route) = proxy.routes.find(&request) else {
+            // The request body is not read, so the connection cannot carry another one.
+            return reply(&mut client_out, 404, "Not Found").await;
+        };
+        // Decided before the client's hop-by-hop headers are stripped.
+        let keep_alive = request.keep_alive();
+        let id = proxy.forwarding.request(&mut request, peer);
+        route.apply(&mut request);
+        let backend = route.backend();
+        let timeouts = route.timeouts();
+
+        let forwarded = proxy.upstreams.forward(
+            &mut client,
+            &mut client_out,
+            request,
+            body,
+            keep_alive,
+            backend,
+            timeouts,
+            |response| proxy.forwarding.response(response),
+        );
+        match forwarded.await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(Failed::Answer(status, reason, e)) => {
+                eprintln!("❌ {peer} -> {} ({backend}) [{id}]: {e}", route.name());
+                return reply(&mut client_out, status, reason).await;
+            }
+            Err(Failed::Abort(e)) => {
+                eprintln!("❌ {peer} -> {} ({backend}) [{id}]: {e}", route.name());
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use regex::Regex;
+use serde_json::json;
+
+use crate::config::{Config, RouteConfig, TimeoutConfig};
+use crate::http::RequestHead;
+use crate::upstream::Timeouts;
+
+/// A group of interchangeable backends, taken in turn.
+struct Upstream {
+    backends: Vec<String>,
+    next: AtomicUsize,
+}
+
+/// A configured route, ready to match requests against.
+pub struct Route {
+    config: RouteConfig,
+    regex: Option<Regex>,
+    upstream: Arc<Upstream>,
+    timeouts: Timeouts,
+}
+
+impl Route {
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// The next backend of the route's upstream.
+    pub fn backend(&self) -> &str {
+        let backends = &self.upstream.backends;
+        &backends[self.upstream.next.fetch_add(1, Ordering::Relaxed) % backends.len()]
+    }
+
+    fn matches(&self, request: &RequestHead) -> bool {
+        let config = &self.config;
+        let path = request.path();
+        if let Some(prefix) = &config.path_prefix
+            && !under(path, prefix)
+        {
+            return false;
+        }
+        if let Some(regex) = &self.regex
+            && !regex.is_match(path)
+        {
+            return false;
+        }
+        if !config.methods.is_empty()
+            && !config
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&request.method))
+        {
+            return false;
+        }
+        if let Some(host) = &config.host {
+            let sent = request.headers.get("Host").unwrap_or_default();
+            if !without_port(sent).eq_ignore_ascii_case(host) {
+                return false;
+            }
+        }
+        config
+            .headers
+            .iter()
+            .all(|(name, value)| request.headers.get(name) == Some(value.as_str()))
+    }
+
+    /// Rewrites the path and headers of a matched request into what the upstream gets.
+    pub fn apply(&self, request: &mut RequestHead) {
+        let config = &self.config;
+        let (path, query) = match request.target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (request.target.as_str(), None),
+        };
+        let rewritten = match (&config.path_prefix, &self.regex, &config.rewrite) {
+            (Some(prefix), _, Some(to)) => Some(format!("{to}{}", &path[prefix.len()..])),
+            (Some(prefix), _, None) if config.strip_prefix => {
+                Some(path[prefix.len()..].to_string())
+            }
+            (None, Some(regex), Some(to)) => Some(regex.replace(path, to.as_str()).into_owned()),
+            _ => None,
+        };
+        if let Some(mut path) = rewritten {
+            if !path.starts_with('/') {
+                path.insert(0, '/');
+            }
+            request.target = match query {
+                Some(query) => format!("{path}?{query}"),
+                None => path,
+            };
+        }
+
+        for name in config
+            .remove_headers
+            .iter()
+            .chain(config.add_headers.keys())
+        {
+            request.headers.remove(name);
+        }
+        for (name, value) in &config.add_headers {
+            request.headers.append(name, value);
+        }
+    }
+}
+
+// Whether `path` is `prefix` or lies below it.
+fn under(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+// `host:port` or `[v6]:port` without the port. The colons inside a bracketed IPv6
+// address are not a port separator.
+fn without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+/// Whether the path has a `.` or `..` segment, plain or percent-encoded. Routes match
+/// and rewrite the path as sent, so such requests are refused rather than letting
+/// `/users/../admin` through the `/users` route, or out from under a rewrite.
+pub fn has_dot_segments(path: &str) -> bool {
+    let path = path
+        .to_ascii_lowercase()
+        .replace("%2e", ".")
+        .replace("%2f", "/")
+        .replace("%5c", "/");
+    path.split(['/', '\\'])
+        .any(|segment| segment == "." || segment == "..")
+}
+
+fn timeouts(config: TimeoutConfig) -> Timeouts {
+    Timeouts {
+        connect: Duration::from_secs(config.connect_secs),
+        read: Duration::from_secs(config.read_secs),
+        idle: Duration::from_secs(config.idle_secs),
+    }
+}
+
+/// The routes of a validated [`Config`], in the order they are tried.
+pub struct RouteTable {
+    routes: Vec<Route>,
+    timeouts: Timeouts,
+}
+
+impl RouteTable {
+    pub fn new(config: &Config) -> Self {
+        let upstreams: HashMap<_, _> = config
+            .upstreams
+            .iter()
+            .map(|(name, backends)| {
+                let upstream = Upstream {
+                    backends: backends.clone(),
+                    next: AtomicUsize::new(0),
+                };
+                (name.as_str(), Arc::new(upstream))
+            })
+            .collect();
+
+        let mut routes: Vec<Route> = config
+            .routes
+            .iter()
+            .map(|route| {
+                let fallback = config.timeouts;
+                let own = route.timeouts;
+                Route {
+                    config: route.clone(),
+                    regex: route
+                        .path_regex
+                        .as_deref()
+                        .map(|pattern| Regex::new(pattern).expect("checked by Config::load")),
+                    upstream: upstreams[route.upstream.as_str()].clone(),
+                    timeouts: timeouts(TimeoutConfig {
+                        connect_secs: own.connect_secs.unwrap_or(fallback.connect_secs),
+                        read_secs: own.read_secs.unwrap_or(fallback.read_secs),
+                        idle_secs: own.idle_secs.unwrap_or(fallback.idle_secs),
+                    }),
+                }
+            })
+            .collect();
+        // Stable, so equal priorities keep their order in the file.
+        routes.sort_by_key(|route| std::cmp::Reverse(route.config.priority));
+
+        RouteTable {
+            routes,
+            timeouts: timeouts(config.timeouts),
+        }
+    }
+
+    /// The first route, in priority order, that `request` meets.
+    pub fn find(&self, request: &RequestHead) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(request))
+    }
+
+    /// The timeouts of requests no route has been picked for yet.
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// The routes in the order they are tried, with their backends and the timeouts in
+    /// effect.
+    pub fn to_json(&self) -> serde_json::Value {
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|route| {
+                json!({
+                    "route": route.config,
+                    "backends": route.upstream.backends,
+                    "timeouts": {
+                        "connect_secs": route.timeouts.connect.as_secs(),
+                        "read_secs": route.timeouts.read.as_secs(),
+                        "idle_secs": route.timeouts.idle.as_secs(),
+                    },
+                })
+            })
+            .collect();
+        json!({ "routes": routes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str, headers: &[&str]) -> RequestHead {
+        let mut lines = vec![line.to_string()];
+        lines.extend(headers.iter().map(|h| h.to_string()));
+        RequestHead::parse(&lines).unwrap()
+    }
+
+    fn load(toml: &str) -> RouteTable {
+        RouteTable::new(&toml::from_str(toml).unwrap())
+    }
+
+    const ROUTES: &str = r#"
+        [upstreams]
+        users = ["127.0.0.1:3001"]
+        v2 = ["127.0.0.1:4001", "127.0.0.1:4002"]
+
+        [[routes]]
+        name = "users"
+        path_prefix = "/users"
+        upstream = "users"
+
+        [[routes]]
+        name = "users-v2"
+        priority = 10
+        path_prefix = "/users"
+        methods = ["GET"]
+        host = "api.example.com"
+        headers = { "X-Version" = "2" }
+        upstream = "v2"
+        rewrite = "/v2/users"
+        add_headers = { "X-Route" = "v2" }
+        remove_headers = ["Cookie"]
+        timeouts = { read_secs = 2 }
+
+        [[routes]]
+        name = "item"
+        path_regex = "^/items/(\\d+)$"
+        upstream = "users"
+        rewrite = "/catalog/$1"
+    "#;
+
+    #[test]
+    fn routes_are_tried_by_priority_and_every_condition_counts() {
+        let table = load(ROUTES);
+        let v2 = ["Host: API.example.com:3000", "X-Version: 2", "Cookie: a=b"];
+        let find = |line, headers: &[&str]| table.find(&request(line, headers)).map(Route::name);
+
+        assert_eq!(find("GET /users/1 HTTP/1.1", &v2), Some("users-v2"));
+        assert_eq!(find("POST /users/1 HTTP/1.1", &v2), Some("users"));
+        assert_eq!(find("GET /users HTTP/1.1", &v2[..1]), Some("users"));
+        assert_eq!(
+            find("GET /users HTTP/1.1", &["Host: other", "X-Version: 2"]),
+            Some("users")
+        );
+        assert_eq!(find("GET /usersx HTTP/1.1", &[]), None);
+        assert_eq!(find("GET /items/7 HTTP/1.1", &[]), Some("item"));
+        assert_eq!(find("GET /items/x HTTP/1.1", &[]), None);
+
+        let v6 = load(
+            r#"
+            upstreams = { local = ["127.0.0.1:3001"] }
+            [[routes]]
+            name = "local"
+            host = "[::1]"
+            upstream = "local"
+            "#,
+        );
+        for host in ["Host: [::1]", "Host: [::1]:8080"] {
+            assert!(
+                v6.find(&request("GET / HTTP/1.1", &[host])).is_some(),
+                "{host}"
+            );
+        }
+        assert!(
+            v6.find(&request("GET / HTTP/1.1", &["Host: [::2]"]))
+                .is_none()
+        );
+
+        let route = table.find(&request("GET /users HTTP/1.1", &v2)).unwrap();
+        assert_eq!(route.timeouts().read, Duration::from_secs(2));
+        assert_eq!(route.timeouts().connect, Duration::from_secs(5));
+        assert_eq!(
+            [route.backend(), route.backend(), route.backend()],
+            ["127.0.0.1:4001", "127.0.0.1:4002", "127.0.0.1:4001"]
+        );
+    }
+
+    #[test]
+    fn matched_requests_are_rewritten_for_the_upstream() {
+        let table = load(ROUTES);
+        let mut req = request(
+            "GET /users/1?full=1 HTTP/1.1",
+            &[
+                "Host: api.example.com",
+                "X-Version: 2",
+                "Cookie: a=b",
+                "X-Route: spoofed",
+            ],
+        );
+        table.find(&req).unwrap().apply(&mut req);
+        assert_eq!(req.target, "/v2/users/1?full=1");
+        assert_eq!(req.headers.get("Cookie"), None);
+        assert_eq!(req.headers.get_all("X-Route").collect::<Vec<_>>(), ["v2"]);
+
+        let mut req = request("GET /items/42 HTTP/1.1", &[]);
+        table.find(&req).unwrap().apply(&mut req);
+        assert_eq!(req.target, "/catalog/42");
+
+        let stripped = load(
+            r#"
+            upstreams = { api = ["127.0.0.1:3001"] }
+            [[routes]]
+            name = "api"
+            path_prefix = "/api"
+            strip_prefix = true
+            upstream = "api"
+            "#,
+        );
+        for (from, to) in [
+            ("/api/users", "/users"),
+            ("/api", "/"),
+            ("/api?x=1", "/?x=1"),
+        ] {
+            let mut req = request(&format!("GET {from} HTTP/1.1"), &[]);
+            stripped.find(&req).unwrap().apply(&mut req);
+            assert_eq!(req.target, to);
+        }
+    }
+
+    #[test]
+    fn dot_segments_are_caught_however_they_are_spelled() {
+        for path in [
+            "/users/../admin",
+            "/users/..",
+            "/./users",
+            "/users/%2e%2E/admin",
+            "/users/.%2e/admin",
+            "/users/..%2fadmin",
+            "/users/..\\admin",
+        ] {
+            assert!(has_dot_segments(path), "{path}");
+        }
+        for path in ["/users", "/users/", "/users/..x", "/v1.2/.well", "/"] {
+            assert!(!has_dot_segments(path), "{path}");
+        }
+    }
+}
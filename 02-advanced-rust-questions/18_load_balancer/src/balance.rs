@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::config::{BalanceConfig, Strategy};
+use crate::http::RequestHead;
+
+/// Points each backend gets on the consistent-hashing ring, per unit of weight.
+const RING_POINTS: u32 = 100;
+
+/// One backend of a pool, with the requests it is serving right now.
+pub struct Backend {
+    pub address: String,
+    pub weight: u32,
+    // Shared with the same backend in earlier pools, so a reload does not lose count of
+    // requests still running.
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Backend {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request against the backend until the returned lease is dropped.
+    pub fn lease(&self) -> Lease {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            address: self.address.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+/// A request being served by a backend.
+pub struct Lease {
+    pub address: String,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What a strategy may look at to choose a backend, besides the backends themselves.
+pub struct Client<'a> {
+    pub ip: IpAddr,
+    pub request: &'a RequestHead,
+}
+
+/// Chooses the backend for each request. Called concurrently, so any state it keeps
+/// must be safe to share.
+pub trait BalancingStrategy: Send + Sync {
+    /// An index into `backends`, which is never empty.
+    fn pick(&self, backends: &[Backend], client: &Client) -> usize;
+}
+
+/// Each backend in turn.
+pub struct RoundRobin {
+    next: Arc<AtomicUsize>,
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn pick(&self, backends: &[Backend], _: &Client) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % backends.len()
+    }
+}
+
+/// Each backend in turn, as many times per round as its weight, spread out over the round
+/// rather than back to back.
+pub struct WeightedRoundRobin {
+    next: Arc<AtomicUsize>,
+    schedule: Vec<usize>,
+}
+
+impl WeightedRoundRobin {
+    fn new(backends: &[Backend], next: Arc<AtomicUsize>) -> Self {
+        // nginx's smooth weighted round-robin, run once for a whole round.
+        let total: i64 = backends.iter().map(|b| i64::from(b.weight)).sum();
+        let mut current = vec![0i64; backends.len()];
+        let mut schedule = Vec::with_capacity(total as usize);
+        for _ in 0..total {
+            for (current, backend) in current.iter_mut().zip(backends) {
+                *current += i64::from(backend.weight);
+            }
+            let (best, _) = current
+                .iter()
+                .enumerate()
+                .max_by_key(|&(i, &current)| (current, std::cmp::Reverse(i)))
+                .expect("pools are never empty");
+            current[best] -= total;
+            schedule.push(best);
+        }
+        Self { next, schedule }
+    }
+}
+
+impl BalancingStrategy for WeightedRoundRobin {
+    fn pick(&self, _: &[Backend], _: &Client) -> usize {
+        self.schedule[self.next.fetch_add(1, Ordering::Relaxed) % self.schedule.len()]
+    }
+}
+
+/// The backend with the fewest requests in flight relative to its weight. Ties go to
+/// each backend in turn, so an idle pool is still shared out.
+pub struct LeastConnections {
+    next: Arc<AtomicUsize>,
+}
+
+impl BalancingStrategy for LeastConnections {
+    fn pick(&self, backends: &[Backend], _: &Client) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..backends.len())
+            .map(|i| (start + i) % backends.len())
+            .min_by(|&a, &b| load(&backends[a]).cmp(&load(&backends[b])))
+            .expect("pools are never empty")
+    }
+}
+
+// In-flight requests per unit of weight, compared without dividing: a/wa < b/wb.
+fn load(backend: &Backend) -> Load {
+    Load {
+        in_flight: backend.in_flight() as u64,
+        weight: u64::from(backend.weight),
+    }
+}
+
+#[derive(PartialEq, Eq)]
+struct Load {
+    in_flight: u64,
+    weight: u64,
+}
+
+impl Ord for Load {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.in_flight * other.weight).cmp(&(other.in_flight * self.weight))
+    }
+}
+
+impl PartialOrd for Load {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Any backend, with chances in proportion to the weights.
+pub struct Random {
+    rng: Rng,
+}
+
+impl BalancingStrategy for Random {
+    fn pick(&self, backends: &[Backend], _: &Client) -> usize {
+        let total: u64 = backends.iter().map(|b| u64::from(b.weight)).sum();
+        let mut point = self.rng.below(total);
+        for (i, backend) in backends.iter().enumerate() {
+            match point.checked_sub(u64::from(backend.weight)) {
+                Some(rest) => point = rest,
+                None => return i,
+            }
+        }
+        unreachable!("point is below the total weight")
+    }
+}
+
+/// The less loaded of two backends taken at random: nearly as even as least-connections
+/// without looking at every backend.
+pub struct PowerOfTwoChoices {
+    rng: Rng,
+}
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn pick(&self, backends: &[Backend], _: &Client) -> usize {
+        let n = backends.len() as u64;
+        if n == 1 {
+            return 0;
+        }
+        let a = self.rng.below(n);
+        // Any backend but `a`.
+        let b = (a + 1 + self.rng.below(n - 1)) % n;
+        let (a, b) = (a as usize, b as usize);
+        if load(&backends[b]) < load(&backends[a]) {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+/// The same backend for the same client IP, or the same value of a header, for as long
+/// as that backend stays in the pool. Adding or removing a backend only moves the
+/// clients that belong on it.
+pub struct ConsistentHash {
+    header: Option<String>,
+    /// Points on the ring, sorted, with the backend each belongs to.
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHash {
+    fn new(backends: &[Backend], header: Option<String>) -> Self {
+        let mut ring: Vec<(u64, usize)> = backends
+            .iter()
+            .enumerate()
+            .flat_map(|(i, backend)| {
+                (0..RING_POINTS * backend.weight)
+                    .map(move |point| (hash(format!("{}#{point}", backend.address).as_bytes()), i))
+            })
+            .collect();
+        ring.sort_unstable();
+        Self { header, ring }
+    }
+}
+
+impl BalancingStrategy for ConsistentHash {
+    fn pick(&self, _: &[Backend], client: &Client) -> usize {
+        // Clients without the header are kept together by address instead.
+        let key = match self
+            .header
+            .as_deref()
+            .and_then(|name| client.request.headers.get(name))
+        {
+            Some(value) => hash(value.as_bytes()),
+            None => match client.ip.to_canonical() {
+                IpAddr::V4(ip) => hash(&ip.octets()),
+                IpAddr::V6(ip) => hash(&ip.octets()),
+            },
+        };
+        let at = self.ring.partition_point(|&(point, _)| point < key);
+        // Past the last point the ring wraps around to the first.
+        self.ring[at % self.ring.len()].1
+    }
+}
+
+/// 64-bit FNV-1a followed by murmur3's finalizer, which spreads the near-identical
+/// inputs FNV leaves close together. Both are fully specified, so placement stays the
+/// same across restarts, platforms and Rust releases.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    h = (h ^ (h >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h = (h ^ (h >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// splitmix64 over an atomic counter: cheap, lock-free and good enough to spread load.
+struct Rng(AtomicU64);
+
+impl Rng {
+    fn new() -> Self {
+        Rng(AtomicU64::new(RandomState::new().hash_one(0u8)))
+    }
+
+    fn next(&self) -> u64 {
+        let mut z = self
+            .0
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Below `n`, which must not be zero.
+    fn below(&self, n: u64) -> u64 {
+        ((u128::from(self.next()) * u128::from(n)) >> 64) as u64
+    }
+}
+
+/// The backends in rotation and the strategy that chooses between them.
+pub struct BackendPool {
+    backends: Vec<Backend>,
+    strategy: Box<dyn BalancingStrategy>,
+    // Carried over on reload, so the rotation goes on where it was.
+    next: Arc<AtomicUsize>,
+}
+
+impl BackendPool {
+    pub fn new(backends: &[String], balance: &BalanceConfig) -> Self {
+        Self::build(backends, balance, Arc::default(), &HashMap::new())
+    }
+
+    /// A pool for new settings that carries on the rotation where this one is, and keeps
+    /// counting the requests still running on backends both pools have.
+    pub fn with_config(&self, backends: &[String], balance: &BalanceConfig) -> Self {
+        let in_flight = self
+            .backends
+            .iter()
+            .map(|b| (b.address.as_str(), b.in_flight.clone()))
+            .collect();
+        Self::build(backends, balance, self.next.clone(), &in_flight)
+    }
+
+    fn build(
+        addresses: &[String],
+        balance: &BalanceConfig,
+        next: Arc<AtomicUsize>,
+        in_flight: &HashMap<&str, Arc<AtomicUsize>>,
+    ) -> Self {
+        let backends: Vec<Backend> = addresses
+            .iter()
+            .map(|address| Backend {
+                address: address.clone(),
+                weight: balance.weights.get(address).copied().unwrap_or(1),
+                in_flight: in_flight.get(address.as_str()).cloned().unwrap_or_default(),
+            })
+            .collect();
+        let strategy: Box<dyn BalancingStrategy> = match balance.strategy {
+            Strategy::RoundRobin => Box::new(RoundRobin { next: next.clone() }),
+            Strategy::WeightedRoundRobin => {
+                Box::new(WeightedRoundRobin::new(&backends, next.clone()))
+            }
+            Strategy::LeastConnections => Box::new(LeastConnections { next: next.clone() }),
+            Strategy::Random => Box::new(Random { rng: Rng::new() }),
+            Strategy::PowerOfTwoChoices => Box::new(PowerOfTwoChoices { rng: Rng::new() }),
+            Strategy::ConsistentHash => {
+                Box::new(ConsistentHash::new(&backends, balance.hash_header.clone()))
+            }
+        };
+        Self {
+            backends,
+            strategy,
+            next,
+        }
+    }
+
+    /// The backend for `client`'s request, counted as busy until the lease is dropped.
+    /// `None` when no backend is in rotation.
+    pub fn get_next_backend(&self, client: &Client) -> Option<Lease> {
+        if self.backends.is_empty() {
+            return None;
+        }
+        Some(self.backends[self.strategy.pick(&self.backends, client)].lease())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(toml: &str) -> BackendPool {
+        let balance: BalanceConfig = toml::from_str(toml).unwrap();
+        let backends: Vec<String> = (1..=4).map(|i| format!("10.0.0.{i}:80")).collect();
+        BackendPool::new(&backends, &balance)
+    }
+
+    fn request(headers: &[&str]) -> RequestHead {
+        let mut lines = vec!["GET / HTTP/1.1".to_string()];
+        lines.extend(headers.iter().map(|h| h.to_string()));
+        RequestHead::parse(&lines).unwrap()
+    }
+
+    // Sends `requests` requests from different clients and counts where each went.
+    fn spread(pool: &BackendPool, requests: u32) -> Vec<u32> {
+        let request = request(&[]);
+        let mut counts = vec![0; pool.backends.len()];
+        for i in 0..requests {
+            let client = Client {
+                ip: IpAddr::from(i.to_be_bytes()),
+                request: &request,
+            };
+            counts[pool.strategy.pick(&pool.backends, &client)] += 1;
+        }
+        counts
+    }
+
+    fn within(counts: &[u32], expected: &[f64], tolerance: f64) {
+        let total: u32 = counts.iter().sum();
+        for (count, share) in counts.iter().zip(expected) {
+            let seen = f64::from(*count) / f64::from(total);
+            assert!(
+                (seen - share).abs() <= tolerance,
+                "{counts:?} is not close to {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_strategy_shares_requests_out_fairly() {
+        let even = [0.25; 4];
+        assert_eq!(spread(&pool(""), 400), [100; 4]);
+        within(
+            &spread(&pool(r#"strategy = "random""#), 40_000),
+            &even,
+            0.02,
+        );
+        within(
+            &spread(&pool(r#"strategy = "power_of_two_choices""#), 40_000),
+            &even,
+            0.02,
+        );
+        within(
+            &spread(&pool(r#"strategy = "consistent_hash""#), 40_000),
+            &even,
+            0.06,
+        );
+
+        let weights = r#"weights = { "10.0.0.1:80" = 5, "10.0.0.2:80" = 3 }"#;
+        let weighted = pool(&format!("strategy = \"weighted_round_robin\"\n{weights}"));
+        assert_eq!(spread(&weighted, 1000), [500, 300, 100, 100]);
+        // Smooth: the heaviest backend never gets more than two in a row.
+        let order: Vec<_> = (0..10)
+            .map(|_| {
+                weighted
+                    .get_next_backend(&Client {
+                        ip: IpAddr::from([127, 0, 0, 1]),
+                        request: &request(&[]),
+                    })
+                    .unwrap()
+                    .address
+                    .clone()
+            })
+            .collect();
+        assert!(
+            order.windows(3).all(|w| !(w[0] == w[1] && w[1] == w[2])),
+            "{order:?}"
+        );
+        within(
+            &spread(&pool(&format!("strategy = \"random\"\n{weights}")), 40_000),
+            &[0.5, 0.3, 0.1, 0.1],
+            0.02,
+        );
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_backends() {
+        for strategy in ["least_connections", "power_of_two_choices"] {
+            let pool = pool(&format!("strategy = \"{strategy}\""));
+            let request = request(&[]);
+            let client = Client {
+                ip: IpAddr::from([127, 0, 0, 1]),
+                request: &request,
+            };
+            // A slow backend holds on to every request it gets; the others finish at once.
+            let mut slow = Vec::new();
+            for _ in 0..1000 {
+                let lease = pool.get_next_backend(&client).unwrap();
+                if lease.address == "10.0.0.1:80" {
+                    slow.push(lease);
+                }
+            }
+            assert_eq!(pool.backends[0].in_flight(), slow.len());
+            assert!(
+                slow.len() <= 10,
+                "{strategy}: {} went to the slow backend",
+                slow.len()
+            );
+            drop(slow);
+            assert!(pool.backends.iter().all(|b| b.in_flight() == 0));
+        }
+    }
+
+    #[test]
+    fn consistent_hashing_moves_only_the_clients_of_a_removed_backend() {
+        let balance: BalanceConfig =
+            toml::from_str("strategy = \"consistent_hash\"\nhash_header = \"X-User\"").unwrap();
+        let backends: Vec<String> = (1..=4).map(|i| format!("10.0.0.{i}:80")).collect();
+        let before = BackendPool::new(&backends, &balance);
+        let after = before.with_config(&backends[..3], &balance);
+
+        let mut moved = 0;
+        for user in 0..2000 {
+            let request = request(&[&format!("X-User: {user}")]);
+            let client = Client {
+                ip: IpAddr::from([192, 0, 2, (user % 200) as u8]),
+                request: &request,
+            };
+            let was = before.get_next_backend(&client).unwrap().address.clone();
+            assert_eq!(before.get_next_backend(&client).unwrap().address, was);
+            let now = after.get_next_backend(&client).unwrap().address.clone();
+            if was != now {
+                assert_eq!(was, "10.0.0.4:80");
+                moved += 1;
+            }
+        }
+        assert!((300..=700).contains(&moved), "{moved} of 2000 moved");
+    }
+
+    #[test]
+    fn ring_placement_does_not_depend_on_the_toolchain() {
+        // Worked out independently of this code; a change here moves every client.
+        assert_eq!(hash(b""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(hash(b"a"), 0x82a2_a958_a9be_ce5b);
+    }
+
+    #[test]
+    fn reloads_keep_the_rotation_and_the_requests_in_flight() {
+        let balance = BalanceConfig::default();
+        let backends = ["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()];
+        let pool = BackendPool::new(&backends, &balance);
+        let request = request(&[]);
+        let client = Client {
+            ip: IpAddr::from([127, 0, 0, 1]),
+            request: &request,
+        };
+        let running = pool.get_next_backend(&client).unwrap();
+        assert_eq!(running.address, "10.0.0.1:80");
+
+        let next = pool.with_config(&backends, &balance);
+        assert_eq!(next.backends[0].in_flight(), 1);
+        assert_eq!(
+            next.get_next_backend(&client).unwrap().address,
+            "10.0.0.2:80"
+        );
+        drop(running);
+        assert_eq!(next.backends[0].in_flight(), 0);
+
+        let empty = next.with_config(&[], &balance);
+        assert!(empty.get_next_backend(&client).is_none());
+    }
+}
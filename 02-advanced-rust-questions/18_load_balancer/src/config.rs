@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::health::HealthConfig;
+use crate::upstream::PoolConfig;
+
+/// Weighted round-robin plans a whole round ahead, so weights are kept small.
+const MAX_WEIGHT: u32 = 1000;
+
+/// Settings read from `lb.toml`, or the file named by `LB_CONFIG`. Anything the file
+/// leaves out keeps its default. Without a file at startup the defaults are used as they
+/// are; a file that goes missing later fails the reload.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    /// Where `GET /metrics` and `GET /backends` are served.
+    pub admin: String,
+    pub backends: Vec<String>,
+    /// How requests are shared out between `backends`.
+    pub balance: BalanceConfig,
+    pub pool: PoolConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceConfig {
+    pub strategy: Strategy,
+    /// Backends that should take more than one share; the rest weigh 1. Used by every
+    /// strategy but plain round-robin.
+    pub weights: BTreeMap<String, u32>,
+    /// For `consistent_hash`: the header whose value picks the backend. Requests without
+    /// it, or every request when unset, are kept together by client IP.
+    pub hash_header: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    Random,
+    PowerOfTwoChoices,
+    ConsistentHash,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "127.0.0.1:3000".to_string(),
+            admin: "127.0.0.1:3100".to_string(),
+            backends: vec!["127.0.0.1:3001".to_string(), "127.0.0.1:3002".to_string()],
+            balance: BalanceConfig::default(),
+            pool: PoolConfig::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        std::env::var_os("LB_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("lb.toml"))
+    }
+
+    /// Loads the settings at `path`, which must exist.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let config: Config =
+            toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like `load`, but with no file at `path` the defaults apply. For startup only: on
+    /// reload, a file deleted or mid-replace must not swap the live backends for these.
+    pub fn load_or_default(path: &Path) -> Result<Self, String> {
+        match std::fs::metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            _ => Config::load(path),
+        }
+    }
+
+    /// Reports every problem at once rather than the first.
+    fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (key, addr) in [("listen", &self.listen), ("admin", &self.admin)] {
+            if addr.parse::<SocketAddr>().is_err() {
+                problems.push(format!("{key}: {addr:?} is not an ip:port"));
+            }
+        }
+        if self.pool.max_connections_per_backend == 0 {
+            problems.push("pool.max_connections_per_backend: must be at least 1".to_string());
+        }
+        let health = &self.health;
+        for (key, value) in [
+            ("interval_secs", health.interval_secs),
+            ("timeout_secs", health.timeout_secs),
+            ("rise", u64::from(health.rise)),
+            ("fall", u64::from(health.fall)),
+        ] {
+            if value == 0 {
+                problems.push(format!("health.{key}: must be at least 1"));
+            }
+        }
+        if !health.path.starts_with('/') {
+            problems.push("health.path: must start with /".to_string());
+        }
+        if self.backends.is_empty() {
+            problems.push("backends: list at least one".to_string());
+        }
+        for backend in &self.backends {
+            let port = backend
+                .rsplit_once(':')
+                .map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                problems.push(format!("backends: {backend:?} is not a host:port"));
+            }
+        }
+        for (backend, weight) in &self.balance.weights {
+            if !self.backends.contains(backend) {
+                problems.push(format!(
+                    "balance.weights: {backend:?} is not one of the backends"
+                ));
+            }
+            if !(1..=MAX_WEIGHT).contains(weight) {
+                problems.push(format!(
+                    "balance.weights: {backend:?} must weigh between 1 and {MAX_WEIGHT}"
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "invalid configuration:\n  {}",
+                problems.join("\n  ")
+            ))
+        }
+    }
+
+    /// Settings that differ in `next` but are only read at startup.
+    pub fn needs_restart(&self, next: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.listen != next.listen {
+            keys.push("listen");
+        }
+        if self.admin != next.admin {
+            keys.push("admin");
+        }
+        if self.pool != next.pool {
+            keys.push("pool");
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balance_settings_are_checked_against_the_backends() {
+        let config: Config = toml::from_str(
+            r#"
+            backends = ["127.0.0.1:3001"]
+            [balance]
+            strategy = "weighted_round_robin"
+            weights = { "127.0.0.1:3001" = 0, "127.0.0.1:3009" = 2 }
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        for expected in [
+            "\"127.0.0.1:3001\" must weigh between 1 and 1000",
+            "\"127.0.0.1:3009\" is not one of the backends",
+        ] {
+            assert!(err.contains(expected), "{expected:?} missing from {err}");
+        }
+        assert!(toml::from_str::<Config>("balance = { strategy = \"fastest\" }").is_err());
+        assert!(Config::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,624 @@
+// Just enough HTTP/1.1 for a proxy: message heads are parsed so they can be routed and
+// re-sent, and bodies are passed through as they arrive, framing included.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::request_id::{REQUEST_ID, request_id};
+
+/// Largest request or response head accepted.
+pub const MAX_HEAD_BYTES: usize = 64 * 1024;
+/// Longest single line in a head or in chunked framing.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Malformed(&'static str),
+    TooLarge,
+    /// Nothing was read or written for the idle timeout.
+    Timeout,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Malformed(what) => write!(f, "malformed {what}"),
+            Error::TooLarge => write!(f, "head too large"),
+            Error::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Runs `fut`, giving up once `idle` has passed.
+pub async fn idle<T>(
+    idle: Duration,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::time::timeout(idle, fut)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+/// Header fields in the order they arrived. Names compare case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether any `name` field lists `token`, as in `Connection: keep-alive, Upgrade`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    fn parse(lines: &[String]) -> Result<Self, Error> {
+        let mut headers = Headers::default();
+        for line in lines {
+            // Obsolete line folding is rejected rather than guessed at (RFC 7230 3.2.4).
+            if line.starts_with([' ', '\t']) {
+                return Err(Error::Malformed("header folding"));
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(Error::Malformed("header line"))?;
+            if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                return Err(Error::Malformed("header name"));
+            }
+            headers.append(name, value.trim());
+        }
+        Ok(headers)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// How a message's body is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// Responses only: the body runs until the backend closes the connection.
+    UntilClose,
+}
+
+// RFC 7230 3.3.3. Transfer-Encoding wins over Content-Length; a message carrying both is
+// passed on without the Content-Length so nothing downstream can read it differently.
+fn framing(headers: &mut Headers) -> Result<Option<Body>, Error> {
+    if headers.get("Transfer-Encoding").is_some() {
+        let last = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .last()
+            .unwrap_or_default();
+        if !last.trim().eq_ignore_ascii_case("chunked") {
+            return Err(Error::Malformed("transfer-encoding"));
+        }
+        headers.remove("Content-Length");
+        return Ok(Some(Body::Chunked));
+    }
+    let mut lengths = headers
+        .get_all("Content-Length")
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().parse::<u64>());
+    let Some(first) = lengths.next() else {
+        return Ok(None);
+    };
+    let first = first.map_err(|_| Error::Malformed("content-length"))?;
+    if lengths.any(|other| other.ok() != Some(first)) {
+        return Err(Error::Malformed("content-length"));
+    }
+    Ok(Some(Body::Length(first)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    pub fn parse(lines: &[String]) -> Result<Self, Error> {
+        let (line, rest) = lines
+            .split_first()
+            .ok_or(Error::Malformed("request line"))?;
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::Malformed("request line"));
+        };
+        if method.is_empty() || !target.starts_with('/') && target != "*" {
+            return Err(Error::Malformed("request line"));
+        }
+        if !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+            return Err(Error::Malformed("HTTP version"));
+        }
+        Ok(RequestHead {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers: Headers::parse(rest)?,
+        })
+    }
+
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Requests without framing headers have no body.
+    pub fn body(&mut self) -> Result<Body, Error> {
+        Ok(framing(&mut self.headers)?.unwrap_or(Body::Empty))
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    pub fn wants_upgrade(&self) -> bool {
+        self.headers.get("Upgrade").is_some() && self.headers.has_token("Connection", "upgrade")
+    }
+
+    /// Keeps an `X-Request-Id` that looks like an ID, or replaces it with a new UUID, so the
+    /// backend gets exactly one. Returns the ID.
+    pub fn tag_request_id(&mut self) -> String {
+        let id = request_id(self.headers.get(REQUEST_ID));
+        self.headers.remove(REQUEST_ID);
+        self.headers.append(REQUEST_ID, &id);
+        id
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.write(&mut out);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn parse(lines: &[String]) -> Result<Self, Error> {
+        let (line, rest) = lines.split_first().ok_or(Error::Malformed("status line"))?;
+        let mut parts = line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        let status = parts
+            .next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .filter(|s| (100..1000).contains(s))
+            .ok_or(Error::Malformed("status line"))?;
+        if !version.starts_with("HTTP/1.") {
+            return Err(Error::Malformed("HTTP version"));
+        }
+        Ok(ResponseHead {
+            version: version.to_string(),
+            status,
+            reason: parts.next().unwrap_or_default().to_string(),
+            headers: Headers::parse(rest)?,
+        })
+    }
+
+    /// 1xx answers other than 101 come before the real response.
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    /// How the body of this response to a `method` request is delimited.
+    pub fn body(&mut self, method: &str) -> Result<Body, Error> {
+        if method == "HEAD" || self.status < 200 || matches!(self.status, 204 | 304) {
+            return Ok(Body::Empty);
+        }
+        Ok(framing(&mut self.headers)?.unwrap_or(Body::UntilClose))
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        self.headers.write(&mut out);
+        out
+    }
+}
+
+fn keep_alive(version: &str, headers: &Headers) -> bool {
+    if version == "HTTP/1.0" {
+        headers.has_token("Connection", "keep-alive")
+    } else {
+        !headers.has_token("Connection", "close")
+    }
+}
+
+/// A short plain-text response that ends the connection, for errors the proxy answers
+/// itself.
+pub fn error_response(status: u16, reason: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}",
+        reason.len()
+    )
+    .into_bytes()
+}
+
+/// Answers with [`error_response`] and closes the connection.
+pub async fn reply<W: AsyncWrite + Unpin>(to: &mut W, status: u16, reason: &str) {
+    let _ = to.write_all(&error_response(status, reason)).await;
+    let _ = to.shutdown().await;
+}
+
+// One line including its line ending, or `None` at a clean end of stream.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    from: &mut R,
+    limit: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    let n = from.take(limit as u64).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(if n == limit {
+            Error::TooLarge
+        } else {
+            io::Error::from(io::ErrorKind::UnexpectedEof).into()
+        });
+    }
+    Ok(Some(line))
+}
+
+/// Reads a head up to its blank line and returns its lines without line endings. `None`
+/// when the peer closed the connection before sending anything.
+pub async fn read_head<R: AsyncBufRead + Unpin>(
+    from: &mut R,
+) -> Result<Option<Vec<String>>, Error> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let Some(line) = read_line(from, MAX_LINE_BYTES.min(MAX_HEAD_BYTES - total)).await? else {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        };
+        total += line.len();
+        let line = String::from_utf8(line).map_err(|_| Error::Malformed("head encoding"))?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            // Blank lines before a request line are tolerated (RFC 7230 3.5).
+            if lines.is_empty() {
+                continue;
+            }
+            return Ok(Some(lines));
+        }
+        if total >= MAX_HEAD_BYTES {
+            return Err(Error::TooLarge);
+        }
+        lines.push(line.to_string());
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(
+    to: &mut W,
+    bytes: &[u8],
+    timeout: Duration,
+) -> Result<(), Error> {
+    idle(timeout, async { Ok(to.write_all(bytes).await?) }).await
+}
+
+// Copies exactly `n` bytes, waiting at most `timeout` for each read or write.
+async fn copy_exact<R, W>(
+    from: &mut R,
+    to: &mut W,
+    mut n: u64,
+    timeout: Duration,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while n > 0 {
+        let buf = idle(timeout, async { Ok(from.fill_buf().await?) }).await?;
+        if buf.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let len = buf.len().min(usize::try_from(n).unwrap_or(usize::MAX));
+        write_all(to, &buf[..len], timeout).await?;
+        from.consume(len);
+        n -= len as u64;
+    }
+    Ok(())
+}
+
+// Copies until `from` closes. Returns the bytes copied.
+async fn copy_to_end<R, W>(from: &mut R, to: &mut W, timeout: Duration) -> Result<u64, Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+    loop {
+        let buf = idle(timeout, async { Ok(from.fill_buf().await?) }).await?;
+        if buf.is_empty() {
+            return Ok(total);
+        }
+        let len = buf.len();
+        write_all(to, buf, timeout).await?;
+        from.consume(len);
+        total += len as u64;
+    }
+}
+
+/// Passes one message body from `from` to `to` as it arrives, framing and all, and stops
+/// where the body ends so the connection can carry the next message. `timeout` bounds each
+/// wait for the peer, not the whole transfer.
+pub async fn copy_body<R, W>(
+    body: Body,
+    from: &mut R,
+    to: &mut W,
+    timeout: Duration,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::Empty => Ok(()),
+        Body::Length(n) => copy_exact(from, to, n, timeout).await,
+        Body::UntilClose => copy_to_end(from, to, timeout).await.map(drop),
+        Body::Chunked => loop {
+            let line = idle(timeout, read_line(from, MAX_LINE_BYTES))
+                .await?
+                .ok_or(Error::Malformed("chunk size"))?;
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|l| l.split([';', '\r', '\n']).next())
+                .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+                .ok_or(Error::Malformed("chunk size"))?;
+            write_all(to, &line, timeout).await?;
+            if size == 0 {
+                // Trailer fields, up to the blank line that ends the message.
+                loop {
+                    let line = idle(timeout, read_line(from, MAX_LINE_BYTES))
+                        .await?
+                        .ok_or(Error::Malformed("chunked trailer"))?;
+                    write_all(to, &line, timeout).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        return Ok(());
+                    }
+                }
+            }
+            copy_exact(from, to, size, timeout).await?;
+            let end = idle(timeout, read_line(from, 2)).await?;
+            if !matches!(end.as_deref(), Some(b"\r\n" | b"\n")) {
+                return Err(Error::Malformed("chunk"));
+            }
+            write_all(to, b"\r\n", timeout).await?;
+        },
+    }
+}
+
+/// Relays bytes both ways until both sides have finished sending, as after a `101
+/// Switching Protocols`. Each side's end of stream is passed on as a write shutdown. Gives
+/// up once neither side has sent anything for `timeout`.
+pub async fn tunnel<A, B, C, D>(
+    client: &mut A,
+    to_client: &mut B,
+    backend: &mut C,
+    to_backend: &mut D,
+    timeout: Duration,
+) -> Result<(), Error>
+where
+    A: AsyncBufRead + Unpin,
+    B: AsyncWrite + Unpin,
+    C: AsyncBufRead + Unpin,
+    D: AsyncWrite + Unpin,
+{
+    let (mut client_done, mut backend_done) = (false, false);
+    while !(client_done && backend_done) {
+        let sent = tokio::time::timeout(timeout, async {
+            tokio::select! {
+                buf = client.fill_buf(), if !client_done => {
+                    let buf = buf?;
+                    let len = buf.len();
+                    if len == 0 {
+                        client_done = true;
+                        to_backend.shutdown().await?;
+                    } else {
+                        to_backend.write_all(buf).await?;
+                        client.consume(len);
+                    }
+                }
+                buf = backend.fill_buf(), if !backend_done => {
+                    let buf = buf?;
+                    let len = buf.len();
+                    if len == 0 {
+                        backend_done = true;
+                        to_client.shutdown().await?;
+                    } else {
+                        to_client.write_all(buf).await?;
+                        backend.consume(len);
+                    }
+                }
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await;
+        match sent {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::Timeout),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    async fn head(raw: &str) -> Vec<String> {
+        read_head(&mut raw.as_bytes()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn parses_and_rewrites_a_request_head() {
+        let raw = "\r\nPOST /users?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nbody";
+        let mut req = RequestHead::parse(&head(raw).await).unwrap();
+        assert_eq!(req.path(), "/users");
+        assert_eq!(req.headers.get("host"), Some("a"));
+        // Chunked wins, and the conflicting length is dropped before forwarding.
+        assert_eq!(req.body().unwrap(), Body::Chunked);
+        assert_eq!(
+            req.to_bytes(),
+            b"POST /users?x=1 HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        assert!(req.keep_alive());
+
+        for bad in [
+            "GET /users HTTP/2\r\n\r\n",
+            "GET users HTTP/1.1\r\n\r\n",
+            "GET /users HTTP/1.1\r\nBad Name: x\r\n\r\n",
+            "GET /users HTTP/1.1\r\nA: b\r\n folded\r\n\r\n",
+        ] {
+            assert!(RequestHead::parse(&head(bad).await).is_err(), "{bad:?}");
+        }
+        let mut lengths = RequestHead::parse(
+            &head("GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n").await,
+        )
+        .unwrap();
+        assert!(lengths.body().is_err());
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_kept_or_replaced() {
+        let mut req =
+            RequestHead::parse(&head("GET / HTTP/1.1\r\nX-Request-Id: edge-42:7\r\n\r\n").await)
+                .unwrap();
+        assert_eq!(req.tag_request_id(), "edge-42:7");
+        assert_eq!(req.headers.get_all(REQUEST_ID).count(), 1);
+
+        for raw in [
+            "GET / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Request-Id: a b\"c\r\n\r\n",
+        ] {
+            let mut req = RequestHead::parse(&head(raw).await).unwrap();
+            let id = req.tag_request_id();
+            assert!(uuid::Uuid::parse_str(&id).is_ok(), "{id}");
+            assert_eq!(
+                req.headers.get_all(REQUEST_ID).collect::<Vec<_>>(),
+                [id.as_str()]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused() {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_LINE_BYTES)
+        );
+        assert!(matches!(
+            read_head(&mut raw.as_bytes()).await,
+            Err(Error::TooLarge)
+        ));
+        assert!(read_head(&mut &b""[..]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn response_framing_follows_status_and_method() {
+        let mut res =
+            ResponseHead::parse(&head("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n").await)
+                .unwrap();
+        assert_eq!(res.body("GET").unwrap(), Body::Length(3));
+        assert_eq!(res.body("HEAD").unwrap(), Body::Empty);
+        let mut res = ResponseHead::parse(&head("HTTP/1.0 404 NOT FOUND\r\n\r\n").await).unwrap();
+        assert_eq!(res.body("GET").unwrap(), Body::UntilClose);
+        assert!(!res.keep_alive());
+        let res = ResponseHead::parse(&head("HTTP/1.1 100 Continue\r\n\r\n").await).unwrap();
+        assert!(res.is_interim());
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_pass_through_and_stop_at_their_end() {
+        let raw = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nTrailer: x\r\n\r\nNEXT";
+        let mut from = &raw[..];
+        let mut to = Vec::new();
+        copy_body(Body::Chunked, &mut from, &mut to, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(to, &raw[..raw.len() - 4]);
+        assert_eq!(from, b"NEXT");
+
+        let mut from = &b"hello world"[..];
+        let mut to = Vec::new();
+        copy_body(Body::Length(5), &mut from, &mut to, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!((to.as_slice(), from), (&b"hello"[..], &b" world"[..]));
+
+        let mut short = &b"abc"[..];
+        assert!(
+            copy_body(Body::Length(5), &mut short, &mut Vec::new(), TIMEOUT)
+                .await
+                .is_err()
+        );
+        let mut bad = &b"zz\r\n"[..];
+        assert!(
+            copy_body(Body::Chunked, &mut bad, &mut Vec::new(), TIMEOUT)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,68 +1,157 @@
+mod admin;
+mod balance;
+mod config;
+mod health;
+mod http;
+mod reload;
+mod request_id;
+mod upstream;
+
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use arc_swap::ArcSwap;
+use tokio::io::BufReader;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
 
-#[derive(Clone)]
-struct BackendPool {
-    backends: Vec<String>,
-    next: Arc<Mutex<usize>>,
-}
+use balance::{BackendPool, Client};
+use config::Config;
+use health::HealthChecker;
+use http::{Error, RequestHead, reply};
+use reload::Reloader;
+use upstream::{Failed, Timeouts, Upstreams};
 
-impl BackendPool {
-    fn new(backends: Vec<&str>) -> Self {
-        Self {
-            backends: backends.into_iter().map(|s| s.to_string()).collect(),
-            next: Arc::new(Mutex::new(0)),
-        }
-    }
+/// Passes each request a client sends to the backend the pool's strategy picks, over a
+/// pooled connection, and streams the response back. Each request goes to the pool in
+/// effect when it arrives, so kept-alive clients follow reloads and health changes too.
+async fn handle_client(
+    inbound: TcpStream,
+    peer: SocketAddr,
+    pools: Arc<ArcSwap<BackendPool>>,
+    upstreams: Arc<Upstreams>,
+) {
+    let (read, mut client_out) = inbound.into_split();
+    let mut client = BufReader::new(read);
+    let timeouts = Timeouts::default();
+
+    loop {
+        let lines = match http::idle(timeouts.idle, http::read_head(&mut client)).await {
+            Ok(Some(lines)) => lines,
+            Ok(None) | Err(Error::Timeout | Error::Io(_)) => return,
+            Err(Error::TooLarge) => {
+                return reply(&mut client_out, 431, "Request Header Fields Too Large").await;
+            }
+            Err(Error::Malformed(_)) => return reply(&mut client_out, 400, "Bad Request").await,
+        };
+        let Ok(mut request) = RequestHead::parse(&lines) else {
+            return reply(&mut client_out, 400, "Bad Request").await;
+        };
+        let Ok(body) = request.body() else {
+            return reply(&mut client_out, 400, "Bad Request").await;
+        };
+        let keep_alive = request.keep_alive();
+        // Kept for the error log; the head itself goes to the backend.
+        let path = request.path().to_string();
+        let id = request.tag_request_id();
 
-    async fn get_next_backend(&self) -> String {
-        let mut idx = self.next.lock().await;
-        let backend = self.backends[*idx].clone();
-        *idx = (*idx + 1) % self.backends.len();
-        backend
+        // Held until the response is through, so the backend counts as busy until then.
+        let lease = pools.load().get_next_backend(&Client {
+            ip: peer.ip(),
+            request: &request,
+        });
+        let Some(lease) = lease else {
+            eprintln!("❌ No healthy backends [{id}]");
+            return reply(&mut client_out, 503, "Service Unavailable").await;
+        };
+        let backend_addr = &lease.address;
+        let forwarded = upstreams.forward(
+            &mut client,
+            &mut client_out,
+            request,
+            body,
+            keep_alive,
+            backend_addr,
+            timeouts,
+            |_| {},
+        );
+        match forwarded.await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(Failed::Answer(status, reason, e)) => {
+                eprintln!("❌ {backend_addr} {path} [{id}]: {e}");
+                return reply(&mut client_out, status, reason).await;
+            }
+            Err(Failed::Abort(e)) => {
+                eprintln!("❌ {backend_addr} {path} [{id}]: {e}");
+                return;
+            }
+        }
     }
 }
 
-async fn handle_client(mut inbound: TcpStream, pool: BackendPool) {
-    let mut buffer = [0; 4096];
-    let n = match inbound.read(&mut buffer).await {
-        Ok(n) if n > 0 => n,
-        _ => return,
-    };
-
-    let backend_addr = pool.get_next_backend().await;
-
-    if let Ok(mut backend) = TcpStream::connect(backend_addr).await {
-        // Forward request
-        backend.write_all(&buffer[..n]).await.unwrap();
-
-        // Read backend response
-        let mut resp = vec![0; 4096];
-        let m = backend.read(&mut resp).await.unwrap();
-
-        // Send back to client
-        inbound.write_all(&resp[..m]).await.unwrap();
-    } else {
-        inbound
-            .write_all(b"HTTP/1.1 502 BAD GATEWAY\r\n\r\nBackend not reachable")
-            .await
-            .unwrap();
-    }
+/// Replaces the pool with one over the healthy backends of `config`.
+fn rotate(pool: &ArcSwap<BackendPool>, health: &HealthChecker, config: &Config) {
+    let next = pool
+        .load()
+        .with_config(&health.healthy(&config.backends), &config.balance);
+    pool.store(Arc::new(next));
 }
 
 #[tokio::main]
 async fn main() {
-    // Example backend servers (replace with multiple backends if needed)
-    let backend_pool = BackendPool::new(vec!["127.0.0.1:3001", "127.0.0.1:3002"]);
+    let path = Config::path();
+    let config = Config::load_or_default(&path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let health = Arc::new(HealthChecker::default());
+    health.sync(&config.backends, &config.health);
+    let backend_pool = Arc::new(ArcSwap::from_pointee(BackendPool::new(
+        &config.backends,
+        &config.balance,
+    )));
+
+    // Edit the config file or send SIGHUP to change the backends without dropping anyone.
+    let reloader = Arc::new(Reloader::new(path, config.clone()));
+    {
+        let backend_pool = backend_pool.clone();
+        let health = health.clone();
+        reloader.watch(move |_, config| {
+            health.sync(&config.backends, &config.health);
+            rotate(&backend_pool, &health, config);
+        });
+    }
+    // Backends leave the pool when they fail their health checks and rejoin on recovery.
+    {
+        let backend_pool = backend_pool.clone();
+        let health = health.clone();
+        let reloader = reloader.clone();
+        tokio::spawn(async move {
+            loop {
+                health.changed().await;
+                rotate(&backend_pool, &health, &reloader.current());
+            }
+        });
+    }
+    // Kept across reloads, so backends that stay keep their idle connections.
+    let upstreams = Arc::new(Upstreams::new(config.pool));
+    tokio::spawn(admin::run(
+        config.admin.clone(),
+        reloader.clone(),
+        upstreams.clone(),
+        health.clone(),
+    ));
 
-    let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-    println!("Load balancer running on 127.0.0.1:3000");
+    let listener = TcpListener::bind(&config.listen).await.unwrap();
+    println!("Load balancer running on {}", config.listen);
 
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let pool = backend_pool.clone();
-        tokio::spawn(handle_client(socket, pool));
+        let (socket, peer) = listener.accept().await.unwrap();
+        tokio::spawn(handle_client(
+            socket,
+            peer,
+            backend_pool.clone(),
+            upstreams.clone(),
+        ));
     }
 }
@@ -0,0 +1,168 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+
+use crate::config::Config;
+
+/// How often the config file is checked for changes.
+const POLL_EVERY: Duration = Duration::from_secs(2);
+
+/// Holds the current settings and replaces them on SIGHUP or when the config file
+/// changes. New settings are swapped in only once they have loaded and validated;
+/// otherwise the current ones stay and the error is logged.
+pub struct Reloader {
+    path: PathBuf,
+    current: ArcSwap<Config>,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Reloader {
+    pub fn new(path: PathBuf, config: Config) -> Self {
+        Self {
+            path,
+            current: ArcSwap::from_pointee(config),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    /// The settings in effect.
+    pub fn current(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    /// Loads the settings again and swaps them in. Returns the previous and the new ones.
+    pub fn reload(&self) -> Result<(Arc<Config>, Arc<Config>), String> {
+        let next = match Config::load(&self.path) {
+            Ok(next) => Arc::new(next),
+            Err(e) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("❌ Config reload failed, keeping the current settings: {e}");
+                return Err(e);
+            }
+        };
+        let previous = self.current.swap(next.clone());
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+        println!(
+            "🔄 Reloaded {}: backends {:?}",
+            self.path.display(),
+            next.backends
+        );
+        let restart = previous.needs_restart(&next);
+        if !restart.is_empty() {
+            println!(
+                "⚠️ Changes to {} take effect after a restart",
+                restart.join(", ")
+            );
+        }
+        Ok((previous, next))
+    }
+
+    /// Reloads on SIGHUP and whenever the config file changes, then hands the previous
+    /// and new settings to `apply`. Failed reloads are logged and counted, and `apply` is
+    /// not called.
+    pub fn watch<F>(self: &Arc<Self>, apply: F)
+    where
+        F: Fn(&Config, &Config) + Send + 'static,
+    {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    eprintln!("⚠️ Cannot listen for SIGHUP, watching the file only: {e}");
+                    None
+                }
+            };
+            let mut ticker = tokio::time::interval(POLL_EVERY);
+            let mut seen = reloader.stamp();
+            loop {
+                tokio::select! {
+                    _ = hangups(&mut hangup) => {}
+                    _ = ticker.tick() => {
+                        if reloader.stamp() == seen {
+                            continue;
+                        }
+                    }
+                }
+                // Also after SIGHUP, so the edit that prompted it is not reloaded twice.
+                seen = reloader.stamp();
+                if let Ok((previous, next)) = reloader.reload() {
+                    apply(&previous, &next);
+                }
+            }
+        });
+    }
+
+    /// The reload counters in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP config_reloads_total Config reloads, by whether the new settings were applied."
+        );
+        let _ = writeln!(out, "# TYPE config_reloads_total counter");
+        for (result, count) in [("success", &self.succeeded), ("failure", &self.failed)] {
+            let _ = writeln!(
+                out,
+                "config_reloads_total{{result=\"{result}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+
+    // Changes when the file is written, created or removed.
+    fn stamp(&self) -> Option<(SystemTime, u64)> {
+        let meta = std::fs::metadata(&self.path).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
+}
+
+// Resolves on the next SIGHUP, and never once there is no way to receive one.
+async fn hangups(hangup: &mut Option<Signal>) {
+    if let Some(signal) = hangup {
+        if signal.recv().await.is_some() {
+            return;
+        }
+        *hangup = None;
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_current_settings_when_the_file_is_invalid_or_missing() {
+        let dir = std::env::temp_dir().join(format!("lb-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lb.toml");
+        std::fs::write(&path, "backends = [\"127.0.0.1:4001\"]\n").unwrap();
+        let reloader = Reloader::new(path.clone(), Config::load(&path).unwrap());
+
+        std::fs::write(&path, "backends = []\n").unwrap();
+        assert!(reloader.reload().is_err());
+        // As when the file is deleted, or briefly gone while an editor replaces it.
+        std::fs::remove_file(&path).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.current().backends, ["127.0.0.1:4001"]);
+
+        std::fs::write(&path, "backends = [\"127.0.0.1:4002\"]\n").unwrap();
+        let (previous, next) = reloader.reload().unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(previous.backends, ["127.0.0.1:4001"]);
+        assert_eq!(reloader.current(), next);
+        let metrics = reloader.metrics();
+        assert!(metrics.contains("config_reloads_total{result=\"success\"} 1"));
+        assert!(metrics.contains("config_reloads_total{result=\"failure\"} 2"));
+    }
+}
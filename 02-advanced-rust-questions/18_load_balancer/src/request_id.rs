@@ -0,0 +1,22 @@
+// Every request leaves the proxy with exactly one `X-Request-Id`, so the backend's log lines
+// can be tied to the client's and to the proxy's own.
+
+/// Header carrying the ID, as the middleware servers name it.
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+/// Keeps `incoming` if it looks like an ID, otherwise makes a new UUID.
+pub fn request_id(incoming: Option<&str>) -> String {
+    match incoming {
+        Some(id) if valid_request_id(id) => id.to_string(),
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+// IDs end up in log lines and other services' headers, so only short, plain ones are
+// trusted.
+fn valid_request_id(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
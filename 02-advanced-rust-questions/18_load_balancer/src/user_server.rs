@@ -13,6 +13,16 @@ pub async fn run() {
             let mut buffer = [0; 1024];
             let n = socket.read(&mut buffer).await.unwrap();
             let request = String::from_utf8_lossy(&buffer[..n]);
+            // Echoed back so the client can quote the ID the proxy gave its request.
+            let request_id = request
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("X-Request-Id")
+                        .then(|| format!("X-Request-Id: {}\r\n", value.trim()))
+                })
+                .unwrap_or_default();
 
             let response = if request.starts_with("GET /users") {
                 let users = json!([
@@ -20,12 +30,13 @@ pub async fn run() {
                     { "id": 2, "name": "Bob" }
                 ]);
                 format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
                     users.to_string().len(),
+                    request_id,
                     users
                 )
             } else {
-                "HTTP/1.1 404 NOT FOUND\r\n\r\nNot Found".to_string()
+                format!("HTTP/1.1 404 NOT FOUND\r\n{request_id}\r\nNot Found")
             };
 
             socket.write_all(response.as_bytes()).await.unwrap();
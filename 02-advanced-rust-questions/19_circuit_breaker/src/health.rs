@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::http::{self, Body, ResponseHead};
+
+/// Most of a health check's body that is read and compared.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// The `[health]` settings: how each backend is checked in the background.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub probe: Probe,
+    /// For `http` probes, the path asked for with `GET`.
+    pub path: String,
+    /// For `http` probes.
+    pub expect_status: u16,
+    /// For `http` probes, text the body must contain.
+    pub expect_body: Option<String>,
+    pub interval_secs: u64,
+    /// For a whole check, connecting included.
+    pub timeout_secs: u64,
+    /// Checks in a row an unhealthy backend must pass to be taken back.
+    pub rise: u32,
+    /// Checks in a row a healthy backend must fail to be taken out.
+    pub fall: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probe: Probe::Tcp,
+            path: "/health".to_string(),
+            expect_status: 200,
+            expect_body: None,
+            interval_secs: 5,
+            timeout_secs: 2,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// Backends are not checked and always count as healthy.
+    None,
+    /// A backend is healthy if it accepts a connection.
+    Tcp,
+    /// A backend is healthy if it answers `GET path` as expected.
+    Http,
+}
+
+/// What the checks have found out about one backend.
+struct Status {
+    healthy: AtomicBool,
+    passed: AtomicU64,
+    failed: AtomicU64,
+    /// Results in a row that disagree with `healthy`, and the last failure.
+    streak: Mutex<(u32, Option<String>)>,
+}
+
+impl Status {
+    fn new() -> Self {
+        // Backends start in rotation, so a restart does not wait out `rise` checks.
+        Self {
+            healthy: AtomicBool::new(true),
+            passed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            streak: Mutex::new((0, None)),
+        }
+    }
+
+    /// Counts one check, and returns the backend's new health when it changes.
+    fn record(&self, result: Result<(), String>, config: &HealthConfig) -> Option<bool> {
+        let ok = result.is_ok();
+        let counter = if ok { &self.passed } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut streak = self.streak.lock().unwrap();
+        if let Err(e) = result {
+            streak.1 = Some(e);
+        }
+        let healthy = self.healthy.load(Ordering::Relaxed);
+        if ok == healthy {
+            streak.0 = 0;
+            return None;
+        }
+        streak.0 += 1;
+        let needed = if healthy { config.fall } else { config.rise };
+        if streak.0 < needed {
+            return None;
+        }
+        streak.0 = 0;
+        self.healthy.store(ok, Ordering::Relaxed);
+        Some(ok)
+    }
+}
+
+/// A backend being checked. Dropping it stops the checks.
+struct Checked {
+    status: Arc<Status>,
+    config: HealthConfig,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Checked {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Checks every backend of the current settings in the background. Backends that fail
+/// `fall` checks in a row are unhealthy until they pass `rise` in a row.
+#[derive(Default)]
+pub struct HealthChecker {
+    backends: Mutex<HashMap<String, Checked>>,
+    changed: Arc<Notify>,
+}
+
+impl HealthChecker {
+    /// Starts checking `backends` as `config` says, and stops checking any others. Backends
+    /// already checked keep their health.
+    pub fn sync(&self, backends: &[String], config: &HealthConfig) {
+        let mut checked = self.backends.lock().unwrap();
+        if config.probe == Probe::None {
+            checked.clear();
+            return;
+        }
+        checked.retain(|addr, _| backends.contains(addr));
+        for addr in backends {
+            let status = match checked.get(addr) {
+                Some(current) if current.config == *config => continue,
+                Some(current) => current.status.clone(),
+                None => Arc::new(Status::new()),
+            };
+            let task = tokio::spawn(check(
+                addr.clone(),
+                config.clone(),
+                status.clone(),
+                self.changed.clone(),
+            ));
+            checked.insert(
+                addr.clone(),
+                Checked {
+                    status,
+                    config: config.clone(),
+                    task,
+                },
+            );
+        }
+    }
+
+    /// Whether `addr` belongs in rotation. Backends that are not checked always do.
+    pub fn is_healthy(&self, addr: &str) -> bool {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(addr)
+            .is_none_or(|checked| checked.status.healthy.load(Ordering::Relaxed))
+    }
+
+    /// The healthy ones of `backends`, in the same order.
+    pub fn healthy(&self, backends: &[String]) -> Vec<String> {
+        backends
+            .iter()
+            .filter(|addr| self.is_healthy(addr))
+            .cloned()
+            .collect()
+    }
+
+    /// Resolves once a backend has turned healthy or unhealthy since the last call.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Each checked backend with its health and check counts, as served on `GET /backends`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let checked = self.backends.lock().unwrap();
+        let mut backends: Vec<_> = checked.iter().collect();
+        backends.sort_by(|a, b| a.0.cmp(b.0));
+        let backends: Vec<_> = backends
+            .into_iter()
+            .map(|(addr, checked)| {
+                let status = &checked.status;
+                json!({
+                    "backend": addr,
+                    "healthy": status.healthy.load(Ordering::Relaxed),
+                    "probe": checked.config.probe,
+                    "checks": {
+                        "passed": status.passed.load(Ordering::Relaxed),
+                        "failed": status.failed.load(Ordering::Relaxed),
+                    },
+                    "last_failure": status.streak.lock().unwrap().1,
+                })
+            })
+            .collect();
+        json!({ "backends": backends })
+    }
+
+    /// Backend health and check counts in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let checked = self.backends.lock().unwrap();
+        let mut backends: Vec<_> = checked.iter().collect();
+        backends.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP backend_healthy Whether each backend passes its health checks and is in rotation."
+        );
+        let _ = writeln!(out, "# TYPE backend_healthy gauge");
+        for (addr, checked) in &backends {
+            let healthy = checked.status.healthy.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "backend_healthy{{backend=\"{addr}\"}} {}",
+                u8::from(healthy)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP backend_health_checks_total Health checks of each backend, by result."
+        );
+        let _ = writeln!(out, "# TYPE backend_health_checks_total counter");
+        for (addr, checked) in &backends {
+            let status = &checked.status;
+            for (result, count) in [("pass", &status.passed), ("fail", &status.failed)] {
+                let _ = writeln!(
+                    out,
+                    "backend_health_checks_total{{backend=\"{addr}\",result=\"{result}\"}} {}",
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+        out
+    }
+}
+
+// Checks `addr` every interval until aborted.
+async fn check(addr: String, config: HealthConfig, status: Arc<Status>, changed: Arc<Notify>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    // A slow check pushes the next one back rather than bunching them up.
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match status.record(probe(&addr, &config).await, &config) {
+            Some(true) => println!("✅ Backend {addr} passes its health checks, back in rotation"),
+            Some(false) => {
+                let reason = status.streak.lock().unwrap().1.clone().unwrap_or_default();
+                eprintln!("⚠️ Backend {addr} failed its health checks, out of rotation: {reason}");
+            }
+            None => continue,
+        }
+        changed.notify_one();
+    }
+}
+
+/// Checks `addr` once.
+async fn probe(addr: &str, config: &HealthConfig) -> Result<(), String> {
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let check = async {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("connect: {e}"))?;
+        match config.probe {
+            Probe::Http => http_probe(stream, addr, config).await,
+            Probe::Tcp | Probe::None => Ok(()),
+        }
+    };
+    tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("no answer within {}s", config.timeout_secs)))
+}
+
+async fn http_probe(stream: TcpStream, addr: &str, config: &HealthConfig) -> Result<(), String> {
+    let mut stream = BufReader::new(stream);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {addr}\r\nUser-Agent: rust-load-balancer-health\r\nConnection: close\r\n\r\n",
+        config.path
+    );
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| format!("send: {e}"))?;
+
+    let lines = http::read_head(&mut stream)
+        .await
+        .map_err(|e| format!("response: {e}"))?
+        .ok_or("closed without answering")?;
+    let mut response = ResponseHead::parse(&lines).map_err(|e| format!("response: {e}"))?;
+    if response.status != config.expect_status {
+        return Err(format!(
+            "status {} instead of {}",
+            response.status, config.expect_status
+        ));
+    }
+    let Some(expected) = &config.expect_body else {
+        return Ok(());
+    };
+
+    let body = response.body("GET").map_err(|e| format!("response: {e}"))?;
+    if matches!(body, Body::Length(n) if n > MAX_BODY_BYTES) {
+        return Err("body too large".to_string());
+    }
+    let mut read = Vec::new();
+    let mut limited = (&mut stream).take(MAX_BODY_BYTES);
+    // Chunked bodies keep their framing, which the expected text is unlikely to span.
+    http::copy_body(
+        body,
+        &mut limited,
+        &mut read,
+        Duration::from_secs(config.timeout_secs),
+    )
+    .await
+    .map_err(|e| format!("body: {e}"))?;
+    if String::from_utf8_lossy(&read).contains(expected.as_str()) {
+        Ok(())
+    } else {
+        Err(format!("body does not contain {expected:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // A backend that answers every connection with `response`.
+    async fn backend(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    // An address nothing listens on.
+    async fn closed() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn backends_fall_and_rise_after_enough_checks_in_a_row() {
+        let config = HealthConfig {
+            rise: 2,
+            fall: 3,
+            ..HealthConfig::default()
+        };
+        let status = Status::new();
+        let fail = || Err("down".to_string());
+        assert_eq!(status.record(fail(), &config), None);
+        assert_eq!(status.record(fail(), &config), None);
+        // A pass in between starts the count again.
+        assert_eq!(status.record(Ok(()), &config), None);
+        assert_eq!(status.record(fail(), &config), None);
+        assert_eq!(status.record(fail(), &config), None);
+        assert_eq!(status.record(fail(), &config), Some(false));
+        assert_eq!(status.record(fail(), &config), None);
+        assert_eq!(status.record(Ok(()), &config), None);
+        assert_eq!(status.record(Ok(()), &config), Some(true));
+        assert_eq!(status.passed.load(Ordering::Relaxed), 3);
+        assert_eq!(status.failed.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn probes_check_the_connection_status_and_body() {
+        let tcp = HealthConfig::default();
+        let http = HealthConfig {
+            probe: Probe::Http,
+            expect_body: Some("\"ok\"".to_string()),
+            ..HealthConfig::default()
+        };
+
+        let up = backend("HTTP/1.1 200 OK\r\nContent-Length: 15\r\n\r\n{\"status\":\"ok\"}").await;
+        let chunked = backend(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nf\r\n{\"status\":\"ok\"}\r\n0\r\n\r\n",
+        )
+        .await;
+        let failing =
+            backend("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+        let degraded =
+            backend("HTTP/1.1 200 OK\r\nContent-Length: 21\r\n\r\n{\"status\":\"degraded\"}").await;
+        let down = closed().await;
+
+        assert_eq!(probe(&up, &tcp).await, Ok(()));
+        assert!(probe(&down, &tcp).await.unwrap_err().starts_with("connect"));
+        assert_eq!(probe(&up, &http).await, Ok(()));
+        assert_eq!(probe(&chunked, &http).await, Ok(()));
+        assert_eq!(
+            probe(&failing, &http).await,
+            Err("status 503 instead of 200".to_string())
+        );
+        assert_eq!(
+            probe(&degraded, &http).await,
+            Err("body does not contain \"\\\"ok\\\"\"".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn unhealthy_backends_leave_the_rotation() {
+        let up = backend("").await;
+        let down = closed().await;
+        let checker = HealthChecker::default();
+        let config = HealthConfig {
+            interval_secs: 1,
+            fall: 1,
+            ..HealthConfig::default()
+        };
+        let backends = [up.clone(), down.clone()];
+        checker.sync(&backends, &config);
+        assert_eq!(checker.healthy(&backends), backends);
+
+        tokio::time::timeout(Duration::from_secs(5), checker.changed())
+            .await
+            .unwrap();
+        assert_eq!(checker.healthy(&backends), [up]);
+        let metrics = checker.metrics();
+        assert!(metrics.contains(&format!("backend_healthy{{backend=\"{down}\"}} 0")));
+        let json = checker.to_json();
+        let listed = json["backends"].as_array().unwrap();
+        let entry = listed.iter().find(|b| b["backend"] == down).unwrap();
+        assert_eq!(entry["healthy"], false);
+        assert_eq!(entry["checks"]["failed"], 1);
+        assert!(
+            entry["last_failure"]
+                .as_str()
+                .unwrap()
+                .starts_with("connect")
+        );
+
+        // Backends that are not checked any more are in rotation again.
+        checker.sync(
+            &backends,
+            &HealthConfig {
+                probe: Probe::None,
+                ..config
+            },
+        );
+        assert_eq!(checker.healthy(&backends), backends);
+    }
+}
@@ -1,7 +1,25 @@
+mod admin;
+mod config;
+mod health;
+mod http;
+mod reload;
+mod request_id;
+mod upstream;
+
+use arc_swap::ArcSwap;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::BufReader;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
-use std::{sync::Arc, time::{Duration, Instant}};
+
+use config::Config;
+use health::HealthChecker;
+use http::{Error, RequestHead, reply};
+use reload::Reloader;
+use upstream::{Failed, Timeouts, Upstreams};
 
 #[derive(Clone)]
 struct Backend {
@@ -18,25 +36,34 @@ enum CircuitState {
     HalfOpen,
 }
 
-#[derive(Clone)]
 struct BackendPool {
     backends: Vec<Backend>,
     next: Arc<Mutex<usize>>,
+    health: Arc<HealthChecker>,
 }
 
 impl BackendPool {
-    fn new(addrs: Vec<&str>) -> Self {
+    fn new(addrs: &[String], health: Arc<HealthChecker>) -> Self {
+        Self {
+            backends: addrs.iter().map(|a| Backend::new(a)).collect(),
+            next: Arc::new(Mutex::new(0)),
+            health,
+        }
+    }
+
+    /// A pool over `addrs` that carries on the rotation where this one is. Backends that
+    /// stay keep their circuit state, so a reload does not close an open circuit.
+    fn with_backends(&self, addrs: &[String]) -> Self {
         Self {
             backends: addrs
-                .into_iter()
-                .map(|a| Backend {
-                    address: a.to_string(),
-                    failure_count: Arc::new(Mutex::new(0)),
-                    last_failure: Arc::new(Mutex::new(None)),
-                    state: Arc::new(Mutex::new(CircuitState::Closed)),
+                .iter()
+                .map(|a| match self.backends.iter().find(|b| &b.address == a) {
+                    Some(backend) => backend.clone(),
+                    None => Backend::new(a),
                 })
                 .collect(),
-            next: Arc::new(Mutex::new(0)),
+            next: self.next.clone(),
+            health: self.health.clone(),
         }
     }
 
@@ -44,10 +71,14 @@ impl BackendPool {
         let total = self.backends.len();
         for _ in 0..total {
             let mut idx = self.next.lock().await;
+            // The list may have shrunk on reload.
+            *idx %= total;
             let backend = &self.backends[*idx];
             *idx = (*idx + 1) % total;
 
-            if backend.is_available().await {
+            // Unhealthy backends are skipped before their circuit is asked, so it does not
+            // go half-open for a backend that is known to be down.
+            if self.health.is_healthy(&backend.address) && backend.is_available().await {
                 return Some(backend.clone());
             }
         }
@@ -56,17 +87,26 @@ impl BackendPool {
 }
 
 impl Backend {
+    fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            failure_count: Arc::new(Mutex::new(0)),
+            last_failure: Arc::new(Mutex::new(None)),
+            state: Arc::new(Mutex::new(CircuitState::Closed)),
+        }
+    }
+
     async fn is_available(&self) -> bool {
         let mut state = self.state.lock().await;
         match *state {
             CircuitState::Closed => true,
             CircuitState::Open => {
                 let last = self.last_failure.lock().await;
-                if let Some(time) = *last {
-                    if time.elapsed() > Duration::from_secs(10) {
-                        *state = CircuitState::HalfOpen;
-                        return true;
-                    }
+                if let Some(time) = *last
+                    && time.elapsed() > Duration::from_secs(10)
+                {
+                    *state = CircuitState::HalfOpen;
+                    return true;
                 }
                 false
             }
@@ -95,50 +135,136 @@ impl Backend {
     }
 }
 
-async fn handle_client(mut inbound: TcpStream, pool: BackendPool) {
-    let mut buffer = [0; 4096];
-    let n = match inbound.read(&mut buffer).await {
-        Ok(n) if n > 0 => n,
-        _ => return,
-    };
-
-    if let Some(backend) = pool.get_next_backend().await {
-        match TcpStream::connect(&backend.address).await {
-            Ok(mut backend_stream) => {
-                if backend_stream.write_all(&buffer[..n]).await.is_ok() {
-                    let mut resp = vec![0; 4096];
-                    match backend_stream.read(&mut resp).await {
-                        Ok(m) if m > 0 => {
-                            backend.record_success().await;
-                            inbound.write_all(&resp[..m]).await.unwrap();
-                            return;
-                        }
-                        _ => backend.record_failure().await,
-                    }
-                } else {
+/// Passes each request a client sends to the next backend whose circuit lets it through,
+/// over a pooled connection, and streams the response back. Backends that cannot be
+/// reached or do not answer count towards opening their circuit.
+async fn handle_client(inbound: TcpStream, pool: Arc<BackendPool>, upstreams: Arc<Upstreams>) {
+    let (read, mut client_out) = inbound.into_split();
+    let mut client = BufReader::new(read);
+    let timeouts = Timeouts::default();
+
+    loop {
+        let lines = match http::idle(timeouts.idle, http::read_head(&mut client)).await {
+            Ok(Some(lines)) => lines,
+            Ok(None) | Err(Error::Timeout | Error::Io(_)) => return,
+            Err(Error::TooLarge) => {
+                return reply(&mut client_out, 431, "Request Header Fields Too Large").await;
+            }
+            Err(Error::Malformed(_)) => return reply(&mut client_out, 400, "Bad Request").await,
+        };
+        let Ok(mut request) = RequestHead::parse(&lines) else {
+            return reply(&mut client_out, 400, "Bad Request").await;
+        };
+        let Ok(body) = request.body() else {
+            return reply(&mut client_out, 400, "Bad Request").await;
+        };
+        let keep_alive = request.keep_alive();
+        // Kept for the error log; the head itself goes to the backend.
+        let path = request.path().to_string();
+        let id = request.tag_request_id();
+
+        let Some(backend) = pool.get_next_backend().await else {
+            eprintln!("❌ All backends unavailable [{id}]");
+            return reply(&mut client_out, 502, "Bad Gateway").await;
+        };
+        let forwarded = upstreams.forward(
+            &mut client,
+            &mut client_out,
+            request,
+            body,
+            keep_alive,
+            &backend.address,
+            timeouts,
+            |_| {},
+        );
+        match forwarded.await {
+            Ok(keep_alive) => {
+                backend.record_success().await;
+                if !keep_alive {
+                    return;
+                }
+            }
+            Err(Failed::Answer(status, reason, e)) => {
+                eprintln!("❌ {} {path} [{id}]: {e}", backend.address);
+                // A 503 means our own connection limit was reached, which says nothing
+                // about the backend.
+                if status != 503 {
                     backend.record_failure().await;
                 }
+                return reply(&mut client_out, status, reason).await;
+            }
+            // The backend answered, so its circuit is left as it is.
+            Err(Failed::Abort(e)) => {
+                eprintln!("❌ {} {path} [{id}]: {e}", backend.address);
+                return;
             }
-            Err(_) => backend.record_failure().await,
         }
     }
-
-    inbound
-        .write_all(b"HTTP/1.1 502 BAD GATEWAY\r\n\r\nAll backends unavailable")
-        .await
-        .unwrap();
 }
 
 #[tokio::main]
 async fn main() {
-    let pool = BackendPool::new(vec!["127.0.0.1:3001", "127.0.0.1:3002"]);
+    let path = Config::path();
+    let config = Config::load_or_default(&path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    // Shared by every pool, so backends keep their health across reloads.
+    let health = Arc::new(HealthChecker::default());
+    health.sync(&config.backends, &config.health);
+    let pool = Arc::new(ArcSwap::from_pointee(BackendPool::new(
+        &config.backends,
+        health.clone(),
+    )));
+
+    // Edit the config file or send SIGHUP to change the backends without dropping anyone.
+    let reloader = Arc::new(Reloader::new(path, config.clone()));
+    {
+        let pool = pool.clone();
+        let health = health.clone();
+        reloader.watch(move |_, config| {
+            health.sync(&config.backends, &config.health);
+            let next = pool.load().with_backends(&config.backends);
+            pool.store(Arc::new(next));
+        });
+    }
+    // Circuits are consulted as requests arrive; this only reports what is in rotation.
+    {
+        let health = health.clone();
+        let reloader = reloader.clone();
+        tokio::spawn(async move {
+            loop {
+                health.changed().await;
+                let backends = &reloader.current().backends;
+                match health.healthy(backends).len() {
+                    0 => eprintln!("❌ No backend passes its health checks"),
+                    up => println!(
+                        "{up} of {} backends pass their health checks",
+                        backends.len()
+                    ),
+                }
+            }
+        });
+    }
+    // Kept across reloads, so backends that stay keep their idle connections.
+    let upstreams = Arc::new(Upstreams::new(config.pool));
+    tokio::spawn(admin::run(
+        config.admin.clone(),
+        reloader.clone(),
+        upstreams.clone(),
+        health,
+    ));
 
-    let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-    println!("Load Balancer with Circuit Breaker running on 127.0.0.1:3000");
+    let listener = TcpListener::bind(&config.listen).await.unwrap();
+    println!(
+        "Load Balancer with Circuit Breaker running on {}",
+        config.listen
+    );
 
     loop {
         let (socket, _) = listener.accept().await.unwrap();
-        let pool = pool.clone();
-        tokio::spawn(handle_client(socket, pool));
+        // Connections already open keep the pool they started with.
+        let pool = pool.load_full();
+        tokio::spawn(handle_client(socket, pool, upstreams.clone()));
     }
-}
\ No newline at end of file
+}
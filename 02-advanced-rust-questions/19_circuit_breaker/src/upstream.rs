@@ -0,0 +1,679 @@
+// Shared by the reverse proxy and both load balancers.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+
+use crate::http::{self, Body, Error, RequestHead, ResponseHead};
+
+/// The `[pool]` settings: how many connections to each backend are opened and kept.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Open connections to one backend, busy or idle. Requests beyond it wait for one.
+    pub max_connections_per_backend: usize,
+    /// Idle connections kept per backend; any more are closed as they come back.
+    pub max_idle_per_backend: usize,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections_per_backend: 100,
+            max_idle_per_backend: 16,
+            idle_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the TCP connection to a backend.
+    pub connect: Duration,
+    /// For a backend to start answering once the request is sent.
+    pub read: Duration,
+    /// For either side to send or accept more bytes mid-message, and for a kept-alive
+    /// client to start its next request.
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(5),
+            read: Duration::from_secs(30),
+            idle: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckoutError {
+    /// The backend stayed at its connection limit for the whole connect timeout.
+    Exhausted,
+    Timeout,
+    Io(io::Error),
+}
+
+impl fmt::Display for CheckoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckoutError::Exhausted => write!(f, "connection limit reached"),
+            CheckoutError::Timeout => write!(f, "connect timed out"),
+            CheckoutError::Io(e) => write!(f, "connect: {e}"),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    // Counts the connection against its backend's limit for as long as it is open.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connection {
+    // An idle connection should have nothing to say. If it can be read from, the
+    // backend has closed it or sent something no request asked for.
+    fn is_stale(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        !matches!(
+            self.reader.get_ref().try_read(&mut [0; 1]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock
+        )
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    opened: AtomicU64,
+    reused: AtomicU64,
+    stale: AtomicU64,
+    expired: AtomicU64,
+    overflow: AtomicU64,
+    retried: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+// Picks one of the counters out of a backend's stats.
+type Counter = fn(&Stats) -> &AtomicU64;
+
+struct Backend {
+    idle: Mutex<Vec<(Connection, Instant)>>,
+    permits: Arc<Semaphore>,
+    stats: Stats,
+}
+
+/// Keep-alive connections to every backend, opened as requests need them and kept open
+/// between requests.
+pub struct Upstreams {
+    config: PoolConfig,
+    backends: Mutex<HashMap<String, Arc<Backend>>>,
+}
+
+impl Upstreams {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn backend(&self, addr: &str) -> Arc<Backend> {
+        let mut backends = self.backends.lock().unwrap();
+        backends
+            .entry(addr.to_string())
+            .or_insert_with(|| {
+                Arc::new(Backend {
+                    idle: Mutex::new(Vec::new()),
+                    permits: Arc::new(Semaphore::new(self.config.max_connections_per_backend)),
+                    stats: Stats::default(),
+                })
+            })
+            .clone()
+    }
+
+    /// A connection to `addr`: the most recently used idle one that is still open, or else
+    /// a new one. At the backend's connection limit this waits for one to close, up to
+    /// `connect_timeout` altogether.
+    pub async fn checkout(
+        &self,
+        addr: &str,
+        connect_timeout: Duration,
+    ) -> Result<Pooled, CheckoutError> {
+        let backend = self.backend(addr);
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        loop {
+            let Some((conn, since)) = backend.idle.lock().unwrap().pop() else {
+                break;
+            };
+            if since.elapsed() > idle_timeout {
+                backend.stats.expired.fetch_add(1, Ordering::Relaxed);
+            } else if conn.is_stale() {
+                backend.stats.stale.fetch_add(1, Ordering::Relaxed);
+            } else {
+                backend.stats.reused.fetch_add(1, Ordering::Relaxed);
+                return Ok(self.pooled(backend, conn, true));
+            }
+        }
+        self.open(backend, addr, connect_timeout).await
+    }
+
+    /// A new connection to `addr`, for repeating a request whose reused connection had
+    /// been closed by the backend.
+    pub async fn reconnect(
+        &self,
+        addr: &str,
+        connect_timeout: Duration,
+    ) -> Result<Pooled, CheckoutError> {
+        let backend = self.backend(addr);
+        backend.stats.retried.fetch_add(1, Ordering::Relaxed);
+        self.open(backend, addr, connect_timeout).await
+    }
+
+    async fn open(
+        &self,
+        backend: Arc<Backend>,
+        addr: &str,
+        connect_timeout: Duration,
+    ) -> Result<Pooled, CheckoutError> {
+        let deadline = tokio::time::Instant::now() + connect_timeout;
+        let permit = match tokio::time::timeout_at(
+            deadline,
+            backend.permits.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(permit) => permit.expect("backend semaphores are never closed"),
+            Err(_) => {
+                backend.stats.exhausted.fetch_add(1, Ordering::Relaxed);
+                return Err(CheckoutError::Exhausted);
+            }
+        };
+        let stream = match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(CheckoutError::Io(e)),
+            Err(_) => return Err(CheckoutError::Timeout),
+        };
+        let _ = stream.set_nodelay(true);
+        backend.stats.opened.fetch_add(1, Ordering::Relaxed);
+        let (reader, writer) = stream.into_split();
+        let conn = Connection {
+            reader: BufReader::new(reader),
+            writer,
+            _permit: permit,
+        };
+        Ok(self.pooled(backend, conn, false))
+    }
+
+    fn pooled(&self, backend: Arc<Backend>, conn: Connection, reused: bool) -> Pooled {
+        Pooled {
+            conn,
+            reused,
+            backend,
+            config: self.config,
+        }
+    }
+
+    /// Pool state and counters for every backend used so far, in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut backends: Vec<_> = self
+            .backends
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, backend)| (addr.clone(), backend.clone()))
+            .collect();
+        backends.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP upstream_connections Open connections to each backend, by whether a request is using them."
+        );
+        let _ = writeln!(out, "# TYPE upstream_connections gauge");
+        for (addr, backend) in &backends {
+            let open =
+                self.config.max_connections_per_backend - backend.permits.available_permits();
+            let idle = backend.idle.lock().unwrap().len();
+            for (state, count) in [("busy", open.saturating_sub(idle)), ("idle", idle)] {
+                let _ = writeln!(
+                    out,
+                    "upstream_connections{{backend=\"{addr}\",state=\"{state}\"}} {count}"
+                );
+            }
+        }
+
+        let counters: [(&str, &str, Counter); 4] = [
+            (
+                "upstream_connections_opened_total",
+                "Connections opened to each backend.",
+                |s| &s.opened,
+            ),
+            (
+                "upstream_connections_reused_total",
+                "Requests sent on an idle connection instead of a new one.",
+                |s| &s.reused,
+            ),
+            (
+                "upstream_requests_retried_total",
+                "Requests repeated on a new connection after a reused one turned out closed.",
+                |s| &s.retried,
+            ),
+            (
+                "upstream_pool_exhausted_total",
+                "Requests that gave up waiting for a backend below its connection limit.",
+                |s| &s.exhausted,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (addr, backend) in &backends {
+                let count = counter(&backend.stats).load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{{backend=\"{addr}\"}} {count}");
+            }
+        }
+
+        let name = "upstream_connections_discarded_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Idle connections closed instead of reused: found closed or readable (stale), idle too long (expired), or beyond max_idle_per_backend (overflow)."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (addr, backend) in &backends {
+            let stats = &backend.stats;
+            for (reason, count) in [
+                ("stale", &stats.stale),
+                ("expired", &stats.expired),
+                ("overflow", &stats.overflow),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "{name}{{backend=\"{addr}\",reason=\"{reason}\"}} {}",
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// How a request that could not be seen through ended.
+pub enum Failed {
+    /// Nothing has reached the client yet, so it can still be told what went wrong.
+    Answer(u16, &'static str, String),
+    /// Part of the response may already be out, so the client can only be cut off.
+    Abort(String),
+}
+
+impl Upstreams {
+    /// Sends one request to `backend` on a pooled connection and streams the response
+    /// back to the client, passing every response head through `respond` first. A request
+    /// that finds its reused connection closed is sent again on a new one when that is
+    /// safe. Returns whether the client connection can carry another request, which
+    /// `keep_alive` says the client wants.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn forward<R, W>(
+        &self,
+        client: &mut R,
+        client_out: &mut W,
+        mut request: RequestHead,
+        body: Body,
+        keep_alive: bool,
+        backend: &str,
+        timeouts: Timeouts,
+        respond: impl Fn(&mut ResponseHead),
+    ) -> Result<bool, Failed>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // HTTP/1.1 connections stay open unless either side says otherwise.
+        if request.version == "HTTP/1.0" && !request.wants_upgrade() {
+            request.headers.append("Connection", "keep-alive");
+        }
+        let head = request.to_bytes();
+
+        let mut retry = false;
+        let (mut conn, sent, mut response) = loop {
+            let checkout = if retry {
+                self.reconnect(backend, timeouts.connect).await
+            } else {
+                self.checkout(backend, timeouts.connect).await
+            };
+            let mut conn = match checkout {
+                Ok(conn) => conn,
+                Err(e @ CheckoutError::Timeout) => {
+                    return Err(Failed::Answer(504, "Gateway Timeout", e.to_string()));
+                }
+                Err(e @ CheckoutError::Exhausted) => {
+                    return Err(Failed::Answer(503, "Service Unavailable", e.to_string()));
+                }
+                Err(e) => return Err(Failed::Answer(502, "Bad Gateway", e.to_string())),
+            };
+            let can_retry = conn.can_retry(&request, body);
+            let (backend_in, backend_out) = conn.split();
+
+            // The body is sent while waiting for the answer: a backend may answer early,
+            // or ask for the body with `100 Continue` first. The read timeout only starts
+            // once the request is out; a slow upload is bounded by `idle` instead.
+            let (sent_tx, sent_rx) = watch::channel(false);
+            let sending = async {
+                let sent = async {
+                    http::idle(timeouts.idle, async {
+                        Ok(backend_out.write_all(&head).await?)
+                    })
+                    .await?;
+                    http::copy_body(body, client, backend_out, timeouts.idle).await
+                }
+                .await;
+                sent_tx.send_replace(true);
+                sent
+            };
+            let answering = async {
+                loop {
+                    let deadline = async {
+                        let _ = sent_rx.clone().wait_for(|sent| *sent).await;
+                        tokio::time::sleep(timeouts.read).await;
+                    };
+                    let lines = tokio::select! {
+                        lines = http::read_head(backend_in) => lines?,
+                        () = deadline => return Err(Error::Timeout),
+                    };
+                    let lines = lines.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::ConnectionAborted, "closed before answering")
+                    })?;
+                    let mut response = ResponseHead::parse(&lines)?;
+                    if !response.is_interim() {
+                        return Ok(response);
+                    }
+                    respond(&mut response);
+                    client_out.write_all(&response.to_bytes()).await?;
+                }
+            };
+            let (sent, answered) = tokio::join!(sending, answering);
+            match answered {
+                Ok(response) => break (conn, sent, response),
+                // How a reused connection the backend had closed fails.
+                Err(Error::Io(_)) if can_retry && !retry => retry = true,
+                Err(Error::Timeout) => {
+                    return Err(Failed::Answer(
+                        504,
+                        "Gateway Timeout",
+                        "no response in time".to_string(),
+                    ));
+                }
+                Err(e) => {
+                    return Err(Failed::Answer(502, "Bad Gateway", format!("response: {e}")));
+                }
+            }
+        };
+        // The response may still be useful to the client, but the connection is out of step.
+        let request_sent = sent.is_ok();
+        let (backend_in, backend_out) = conn.split();
+
+        if response.status == 101 && request.wants_upgrade() {
+            respond(&mut response);
+            write(client_out, &response.to_bytes(), timeouts.idle).await?;
+            http::tunnel(client, client_out, backend_in, backend_out, timeouts.idle)
+                .await
+                .map_err(|e| Failed::Abort(format!("tunnel: {e}")))?;
+            return Ok(false);
+        }
+
+        let body = match response.body(&request.method) {
+            Ok(body) => body,
+            Err(e) => return Err(Failed::Answer(502, "Bad Gateway", format!("response: {e}"))),
+        };
+        let reusable = request_sent && response.keep_alive() && body != Body::UntilClose;
+        // Whether the backend keeps its connection has no bearing on the client's, unless
+        // the body only ends when the backend closes.
+        let keep_alive = request_sent && keep_alive && body != Body::UntilClose;
+        respond(&mut response);
+        if !keep_alive {
+            response.headers.append("Connection", "close");
+        } else if request.version == "HTTP/1.0" {
+            response.headers.append("Connection", "keep-alive");
+        }
+        write(client_out, &response.to_bytes(), timeouts.idle).await?;
+        http::copy_body(body, backend_in, client_out, timeouts.idle)
+            .await
+            .map_err(|e| Failed::Abort(format!("response body: {e}")))?;
+
+        if reusable {
+            conn.release();
+        }
+        Ok(keep_alive)
+    }
+}
+
+async fn write<W: AsyncWrite + Unpin>(
+    to: &mut W,
+    bytes: &[u8],
+    timeout: Duration,
+) -> Result<(), Failed> {
+    http::idle(timeout, async { Ok(to.write_all(bytes).await?) })
+        .await
+        .map_err(|e| Failed::Abort(format!("client: {e}")))
+}
+
+/// A connection checked out for one request. Dropping it closes the connection; only
+/// [`Pooled::release`] keeps it for the next request.
+pub struct Pooled {
+    conn: Connection,
+    reused: bool,
+    backend: Arc<Backend>,
+    config: PoolConfig,
+}
+
+impl Pooled {
+    pub fn split(&mut self) -> (&mut BufReader<OwnedReadHalf>, &mut OwnedWriteHalf) {
+        (&mut self.conn.reader, &mut self.conn.writer)
+    }
+
+    /// Whether `request` can go again on a new connection after failing on this one
+    /// without an answer. A reused connection may have been closed by the backend just as
+    /// it was picked, and a request that is idempotent and has no body, so nothing of it
+    /// was consumed from the client, is safe to repeat (RFC 7230 6.3.1).
+    pub fn can_retry(&self, request: &RequestHead, body: Body) -> bool {
+        self.reused
+            && body == Body::Empty
+            && matches!(
+                request.method.as_str(),
+                "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE"
+            )
+    }
+
+    /// Keeps the connection for another request. Only for connections whose last response
+    /// was read to its end and that the backend keeps open.
+    pub fn release(self) {
+        let mut idle = self.backend.idle.lock().unwrap();
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        // Oldest first, so expired connections are at the front.
+        let expired = idle
+            .iter()
+            .take_while(|(_, since)| since.elapsed() > idle_timeout)
+            .count();
+        idle.drain(..expired);
+        self.backend
+            .stats
+            .expired
+            .fetch_add(expired as u64, Ordering::Relaxed);
+        if idle.len() < self.config.max_idle_per_backend {
+            idle.push((self.conn, Instant::now()));
+        } else {
+            self.backend.stats.overflow.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // A backend that hands every connection it accepts to the test.
+    async fn backend() -> (String, mpsc::UnboundedReceiver<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (accepted, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = accepted.send(stream);
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn released_connections_are_reused_until_the_backend_closes_them() {
+        let (addr, mut accepted) = backend().await;
+        let upstreams = Upstreams::new(PoolConfig::default());
+
+        let first = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(!first.reused);
+        let server_side = accepted.recv().await.unwrap();
+        first.release();
+        let again = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(again.reused);
+        again.release();
+
+        drop(server_side);
+        // Let the close reach this side.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let fresh = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(!fresh.reused);
+        accepted.recv().await.unwrap();
+
+        let metrics = upstreams.metrics();
+        for line in [
+            format!("upstream_connections_opened_total{{backend=\"{addr}\"}} 2"),
+            format!("upstream_connections_reused_total{{backend=\"{addr}\"}} 1"),
+            format!(
+                "upstream_connections_discarded_total{{backend=\"{addr}\",reason=\"stale\"}} 1"
+            ),
+            format!("upstream_connections{{backend=\"{addr}\",state=\"busy\"}} 1"),
+        ] {
+            assert!(metrics.contains(&line), "{line} missing from\n{metrics}");
+        }
+    }
+
+    #[tokio::test]
+    async fn backends_are_held_to_their_limits() {
+        let (addr, _accepted) = backend().await;
+        let upstreams = Upstreams::new(PoolConfig {
+            max_connections_per_backend: 2,
+            max_idle_per_backend: 1,
+            idle_timeout_secs: 30,
+        });
+
+        let a = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        let b = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(matches!(
+            upstreams.checkout(&addr, Duration::from_millis(50)).await,
+            Err(CheckoutError::Exhausted)
+        ));
+
+        // Only one is kept idle; the other is closed, which frees its slot.
+        a.release();
+        b.release();
+        let reused = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(reused.reused);
+        let opened = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(!opened.reused);
+        assert!(upstreams.metrics().contains(&format!(
+            "upstream_connections_discarded_total{{backend=\"{addr}\",reason=\"overflow\"}} 1"
+        )));
+    }
+
+    #[tokio::test]
+    async fn the_read_timeout_starts_once_a_slow_upload_is_sent() {
+        let (addr, mut accepted) = backend().await;
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(accepted.recv().await.unwrap());
+            http::read_head(&mut stream).await.unwrap();
+            let mut body = [0; 5];
+            tokio::io::AsyncReadExt::read_exact(&mut stream, &mut body)
+                .await
+                .unwrap();
+            stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+        });
+
+        // Five bytes, 100ms apart: the upload outlasts the read timeout, but no write
+        // waits as long as the idle one.
+        let (mut uploader, client) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for byte in b"hello" {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                uploader.write_all(&[*byte]).await.unwrap();
+            }
+        });
+        let mut client = BufReader::new(client);
+        let mut request = RequestHead::parse(&[
+            "POST /upload HTTP/1.1".to_string(),
+            "Content-Length: 5".to_string(),
+        ])
+        .unwrap();
+        let body = request.body().unwrap();
+        let timeouts = Timeouts {
+            connect: TIMEOUT,
+            read: Duration::from_millis(200),
+            idle: TIMEOUT,
+        };
+
+        let mut out = Vec::new();
+        let upstreams = Upstreams::new(PoolConfig::default());
+        let forwarded = upstreams
+            .forward(
+                &mut client,
+                &mut out,
+                request,
+                body,
+                true,
+                &addr,
+                timeouts,
+                |_| {},
+            )
+            .await;
+        assert!(matches!(forwarded, Ok(true)));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"), "{out}");
+        assert!(out.ends_with("\r\n\r\nok"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn only_safe_requests_are_retried() {
+        let (addr, _accepted) = backend().await;
+        let upstreams = Upstreams::new(PoolConfig::default());
+        let get = RequestHead::parse(&["GET / HTTP/1.1".to_string()]).unwrap();
+        let post = RequestHead::parse(&["POST / HTTP/1.1".to_string()]).unwrap();
+
+        let new = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(!new.can_retry(&get, Body::Empty));
+        new.release();
+        let reused = upstreams.checkout(&addr, TIMEOUT).await.unwrap();
+        assert!(reused.can_retry(&get, Body::Empty));
+        assert!(!reused.can_retry(&get, Body::Length(3)));
+        assert!(!reused.can_retry(&post, Body::Empty));
+    }
+}